@@ -8,6 +8,7 @@ use crate::bitcoin::adapter::Adapter;
 use crate::bitcoin::{Bitcoin, Nbtc};
 use crate::cosmos::{Chain, Cosmos, Proof};
 
+use crate::governance::{ParamChange, ParamGovernance};
 use crate::incentives::Incentives;
 use bitcoin::util::merkleblock::PartialMerkleTree;
 use bitcoin::{Script, Transaction, TxOut};
@@ -69,7 +70,7 @@ const VALIDATOR_BOOTSTRAP_ADDRESS: &str = "nomic1fd9mxxt84lw3jdcsmjh6jy8m6luafhq
 const IBC_FEE_USATS: u64 = 1_000_000;
 const DECLARE_FEE_USATS: u64 = 100_000_000;
 
-#[orga(version = 3)]
+#[orga(version = 4)]
 pub struct InnerApp {
     #[call]
     pub accounts: Accounts<Nom>,
@@ -100,8 +101,11 @@ pub struct InnerApp {
     pub incentives: Incentives,
 
     #[cfg(feature = "testnet")]
-    #[orga(version(V3))]
+    #[orga(version(V3, V4))]
     pub cosmos: Cosmos,
+
+    #[orga(version(V4))]
+    pub governance: ParamGovernance,
 }
 
 #[orga]
@@ -170,6 +174,7 @@ impl InnerApp {
             dest.source_port()?;
             dest.source_channel()?;
             dest.sender_address()?;
+            self.bitcoin.check_not_paused()?;
 
             let signer = self.signer()?;
             let mut coins = self.bitcoin.accounts.withdraw(signer, amount)?;
@@ -200,6 +205,7 @@ impl InnerApp {
             self.ibc
                 .transfer_mut()
                 .burn_coins_execute(&signer, &coins.into())?;
+            self.bitcoin.ibc_escrow = self.bitcoin.ibc_escrow.saturating_sub(amount.into());
             self.bitcoin.accounts.deposit(signer, amount.into())?;
 
             Ok(())
@@ -337,11 +343,17 @@ impl InnerApp {
                     self.ibc
                         .transfer_mut()
                         .burn_coins_execute(&receiver, &coins.into())?;
-                    if self.bitcoin.add_withdrawal(script, amount.into()).is_err() {
+                    self.bitcoin.ibc_escrow = self.bitcoin.ibc_escrow.saturating_sub(amount.into());
+                    if self
+                        .bitcoin
+                        .add_withdrawal(receiver, script, amount.into())
+                        .is_err()
+                    {
                         let coins = Coin::<Nbtc>::mint(amount);
                         self.ibc
                             .transfer_mut()
                             .mint_coins_execute(&receiver, &coins.into())?;
+                        self.bitcoin.ibc_escrow += u64::from(amount);
                     }
                 }
             }
@@ -362,11 +374,32 @@ impl InnerApp {
     fn deduct_nbtc_fee(&mut self, amount: Amount) -> Result<()> {
         disable_fee();
         let signer = self.signer()?;
-        self.bitcoin.accounts.withdraw(signer, amount)?.burn();
+        let fee = self.bitcoin.accounts.withdraw(signer, amount)?;
+        self.bitcoin.burn_nbtc(fee);
+
+        Ok(())
+    }
+
+    #[call]
+    pub fn propose_param_change(&mut self, change: ParamChange) -> Result<()> {
+        let signer = self.signer()?;
+        let now = self
+            .context::<Time>()
+            .ok_or_else(|| Error::App("No Time context available".into()))?
+            .seconds as u64;
+
+        self.governance
+            .propose(signer, change, now, &self.staking, &self.bitcoin)?;
 
         Ok(())
     }
 
+    #[call]
+    pub fn vote_param_change(&mut self, id: u64, approve: bool) -> Result<()> {
+        let signer = self.signer()?;
+        Ok(self.governance.vote(signer, id, approve, &self.staking)?)
+    }
+
     #[call]
     pub fn app_noop(&mut self) -> Result<()> {
         Ok(())
@@ -421,7 +454,7 @@ mod abci {
     use super::*;
 
     impl InitChain for InnerApp {
-        fn init_chain(&mut self, _ctx: &InitChainCtx) -> Result<()> {
+        fn init_chain(&mut self, ctx: &InitChainCtx) -> Result<()> {
             self.staking.max_validators = 30;
             self.staking.max_offline_blocks = 20_000;
             self.staking.downtime_jail_seconds = 60 * 30; // 30 minutes
@@ -441,6 +474,10 @@ mod abci {
 
             self.configure_faucets()?;
 
+            if let Some(network) = crate::bitcoin::genesis_network(&ctx.app_state_bytes)? {
+                self.bitcoin.configure_network(network)?;
+            }
+
             self.upgrade
                 .current_version
                 .insert((), vec![Self::CONSENSUS_VERSION].try_into().unwrap())?;
@@ -477,6 +514,12 @@ mod abci {
             let ip_reward = self.incentive_pool_rewards.mint()?;
             self.incentive_pool.give(ip_reward)?;
 
+            let param_changes = self.governance.step(now as u64, &self.staking)?;
+            for (id, change) in param_changes {
+                let success = change.apply(&mut self.bitcoin).is_ok();
+                self.governance.set_executed(id, success)?;
+            }
+
             let pending_nbtc_transfers = self.bitcoin.take_pending()?;
             for (dest, coins) in pending_nbtc_transfers {
                 self.credit_transfer(dest, coins)?;
@@ -852,6 +895,22 @@ impl ConvertSdkTx for InnerApp {
                         Ok(PaidCall { payer, paid })
                     }
 
+                    "nomic/MsgCancelWithdrawal" => {
+                        let msg: MsgCancelWithdrawal =
+                            serde_json::value::from_value(msg.value.clone())
+                                .map_err(|e| Error::App(e.to_string()))?;
+
+                        let id: u64 = msg
+                            .id
+                            .parse()
+                            .map_err(|e: std::num::ParseIntError| Error::App(e.to_string()))?;
+
+                        let payer = build_call!(self.bitcoin.cancel_withdrawal(id));
+                        let paid = build_call!(self.app_noop());
+
+                        Ok(PaidCall { payer, paid })
+                    }
+
                     "nomic/MsgClaimIbcBitcoin" => {
                         let msg = msg
                             .value
@@ -988,6 +1047,11 @@ pub struct MsgWithdraw {
     pub dst_address: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct MsgCancelWithdrawal {
+    pub id: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct MsgIbcTransfer {
     pub channel_id: String,
@@ -1036,6 +1100,7 @@ impl IbcDest {
 
         ibc.transfer_mut()
             .mint_coins_execute(&self.sender_address()?, &coins.into())?;
+        bitcoin.ibc_escrow += u64::from(nbtc_amount);
 
         let msg_transfer = MsgTransfer {
             port_id_on_a: self.source_port()?,
@@ -1145,6 +1210,12 @@ impl Query for Dest {
     }
 }
 
+impl Default for Dest {
+    fn default() -> Self {
+        Dest::Address(Address::NULL)
+    }
+}
+
 impl Migrate for Dest {
     fn migrate(src: Store, _dest: Store, bytes: &mut &[u8]) -> Result<Self> {
         Self::load(src, bytes)
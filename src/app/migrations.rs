@@ -1,6 +1,6 @@
 use crate::incentives::Incentives;
 
-use super::{InnerAppV0, InnerAppV1, InnerAppV2, InnerAppV3};
+use super::{InnerAppV0, InnerAppV1, InnerAppV2, InnerAppV3, InnerAppV4};
 use orga::{
     coins::Take,
     migrate::{Migrate, MigrateFrom},
@@ -45,3 +45,39 @@ impl MigrateFrom<InnerAppV2> for InnerAppV3 {
         })
     }
 }
+
+impl MigrateFrom<InnerAppV3> for InnerAppV4 {
+    fn migrate_from(other: InnerAppV3) -> Result<Self> {
+        let mut bitcoin = other.bitcoin;
+        // nBTC transferred out over IBC before the escrow was tracked is held
+        // in the transfer channels' escrow accounts. nBTC already returned to
+        // an account in the transfer module but not yet claimed isn't counted.
+        #[cfg(feature = "testnet")]
+        {
+            bitcoin.ibc_escrow = crate::cosmos::escrowed_nbtc(&other.ibc)?;
+        }
+        bitcoin.seed_supply()?;
+        bitcoin.seed_reserve_fees()?;
+
+        Ok(Self {
+            accounts: other.accounts,
+            staking: other.staking,
+            airdrop: other.airdrop,
+            community_pool: other.community_pool,
+            incentive_pool: other.incentive_pool,
+            staking_rewards: other.staking_rewards,
+            dev_rewards: other.dev_rewards,
+            community_pool_rewards: other.community_pool_rewards,
+            incentive_pool_rewards: other.incentive_pool_rewards,
+            bitcoin,
+            reward_timer: other.reward_timer,
+            #[cfg(feature = "testnet")]
+            ibc: other.ibc,
+            upgrade: other.upgrade,
+            incentives: other.incentives,
+            #[cfg(feature = "testnet")]
+            cosmos: other.cosmos,
+            governance: Default::default(),
+        })
+    }
+}
@@ -11,9 +11,16 @@ use nomic::app::Dest;
 use nomic::app::IbcDest;
 use nomic::app::InnerApp;
 use nomic::app::Nom;
+use nomic::bitcoin::address_server;
+use nomic::bitcoin::relayer_db::{self, RelayerDb};
+use nomic::bitcoin::remote_signer::{self, Endpoint, RemoteSigner, SignerDaemon};
 use nomic::bitcoin::Nbtc;
-use nomic::bitcoin::{relayer::Relayer, signer::Signer};
+use nomic::bitcoin::{
+    electrum::ElectrumClient, esplora::EsploraClient, p2p::P2pClient, relayer::Relayer,
+    signer::Signer,
+};
 use nomic::error::Result;
+use nomic::governance::{ParamChange, ProposalStatus};
 use orga::abci::Node;
 use orga::client::wallet::{SimpleWallet, Wallet};
 use orga::coins::{Address, Commission, Decimal, Declaration, Symbol};
@@ -31,8 +38,9 @@ use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::fs::Permissions;
 use std::os::unix::fs::PermissionsExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::Arc;
 use tendermint_rpc::Client as _;
 
 const BANNER: &str = r#"
@@ -84,12 +92,23 @@ pub enum Command {
     Airdrop(AirdropCmd),
     ClaimAirdrop(ClaimAirdropCmd),
     Relayer(RelayerCmd),
+    WatchedAddrs(WatchedAddrsCmd),
     Signer(SignerCmd),
+    SignerDaemon(SignerDaemonCmd),
     SetSignatoryKey(SetSignatoryKeyCmd),
     Deposit(DepositCmd),
     #[cfg(feature = "testnet")]
     InterchainDeposit(InterchainDepositCmd),
     Withdraw(WithdrawCmd),
+    CancelWithdrawal(CancelWithdrawalCmd),
+    Withdrawals(WithdrawalsCmd),
+    ClaimRefund(ClaimRefundCmd),
+    Reserves(ReservesCmd),
+    ProposeParam(ProposeParamCmd),
+    VoteParam(VoteParamCmd),
+    ParamProposals(ParamProposalsCmd),
+    VoteResume(VoteResumeCmd),
+    Checkpoints(CheckpointsCmd),
     // #[cfg(feature = "testnet")]
     // IbcDepositNbtc(IbcDepositNbtcCmd),
     #[cfg(feature = "testnet")]
@@ -138,12 +157,23 @@ impl Command {
                 ClaimAirdrop(cmd) => cmd.run().await,
                 Airdrop(cmd) => cmd.run().await,
                 Relayer(cmd) => cmd.run().await,
+                WatchedAddrs(cmd) => cmd.run().await,
                 Signer(cmd) => cmd.run().await,
+                SignerDaemon(cmd) => cmd.run().await,
                 SetSignatoryKey(cmd) => cmd.run().await,
                 Deposit(cmd) => cmd.run().await,
                 #[cfg(feature = "testnet")]
                 InterchainDeposit(cmd) => cmd.run().await,
                 Withdraw(cmd) => cmd.run().await,
+                CancelWithdrawal(cmd) => cmd.run().await,
+                Withdrawals(cmd) => cmd.run().await,
+                ClaimRefund(cmd) => cmd.run().await,
+                Reserves(cmd) => cmd.run().await,
+                ProposeParam(cmd) => cmd.run().await,
+                VoteParam(cmd) => cmd.run().await,
+                ParamProposals(cmd) => cmd.run().await,
+                VoteResume(cmd) => cmd.run().await,
+                Checkpoints(cmd) => cmd.run().await,
                 // #[cfg(feature = "testnet")]
                 // IbcDepositNbtc(cmd) => cmd.run().await,
                 #[cfg(feature = "testnet")]
@@ -189,6 +219,13 @@ pub struct StartCmd {
     pub validator_key: Option<String>,
     #[clap(long)]
     pub node_key: Option<String>,
+    /// The Bitcoin network to write into the genesis file: bitcoin, testnet,
+    /// testnet4, signet or regtest. Ignored once the node is initialized.
+    #[clap(long)]
+    pub bitcoin_network: Option<String>,
+    /// Serve Prometheus metrics at this address
+    #[clap(long)]
+    pub metrics_addr: Option<std::net::SocketAddr>,
 }
 
 impl StartCmd {
@@ -324,6 +361,15 @@ impl StartCmd {
             };
             std::fs::write(home.join("tendermint/config/genesis.json"), genesis_bytes)?;
         }
+        if let Some(network) = &cmd.bitcoin_network {
+            if has_node {
+                log::warn!(
+                    "--bitcoin-network only applies when initializing a network home, ignoring"
+                );
+            } else {
+                set_genesis_network(&home.join("tendermint/config/genesis.json"), network)?;
+            }
+        }
         if cmd.migrate || should_migrate {
             node = node.migrate(
                 vec![InnerApp::CONSENSUS_VERSION],
@@ -376,6 +422,15 @@ impl StartCmd {
             });
         }
 
+        if let Some(addr) = cmd.metrics_addr {
+            spawn_metrics(addr, "http://localhost:26657".to_string());
+        }
+
+        spawn_archiver(
+            home.join(nomic::bitcoin::archive::FILE_NAME),
+            "http://localhost:26657".to_string(),
+        );
+
         if std::env::var("NOMIC_EXIT_ON_START").is_ok() {
             std::process::exit(139);
         }
@@ -518,6 +573,27 @@ fn legacy_bin(config: &nomic::network::Config) -> Result<Option<PathBuf>> {
     Ok(None)
 }
 
+/// Serves metrics at `addr` on a separate thread, refreshing the chain metrics
+/// from the node at `node`.
+fn spawn_metrics(addr: std::net::SocketAddr, node: String) {
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async move {
+            futures::join!(
+                nomic::metrics::serve(addr),
+                nomic::metrics::poll_chain(node)
+            );
+        });
+    });
+}
+
+fn spawn_archiver(path: PathBuf, node: String) {
+    std::thread::spawn(move || {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(nomic::bitcoin::archive::poll(path, node));
+    });
+}
+
 async fn relaunch_on_migrate(config: &nomic::network::Config) -> Result<()> {
     let home = match config.home() {
         Some(home) => home,
@@ -550,6 +626,27 @@ async fn relaunch_on_migrate(config: &nomic::network::Config) -> Result<()> {
     }
 }
 
+/// Chooses the Bitcoin network in the genesis file's app state, which the
+/// chain configures itself for at genesis.
+fn set_genesis_network(genesis_path: &Path, network: &str) -> Result<()> {
+    let network: nomic::bitcoin::BitcoinNetwork = network.parse()?;
+    let network = format!("{:?}", network).to_lowercase();
+
+    let genesis_bytes = std::fs::read(genesis_path)?;
+    let mut genesis: serde_json::Value = serde_json::from_slice(&genesis_bytes)
+        .map_err(|e| orga::Error::App(format!("Invalid genesis file: {}", e)))?;
+    if !genesis["app_state"].is_object() {
+        genesis["app_state"] = serde_json::json!({});
+    }
+    genesis["app_state"]["bitcoin"]["network"] = network.into();
+
+    let genesis_bytes =
+        serde_json::to_vec_pretty(&genesis).map_err(|e| orga::Error::App(e.to_string()))?;
+    std::fs::write(genesis_path, genesis_bytes)?;
+
+    Ok(())
+}
+
 fn configure_node<P, F>(cfg_path: &P, configure: F)
 where
     P: AsRef<std::path::Path>,
@@ -1137,12 +1234,56 @@ pub struct RelayerCmd {
     #[clap(short = 'P', long)]
     rpc_pass: Option<String>,
 
+    /// Relay through a Bitcoin peer which serves compact block filters, at
+    /// this address, instead of through a full node's RPC
+    #[clap(long, conflicts_with_all = &["esplora_url", "electrum_addr"])]
+    p2p_peer: Option<std::net::SocketAddr>,
+
+    /// Cross-check the filter headers of the --p2p-peer with the Bitcoin peer
+    /// at this address. Can be given more than once
+    #[clap(long, requires = "p2p_peer")]
+    p2p_witness: Vec<std::net::SocketAddr>,
+
+    /// Relay through the Esplora HTTP API at this URL, instead of through a
+    /// full node's RPC
+    #[clap(long, conflicts_with = "electrum_addr")]
+    esplora_url: Option<String>,
+
+    /// Relay through the Electrum server at this address, e.g.
+    /// localhost:50001, instead of through a full node's RPC
+    #[clap(long)]
+    electrum_addr: Option<String>,
+
+    /// The address the deposit address server listens on
+    #[clap(long, default_value = "0.0.0.0:8999")]
+    address_server_addr: std::net::SocketAddr,
+
+    /// How many requests each IP address may make to the deposit address
+    /// server per minute
+    #[clap(long, default_value_t = 30)]
+    address_rate_limit: u32,
+
+    /// Rate limit the deposit address server by the X-Forwarded-For header,
+    /// when it is behind a reverse proxy
+    #[clap(long)]
+    trust_forwarded_for: bool,
+
+    /// Only accept deposit addresses for accounts if the announcement is
+    /// signed by the account. The web wallet does not sign announcements, so
+    /// its deposit addresses are rejected
+    #[clap(long)]
+    require_signed_commitments: bool,
+
+    /// Serve Prometheus metrics at this address
+    #[clap(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+
     #[clap(flatten)]
     config: nomic::network::Config,
 }
 
 impl RelayerCmd {
-    async fn btc_client(&self) -> Result<BtcClient> {
+    async fn btc_client(&self) -> Result<Arc<BtcClient>> {
         let rpc_url = format!("http://localhost:{}", self.rpc_port);
         let auth = match (self.rpc_user.clone(), self.rpc_pass.clone()) {
             (Some(user), Some(pass)) => Auth::UserPass(user, pass),
@@ -1152,23 +1293,48 @@ impl RelayerCmd {
         let btc_client =
             BtcClient::new(&rpc_url, auth).map_err(|e| orga::Error::App(e.to_string()))?;
 
-        Ok(btc_client)
+        Ok(Arc::new(btc_client))
     }
 
     async fn run(&self) -> Result<()> {
-        let create_relayer = async || {
-            let btc_client = self.btc_client().await.unwrap();
-
-            Relayer::new(btc_client, self.config.node.as_ref().unwrap().to_string())
+        let node = self.config.node.as_ref().unwrap().to_string();
+        let p2p_client = match self.p2p_peer {
+            Some(peer) => {
+                Some(P2pClient::from_sidechain(peer, self.p2p_witness.clone(), &node).await?)
+            }
+            None => None,
         };
 
-        let mut relayer = create_relayer().await;
-        let headers = relayer.start_header_relay();
-
         let relayer_dir_path = self.config.home_expect()?.join("relayer");
         if !relayer_dir_path.exists() {
             std::fs::create_dir(&relayer_dir_path)?;
         }
+        let db = RelayerDb::open(relayer_dir_path.join(relayer_db::FILE_NAME))?;
+        if let Some(addr) = self.metrics_addr {
+            spawn_metrics(addr, node.clone());
+        }
+        let address_server = address_server::Config {
+            listen_addr: self.address_server_addr,
+            max_requests_per_minute: self.address_rate_limit,
+            trust_forwarded_for: self.trust_forwarded_for,
+            require_signed_commitments: self.require_signed_commitments,
+            ..Default::default()
+        };
+
+        let create_relayer = async || {
+            let relayer = match (p2p_client.clone(), &self.esplora_url, &self.electrum_addr) {
+                (Some(p2p_client), _, _) => Relayer::new(p2p_client, node.clone()),
+                (None, Some(url), _) => Relayer::new(EsploraClient::new(url), node.clone()),
+                (None, None, Some(addr)) => Relayer::new(ElectrumClient::new(addr), node.clone()),
+                (None, None, None) => Relayer::new(self.btc_client().await.unwrap(), node.clone()),
+            };
+            relayer
+                .with_db(db.clone())
+                .with_address_server(address_server.clone())
+        };
+
+        let mut relayer = create_relayer().await;
+        let headers = relayer.start_header_relay();
 
         let relayer = create_relayer().await;
         let deposits = relayer.start_deposit_relay(relayer_dir_path);
@@ -1213,6 +1379,27 @@ pub struct SignerCmd {
     /// newly-proposed signatory set may not exceed this value
     #[clap(long, default_value_t = 0.04)]
     max_sigset_change_rate: f64,
+
+    /// The RPC port of a Bitcoin node used to estimate fee rates, which are
+    /// reported when signing checkpoints
+    #[clap(long)]
+    fee_estimate_rpc_port: Option<u16>,
+    #[clap(long)]
+    rpc_user: Option<String>,
+    #[clap(long)]
+    rpc_pass: Option<String>,
+
+    /// Serve Prometheus metrics at this address
+    #[clap(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Sign with the key held by a signer daemon at this endpoint, either
+    /// `unix:<path>` or a `host:port` reached over mutual TLS, instead of
+    /// with a local key
+    #[clap(long)]
+    remote_signer: Option<String>,
+    #[clap(flatten)]
+    tls: TlsOpts,
 }
 
 impl SignerCmd {
@@ -1224,15 +1411,51 @@ impl SignerCmd {
 
         let key_path = signer_dir_path.join("xpriv");
 
-        let signer = Signer::load_or_generate(
-            my_address(),
-            key_path,
-            self.max_withdrawal_rate,
-            self.max_sigset_change_rate,
-            // TODO: check for custom RPC port, allow config, etc
-            || nomic::app_client("http://localhost:26657").with_wallet(wallet()),
-        )?
-        .start();
+        // TODO: check for custom RPC port, allow config, etc
+        let app_client = || nomic::app_client("http://localhost:26657").with_wallet(wallet());
+
+        let mut signer = if let Some(endpoint) = &self.remote_signer {
+            let endpoint = Endpoint::parse(endpoint, self.tls.files())?;
+            Signer::new(
+                my_address(),
+                RemoteSigner::connect(endpoint).await?,
+                self.max_withdrawal_rate,
+                self.max_sigset_change_rate,
+                app_client,
+            )
+        } else {
+            let network = self
+                .config
+                .client()
+                .query(|app| Ok(app.bitcoin.headers.bitcoin_network()))
+                .await?;
+
+            Signer::load_or_generate(
+                my_address(),
+                key_path,
+                network,
+                self.max_withdrawal_rate,
+                self.max_sigset_change_rate,
+                app_client,
+            )?
+        };
+
+        if let Some(rpc_port) = self.fee_estimate_rpc_port {
+            let rpc_url = format!("http://localhost:{}", rpc_port);
+            let auth = match (self.rpc_user.clone(), self.rpc_pass.clone()) {
+                (Some(user), Some(pass)) => Auth::UserPass(user, pass),
+                _ => Auth::None,
+            };
+            let btc_client =
+                BtcClient::new(&rpc_url, auth).map_err(|e| orga::Error::App(e.to_string()))?;
+            signer = signer.with_fee_estimator(btc_client);
+        }
+
+        if let Some(addr) = self.metrics_addr {
+            spawn_metrics(addr, "http://localhost:26657".to_string());
+        }
+
+        let signer = signer.start();
 
         let relaunch = relaunch_on_migrate(&self.config);
 
@@ -1242,6 +1465,84 @@ impl SignerCmd {
     }
 }
 
+/// PEM files for connecting a signer to a signer daemon over mutual TLS.
+#[derive(Parser, Debug)]
+pub struct TlsOpts {
+    /// The CA certificate which signs the daemon's and signers' certificates
+    #[clap(long)]
+    tls_ca: Option<PathBuf>,
+    /// This process's certificate
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+    /// The PKCS #8 private key of this process's certificate
+    #[clap(long)]
+    tls_key: Option<PathBuf>,
+}
+
+impl TlsOpts {
+    fn files(&self) -> Option<remote_signer::TlsFiles> {
+        Some(remote_signer::TlsFiles {
+            ca: self.tls_ca.clone()?,
+            cert: self.tls_cert.clone()?,
+            key: self.tls_key.clone()?,
+        })
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct SignerDaemonCmd {
+    #[clap(flatten)]
+    config: nomic::network::Config,
+
+    /// The endpoint to listen at, either `unix:<path>` or a `host:port`
+    /// address served over mutual TLS. Defaults to a Unix socket at
+    /// `signer/daemon.sock` in the Nomic home directory
+    #[clap(long)]
+    listen: Option<String>,
+    #[clap(flatten)]
+    tls: TlsOpts,
+
+    /// Refuses to sign checkpoints while the fraction of the total reserve
+    /// withdrawn within the trailing 24-hour period is above this value
+    #[clap(long, default_value_t = 0.04)]
+    max_withdrawal_rate: f64,
+    /// Refuses to sign checkpoints while the signatory set change within 24
+    /// hours is above this value
+    #[clap(long, default_value_t = 0.04)]
+    max_sigset_change_rate: f64,
+}
+
+impl SignerDaemonCmd {
+    async fn run(&self) -> Result<()> {
+        let signer_dir_path = self.config.home_expect()?.join("signer");
+        if !signer_dir_path.exists() {
+            std::fs::create_dir(&signer_dir_path)?;
+        }
+
+        let network = self
+            .config
+            .client()
+            .query(|app| Ok(app.bitcoin.headers.bitcoin_network()))
+            .await?;
+        let xpriv =
+            nomic::bitcoin::signer::load_or_generate_xpriv(signer_dir_path.join("xpriv"), network)?;
+
+        let endpoint = match &self.listen {
+            Some(endpoint) => Endpoint::parse(endpoint, self.tls.files())?,
+            None => Endpoint::Unix(signer_dir_path.join("daemon.sock")),
+        };
+        let policy = remote_signer::Policy {
+            max_withdrawal_rate: self.max_withdrawal_rate,
+            max_sigset_change_rate: self.max_sigset_change_rate,
+        };
+        let node = self.config.node.as_ref().unwrap().to_string();
+
+        SignerDaemon::new(xpriv, policy, node)
+            .listen(&endpoint)
+            .await
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct SetSignatoryKeyCmd {
     xpub: bitcoin::util::bip32::ExtendedPubKey,
@@ -1265,20 +1566,189 @@ impl SetSignatoryKeyCmd {
     }
 }
 
+/// Votes, as a validator, to resume the bridge after it has been paused by a
+/// failed supply check.
+#[derive(Parser, Debug)]
+pub struct VoteResumeCmd {
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl VoteResumeCmd {
+    async fn run(&self) -> Result<()> {
+        self.config
+            .client()
+            .with_wallet(wallet())
+            .call(
+                |app| build_call!(app.accounts.take_as_funding(MIN_FEE.into())),
+                |app| build_call!(app.bitcoin.vote_resume()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct CheckpointsCmd {
+    #[clap(subcommand)]
+    cmd: CheckpointsSubcommand,
+}
+
+#[derive(Parser, Debug)]
+pub enum CheckpointsSubcommand {
+    /// Inspect the archive of checkpoints kept by this node after they are
+    /// pruned from state
+    Archive(CheckpointArchiveCmd),
+}
+
+impl CheckpointsCmd {
+    async fn run(&self) -> Result<()> {
+        match &self.cmd {
+            CheckpointsSubcommand::Archive(cmd) => cmd.run().await,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct CheckpointArchiveCmd {
+    /// Print the archived checkpoint with this index
+    #[clap(long)]
+    index: Option<u32>,
+
+    /// Check the archive's hash chain and the links between its checkpoint
+    /// transactions
+    #[clap(long)]
+    verify: bool,
+
+    /// Write the archived checkpoints to this file, as a JSON array
+    #[clap(long)]
+    export: Option<PathBuf>,
+
+    /// The archive file, defaulting to the one in the node home
+    #[clap(long)]
+    path: Option<PathBuf>,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl CheckpointArchiveCmd {
+    async fn run(&self) -> Result<()> {
+        use nomic::bitcoin::archive;
+
+        let path = match &self.path {
+            Some(path) => path.clone(),
+            None => self.config.home_expect()?.join(archive::FILE_NAME),
+        };
+
+        if self.verify {
+            let count = archive::verify(&path)?;
+            println!("Verified {} archived checkpoints", count);
+        }
+
+        let records: Vec<_> = archive::read(&path)?
+            .into_iter()
+            .map(|(record, _)| record)
+            .collect();
+
+        if let Some(index) = self.index {
+            let record = records
+                .iter()
+                .find(|record| record.index == index)
+                .ok_or_else(|| orga::Error::App(format!("Checkpoint {} is not archived", index)))?;
+            println!("{}", serde_json::to_string_pretty(record).unwrap());
+        }
+
+        if let Some(export) = &self.export {
+            let file = std::fs::File::create(export)?;
+            serde_json::to_writer_pretty(file, &records).unwrap();
+            println!(
+                "Exported {} checkpoints to {}",
+                records.len(),
+                export.display()
+            );
+        }
+
+        if self.index.is_none() && self.export.is_none() && !self.verify {
+            match (records.first(), records.last()) {
+                (Some(first), Some(last)) => println!(
+                    "{} archived checkpoints, #{} to #{}",
+                    records.len(),
+                    first.index,
+                    last.index
+                ),
+                _ => println!("No archived checkpoints in {}", path.display()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Share the deposit addresses watched by this node's relayer, which must not
+/// be running
+#[derive(Parser, Debug)]
+pub struct WatchedAddrsCmd {
+    #[clap(subcommand)]
+    cmd: WatchedAddrsSubcommand,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+#[derive(Parser, Debug)]
+pub enum WatchedAddrsSubcommand {
+    /// Write the watched addresses to a file
+    Export { path: PathBuf },
+    /// Watch the addresses in a file written by `export`
+    Import { path: PathBuf },
+}
+
+impl WatchedAddrsCmd {
+    async fn run(&self) -> Result<()> {
+        use nomic::bitcoin::watched_addrs::WatchedScriptStore;
+
+        let relayer_dir_path = self.config.home_expect()?.join("relayer");
+        match &self.cmd {
+            WatchedAddrsSubcommand::Export { path } => {
+                let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+                let count = WatchedScriptStore::export(&relayer_dir_path, file)?;
+                println!("Exported {} addresses to {}", count, path.display());
+            }
+            WatchedAddrsSubcommand::Import { path } => {
+                let file = std::io::BufReader::new(std::fs::File::open(path)?);
+                let node = self.config.node.as_ref().unwrap();
+                let count = WatchedScriptStore::import(&relayer_dir_path, file, node).await?;
+                println!("Imported {} addresses from {}", count, path.display());
+            }
+        }
+
+        Ok(())
+    }
+}
+
 async fn deposit(
     dest: Dest,
     client: AppClient<InnerApp, InnerApp, HttpClient, Nom, orga::client::wallet::Unsigned>,
+    privkey: Option<bitcoin::secp256k1::SecretKey>,
 ) -> Result<()> {
-    let (sigset, threshold) = client
+    let (sigset, threshold, config, network) = client
         .query(|app| {
             Ok((
                 app.bitcoin.checkpoints.active_sigset()?,
-                app.bitcoin.checkpoints.config.sigset_threshold,
+                app.bitcoin.checkpoints.active_sigset_threshold()?,
+                app.bitcoin.checkpoints.config.clone(),
+                app.bitcoin.network(),
             ))
         })
         .await?;
-    let script = sigset.output_script(dest.commitment_bytes()?.as_slice(), threshold)?;
-    let btc_addr = bitcoin::Address::from_script(&script, nomic::bitcoin::NETWORK).unwrap();
+    let script = config.output_script(&sigset, threshold, dest.commitment_bytes()?.as_slice())?;
+    let btc_addr = bitcoin::Address::from_script(&script, network).unwrap();
+
+    let signature = privkey
+        .map(|privkey| address_server::sign_commitment(&privkey, &btc_addr.to_string()))
+        .unwrap_or_default();
 
     let client = reqwest::Client::new();
     let res = client
@@ -1287,6 +1757,7 @@ async fn deposit(
             ("sigset_index", sigset.index().to_string()),
             ("deposit_addr", btc_addr.to_string()),
         ])
+        .query(&signature)
         .body(dest.encode()?)
         .send()
         .await
@@ -1313,9 +1784,17 @@ pub struct DepositCmd {
 
 impl DepositCmd {
     async fn run(&self) -> Result<()> {
-        let dest_addr = self.address.unwrap_or_else(my_address);
+        // Announcements for our own address are signed, for relayers which
+        // require it
+        let (dest_addr, privkey) = match self.address {
+            Some(address) => (address, None),
+            None => (
+                my_address(),
+                Some(nomic::utils::load_privkey(&home::home_dir().unwrap())?),
+            ),
+        };
 
-        deposit(Dest::Address(dest_addr), self.config.client()).await
+        deposit(Dest::Address(dest_addr), self.config.client(), privkey).await
     }
 }
 
@@ -1352,7 +1831,7 @@ impl InterchainDepositCmd {
             memo: self.memo.clone().try_into().unwrap(),
         });
 
-        deposit(dest, self.config.client()).await
+        deposit(dest, self.config.client(), None).await
     }
 }
 
@@ -1384,6 +1863,303 @@ impl WithdrawCmd {
     }
 }
 
+#[derive(Parser, Debug)]
+pub struct CancelWithdrawalCmd {
+    id: u64,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl CancelWithdrawalCmd {
+    async fn run(&self) -> Result<()> {
+        self.config
+            .client()
+            .with_wallet(wallet())
+            .call(
+                |app| build_call!(app.bitcoin.cancel_withdrawal(self.id)),
+                |app| build_call!(app.app_noop()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct WithdrawalsCmd {
+    address: Option<Address>,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl WithdrawalsCmd {
+    async fn run(&self) -> Result<()> {
+        let address = self.address.unwrap_or_else(my_address);
+        let (withdrawals, network) = self
+            .config
+            .client()
+            .query(|app| {
+                Ok((
+                    app.bitcoin.withdrawals_by_owner(address)?,
+                    app.bitcoin.network(),
+                ))
+            })
+            .await?;
+
+        for info in withdrawals {
+            let withdrawal = &info.withdrawal;
+            let dest = bitcoin::Address::from_script(&withdrawal.script_pubkey, network)
+                .map_or_else(|_| withdrawal.script_pubkey.to_string(), |a| a.to_string());
+
+            println!("withdrawal #{}", info.id);
+            println!("  status: {:?}", info.status);
+            println!("  destination: {}", dest);
+            println!("  value: {} sats", withdrawal.value);
+            println!("  checkpoint: {}", withdrawal.checkpoint_index);
+            if let (Some(txid), Some(vout)) = (withdrawal.txid(), withdrawal.output_index) {
+                println!("  output: {}:{}", txid, vout);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct ClaimRefundCmd {
+    /// The transaction containing the deposit output
+    txid: bitcoin::Txid,
+    vout: u32,
+    /// The index of the signatory set the deposit address was generated for
+    sigset_index: u32,
+    /// The address to pay the refund to
+    refund_address: bitcoin::Address,
+
+    #[clap(short = 'p', long, default_value_t = 8332)]
+    rpc_port: u16,
+    #[clap(short = 'u', long)]
+    rpc_user: Option<String>,
+    #[clap(short = 'P', long)]
+    rpc_pass: Option<String>,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl ClaimRefundCmd {
+    async fn run(&self) -> Result<()> {
+        use bitcoin::consensus::Decodable;
+        use bitcoind::bitcoincore_rpc::RpcApi;
+        use nomic::bitcoin::adapter::Adapter;
+
+        let rpc_url = format!("http://localhost:{}", self.rpc_port);
+        let auth = match (self.rpc_user.clone(), self.rpc_pass.clone()) {
+            (Some(user), Some(pass)) => Auth::UserPass(user, pass),
+            _ => Auth::None,
+        };
+        let btc_client =
+            BtcClient::new(&rpc_url, auth).map_err(|e| orga::Error::App(e.to_string()))?;
+
+        let tx_info = btc_client.get_raw_transaction_info(&self.txid, None)?;
+        let block_hash = tx_info
+            .blockhash
+            .ok_or_else(|| orga::Error::App("Transaction is not confirmed".to_string()))?;
+        let height = btc_client.get_block_header_info(&block_hash)?.height as u32;
+        let tx = btc_client.get_raw_transaction(&self.txid, Some(&block_hash))?;
+        let proof_bytes = btc_client.get_tx_out_proof(&[self.txid], Some(&block_hash))?;
+        let proof = bitcoin::MerkleBlock::consensus_decode(&mut proof_bytes.as_slice())?.txn;
+
+        let dest = Dest::Address(my_address());
+        let script = self.refund_address.script_pubkey();
+
+        self.config
+            .client()
+            .with_wallet(wallet())
+            .call(
+                |app| {
+                    build_call!(app.bitcoin.claim_refund(
+                        Adapter::new(tx.clone()),
+                        height,
+                        Adapter::new(proof.clone()),
+                        self.vout,
+                        self.sigset_index,
+                        dest.clone(),
+                        Adapter::new(script.clone())
+                    ))
+                },
+                |app| build_call!(app.app_noop()),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct ReservesCmd {
+    /// The checkpoint to report on, defaulting to the last completed one
+    index: Option<u32>,
+
+    /// Also check that the wallet's balance was counted in the report
+    #[clap(long)]
+    prove: bool,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl ReservesCmd {
+    async fn run(&self) -> Result<()> {
+        use bitcoin::hashes::Hash;
+
+        let client = self.config.client();
+        let index = match self.index {
+            Some(index) => index,
+            None => {
+                client
+                    .query(|app| Ok(app.bitcoin.checkpoints.last_completed_index()?))
+                    .await?
+            }
+        };
+        let report = client
+            .query(|app| Ok(app.bitcoin.reserve_report(index)?))
+            .await?;
+
+        let (txid, vout) = report.reserve_outpoint;
+        println!("checkpoint #{}", report.checkpoint_index);
+        println!(
+            "  reserve output: {}:{}",
+            bitcoin::Txid::from_inner(txid),
+            vout
+        );
+        println!("  reserve value: {} sats", report.reserve_value);
+        match report.btc_height {
+            Some(height) => println!("  confirmed at Bitcoin height: {}", height),
+            None => println!("  no inclusion proof relayed"),
+        }
+        println!("  account balances: {} usats", report.account_balances);
+        println!("  pending transfers: {} usats", report.pending);
+        println!("  reward pool: {} usats", report.reward_pool);
+        println!("  IBC escrow: {} usats", report.ibc_escrow);
+        println!("  liabilities: {} usats", report.liabilities);
+        println!("  fees paid from reserve: {} sats", report.reserve_fees);
+        println!(
+            "  balances root: {}",
+            hex::encode(report.balances_root.hash)
+        );
+
+        if self.prove {
+            let address = my_address();
+            let (root, balance, proof) = client
+                .query(|app| Ok(app.bitcoin.balance_proof(address)?))
+                .await?;
+            if !proof.verify(address, balance, &root)? {
+                return Err(orga::Error::App("Balance proof is invalid".to_string()).into());
+            }
+            println!(
+                "Balance of {} usats for {} is included in root {}",
+                balance,
+                address,
+                hex::encode(root.hash)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct ProposeParamCmd {
+    /// The name of the parameter, e.g. `capacity_limit`
+    name: String,
+    /// The new value, given as `numerator/denominator` for thresholds
+    value: String,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl ProposeParamCmd {
+    async fn run(&self) -> Result<()> {
+        let change = ParamChange::parse(&self.name, &self.value)?;
+
+        self.config
+            .client()
+            .with_wallet(wallet())
+            .call(
+                |app| build_call!(app.accounts.take_as_funding(MIN_FEE.into())),
+                |app| build_call!(app.propose_param_change(change)),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct VoteParamCmd {
+    id: u64,
+    /// Whether to approve the proposal
+    #[clap(long, action = clap::ArgAction::Set)]
+    approve: bool,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl VoteParamCmd {
+    async fn run(&self) -> Result<()> {
+        self.config
+            .client()
+            .with_wallet(wallet())
+            .call(
+                |app| build_call!(app.accounts.take_as_funding(MIN_FEE.into())),
+                |app| build_call!(app.vote_param_change(self.id, self.approve)),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct ParamProposalsCmd {
+    /// Show rejected and executed proposals instead of pending ones
+    #[clap(long)]
+    past: bool,
+
+    #[clap(flatten)]
+    config: nomic::network::Config,
+}
+
+impl ParamProposalsCmd {
+    async fn run(&self) -> Result<()> {
+        let client = self.config.client();
+        let proposals = if self.past {
+            client.query(|app| app.governance.past()).await?
+        } else {
+            client.query(|app| app.governance.pending()).await?
+        };
+
+        for (id, proposal) in proposals {
+            println!("proposal #{}", id);
+            println!("  change: {:?}", proposal.change);
+            println!("  status: {:?}", proposal.status);
+            println!("  proposer: {}", proposal.proposer);
+            println!("  voting ends: {}", proposal.voting_end);
+            println!("  executes at: {}", proposal.execute_at);
+            if proposal.status != ProposalStatus::Voting {
+                println!("  yes: {}", proposal.yes);
+                println!("  no: {}", proposal.no);
+            }
+        }
+
+        Ok(())
+    }
+}
+
 // #[cfg(feature = "testnet")]
 // #[derive(Parser, Debug)]
 // pub struct IbcTransferNbtcCmd {
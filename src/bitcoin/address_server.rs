@@ -0,0 +1,602 @@
+//! The HTTP server where clients announce the deposit addresses they
+//! generated, so the relayer watches them for deposits.
+//!
+//! Requests are rate limited per IP address, and errors are answered as JSON
+//! objects with a `code` and an `error` message. Commitments to an
+//! [`Dest::Address`] destination may be signed by that account's key (see
+//! [`sign_commitment`]), which the server can be configured to require so
+//! that clients can't make it watch addresses for accounts they don't
+//! control.
+
+use super::relayer::{DepositAddress, RawSignatorySet};
+use super::signatory::SignatorySet;
+use crate::app::Dest;
+use crate::app_client;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::secp256k1::{ecdsa::Signature, Message, PublicKey, Secp256k1, SecretKey};
+use bitcoin::Network;
+use bytes::Bytes;
+use log::{debug, warn};
+use orga::coins::Address;
+use orga::encoding::Decode;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Sender;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+/// The period over which requests are counted for rate limiting.
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How long a cached signatory set is used before it is fetched again, which
+/// bounds how long announcements are accepted after deposits are disabled for
+/// it.
+const SIGSET_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// The largest request body accepted, which is far more than an encoded
+/// [`Dest`] needs.
+const MAX_BODY_SIZE: u64 = 4096;
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The address the server listens on.
+    pub listen_addr: SocketAddr,
+    /// How many requests each IP address may make per minute.
+    pub max_requests_per_minute: u32,
+    /// Whether to rate limit by the first address in the `X-Forwarded-For`
+    /// header, for a server behind a reverse proxy.
+    pub trust_forwarded_for: bool,
+    /// How many signatory sets are kept in memory to check deposit addresses
+    /// against.
+    pub max_cached_sigsets: usize,
+    /// Whether commitments to an [`Dest::Address`] destination must be signed
+    /// by that account. The web wallet does not sign its announcements, so
+    /// enabling this rejects deposit addresses generated by it; only clients
+    /// using [`sign_commitment`], such as `nomic deposit`, are accepted.
+    pub require_signed_commitments: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_addr: ([0, 0, 0, 0], 8999).into(),
+            max_requests_per_minute: 30,
+            trust_forwarded_for: false,
+            max_cached_sigsets: 16,
+            require_signed_commitments: false,
+        }
+    }
+}
+
+/// An error answered to a client.
+#[derive(thiserror::Error, Debug)]
+pub enum ApiError {
+    #[error("Too many requests")]
+    RateLimited,
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
+    #[error("Not found")]
+    NotFound,
+    #[error("Method not allowed")]
+    MethodNotAllowed,
+    #[error("Unknown signatory set {0}")]
+    UnknownSigset(u32),
+    #[error("Deposits are disabled for signatory set {0}")]
+    DepositsDisabled(u32),
+    #[error("Deposit address does not match the commitment")]
+    InvalidDepositAddress,
+    #[error("Commitment must be signed by the destination account")]
+    MissingSignature,
+    #[error("Invalid commitment signature")]
+    InvalidSignature,
+    #[error("Could not query the sidechain")]
+    Unavailable,
+    #[error("Internal error")]
+    Internal,
+}
+
+impl warp::reject::Reject for ApiError {}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::RateLimited => "rate_limited",
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::NotFound => "not_found",
+            ApiError::MethodNotAllowed => "method_not_allowed",
+            ApiError::UnknownSigset(_) => "unknown_sigset",
+            ApiError::DepositsDisabled(_) => "deposits_disabled",
+            ApiError::InvalidDepositAddress => "invalid_deposit_address",
+            ApiError::MissingSignature => "missing_signature",
+            ApiError::InvalidSignature => "invalid_signature",
+            ApiError::Unavailable => "unavailable",
+            ApiError::Internal => "internal",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::InvalidRequest(_)
+            | ApiError::DepositsDisabled(_)
+            | ApiError::InvalidDepositAddress => StatusCode::BAD_REQUEST,
+            ApiError::NotFound | ApiError::UnknownSigset(_) => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::MissingSignature | ApiError::InvalidSignature => StatusCode::FORBIDDEN,
+            ApiError::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorBody {
+    code: &'static str,
+    error: String,
+}
+
+async fn handle_rejection(rejection: Rejection) -> Result<impl Reply, Infallible> {
+    let fallback;
+    let err = match rejection.find::<ApiError>() {
+        Some(err) => err,
+        None => {
+            fallback = if rejection.is_not_found() {
+                ApiError::NotFound
+            } else if let Some(err) = rejection.find::<warp::reject::InvalidQuery>() {
+                ApiError::InvalidRequest(err.to_string())
+            } else if let Some(err) = rejection.find::<warp::reject::PayloadTooLarge>() {
+                ApiError::InvalidRequest(err.to_string())
+            } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+                ApiError::MethodNotAllowed
+            } else {
+                warn!("Unhandled address server rejection: {:?}", rejection);
+                ApiError::Internal
+            };
+            &fallback
+        }
+    };
+
+    let body = ErrorBody {
+        code: err.code(),
+        error: err.to_string(),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&body),
+        err.status(),
+    ))
+}
+
+/// Counts requests per IP address over fixed windows.
+struct RateLimiter {
+    max_requests: u32,
+    window: Duration,
+    counts: HashMap<IpAddr, (Instant, u32)>,
+    last_prune: Instant,
+}
+
+impl RateLimiter {
+    fn new(max_requests: u32, window: Duration) -> Self {
+        RateLimiter {
+            max_requests,
+            window,
+            counts: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Counts a request from `ip`, returning whether it is within the limit.
+    fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+        let window = self.window;
+        if now.duration_since(self.last_prune) >= window {
+            self.counts
+                .retain(|_, (start, _)| now.duration_since(*start) < window);
+            self.last_prune = now;
+        }
+
+        let (start, count) = self.counts.entry(ip).or_insert((now, 0));
+        if now.duration_since(*start) >= window {
+            *start = now;
+            *count = 0;
+        }
+        if *count >= self.max_requests {
+            return false;
+        }
+        *count += 1;
+
+        true
+    }
+}
+
+/// A signatory set with its threshold, and the network its addresses are
+/// for.
+type SigsetInfo = (SignatorySet, (u64, u64), Network);
+
+/// The signatory sets fetched from the sidechain, with the time they were
+/// fetched, evicting the oldest when full since clients generate addresses for
+/// recent ones.
+struct SigsetCache {
+    capacity: usize,
+    sigsets: BTreeMap<u32, (SigsetInfo, Instant)>,
+}
+
+impl SigsetCache {
+    fn new(capacity: usize) -> Self {
+        SigsetCache {
+            capacity,
+            sigsets: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the signatory set if it was fetched less than
+    /// [`SIGSET_CACHE_TTL`] before `now`.
+    fn get(&self, index: u32, now: Instant) -> Option<SigsetInfo> {
+        self.sigsets
+            .get(&index)
+            .filter(|(_, fetched_at)| now.duration_since(*fetched_at) < SIGSET_CACHE_TTL)
+            .map(|(info, _)| info.clone())
+    }
+
+    fn insert(&mut self, index: u32, info: SigsetInfo, now: Instant) {
+        self.sigsets.insert(index, (info, now));
+        while self.sigsets.len() > self.capacity {
+            self.sigsets.pop_first();
+        }
+    }
+}
+
+/// The optional query parameters proving a commitment was made by the
+/// destination account: its hex-encoded compressed public key, and a
+/// hex-encoded compact ECDSA signature of [`commitment_message`].
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct CommitmentSignature {
+    pub pubkey: Option<String>,
+    pub signature: Option<String>,
+}
+
+/// The message an account signs to announce a deposit address to itself.
+pub fn commitment_message(deposit_addr: &str) -> Message {
+    let mut bytes = b"nomic-deposit-address:".to_vec();
+    bytes.extend_from_slice(deposit_addr.as_bytes());
+    let hash = sha256::Hash::hash(&bytes);
+
+    Message::from_slice(&hash[..]).unwrap()
+}
+
+/// Signs the announcement of `deposit_addr` with the key of the account it
+/// deposits to.
+pub fn sign_commitment(privkey: &SecretKey, deposit_addr: &str) -> CommitmentSignature {
+    let secp = Secp256k1::signing_only();
+    let pubkey = PublicKey::from_secret_key(&secp, privkey);
+    let signature = secp.sign_ecdsa(&commitment_message(deposit_addr), privkey);
+
+    CommitmentSignature {
+        pubkey: Some(hex::encode(pubkey.serialize())),
+        signature: Some(hex::encode(signature.serialize_compact())),
+    }
+}
+
+/// Checks the signature of a commitment to an [`Dest::Address`]
+/// destination, if it has one or `required` is set. Other destinations are
+/// not checked.
+fn verify_commitment(
+    dest: &Dest,
+    deposit_addr: &str,
+    sig: &CommitmentSignature,
+    required: bool,
+) -> Result<(), ApiError> {
+    let addr = match dest {
+        Dest::Address(addr) => addr,
+        _ => return Ok(()),
+    };
+    let (pubkey, signature) = match (&sig.pubkey, &sig.signature) {
+        (Some(pubkey), Some(signature)) => (pubkey, signature),
+        (None, None) if !required => return Ok(()),
+        _ => return Err(ApiError::MissingSignature),
+    };
+
+    let pubkey = hex::decode(pubkey)
+        .ok()
+        .and_then(|bytes| PublicKey::from_slice(&bytes).ok())
+        .ok_or(ApiError::InvalidSignature)?;
+    let signature = hex::decode(signature)
+        .ok()
+        .and_then(|bytes| Signature::from_compact(&bytes).ok())
+        .ok_or(ApiError::InvalidSignature)?;
+    if Address::from_pubkey(pubkey.serialize()) != *addr {
+        return Err(ApiError::InvalidSignature);
+    }
+
+    Secp256k1::verification_only()
+        .verify_ecdsa(&commitment_message(deposit_addr), &signature, &pubkey)
+        .map_err(|_| ApiError::InvalidSignature)
+}
+
+/// Rejects requests from IP addresses over the rate limit.
+fn rate_limit(
+    config: &Config,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static {
+    let limiter = Arc::new(Mutex::new(RateLimiter::new(
+        config.max_requests_per_minute,
+        RATE_LIMIT_WINDOW,
+    )));
+    let trust_forwarded_for = config.trust_forwarded_for;
+
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("x-forwarded-for"))
+        .and_then(
+            move |remote: Option<SocketAddr>, forwarded_for: Option<String>| {
+                let limiter = limiter.clone();
+                async move {
+                    let forwarded_ip = forwarded_for
+                        .filter(|_| trust_forwarded_for)
+                        .and_then(|header| header.split(',').next()?.trim().parse().ok());
+                    let ip = match forwarded_ip.or(remote.map(|addr| addr.ip())) {
+                        Some(ip) => ip,
+                        None => return Ok(()),
+                    };
+
+                    if limiter.lock().unwrap().check(ip, Instant::now()) {
+                        Ok(())
+                    } else {
+                        debug!("Rate limited address server request from {}", ip);
+                        Err(warp::reject::custom(ApiError::RateLimited))
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Fetches the signatory set from the sidechain, or from the cache, failing
+/// if deposits to it are disabled.
+async fn get_sigset(
+    app_client_addr: &str,
+    sigsets: &Mutex<SigsetCache>,
+    index: u32,
+) -> Result<SigsetInfo, ApiError> {
+    if let Some(info) = sigsets.lock().unwrap().get(index, Instant::now()) {
+        return Ok(info);
+    }
+
+    let (info, deposits_enabled) = app_client(app_client_addr)
+        .query(|app| {
+            let checkpoints = &app.bitcoin.checkpoints;
+            let first_index = checkpoints.index() + 1 - checkpoints.len()?;
+            if index > checkpoints.index() || index < first_index {
+                return Ok(None);
+            }
+            let cp = checkpoints.get(index)?;
+            Ok(Some((
+                (
+                    cp.sigset.clone(),
+                    cp.sigset_threshold,
+                    app.bitcoin.network(),
+                ),
+                cp.deposits_enabled,
+            )))
+        })
+        .await
+        .map_err(|err| {
+            warn!("Address server query failed: {}", err);
+            ApiError::Unavailable
+        })?
+        .ok_or(ApiError::UnknownSigset(index))?;
+    if !deposits_enabled {
+        return Err(ApiError::DepositsDisabled(index));
+    }
+
+    sigsets
+        .lock()
+        .unwrap()
+        .insert(index, info.clone(), Instant::now());
+
+    Ok(info)
+}
+
+/// Checks a deposit address announcement, returning the destination it
+/// commits to.
+async fn check_announcement(
+    config: &Config,
+    app_client_addr: &str,
+    sigsets: &Mutex<SigsetCache>,
+    query: &DepositAddress,
+    sig: &CommitmentSignature,
+    body: Bytes,
+) -> Result<Dest, ApiError> {
+    let dest = Dest::decode(body.as_ref())
+        .map_err(|err| ApiError::InvalidRequest(format!("Invalid commitment: {}", err)))?;
+    let dest_bytes = dest
+        .commitment_bytes()
+        .map_err(|err| ApiError::InvalidRequest(format!("Invalid commitment: {}", err)))?;
+
+    let (sigset, threshold, network) =
+        get_sigset(app_client_addr, sigsets, query.sigset_index).await?;
+    let expected_scripts = [
+        sigset.output_script(dest_bytes.as_slice(), threshold),
+        sigset.taproot_output_script(dest_bytes.as_slice(), threshold),
+    ];
+    let is_expected_addr = expected_scripts.iter().any(|script| {
+        script
+            .as_ref()
+            .ok()
+            .and_then(|script| ::bitcoin::Address::from_script(script, network).ok())
+            .map_or(false, |addr| addr.to_string() == query.deposit_addr)
+    });
+    if !is_expected_addr {
+        return Err(ApiError::InvalidDepositAddress);
+    }
+
+    verify_commitment(
+        &dest,
+        &query.deposit_addr,
+        sig,
+        config.require_signed_commitments,
+    )?;
+
+    Ok(dest)
+}
+
+/// Serves the address server, sending each valid announcement's destination
+/// and signatory set index to `send`.
+pub fn serve(
+    config: Config,
+    app_client_addr: String,
+    send: Sender<(Dest, u32)>,
+) -> impl Future<Output = ()> {
+    // TODO: pass into closures more cleanly
+    let app_client_addr: &'static str = app_client_addr.leak();
+    let sigsets = Arc::new(Mutex::new(SigsetCache::new(config.max_cached_sigsets)));
+    let listen_addr = config.listen_addr;
+    let rate_limit = rate_limit(&config);
+
+    let bcast_route = warp::post()
+        .and(warp::path("address"))
+        .and(warp::query::<DepositAddress>())
+        .and(warp::query::<CommitmentSignature>())
+        .and(warp::body::content_length_limit(MAX_BODY_SIZE))
+        .and(warp::body::bytes())
+        .and_then(
+            move |query: DepositAddress, sig: CommitmentSignature, body: Bytes| {
+                let config = config.clone();
+                let sigsets = sigsets.clone();
+                let send = send.clone();
+                async move {
+                    let dest =
+                        check_announcement(&config, app_client_addr, &sigsets, &query, &sig, body)
+                            .await
+                            .map_err(warp::reject::custom)?;
+
+                    debug!(
+                        "Received deposit commitment: {:?}, {}",
+                        dest, query.sigset_index
+                    );
+                    send.send((dest, query.sigset_index))
+                        .await
+                        .map_err(|_| warp::reject::custom(ApiError::Internal))?;
+
+                    Ok::<_, Rejection>("OK")
+                }
+            },
+        );
+
+    let sigset_route = warp::path("sigset").and_then(move || async {
+        let sigset = app_client(app_client_addr)
+            .query(|app| {
+                let sigset = RawSignatorySet::new(
+                    app.bitcoin.checkpoints.active_sigset()?,
+                    app.bitcoin.checkpoints.active_sigset_threshold()?,
+                );
+                Ok(sigset)
+            })
+            .await
+            .map_err(|err| {
+                warn!("Address server query failed: {}", err);
+                warp::reject::custom(ApiError::Unavailable)
+            })?;
+
+        Ok::<_, Rejection>(warp::reply::json(&sigset))
+    });
+
+    let routes = rate_limit
+        .and(bcast_route.or(sigset_route))
+        .recover(handle_rejection)
+        .with(
+            warp::cors()
+                .allow_any_origin()
+                .allow_headers(vec![
+                    "User-Agent",
+                    "Sec-Fetch-Mode",
+                    "Referer",
+                    "Origin",
+                    "Access-Control-Request-Method",
+                    "Access-Control-Request-Headers",
+                    "content-type",
+                ])
+                .allow_method("POST"),
+        );
+
+    warp::serve(routes).run(listen_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::rand;
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::new(2, RATE_LIMIT_WINDOW);
+        let now = Instant::now();
+        let a = IpAddr::from([1, 2, 3, 4]);
+        let b = IpAddr::from([5, 6, 7, 8]);
+
+        assert!(limiter.check(a, now));
+        assert!(limiter.check(a, now));
+        assert!(!limiter.check(a, now));
+        assert!(limiter.check(b, now));
+
+        let later = now + RATE_LIMIT_WINDOW;
+        assert!(limiter.check(a, later));
+        assert_eq!(limiter.counts.len(), 1);
+    }
+
+    #[test]
+    fn sigset_cache() {
+        let mut cache = SigsetCache::new(2);
+        let info = (SignatorySet::default(), (2, 3), Network::Bitcoin);
+        let now = Instant::now();
+        for index in [5, 3, 7] {
+            cache.insert(index, info.clone(), now);
+        }
+
+        assert!(cache.get(3, now).is_none());
+        assert!(cache.get(5, now).is_some());
+        assert!(cache.get(7, now).is_some());
+
+        // expired sets are fetched again, to re-check that deposits are
+        // enabled
+        assert!(cache.get(7, now + SIGSET_CACHE_TTL).is_none());
+    }
+
+    #[test]
+    fn commitment_signature() {
+        let privkey = SecretKey::new(&mut rand::thread_rng());
+        let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &privkey);
+        let dest = Dest::Address(Address::from_pubkey(pubkey.serialize()));
+        let other = Dest::Address(Address::from_pubkey([2; 33]));
+        let sig = sign_commitment(&privkey, "bc1qexample");
+
+        verify_commitment(&dest, "bc1qexample", &sig, true).unwrap();
+        verify_commitment(&dest, "bc1qexample", &Default::default(), false).unwrap();
+        assert!(matches!(
+            verify_commitment(&dest, "bc1qexample", &Default::default(), true),
+            Err(ApiError::MissingSignature)
+        ));
+        assert!(matches!(
+            verify_commitment(&dest, "bc1qother", &sig, false),
+            Err(ApiError::InvalidSignature)
+        ));
+        assert!(matches!(
+            verify_commitment(&other, "bc1qexample", &sig, false),
+            Err(ApiError::InvalidSignature)
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_reply() {
+        let res = handle_rejection(warp::reject::custom(ApiError::RateLimited))
+            .await
+            .unwrap()
+            .into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "rate_limited");
+    }
+}
@@ -0,0 +1,394 @@
+//! An append-only, node-local archive of checkpoints, which keeps them once
+//! [`CheckpointQueue::prune`](super::checkpoint::CheckpointQueue::prune) drops
+//! them from state.
+//!
+//! A node runs [`poll`] alongside the chain, which queries the node for
+//! checkpoints as they complete and appends them to the archive file. The file
+//! is in JSON Lines format: one [`ArchivedCheckpoint`] object per line, in
+//! increasing index order, each terminated by `\n`. Transactions are
+//! hex-encoded in Bitcoin's consensus serialization, including any signatures.
+//!
+//! Each record's `prev_hash` is the hex-encoded SHA-256 hash of the previous
+//! line's bytes (excluding the newline), or 32 zero bytes for the first
+//! record, so the records form a hash chain which [`verify`] checks along with
+//! the links between consecutive checkpoint transactions.
+//!
+//! Checkpoints are only kept in state for the checkpoint queue's `max_age`, so
+//! a node which is stopped for longer than that can't fill in the checkpoints
+//! pruned in the meantime. Archiving then stops with an error rather than
+//! leaving a gap in the archive.
+
+use super::checkpoint::{BatchType, Checkpoint, CheckpointStatus};
+use crate::app_client;
+use crate::error::{Error, Result};
+use bitcoin::consensus::{deserialize, serialize};
+use bitcoin::Transaction;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The name of the archive file in the node home.
+pub const FILE_NAME: &str = "checkpoint-archive.jsonl";
+
+/// How often [`poll`] checks the node for newly completed checkpoints.
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedSignatory {
+    /// The signatory's public key for the checkpoint's signatory set, in hex.
+    pub pubkey: String,
+    pub voting_power: u64,
+}
+
+/// A pruned checkpoint, as stored in one line of the archive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ArchivedCheckpoint {
+    pub index: u32,
+    /// The hash of the previous record, in hex.
+    pub prev_hash: String,
+    pub status: CheckpointStatus,
+    pub create_time: u64,
+    pub fee_rate: u64,
+    pub signed_at_btc_height: Option<u32>,
+    pub sigset_index: u32,
+    pub sigset_threshold: (u64, u64),
+    pub present_vp: u64,
+    pub possible_vp: u64,
+    pub signatories: Vec<ArchivedSignatory>,
+    /// The transactions of each batch, in batch order: disbursal,
+    /// intermediate, then checkpoint. Each is hex-encoded.
+    pub batches: Vec<Vec<String>>,
+}
+
+impl ArchivedCheckpoint {
+    pub fn from_checkpoint(index: u32, checkpoint: &Checkpoint) -> Result<Self> {
+        let mut batches = vec![];
+        for batch in checkpoint.batches.iter()? {
+            let mut txs = vec![];
+            for tx in batch?.iter()? {
+                txs.push(hex::encode(serialize(&tx?.to_bitcoin_tx()?)));
+            }
+            batches.push(txs);
+        }
+
+        let sigset = &checkpoint.sigset;
+        Ok(ArchivedCheckpoint {
+            index,
+            prev_hash: hex::encode([0; 32]),
+            status: checkpoint.status,
+            create_time: checkpoint.create_time(),
+            fee_rate: checkpoint.fee_rate,
+            signed_at_btc_height: checkpoint.signed_at_btc_height,
+            sigset_index: sigset.index,
+            sigset_threshold: checkpoint.sigset_threshold,
+            present_vp: sigset.present_vp,
+            possible_vp: sigset.possible_vp,
+            signatories: sigset
+                .iter()
+                .map(|signatory| ArchivedSignatory {
+                    pubkey: hex::encode(signatory.pubkey.as_slice()),
+                    voting_power: signatory.voting_power,
+                })
+                .collect(),
+            batches,
+        })
+    }
+
+    /// Decodes the transactions of the batch of the given type.
+    pub fn txs(&self, batch: BatchType) -> Result<Vec<Transaction>> {
+        self.batches
+            .get(batch as usize)
+            .map_or(&[][..], |txs| txs.as_slice())
+            .iter()
+            .map(|tx| {
+                let bytes = hex::decode(tx)
+                    .map_err(|_| Error::Checkpoint("Invalid transaction hex".to_string()))?;
+                Ok(deserialize(&bytes)?)
+            })
+            .collect()
+    }
+
+    pub fn checkpoint_tx(&self) -> Result<Transaction> {
+        self.txs(BatchType::Checkpoint)?
+            .pop()
+            .ok_or_else(|| Error::Checkpoint("Checkpoint has no transaction".to_string()))
+    }
+}
+
+/// An archive file opened for appending, along with the index and hash of its
+/// last record.
+pub struct Archive {
+    path: PathBuf,
+    last: Option<(u32, [u8; 32])>,
+}
+
+impl Archive {
+    /// Opens the archive at `path`, which is created on the first append if it
+    /// doesn't exist.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let last = read(&path)?
+            .last()
+            .map(|(record, hash)| (record.index, *hash));
+
+        Ok(Archive { path, last })
+    }
+
+    /// The index of the last archived checkpoint.
+    pub fn last_index(&self) -> Option<u32> {
+        self.last.map(|(index, _)| index)
+    }
+
+    /// Appends the record, which must follow the last record, linking it to
+    /// the last record's hash.
+    pub fn append(&mut self, mut record: ArchivedCheckpoint) -> Result<()> {
+        if let Some((last_index, hash)) = self.last {
+            if record.index != last_index + 1 {
+                return Err(Error::Checkpoint(format!(
+                    "Checkpoint {} does not follow the last archived checkpoint {}",
+                    record.index, last_index
+                )));
+            }
+            record.prev_hash = hex::encode(hash);
+        }
+        let line = serde_json::to_string(&record)
+            .map_err(|err| Error::Checkpoint(format!("Could not encode checkpoint: {}", err)))?;
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path.as_path())?;
+        file.write_all(line.as_bytes())?;
+        file.write_all(b"\n")?;
+        file.sync_data()?;
+
+        self.last = Some((record.index, Sha256::digest(line.as_bytes()).into()));
+
+        Ok(())
+    }
+
+    /// Appends the checkpoints the node at `app_client_addr` has completed
+    /// since the last archived checkpoint. An empty archive starts from the
+    /// oldest checkpoint still in state.
+    pub async fn sync(&mut self, app_client_addr: &str) -> Result<()> {
+        let (first_index, last_completed) = app_client(app_client_addr)
+            .query(|app| {
+                let checkpoints = &app.bitcoin.checkpoints;
+                Ok((
+                    checkpoints.index() + 1 - checkpoints.len()?,
+                    checkpoints.last_completed_index().ok(),
+                ))
+            })
+            .await?;
+        let Some(last_completed) = last_completed else {
+            return Ok(());
+        };
+
+        let next = match self.last_index() {
+            Some(last_index) if last_index + 1 < first_index => {
+                return Err(Error::Checkpoint(format!(
+                    "Checkpoints {} to {} were pruned from state before they were archived",
+                    last_index + 1,
+                    first_index - 1
+                )));
+            }
+            Some(last_index) => last_index + 1,
+            None => first_index,
+        };
+
+        for index in next..=last_completed {
+            let record = app_client(app_client_addr)
+                .query(|app| {
+                    let checkpoint = app.bitcoin.checkpoints.get(index)?;
+                    Ok(ArchivedCheckpoint::from_checkpoint(index, &checkpoint)?)
+                })
+                .await?;
+            self.append(record)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Periodically archives the checkpoints completed by the node at
+/// `app_client_addr` to the file at `path`.
+pub async fn poll(path: PathBuf, app_client_addr: String) {
+    let mut archive = match Archive::open(path) {
+        Ok(archive) => archive,
+        Err(err) => {
+            log::error!("Failed to open checkpoint archive: {}", err);
+            return;
+        }
+    };
+
+    loop {
+        if let Err(err) = archive.sync(&app_client_addr).await {
+            log::error!("Failed to archive checkpoints: {}", err);
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Reads all records of the archive at `path`, along with the hash of each
+/// line. A missing file is an empty archive.
+pub fn read(path: &Path) -> Result<Vec<(ArchivedCheckpoint, [u8; 32])>> {
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    let mut records = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        let record: ArchivedCheckpoint = serde_json::from_str(&line)
+            .map_err(|err| Error::Checkpoint(format!("Invalid archive record: {}", err)))?;
+        records.push((record, Sha256::digest(line.as_bytes()).into()));
+    }
+
+    Ok(records)
+}
+
+/// Checks the archive's hash chain, that its records have consecutive indexes,
+/// and that each checkpoint transaction spends the reserve output of the one
+/// before it. Returns the number of records.
+pub fn verify(path: &Path) -> Result<usize> {
+    let records = read(path)?;
+
+    let mut prev: Option<(&ArchivedCheckpoint, &[u8; 32])> = None;
+    for (record, hash) in records.iter() {
+        let checkpoint_tx = record.checkpoint_tx()?;
+        record.txs(BatchType::Disbursal)?;
+        record.txs(BatchType::IntermediateTx)?;
+
+        let Some((prev_record, prev_hash)) = prev else {
+            prev = Some((record, hash));
+            continue;
+        };
+
+        if record.index != prev_record.index + 1 {
+            return Err(Error::Checkpoint(format!(
+                "Archive skips from checkpoint {} to {}",
+                prev_record.index, record.index
+            )));
+        }
+
+        if record.prev_hash != hex::encode(prev_hash) {
+            return Err(Error::Checkpoint(format!(
+                "Checkpoint {} does not commit to the previous record",
+                record.index
+            )));
+        }
+
+        let prev_txid = prev_record.checkpoint_tx()?.txid();
+        let spends_reserve = checkpoint_tx.input.first().map_or(false, |input| {
+            input.previous_output.txid == prev_txid && input.previous_output.vout == 0
+        });
+        if !spends_reserve {
+            return Err(Error::Checkpoint(format!(
+                "Checkpoint {} does not spend the reserve of checkpoint {}",
+                record.index, prev_record.index
+            )));
+        }
+
+        prev = Some((record, hash));
+    }
+
+    Ok(records.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+    use bitcoin::{OutPoint, PackedLockTime, Script, TxIn, TxOut};
+
+    fn record(index: u32, prev_txid: bitcoin::Txid, prev_hash: [u8; 32]) -> ArchivedCheckpoint {
+        let tx = Transaction {
+            version: 1,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(prev_txid, 0),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: Script::new(),
+            }],
+        };
+
+        ArchivedCheckpoint {
+            index,
+            prev_hash: hex::encode(prev_hash),
+            status: CheckpointStatus::Complete,
+            create_time: 0,
+            fee_rate: 1,
+            signed_at_btc_height: None,
+            sigset_index: index,
+            sigset_threshold: (2, 3),
+            present_vp: 0,
+            possible_vp: 0,
+            signatories: vec![],
+            batches: vec![vec![], vec![], vec![hex::encode(serialize(&tx))]],
+        }
+    }
+
+    fn write(path: &Path, records: &[ArchivedCheckpoint]) {
+        let lines: Vec<_> = records
+            .iter()
+            .map(|record| serde_json::to_string(record).unwrap() + "\n")
+            .collect();
+        std::fs::write(path, lines.concat()).unwrap();
+    }
+
+    #[test]
+    fn verify_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        assert_eq!(verify(&path).unwrap(), 0);
+
+        let first = record(5, bitcoin::Txid::all_zeros(), [0; 32]);
+        let first_hash: [u8; 32] = Sha256::digest(serde_json::to_string(&first).unwrap()).into();
+        let first_txid = first.checkpoint_tx().unwrap().txid();
+
+        write(&path, &[first.clone(), record(6, first_txid, first_hash)]);
+        assert_eq!(verify(&path).unwrap(), 2);
+
+        write(&path, &[first.clone(), record(6, first_txid, [1; 32])]);
+        assert!(verify(&path).is_err());
+
+        write(&path, &[first.clone(), record(7, first_txid, first_hash)]);
+        assert!(verify(&path).is_err());
+
+        write(
+            &path,
+            &[first, record(6, bitcoin::Txid::all_zeros(), first_hash)],
+        );
+        assert!(verify(&path).is_err());
+    }
+
+    #[test]
+    fn append_to_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+
+        let mut archive = Archive::open(path.clone()).unwrap();
+        assert_eq!(archive.last_index(), None);
+
+        let first = record(5, bitcoin::Txid::all_zeros(), [0; 32]);
+        let first_txid = first.checkpoint_tx().unwrap().txid();
+        archive.append(first).unwrap();
+        // the previous hash is filled in when appending
+        archive.append(record(6, first_txid, [1; 32])).unwrap();
+        assert_eq!(archive.last_index(), Some(6));
+        assert_eq!(verify(&path).unwrap(), 2);
+
+        let mut archive = Archive::open(path.clone()).unwrap();
+        assert_eq!(archive.last_index(), Some(6));
+        assert!(archive
+            .append(record(8, bitcoin::Txid::all_zeros(), [0; 32]))
+            .is_err());
+        assert_eq!(verify(&path).unwrap(), 2);
+    }
+}
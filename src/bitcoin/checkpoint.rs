@@ -2,8 +2,8 @@
 use super::ConsensusKey;
 use super::{
     adapter::Adapter,
-    signatory::SignatorySet,
-    threshold_sig::{Signature, ThresholdSig},
+    signatory::{taproot_control_block, SignatorySet},
+    threshold_sig::{Pubkey, SigScheme, Signature, ThresholdSig},
     Xpub,
 };
 use crate::error::{Error, Result};
@@ -12,6 +12,8 @@ use crate::{
     bitcoin::{signatory::derive_pubkey, Nbtc},
 };
 use bitcoin::hashes::Hash;
+use bitcoin::util::sighash::{Prevouts, SchnorrSighashType, SighashCache};
+use bitcoin::util::taproot::{LeafVersion, TapLeafHash};
 use bitcoin::{blockdata::transaction::EcdsaSighashType, Sequence, Transaction, TxIn, TxOut};
 use derive_more::{Deref, DerefMut};
 use log::info;
@@ -113,9 +115,12 @@ pub struct Input {
 
 impl Input {
     pub fn to_txin(&self) -> Result<TxIn> {
-        let mut witness = self.signatures.to_witness()?;
+        let mut witness = self.signatures.to_witness(self.sig_scheme())?;
         if self.signatures.signed() {
             witness.push(self.redeem_script.to_bytes());
+            if self.is_taproot() {
+                witness.push(taproot_control_block(&self.redeem_script)?);
+            }
         }
 
         Ok(bitcoin::TxIn {
@@ -132,9 +137,21 @@ impl Input {
         dest: &[u8],
         amount: u64,
         threshold: (u64, u64),
+        taproot: bool,
     ) -> Result<Self> {
-        let script_pubkey = sigset.output_script(dest, threshold)?;
-        let redeem_script = sigset.redeem_script(dest, threshold)?;
+        let (script_pubkey, redeem_script, est_witness_vsize) = if taproot {
+            (
+                sigset.taproot_output_script(dest, threshold)?,
+                sigset.taproot_script(dest, threshold)?,
+                sigset.est_taproot_witness_vsize(),
+            )
+        } else {
+            (
+                sigset.output_script(dest, threshold)?,
+                sigset.redeem_script(dest, threshold)?,
+                sigset.est_witness_vsize(),
+            )
+        };
 
         Ok(Input {
             prevout: Adapter::new(prevout),
@@ -143,14 +160,56 @@ impl Input {
             sigset_index: sigset.index(),
             dest: dest.encode()?.try_into()?,
             amount,
-            est_witness_vsize: sigset.est_witness_vsize(),
-            signatures: ThresholdSig::from_sigset(sigset)?,
+            est_witness_vsize,
+            signatures: ThresholdSig::from_sigset(sigset, threshold)?,
         })
     }
 
     pub fn est_vsize(&self) -> u64 {
         self.est_witness_vsize + 40
     }
+
+    /// Whether this input spends a taproot output through the signatory
+    /// script path.
+    pub fn is_taproot(&self) -> bool {
+        self.script_pubkey.is_v1_p2tr()
+    }
+
+    pub fn sig_scheme(&self) -> SigScheme {
+        if self.is_taproot() {
+            SigScheme::Schnorr
+        } else {
+            SigScheme::Ecdsa
+        }
+    }
+
+    /// Computes the message signatories must sign for this input, given the
+    /// spending transaction and the outputs spent by all of its inputs.
+    fn sighash(
+        &self,
+        sc: &mut SighashCache<&Transaction>,
+        input_index: usize,
+        prevouts: &[TxOut],
+    ) -> Result<[u8; 32]> {
+        if self.is_taproot() {
+            let leaf_hash = TapLeafHash::from_script(&self.redeem_script, LeafVersion::TapScript);
+            let sighash = sc.taproot_script_spend_signature_hash(
+                input_index,
+                &Prevouts::All(prevouts),
+                leaf_hash,
+                SchnorrSighashType::Default,
+            )?;
+            Ok(sighash.into_inner())
+        } else {
+            let sighash = sc.segwit_signature_hash(
+                input_index,
+                &self.redeem_script,
+                self.amount,
+                EcdsaSighashType::All,
+            )?;
+            Ok(sighash.into_inner())
+        }
+    }
 }
 
 impl MigrateFrom<InputV0> for InputV1 {
@@ -214,22 +273,76 @@ impl BitcoinTx {
             .fold(Ok(0), |sum: Result<u64>, out| Ok(sum? + out?.value))
     }
 
+    /// The virtual size of a checkpoint transaction's inputs and outputs
+    /// which depositors and withdrawers are charged for: every input but the
+    /// reserve's, which commits to the destination `[0]`, and every output
+    /// but the reserve output.
+    pub fn charged_vsize(&self) -> Result<u64> {
+        let mut vsize = 0;
+        for input in self.input.iter()? {
+            let input = input?;
+            if input.dest.as_slice() != [0u8] {
+                vsize += input.est_vsize();
+            }
+        }
+        for output in self.output.iter()?.skip(1) {
+            vsize += 9 + output?.script_pubkey.len() as u64;
+        }
+
+        Ok(vsize)
+    }
+
+    /// The outputs spent by each of the transaction's inputs, in order. These
+    /// are committed to by taproot signature hashes.
+    pub fn prevouts(&self) -> Result<Vec<TxOut>> {
+        self.input
+            .iter()?
+            .map(|input| {
+                let input = input?;
+                Ok(TxOut {
+                    value: input.amount,
+                    script_pubkey: input.script_pubkey.clone().into_inner(),
+                })
+            })
+            .collect()
+    }
+
+    /// Removes the first output matching `txout`, preserving the order of the
+    /// remaining outputs by shifting the outputs after it down one index.
+    /// Returns whether an output was removed.
+    pub fn remove_output(&mut self, txout: &TxOut) -> Result<bool> {
+        let mut index = None;
+        for (i, output) in self.output.iter()?.enumerate() {
+            if **output? == *txout {
+                index = Some(i as u64);
+                break;
+            }
+        }
+        let index = match index {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        for i in index..self.output.len() - 1 {
+            let next = self.output.get(i + 1)?.unwrap().clone();
+            *self.output.get_mut(i)?.unwrap() = next;
+        }
+        self.output.pop_back()?;
+
+        Ok(true)
+    }
+
     pub fn populate_input_sig_message(&mut self, input_index: usize) -> Result<()> {
         let bitcoin_tx = self.to_bitcoin_tx()?;
-        let mut sc = bitcoin::util::sighash::SighashCache::new(&bitcoin_tx);
+        let prevouts = self.prevouts()?;
+        let mut sc = SighashCache::new(&bitcoin_tx);
         let mut input = self
             .input
             .get_mut(input_index as u64)?
             .ok_or(Error::InputIndexOutOfBounds(input_index))?;
 
-        let sighash = sc.segwit_signature_hash(
-            input_index,
-            &input.redeem_script,
-            input.amount,
-            EcdsaSighashType::All,
-        )?;
-
-        input.signatures.set_message(sighash.into_inner());
+        let sighash = input.sighash(&mut sc, input_index, &prevouts)?;
+        input.signatures.set_message(sighash);
 
         Ok(())
     }
@@ -308,20 +421,38 @@ impl Batch {
 
 pub const DEFAULT_FEE_RATE: u64 = 10;
 
-#[orga(skip(Default), version = 3)]
+/// The maximum number of unconfirmed checkpoints a fee bump will be chained
+/// onto, keeping the package within Bitcoin Core's default mempool ancestor
+/// limit of 25 transactions.
+pub const MAX_FEE_BUMP_ANCESTORS: u32 = 20;
+
+#[orga(skip(Default), version = 5)]
 #[derive(Debug)]
 pub struct Checkpoint {
     pub status: CheckpointStatus,
     pub batches: Deque<Batch>,
-    #[orga(version(V2, V3))]
+    #[orga(version(V2, V3, V4, V5))]
     pub pending: Map<Dest, Coin<Nbtc>>,
-    #[orga(version(V3))]
+    #[orga(version(V3, V4, V5))]
     pub fee_rate: u64,
-    #[orga(version(V3))]
+    #[orga(version(V3, V4, V5))]
     pub signed_at_btc_height: Option<u32>,
-    #[orga(version(V3))]
+    #[orga(version(V3, V4, V5))]
     pub deposits_enabled: bool,
     pub sigset: SignatorySet,
+    /// The fraction of the signatory set's voting power required to spend
+    /// outputs paying to it, as configured when the checkpoint was created.
+    #[orga(version(V4, V5))]
+    pub sigset_threshold: (u64, u64),
+    /// Fee rates, in satoshis per virtual byte, estimated by signatories'
+    /// Bitcoin nodes and reported when signing this checkpoint.
+    #[orga(version(V5))]
+    pub fee_rate_estimates: Map<Pubkey, u64>,
+    /// The part of the checkpoint transaction's fee paid out of the reserve
+    /// rather than charged to depositors and withdrawers, in satoshis. Set
+    /// when the checkpoint stops building.
+    #[orga(version(V5))]
+    pub reserve_fee: u64,
 }
 
 impl MigrateFrom<CheckpointV0> for CheckpointV1 {
@@ -355,9 +486,42 @@ impl MigrateFrom<CheckpointV2> for CheckpointV3 {
     }
 }
 
+impl MigrateFrom<CheckpointV3> for CheckpointV4 {
+    fn migrate_from(value: CheckpointV3) -> OrgaResult<Self> {
+        Ok(Self {
+            status: value.status,
+            batches: value.batches,
+            pending: value.pending,
+            fee_rate: value.fee_rate,
+            signed_at_btc_height: value.signed_at_btc_height,
+            deposits_enabled: value.deposits_enabled,
+            sigset: value.sigset,
+            // the threshold was fixed at 9/10 before it was stored
+            sigset_threshold: (9, 10),
+        })
+    }
+}
+
+impl MigrateFrom<CheckpointV4> for CheckpointV5 {
+    fn migrate_from(value: CheckpointV4) -> OrgaResult<Self> {
+        Ok(Self {
+            status: value.status,
+            batches: value.batches,
+            pending: value.pending,
+            fee_rate: value.fee_rate,
+            signed_at_btc_height: value.signed_at_btc_height,
+            deposits_enabled: value.deposits_enabled,
+            sigset: value.sigset,
+            sigset_threshold: value.sigset_threshold,
+            fee_rate_estimates: Map::new(),
+            reserve_fee: 0,
+        })
+    }
+}
+
 #[orga]
 impl Checkpoint {
-    pub fn new(sigset: SignatorySet) -> Result<Self> {
+    pub fn new(sigset: SignatorySet, sigset_threshold: (u64, u64)) -> Result<Self> {
         let mut checkpoint = Checkpoint {
             status: CheckpointStatus::default(),
             batches: Deque::default(),
@@ -366,6 +530,9 @@ impl Checkpoint {
             signed_at_btc_height: None,
             deposits_enabled: true,
             sigset,
+            sigset_threshold,
+            fee_rate_estimates: Map::new(),
+            reserve_fee: 0,
         };
 
         let disbursal_batch = Batch::default();
@@ -415,7 +582,8 @@ impl Checkpoint {
                     sig_index += 1;
 
                     let input_was_signed = input.signatures.signed();
-                    input.signatures.sign(pubkey.into(), sig)?;
+                    let scheme = input.sig_scheme();
+                    input.signatures.sign(pubkey.into(), sig, scheme)?;
 
                     if !input_was_signed && input.signatures.signed() {
                         tx.signed_inputs += 1;
@@ -455,6 +623,23 @@ impl Checkpoint {
         ))
     }
 
+    /// The part of the checkpoint transaction's fee paid out of the reserve,
+    /// for a checkpoint which stopped building before `reserve_fee` was
+    /// tracked. Fees were never bumped then, so depositors and withdrawers
+    /// were charged at the checkpoint's fee rate.
+    pub fn untracked_reserve_fee(&self) -> Result<u64> {
+        let checkpoint_batch = self.batches.get(BatchType::Checkpoint as u64)?.unwrap();
+        let checkpoint_tx = checkpoint_batch.back()?.unwrap();
+
+        let mut in_amount = 0;
+        for input in checkpoint_tx.input.iter()? {
+            in_amount += input?.amount;
+        }
+        let fee = in_amount.saturating_sub(checkpoint_tx.value()?);
+
+        Ok(fee.saturating_sub(checkpoint_tx.charged_vsize()? * self.fee_rate))
+    }
+
     pub fn reserve_output(&self) -> Result<Option<TxOut>> {
         let checkpoint_tx = self.checkpoint_tx()?;
         if let Some(output) = checkpoint_tx.output.get(0) {
@@ -464,9 +649,58 @@ impl Checkpoint {
         }
     }
 
+    /// Records a fee rate estimate reported by the signatory with the given
+    /// xpub, replacing any previous estimate. Keys which are not part of the
+    /// checkpoint's signatory set are ignored.
+    fn report_fee_rate(&mut self, xpub: Xpub, fee_rate: u64) -> Result<()> {
+        let secp = bitcoin::secp256k1::Secp256k1::verification_only();
+        let pubkey: Pubkey = derive_pubkey(&secp, xpub, self.sigset.index)?.into();
+
+        let is_signatory = self
+            .sigset
+            .iter()
+            .any(|signatory| Pubkey::from(signatory.pubkey) == pubkey);
+        if is_signatory {
+            self.fee_rate_estimates.insert(pubkey, fee_rate)?;
+        }
+
+        Ok(())
+    }
+
+    /// The median of the reported fee rate estimates, weighted by the voting
+    /// power of the reporting signatories. Returns `None` if the estimates
+    /// represent no more than half of the signatory set's voting power.
+    #[query]
+    pub fn fee_rate_estimate(&self) -> Result<Option<u64>> {
+        let mut estimates = vec![];
+        let mut reported_vp = 0;
+        for signatory in self.sigset.iter() {
+            let pubkey = Pubkey::from(signatory.pubkey);
+            if let Some(fee_rate) = self.fee_rate_estimates.get(pubkey)? {
+                estimates.push((*fee_rate, signatory.voting_power));
+                reported_vp += signatory.voting_power;
+            }
+        }
+
+        if reported_vp * 2 <= self.sigset.present_vp {
+            return Ok(None);
+        }
+
+        estimates.sort_unstable();
+        let mut cumulative_vp = 0;
+        for (fee_rate, voting_power) in estimates {
+            cumulative_vp += voting_power;
+            if cumulative_vp * 2 >= reported_vp {
+                return Ok(Some(fee_rate));
+            }
+        }
+
+        Ok(None)
+    }
+
     //TODO: thread local secpk256k1 context
     #[query]
-    pub fn to_sign(&self, xpub: Xpub) -> Result<Vec<([u8; 32], u32)>> {
+    pub fn to_sign(&self, xpub: Xpub) -> Result<Vec<([u8; 32], u32, SigScheme)>> {
         let secp = bitcoin::secp256k1::Secp256k1::verification_only();
 
         let mut msgs = vec![];
@@ -479,7 +713,11 @@ impl Checkpoint {
 
                     let pubkey = derive_pubkey(&secp, xpub, input.sigset_index)?;
                     if input.signatures.needs_sig(pubkey.into())? {
-                        msgs.push((input.signatures.message(), input.sigset_index));
+                        msgs.push((
+                            input.signatures.message(),
+                            input.sigset_index,
+                            input.sig_scheme(),
+                        ));
                     }
                 }
             }
@@ -521,7 +759,7 @@ impl Checkpoint {
     }
 }
 
-#[orga(skip(Default), version = 1)]
+#[orga(skip(Default), version = 2)]
 #[derive(Clone)]
 pub struct Config {
     pub min_checkpoints: u64,
@@ -532,36 +770,74 @@ pub struct Config {
     #[orga(version(V0))]
     pub fee_rate: u64,
     pub max_age: u64,
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub target_checkpoint_inclusion: u32,
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub min_fee_rate: u64,
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub max_fee_rate: u64,
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub sigset_threshold: (u64, u64),
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub emergency_disbursal_min_tx_amt: u64,
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub emergency_disbursal_lock_time_interval: u32,
-    #[orga(version(V1))]
+    #[orga(version(V1, V2))]
     pub emergency_disbursal_max_tx_size: u64,
+    #[orga(version(V2))]
+    pub taproot_outputs: bool,
 }
 
 impl MigrateFrom<ConfigV0> for ConfigV1 {
     fn migrate_from(value: ConfigV0) -> OrgaResult<Self> {
+        let default = Config::default();
         Ok(Self {
+            min_checkpoints: default.min_checkpoints,
             min_checkpoint_interval: value.min_checkpoint_interval,
             max_checkpoint_interval: value.max_checkpoint_interval,
             max_inputs: value.max_inputs,
             max_outputs: value.max_outputs,
             max_age: value.max_age,
-            ..Self::default()
+            target_checkpoint_inclusion: default.target_checkpoint_inclusion,
+            min_fee_rate: default.min_fee_rate,
+            max_fee_rate: default.max_fee_rate,
+            sigset_threshold: default.sigset_threshold,
+            emergency_disbursal_min_tx_amt: default.emergency_disbursal_min_tx_amt,
+            emergency_disbursal_lock_time_interval: default.emergency_disbursal_lock_time_interval,
+            emergency_disbursal_max_tx_size: default.emergency_disbursal_max_tx_size,
+        })
+    }
+}
+
+impl MigrateFrom<ConfigV1> for ConfigV2 {
+    fn migrate_from(value: ConfigV1) -> OrgaResult<Self> {
+        Ok(Self {
+            min_checkpoints: value.min_checkpoints,
+            min_checkpoint_interval: value.min_checkpoint_interval,
+            max_checkpoint_interval: value.max_checkpoint_interval,
+            max_inputs: value.max_inputs,
+            max_outputs: value.max_outputs,
+            max_age: value.max_age,
+            target_checkpoint_inclusion: value.target_checkpoint_inclusion,
+            min_fee_rate: value.min_fee_rate,
+            max_fee_rate: value.max_fee_rate,
+            sigset_threshold: value.sigset_threshold,
+            emergency_disbursal_min_tx_amt: value.emergency_disbursal_min_tx_amt,
+            emergency_disbursal_lock_time_interval: value.emergency_disbursal_lock_time_interval,
+            emergency_disbursal_max_tx_size: value.emergency_disbursal_max_tx_size,
+            taproot_outputs: false,
         })
     }
 }
 
 impl Config {
+    pub fn for_network(network: super::BitcoinNetwork) -> Self {
+        match network {
+            super::BitcoinNetwork::Regtest => Config::regtest(),
+            _ => Config::bitcoin(),
+        }
+    }
+
     fn regtest() -> Self {
         Self {
             min_checkpoint_interval: 15,
@@ -586,17 +862,33 @@ impl Config {
             emergency_disbursal_min_tx_amt: 1000,
             emergency_disbursal_lock_time_interval: 60 * 60 * 24 * 7, // one week
             emergency_disbursal_max_tx_size: 50_000,
+            taproot_outputs: false,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the output script paying to the given signatory set, as a P2TR
+    /// output if taproot outputs are enabled, or a P2WSH output otherwise. The
+    /// threshold must be the one stored with the signatory set's checkpoint,
+    /// which may differ from the currently configured `sigset_threshold`.
+    pub fn output_script(
+        &self,
+        sigset: &SignatorySet,
+        threshold: (u64, u64),
+        dest: &[u8],
+    ) -> Result<bitcoin::Script> {
+        if self.taproot_outputs {
+            sigset.taproot_output_script(dest, threshold)
+        } else {
+            sigset.output_script(dest, threshold)
         }
     }
 }
 
 impl Default for Config {
     fn default() -> Self {
-        match super::NETWORK {
-            bitcoin::Network::Regtest => Config::regtest(),
-            bitcoin::Network::Testnet | bitcoin::Network::Bitcoin => Config::bitcoin(),
-            _ => unimplemented!(),
-        }
+        Config::for_network(super::DEFAULT_NETWORK)
     }
 }
 
@@ -668,6 +960,38 @@ pub struct BuildingCheckpoint<'a>(Ref<'a, Checkpoint>);
 #[derive(Deref, DerefMut)]
 pub struct BuildingCheckpointMut<'a>(ChildMut<'a, u64, Checkpoint>);
 
+/// A transaction in the emergency disbursal tree, along with the values used
+/// while building it.
+struct DisbursalNode {
+    tx: BitcoinTx,
+    /// The range of this node's children in the level below.
+    children: std::ops::Range<usize>,
+    /// The total value of the disbursal outputs below this node.
+    gross: u64,
+    /// The share of ancestor fees paid by this node's subtree.
+    burden: u64,
+    /// The value of the output this node spends.
+    value: u64,
+    est_vsize: u64,
+    prevout: Option<bitcoin::OutPoint>,
+    pruned: bool,
+}
+
+impl DisbursalNode {
+    fn new(lock_time: u32, est_vsize: u64, children: std::ops::Range<usize>) -> Self {
+        DisbursalNode {
+            tx: BitcoinTx::with_lock_time(lock_time),
+            children,
+            gross: 0,
+            burden: 0,
+            value: 0,
+            est_vsize,
+            prevout: None,
+            pruned: false,
+        }
+    }
+}
+
 type BuildingAdvanceRes = (
     bitcoin::OutPoint,
     u64,
@@ -676,102 +1000,14 @@ type BuildingAdvanceRes = (
 );
 
 impl<'a> BuildingCheckpointMut<'a> {
-    fn link_intermediate_tx(&mut self, tx: &mut BitcoinTx, threshold: (u64, u64)) -> Result<()> {
-        let sigset = self.sigset.clone();
-        let output_script = sigset.output_script(&[0u8], threshold)?;
-        let tx_value = tx.value()?;
-
-        let mut intermediate_tx_batch = self
-            .batches
-            .get_mut(BatchType::IntermediateTx as u64)?
-            .unwrap();
-        let mut intermediate_tx = intermediate_tx_batch.get_mut(0)?.unwrap();
-        let num_outputs = u32::try_from(intermediate_tx.output.len())?;
-
-        let final_tx_input = Input::new(
-            bitcoin::OutPoint::new(intermediate_tx.txid()?, num_outputs),
-            &sigset,
-            &[0u8],
-            tx_value,
-            threshold,
-        )?;
-
-        let intermediate_tx_output = bitcoin::TxOut {
-            value: tx_value,
-            script_pubkey: output_script,
-        };
-
-        intermediate_tx
-            .output
-            .push_back(intermediate_tx_output.into())?;
-
-        tx.input.push_back(final_tx_input)?;
-
-        Ok(())
-    }
-
-    //TODO: Unit tests
-    fn deduct_emergency_disbursal_fees(&mut self, fee_rate: u64) -> Result<()> {
-        let intermediate_tx_fee = {
-            let mut intermediate_tx_batch = self
-                .batches
-                .get_mut(BatchType::IntermediateTx as u64)?
-                .unwrap();
-            let mut intermediate_tx = intermediate_tx_batch.get_mut(0)?.unwrap();
-            let fee = intermediate_tx.vsize()? * fee_rate;
-            intermediate_tx.deduct_fee(fee)?;
-            fee
-        };
-
-        let intermediate_tx_batch = self.batches.get(BatchType::IntermediateTx as u64)?.unwrap();
-        let intermediate_tx = intermediate_tx_batch.get(0)?.unwrap();
-        let intermediate_tx_id = intermediate_tx.txid()?;
-        let intermediate_tx_len = intermediate_tx.output.len();
-
-        if intermediate_tx_len == 0 {
-            log::warn!("Generated empty emergency disbursal");
-            return Ok(());
-        }
-
-        let mut intermediate_tx_outputs: Vec<(usize, u64)> = intermediate_tx
-            .output
-            .iter()?
-            .enumerate()
-            .map(|(i, output)| Ok((i, output?.value)))
-            .collect::<Result<_>>()?;
-
-        let mut disbursal_batch = self.batches.get_mut(BatchType::Disbursal as u64)?.unwrap();
-        disbursal_batch.retain_unordered(|mut tx| {
-            let mut input = match tx.input.get_mut(0)? {
-                Some(input) => input,
-                None => return Ok(false),
-            };
-            if input.amount < intermediate_tx_fee / intermediate_tx_len {
-                return Ok(false);
-            }
-            input.amount -= intermediate_tx_fee / intermediate_tx_len;
-            for (i, output) in intermediate_tx_outputs.iter() {
-                if output == &(input.amount) {
-                    input.prevout = Adapter::new(bitcoin::OutPoint {
-                        txid: intermediate_tx_id,
-                        vout: *i as u32,
-                    });
-                    intermediate_tx_outputs.remove(*i);
-                    let tx_size = tx.vsize().map_err(|err| OrgaError::App(err.to_string()))?;
-                    let fee = intermediate_tx_fee / intermediate_tx_len + tx_size * fee_rate;
-                    tx.deduct_fee(fee)
-                        .map_err(|err| OrgaError::App(err.to_string()))?;
-                    return Ok(true);
-                }
-            }
-            Ok(false)
-        })?;
-
-        Ok(())
-    }
-
-    //TODO: Generalize emergency disbursal to dynamic tree structure for
-    //intermediate tx overflow
+    /// Builds the emergency disbursal transactions for the checkpoint.
+    ///
+    /// Disbursal outputs are packed into final transactions of at most
+    /// `emergency_disbursal_max_tx_size` vbytes each. The final transactions
+    /// are funded by a tree of intermediate transactions rooted at the reserve
+    /// output, where each intermediate transaction fans out to as many
+    /// children as fit within the same size limit. Each level's fees are paid
+    /// by the disbursal outputs below it, in proportion to their value.
     #[allow(clippy::too_many_arguments)]
     fn generate_emergency_disbursal_txs(
         &mut self,
@@ -801,6 +1037,7 @@ impl<'a> BuildingCheckpointMut<'a> {
                 .ok_or_else(|| OrgaError::Coins("No Time context found".into()))?;
 
             let sigset = self.sigset.clone();
+            let threshold = self.sigset_threshold;
 
             let lock_time = time.seconds as u32 + config.emergency_disbursal_lock_time_interval;
 
@@ -837,7 +1074,29 @@ impl<'a> BuildingCheckpointMut<'a> {
                 })
                 .collect();
 
-            let mut final_txs = vec![BitcoinTx::with_lock_time(lock_time)];
+            let output_script = config.output_script(&sigset, threshold, &[0u8])?;
+            let new_input = |prevout: bitcoin::OutPoint, amount: u64| {
+                Input::new(
+                    prevout,
+                    &sigset,
+                    &[0u8],
+                    amount,
+                    threshold,
+                    config.taproot_outputs,
+                )
+            };
+
+            // version, lock time, input and output counts, plus the single
+            // signatory input every disbursal tree transaction spends
+            let base_vsize = 10 + new_input(reserve_outpoint, 0)?.est_vsize();
+            let output_vsize = bitcoin::consensus::serialize(&TxOut {
+                value: 0,
+                script_pubkey: output_script.clone(),
+            })
+            .len() as u64;
+
+            let mut leaves = vec![];
+            let mut curr = DisbursalNode::new(lock_time, base_vsize, 0..0);
             for output in outputs
                 .into_iter()
                 .chain(pending_outputs.into_iter())
@@ -849,71 +1108,208 @@ impl<'a> BuildingCheckpointMut<'a> {
                     continue;
                 }
 
-                let mut curr_tx = final_txs.pop().unwrap();
-                if curr_tx.vsize()? >= config.emergency_disbursal_max_tx_size {
-                    self.link_intermediate_tx(&mut curr_tx, config.sigset_threshold)?;
-                    final_txs.push(curr_tx);
-                    curr_tx = BitcoinTx::with_lock_time(lock_time);
+                let vsize = bitcoin::consensus::serialize(&output).len() as u64;
+                if !curr.tx.output.is_empty()
+                    && curr.est_vsize + vsize > config.emergency_disbursal_max_tx_size
+                {
+                    let next = DisbursalNode::new(lock_time, base_vsize, 0..0);
+                    leaves.push(std::mem::replace(&mut curr, next));
                 }
 
-                curr_tx.output.push_back(Adapter::new(output))?;
+                curr.gross += output.value;
+                curr.est_vsize += vsize;
+                curr.tx.output.push_back(Adapter::new(output))?;
+            }
+            if !curr.tx.output.is_empty() {
+                leaves.push(curr);
+            }
 
-                final_txs.push(curr_tx);
+            if leaves.is_empty() {
+                log::warn!("Generated empty emergency disbursal");
+                return Ok(());
             }
 
-            let mut last_tx = final_txs.pop().unwrap();
-            self.link_intermediate_tx(&mut last_tx, config.sigset_threshold)?;
-            final_txs.push(last_tx);
+            // build intermediate levels until a single root remains, making
+            // sure there is always at least one intermediate tx spending the
+            // reserve output
+            let fanout = (config
+                .emergency_disbursal_max_tx_size
+                .saturating_sub(base_vsize + output_vsize)
+                / output_vsize)
+                .max(2) as usize;
+            let mut levels = vec![leaves];
+            while levels.len() == 1 || levels.last().unwrap().len() > 1 {
+                let children = levels.last().unwrap();
+                let parents: Vec<DisbursalNode> = (0..children.len())
+                    .step_by(fanout)
+                    .map(|start| {
+                        let end = (start + fanout).min(children.len());
+                        let mut parent = DisbursalNode::new(
+                            lock_time,
+                            base_vsize + (end - start) as u64 * output_vsize,
+                            start..end,
+                        );
+                        parent.gross = children[start..end].iter().map(|c| c.gross).sum();
+                        parent
+                    })
+                    .collect();
+                levels.push(parents);
+            }
+            let root_level = levels.len() - 1;
+            // the root also pays the excess reserve value back to the signatories
+            levels[root_level][0].est_vsize += output_vsize;
+
+            // split each node's fee, along with the fees its ancestors passed
+            // down to it, among its children
+            for level in (1..levels.len()).rev() {
+                let (lower, upper) = levels.split_at_mut(level);
+                let children = &mut lower[level - 1];
+                for node in upper[0].iter() {
+                    let total = node.burden + node.est_vsize * fee_rate;
+                    let mut remaining = total;
+                    for i in node.children.clone() {
+                        let share = if i == node.children.end - 1 || node.gross == 0 {
+                            remaining
+                        } else {
+                            (total as u128 * children[i].gross as u128 / node.gross as u128) as u64
+                        };
+                        remaining -= share;
+                        children[i].burden = share;
+                    }
+                }
+            }
+
+            let dust_value = output_script.dust_value().to_sat();
+            for (level, nodes) in levels.iter_mut().enumerate() {
+                for node in nodes.iter_mut() {
+                    node.value = node.gross.saturating_sub(node.burden);
+                    if level > 0 {
+                        node.pruned = node.value < dust_value;
+                        continue;
+                    }
+
+                    if node
+                        .tx
+                        .deduct_fee(node.burden + node.est_vsize * fee_rate)
+                        .is_err()
+                        || node.tx.output.is_empty()
+                    {
+                        node.pruned = true;
+                    }
+                }
+            }
+
+            // a node is pruned along with its ancestors' pruned subtrees, and an
+            // intermediate tx with no remaining children is not needed
+            for level in 1..levels.len() {
+                let (lower, upper) = levels.split_at_mut(level);
+                for node in upper[0].iter_mut() {
+                    node.pruned |= node.children.clone().all(|i| lower[level - 1][i].pruned);
+                }
+            }
+            if levels[root_level][0].pruned {
+                log::warn!("Generated empty emergency disbursal");
+                return Ok(());
+            }
+            for level in (1..levels.len()).rev() {
+                let (lower, upper) = levels.split_at_mut(level);
+                for node in upper[0].iter() {
+                    for i in node.children.clone() {
+                        lower[level - 1][i].pruned |= node.pruned;
+                    }
+                }
+            }
+
+            // link the tree together from the reserve output downwards, now
+            // that every output value is known
+            {
+                let root = &mut levels[root_level][0];
+                root.prevout = Some(reserve_outpoint);
+                root.value = reserve_value;
+            }
+            for level in (0..levels.len()).rev() {
+                let (lower, upper) = levels.split_at_mut(level);
+                for node in upper[0].iter_mut() {
+                    if node.pruned {
+                        continue;
+                    }
+
+                    let prevout = node.prevout.unwrap();
+                    node.tx.input.push_back(new_input(prevout, node.value)?)?;
+
+                    if level == 0 {
+                        continue;
+                    }
+
+                    let children = &mut lower[level - 1];
+                    let mut links = vec![];
+                    let mut out_value = 0;
+                    for i in node.children.clone() {
+                        if children[i].pruned {
+                            continue;
+                        }
+                        links.push((i, node.tx.output.len() as u32));
+                        out_value += children[i].value;
+                        node.tx.output.push_back(Adapter::new(TxOut {
+                            value: children[i].value,
+                            script_pubkey: output_script.clone(),
+                        }))?;
+                    }
+
+                    // the root pays the excess reserve value back to the
+                    // signatories, and other nodes pay back the value of
+                    // their pruned children, in the place of one of their
+                    // outputs
+                    let excess_value = if level == root_level {
+                        reserve_value
+                            .checked_sub(out_value + node.est_vsize * fee_rate)
+                            .ok_or_else(|| {
+                                OrgaError::App(
+                                    "Insufficient reserve value for emergency disbursal"
+                                        .to_string(),
+                                )
+                            })?
+                    } else {
+                        node.value
+                            .saturating_sub(out_value + node.est_vsize * fee_rate)
+                    };
+                    if excess_value >= dust_value {
+                        node.tx.output.push_back(Adapter::new(TxOut {
+                            value: excess_value,
+                            script_pubkey: output_script.clone(),
+                        }))?;
+                    }
+
+                    let txid = node.tx.txid()?;
+                    for (i, vout) in links {
+                        children[i].prevout = Some(bitcoin::OutPoint::new(txid, vout));
+                    }
+                }
+            }
 
-            let tx_in = Input::new(
-                reserve_outpoint,
-                &sigset,
-                &[0u8],
-                reserve_value,
-                config.sigset_threshold,
-            )?;
-            let output_script = self.sigset.output_script(&[0u8], config.sigset_threshold)?;
             let mut intermediate_tx_batch = self
                 .batches
                 .get_mut(BatchType::IntermediateTx as u64)?
                 .unwrap();
-            let mut intermediate_tx = intermediate_tx_batch.get_mut(0)?.unwrap();
-            intermediate_tx.lock_time = lock_time;
-            intermediate_tx.input.push_back(tx_in)?;
-
-            let intermediate_tx_out_value = intermediate_tx.value()?;
-            let excess_value = reserve_value - intermediate_tx_out_value;
-            let excess_tx_out = bitcoin::TxOut {
-                value: excess_value,
-                script_pubkey: output_script,
-            };
-            intermediate_tx
-                .output
-                .push_back(Adapter::new(excess_tx_out))?;
-
-            let mut disbursal_batch = self.batches.get_mut(BatchType::Disbursal as u64)?.unwrap();
-            for tx in final_txs {
-                disbursal_batch.push_back(tx)?;
+            intermediate_tx_batch.pop_front()?;
+            let mut levels = levels.into_iter().rev();
+            for nodes in levels.by_ref().take(root_level) {
+                for mut node in nodes.into_iter().filter(|node| !node.pruned) {
+                    node.tx.populate_input_sig_message(0)?;
+                    intermediate_tx_batch.push_back(node.tx)?;
+                }
             }
-        }
-
-        self.deduct_emergency_disbursal_fees(fee_rate)?;
+            drop(intermediate_tx_batch);
 
-        let mut disbursal_batch = self.batches.get_mut(BatchType::Disbursal as u64)?.unwrap();
-        for i in 0..disbursal_batch.len() {
-            let mut tx = disbursal_batch.get_mut(i)?.unwrap();
-            for j in 0..tx.input.len() {
-                tx.populate_input_sig_message(j.try_into()?)?;
+            let mut disbursal_batch = self.batches.get_mut(BatchType::Disbursal as u64)?.unwrap();
+            for nodes in levels {
+                for mut node in nodes.into_iter().filter(|node| !node.pruned) {
+                    node.tx.populate_input_sig_message(0)?;
+                    disbursal_batch.push_back(node.tx)?;
+                }
             }
         }
 
-        let mut intermediate_tx_batch = self
-            .batches
-            .get_mut(BatchType::IntermediateTx as u64)?
-            .unwrap();
-        let mut intermediate_tx = intermediate_tx_batch.get_mut(0)?.unwrap();
-        intermediate_tx.populate_input_sig_message(0)?;
-
         Ok(())
     }
 
@@ -923,16 +1319,15 @@ impl<'a> BuildingCheckpointMut<'a> {
         nbtc_accounts: &Accounts<Nbtc>,
         recovery_scripts: &Map<orga::coins::Address, Adapter<bitcoin::Script>>,
         external_outputs: impl Iterator<Item = Result<bitcoin::TxOut>>,
+        ancestor_fee: u64,
+        charged_fee_rate: u64,
         config: &Config,
     ) -> Result<BuildingAdvanceRes> {
         self.0.status = CheckpointStatus::Signing;
 
         let reserve_out = bitcoin::TxOut {
             value: 0, // will be updated after counting ins/outs and fees
-            script_pubkey: self
-                .0
-                .sigset
-                .output_script(&[0u8], config.sigset_threshold)?,
+            script_pubkey: config.output_script(&self.0.sigset, self.0.sigset_threshold, &[0u8])?,
         };
 
         let fee_rate = self.fee_rate;
@@ -979,30 +1374,34 @@ impl<'a> BuildingCheckpointMut<'a> {
                     Ok(sum? + input?.est_witness_vsize)
                 })?;
 
-        let fee = est_vsize * fee_rate;
+        // when bumping fees, the checkpoint also pays for the shortfall of its
+        // unconfirmed ancestors (CPFP)
+        let fee = est_vsize * fee_rate + ancestor_fee;
         let reserve_value = in_amount
             .checked_sub(out_amount + fee)
             .ok_or_else(|| OrgaError::App("Insufficient funds to cover fees".to_string()))?;
         let mut reserve_out = checkpoint_tx.output.get_mut(0)?.unwrap();
         reserve_out.value = reserve_value;
 
+        // depositors and withdrawers were charged for their inputs and
+        // outputs at the fee rate before any bump, and the reserve pays the
+        // rest
+        let reserve_fee = fee.saturating_sub(checkpoint_tx.charged_vsize()? * charged_fee_rate);
+
         let bitcoin_tx = checkpoint_tx.to_bitcoin_tx()?;
-        let mut sc = bitcoin::util::sighash::SighashCache::new(&bitcoin_tx);
+        let prevouts = checkpoint_tx.prevouts()?;
+        let mut sc = SighashCache::new(&bitcoin_tx);
         for i in 0..checkpoint_tx.input.len() {
             let mut input = checkpoint_tx.input.get_mut(i)?.unwrap();
-            let sighash = sc.segwit_signature_hash(
-                i as usize,
-                &input.redeem_script,
-                input.amount,
-                EcdsaSighashType::All,
-            )?;
-            input.signatures.set_message(sighash.into_inner());
+            let sighash = input.sighash(&mut sc, i as usize, &prevouts)?;
+            input.signatures.set_message(sighash);
         }
 
         let reserve_outpoint = bitcoin::OutPoint {
             txid: checkpoint_tx.txid()?,
             vout: 0,
         };
+        self.0.reserve_fee = reserve_fee;
 
         self.generate_emergency_disbursal_txs(
             nbtc_accounts,
@@ -1169,8 +1568,15 @@ impl CheckpointQueue {
                 .batches
                 .get(BatchType::IntermediateTx as u64)?
                 .unwrap();
-            let Some(intermediate_tx) = intermediate_tx_batch.get(0)? else { return Ok(txs) };
-            txs.push(Adapter::new(intermediate_tx.to_bitcoin_tx()?));
+            // intermediate txs are stored parents-first, so they can be
+            // broadcast in order
+            for tx in intermediate_tx_batch.iter()? {
+                let tx = tx?;
+                if tx.input.is_empty() {
+                    continue;
+                }
+                txs.push(Adapter::new(tx.to_bitcoin_tx()?));
+            }
 
             let disbursal_batch = completed.batches.get(BatchType::Disbursal as u64)?.unwrap();
             for tx in disbursal_batch.iter()? {
@@ -1245,10 +1651,19 @@ impl CheckpointQueue {
         btc_height: u32,
         should_allow_deposits: bool,
     ) -> Result<bool> {
-        if !self.should_push(sig_keys)? {
+        if !self.should_push(sig_keys, btc_height)? {
             return Ok(false);
         }
 
+        // decided before pushing, while the checkpoint to be advanced is still
+        // the building checkpoint
+        let fee_bump = if self.fee_bump_due(btc_height)? {
+            let fee_rate = adjust_fee_rate(self.building()?.fee_rate, true, &self.config);
+            Some((fee_rate, self.ancestor_fee_deficit(fee_rate)?))
+        } else {
+            None
+        };
+
         if self.maybe_push(sig_keys, should_allow_deposits)?.is_none() {
             return Ok(false);
         }
@@ -1258,18 +1673,26 @@ impl CheckpointQueue {
 
         if self.index > 0 {
             let config = self.config();
-            let second = self.get_mut(self.index - 1)?;
+            let mut second = self.get_mut(self.index - 1)?;
+            let charged_fee_rate = second.fee_rate;
+            if let Some((fee_rate, _)) = fee_bump {
+                second.fee_rate = fee_rate;
+            }
             let sigset = second.sigset.clone();
+            let sigset_threshold = second.sigset_threshold;
             let prev_fee_rate = second.fee_rate;
             let (reserve_outpoint, reserve_value, excess_inputs, excess_outputs) =
                 BuildingCheckpointMut(second).advance(
                     nbtc_accounts,
                     recovery_scripts,
                     external_outputs,
+                    fee_bump.map_or(0, |(_, ancestor_fee)| ancestor_fee),
+                    charged_fee_rate,
                     &config,
                 )?;
 
-            let fee_rate = if let Some(first_unconf_index) = self.first_unconfirmed_index()? {
+            let first_unconf_index = self.first_unconfirmed_index()?;
+            let heuristic_fee_rate = if let Some(first_unconf_index) = first_unconf_index {
                 // There are unconfirmed checkpoints.
 
                 let first_unconf = self.get(first_unconf_index)?;
@@ -1303,6 +1726,26 @@ impl CheckpointQueue {
                 }
             };
 
+            // signatories' estimates, reported while signing the last completed
+            // checkpoint, take precedence over the confirmation heuristic, except
+            // that the heuristic's rate is kept as a floor while checkpoints are
+            // unconfirmed so they are still bumped if miners exclude them
+            let fee_rate_estimate = match self.last_completed() {
+                Ok(last_completed) => last_completed.fee_rate_estimate()?,
+                Err(_) => None,
+            };
+            let fee_rate = match fee_rate_estimate {
+                Some(estimate) => {
+                    let estimate = estimate.min(config.max_fee_rate).max(config.min_fee_rate);
+                    if first_unconf_index.is_some() {
+                        estimate.max(heuristic_fee_rate)
+                    } else {
+                        estimate
+                    }
+                }
+                None => heuristic_fee_rate,
+            };
+
             let mut building = self.building_mut()?;
             building.fee_rate = fee_rate;
             let mut building_checkpoint_batch = building
@@ -1316,15 +1759,17 @@ impl CheckpointQueue {
                 &sigset,
                 &[0u8], // TODO: double-check safety
                 reserve_value,
-                config.sigset_threshold,
+                sigset_threshold,
+                config.taproot_outputs,
             )?;
 
             checkpoint_tx.input.push_back(input)?;
 
             for input in excess_inputs {
                 let shares = input.signatures.shares()?;
+                let threshold = input.signatures.threshold;
                 let mut data = input.into_inner();
-                data.signatures = ThresholdSig::from_shares(shares)?;
+                data.signatures = ThresholdSig::from_shares(shares, threshold)?;
                 checkpoint_tx.input.push_back(data)?;
             }
 
@@ -1338,7 +1783,11 @@ impl CheckpointQueue {
     }
 
     #[cfg(feature = "full")]
-    pub fn should_push(&mut self, sig_keys: &Map<ConsensusKey, Xpub>) -> Result<bool> {
+    pub fn should_push(
+        &mut self,
+        sig_keys: &Map<ConsensusKey, Xpub>,
+        btc_height: u32,
+    ) -> Result<bool> {
         if self.signing()?.is_some() {
             return Ok(false);
         }
@@ -1366,7 +1815,11 @@ impl CheckpointQueue {
                 let has_pending_withdrawal = !checkpoint_tx.output.is_empty();
                 let has_pending_transfers = building.pending.iter()?.next().transpose()?.is_some();
 
-                if !has_pending_deposit && !has_pending_withdrawal && !has_pending_transfers {
+                if !has_pending_deposit
+                    && !has_pending_withdrawal
+                    && !has_pending_transfers
+                    && !self.fee_bump_due(btc_height)?
+                {
                     return Ok(false);
                 }
             }
@@ -1413,7 +1866,8 @@ impl CheckpointQueue {
 
         self.index = index;
 
-        self.queue.push_back(Checkpoint::new(sigset)?)?;
+        let threshold = self.config.sigset_threshold;
+        self.queue.push_back(Checkpoint::new(sigset, threshold)?)?;
 
         let mut building = self.building_mut()?;
         building.deposits_enabled = deposits_enabled;
@@ -1432,6 +1886,7 @@ impl CheckpointQueue {
         sigs: LengthVec<u16, Signature>,
         index: u32,
         btc_height: u32,
+        fee_rate_estimate: Option<u64>,
     ) -> Result<()> {
         super::exempt_from_fee()?;
 
@@ -1442,6 +1897,9 @@ impl CheckpointQueue {
         }
 
         checkpoint.sign(xpub, sigs, btc_height)?;
+        if let Some(fee_rate) = fee_rate_estimate {
+            checkpoint.report_fee_rate(xpub, fee_rate)?;
+        }
 
         if matches!(status, CheckpointStatus::Signing) && checkpoint.signed()? {
             let checkpoint_tx = checkpoint.checkpoint_tx()?;
@@ -1452,11 +1910,21 @@ impl CheckpointQueue {
         Ok(())
     }
 
+    #[query]
+    pub fn active_sigset_threshold(&self) -> Result<(u64, u64)> {
+        Ok(self.building()?.sigset_threshold)
+    }
+
     #[query]
     pub fn sigset(&self, index: u32) -> Result<SignatorySet> {
         Ok(self.get(index)?.sigset.clone())
     }
 
+    #[query]
+    pub fn sigset_threshold(&self, index: u32) -> Result<(u64, u64)> {
+        Ok(self.get(index)?.sigset_threshold)
+    }
+
     #[query]
     pub fn num_unconfirmed(&self) -> Result<u32> {
         let has_signing = self.signing()?.is_some();
@@ -1476,6 +1944,61 @@ impl CheckpointQueue {
         Ok(last_completed_index - confirmed_index)
     }
 
+    /// Returns true if the most recently completed checkpoint has gone
+    /// unconfirmed for at least `target_checkpoint_inclusion` Bitcoin blocks.
+    /// In that case the building checkpoint is pushed early, at a higher fee
+    /// rate, as a child paying for its unconfirmed ancestors (CPFP). Once
+    /// signed, the relayer broadcasts it along with the other completed
+    /// checkpoints.
+    #[query]
+    pub fn fee_bump_due(&self, btc_height: u32) -> Result<bool> {
+        if self.signing()?.is_some() || self.first_unconfirmed_index()?.is_none() {
+            return Ok(false);
+        }
+
+        if self.num_unconfirmed()? >= MAX_FEE_BUMP_ANCESTORS {
+            return Ok(false);
+        }
+
+        let signed_at_btc_height = match self.last_completed()?.signed_at_btc_height {
+            Some(height) => height,
+            None => return Ok(false),
+        };
+
+        Ok(btc_height.saturating_sub(signed_at_btc_height)
+            >= self.config.target_checkpoint_inclusion)
+    }
+
+    /// The total fee the unconfirmed checkpoints are short of paying at the
+    /// given fee rate. Miners evaluate the checkpoints as a package, so a
+    /// checkpoint paying more than its share offsets the others.
+    pub fn ancestor_fee_deficit(&self, fee_rate: u64) -> Result<u64> {
+        let first_unconf_index = match self.first_unconfirmed_index()? {
+            Some(index) => index,
+            None => return Ok(0),
+        };
+
+        let mut required = 0;
+        let mut paid = 0;
+        for index in first_unconf_index..=self.last_completed_index()? {
+            let checkpoint = self.get(index)?;
+            let batch = checkpoint
+                .batches
+                .get(BatchType::Checkpoint as u64)?
+                .unwrap();
+            let tx = batch.back()?.unwrap();
+
+            let in_amount = tx
+                .input
+                .iter()?
+                .fold(Ok(0), |sum: Result<u64>, input| Ok(sum? + input?.amount))?;
+            paid += in_amount.saturating_sub(tx.value()?);
+            required += tx.vsize()? * fee_rate;
+        }
+
+        Ok(required.saturating_sub(paid))
+    }
+
     #[query]
     pub fn first_unconfirmed_index(&self) -> Result<Option<u32>> {
         let num_unconf = self.num_unconfirmed()?;
@@ -1504,6 +2027,8 @@ pub fn adjust_fee_rate(prev_fee_rate: u64, up: bool, config: &Config) -> u64 {
 mod test {
     use std::{cell::RefCell, rc::Rc};
 
+    #[cfg(feature = "full")]
+    use crate::bitcoin::signatory::Signatory;
     #[cfg(all(feature = "full"))]
     use bitcoin::{
         secp256k1::Secp256k1,
@@ -1522,6 +2047,49 @@ mod test {
         tx.output.push_back(Output::new(tx_out)).unwrap();
     }
 
+    #[test]
+    fn remove_output() {
+        let mut bitcoin_tx = BitcoinTx::default();
+        push_bitcoin_tx_output(&mut bitcoin_tx, 100);
+        push_bitcoin_tx_output(&mut bitcoin_tx, 200);
+        push_bitcoin_tx_output(&mut bitcoin_tx, 100);
+        push_bitcoin_tx_output(&mut bitcoin_tx, 300);
+
+        let target = bitcoin::TxOut {
+            value: 100,
+            script_pubkey: bitcoin::Script::new(),
+        };
+        assert!(bitcoin_tx.remove_output(&target).unwrap());
+
+        let values: Vec<_> = bitcoin_tx
+            .output
+            .iter()
+            .unwrap()
+            .map(|output| output.unwrap().value)
+            .collect();
+        assert_eq!(values, vec![200, 100, 300]);
+
+        let missing = bitcoin::TxOut {
+            value: 400,
+            script_pubkey: bitcoin::Script::new(),
+        };
+        assert!(!bitcoin_tx.remove_output(&missing).unwrap());
+        assert_eq!(bitcoin_tx.output.len(), 3);
+
+        let last = bitcoin::TxOut {
+            value: 300,
+            script_pubkey: bitcoin::Script::new(),
+        };
+        assert!(bitcoin_tx.remove_output(&last).unwrap());
+        let values: Vec<_> = bitcoin_tx
+            .output
+            .iter()
+            .unwrap()
+            .map(|output| output.unwrap().value)
+            .collect();
+        assert_eq!(values, vec![200, 100]);
+    }
+
     #[test]
     fn deduct_fee() {
         let mut bitcoin_tx = BitcoinTx::default();
@@ -1559,6 +2127,79 @@ mod test {
 
     //TODO: More fee deduction tests
 
+    #[cfg(feature = "full")]
+    #[test]
+    fn taproot_input() {
+        let secp = Secp256k1::signing_only();
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[0]).unwrap();
+        let xpub = ExtendedPubKey::from_priv(&secp, &xpriv);
+        let pubkey = derive_pubkey(&Secp256k1::new(), Xpub::new(xpub), 0).unwrap();
+
+        let sigset = SignatorySet {
+            create_time: 0,
+            present_vp: 100,
+            possible_vp: 100,
+            index: 0,
+            signatories: vec![Signatory {
+                voting_power: 100,
+                pubkey: pubkey.into(),
+            }],
+        };
+
+        let input = Input::new(
+            OutPoint {
+                txid: Txid::from_slice(&[0; 32]).unwrap(),
+                vout: 0,
+            },
+            &sigset,
+            &[0u8],
+            100_000,
+            (9, 10),
+            true,
+        )
+        .unwrap();
+        assert!(input.script_pubkey.is_v1_p2tr());
+        assert_eq!(input.sig_scheme(), SigScheme::Schnorr);
+        assert_eq!(input.est_witness_vsize, sigset.est_taproot_witness_vsize());
+
+        // the control block makes taproot spends larger for small sets, but
+        // smaller once there are a few signatories
+        assert!(sigset.est_taproot_witness_vsize() > sigset.est_witness_vsize());
+        let mut large_sigset = sigset.clone();
+        large_sigset.signatories = vec![sigset.signatories[0].clone(); 5];
+        assert!(large_sigset.est_taproot_witness_vsize() < large_sigset.est_witness_vsize());
+
+        let mut bitcoin_tx = BitcoinTx::default();
+        bitcoin_tx.input.push_back(input).unwrap();
+        push_bitcoin_tx_output(&mut bitcoin_tx, 90_000);
+        bitcoin_tx.populate_input_sig_message(0).unwrap();
+
+        let mut input = bitcoin_tx.input.get_mut(0).unwrap().unwrap();
+        let to_sign = [(
+            input.signatures.message(),
+            input.sigset_index,
+            input.sig_scheme(),
+        )];
+        let sigs = crate::bitcoin::signer::sign(&secp, &xpriv, &to_sign).unwrap();
+
+        // an ECDSA signature over the same message is rejected
+        assert!(input
+            .signatures
+            .sign(pubkey.into(), sigs[0], SigScheme::Ecdsa)
+            .is_err());
+        input
+            .signatures
+            .sign(pubkey.into(), sigs[0], SigScheme::Schnorr)
+            .unwrap();
+        assert!(input.signatures.signed());
+
+        let witness = input.to_txin().unwrap().witness.to_vec();
+        assert_eq!(witness.len(), 3);
+        assert_eq!(witness[0].len(), 64);
+        assert_eq!(witness[1], input.redeem_script.to_bytes());
+        assert_eq!(witness[2].len(), 33);
+    }
+
     fn create_queue_with_statuses(complete: u32, signing: bool) -> CheckpointQueue {
         let mut queue = CheckpointQueue::default();
         let mut push = |status| {
@@ -1570,6 +2211,9 @@ mod test {
                 signed_at_btc_height: None,
                 deposits_enabled: true,
                 sigset: SignatorySet::default(),
+                sigset_threshold: (9, 10),
+                fee_rate_estimates: Map::new(),
+                reserve_fee: 0,
             };
             cp.status = status;
             queue.queue.push_back(cp).unwrap();
@@ -1689,6 +2333,31 @@ mod test {
         assert_eq!(queue.first_unconfirmed_index().unwrap(), Some(0));
     }
 
+    #[test]
+    fn fee_bump_due() {
+        let mut queue = create_queue_with_statuses(10, false);
+        queue.confirmed_index = Some(5);
+        queue.get_mut(9).unwrap().signed_at_btc_height = Some(100);
+
+        assert!(!queue.fee_bump_due(100).unwrap());
+        assert!(!queue.fee_bump_due(101).unwrap());
+        assert!(queue.fee_bump_due(102).unwrap());
+
+        queue.confirmed_index = Some(9);
+        assert!(!queue.fee_bump_due(102).unwrap());
+
+        let mut queue = create_queue_with_statuses(10, true);
+        queue.confirmed_index = Some(5);
+        queue.get_mut(9).unwrap().signed_at_btc_height = Some(100);
+        assert!(!queue.fee_bump_due(102).unwrap());
+
+        let mut queue = create_queue_with_statuses(30, false);
+        queue.get_mut(29).unwrap().signed_at_btc_height = Some(100);
+        assert!(!queue.fee_bump_due(102).unwrap());
+        queue.confirmed_index = Some(20);
+        assert!(queue.fee_bump_due(102).unwrap());
+    }
+
     #[test]
     fn adjust_fee_rate() {
         let config = Config::default();
@@ -1701,6 +2370,198 @@ mod test {
         assert_eq!(super::adjust_fee_rate(300, true, &config), 200);
     }
 
+    #[cfg(feature = "full")]
+    #[test]
+    fn fee_rate_estimate() {
+        let secp = Secp256k1::new();
+        let xpubs: Vec<_> = (0..4u8)
+            .map(|i| {
+                let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[i]).unwrap();
+                Xpub::new(ExtendedPubKey::from_priv(&secp, &xpriv))
+            })
+            .collect();
+
+        let voting_powers = [40, 30, 20, 10];
+        let sigset = SignatorySet {
+            create_time: 0,
+            present_vp: 100,
+            possible_vp: 100,
+            index: 0,
+            signatories: xpubs
+                .iter()
+                .zip(voting_powers)
+                .map(|(xpub, voting_power)| Signatory {
+                    voting_power,
+                    pubkey: derive_pubkey(&secp, *xpub, 0).unwrap().into(),
+                })
+                .collect(),
+        };
+        let mut checkpoint = Checkpoint::new(sigset, (2, 3)).unwrap();
+
+        // estimates must represent a majority of voting power
+        checkpoint.report_fee_rate(xpubs[0], 50).unwrap();
+        checkpoint.report_fee_rate(xpubs[3], 10).unwrap();
+        assert_eq!(checkpoint.fee_rate_estimate().unwrap(), None);
+
+        checkpoint.report_fee_rate(xpubs[2], 20).unwrap();
+        assert_eq!(checkpoint.fee_rate_estimate().unwrap(), Some(50));
+
+        checkpoint.report_fee_rate(xpubs[1], 30).unwrap();
+        assert_eq!(checkpoint.fee_rate_estimate().unwrap(), Some(30));
+
+        // keys outside of the signatory set are ignored
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[9]).unwrap();
+        let outsider = Xpub::new(ExtendedPubKey::from_priv(&secp, &xpriv));
+        checkpoint.report_fee_rate(outsider, 1).unwrap();
+        assert_eq!(checkpoint.fee_rate_estimates.iter().unwrap().count(), 4);
+    }
+
+    #[cfg(feature = "full")]
+    #[test]
+    #[serial_test::serial]
+    fn emergency_disbursal_tree() {
+        let paid = orga::plugins::Paid::default();
+        Context::add(paid);
+
+        let mut vals = orga::plugins::Validators::new(
+            Rc::new(RefCell::new(Some(EntryMap::new()))),
+            Rc::new(RefCell::new(None)),
+        );
+        vals.set_voting_power([0; 32], 100);
+        Context::add(vals);
+
+        let secp = Secp256k1::new();
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[0]).unwrap();
+        let xpub = ExtendedPubKey::from_priv(&secp, &xpriv);
+
+        let mut sig_keys = Map::new();
+        sig_keys.insert([0; 32], Xpub::new(xpub)).unwrap();
+
+        let mut queue = CheckpointQueue::default();
+        queue.config = Config {
+            min_checkpoint_interval: 100,
+            emergency_disbursal_max_tx_size: 1,
+            emergency_disbursal_min_tx_amt: 0,
+            ..Default::default()
+        };
+
+        let maybe_step = |queue: &mut CheckpointQueue, time| {
+            Context::add(orga::plugins::Time::from_seconds(time));
+            // the output of 600 sats can't pay for its final tx, so it is
+            // pruned from below a non-root intermediate tx
+            let external_outputs = (0..8).map(|i| {
+                Ok(bitcoin::TxOut {
+                    script_pubkey: Script::new(),
+                    value: if i == 5 { 600 } else { 1_000_000 },
+                })
+            });
+            queue
+                .maybe_step(
+                    &sig_keys,
+                    &Accounts::default(),
+                    &Map::new(),
+                    external_outputs,
+                    10,
+                    true,
+                )
+                .unwrap();
+        };
+
+        maybe_step(&mut queue, 0);
+
+        let sigset = queue.building().unwrap().sigset.clone();
+        let input = Input::new(
+            OutPoint {
+                txid: Txid::from_slice(&[0; 32]).unwrap(),
+                vout: 0,
+            },
+            &sigset,
+            &[0u8],
+            100_000_000,
+            (9, 10),
+            false,
+        )
+        .unwrap();
+        queue
+            .building_mut()
+            .unwrap()
+            .batches
+            .get_mut(BatchType::Checkpoint as u64)
+            .unwrap()
+            .unwrap()
+            .get_mut(0)
+            .unwrap()
+            .unwrap()
+            .input
+            .push_back(input)
+            .unwrap();
+
+        maybe_step(&mut queue, 1_000);
+
+        let cp = queue.get(0).unwrap();
+        let checkpoint_tx = cp.checkpoint_tx().unwrap();
+        let intermediate_txs = cp
+            .batches
+            .get(BatchType::IntermediateTx as u64)
+            .unwrap()
+            .unwrap();
+        let disbursal_txs = cp
+            .batches
+            .get(BatchType::Disbursal as u64)
+            .unwrap()
+            .unwrap();
+
+        // the only input is the reserve's, so the whole fee is paid out of
+        // the reserve
+        assert_eq!(cp.reserve_fee, 100_000_000 - checkpoint_tx.output[0].value);
+
+        // one output per final tx and a fan-out of 2 gives a 3 level tree
+        assert_eq!(disbursal_txs.len(), 7);
+        assert_eq!(intermediate_txs.len(), 4 + 2 + 1);
+
+        let reserve_value = checkpoint_tx.output[0].value;
+        let mut unspent = std::collections::HashMap::new();
+        unspent.insert(OutPoint::new(checkpoint_tx.txid(), 0), reserve_value);
+
+        let mut disbursed = 0;
+        let mut fees = 0;
+        let txs = intermediate_txs
+            .iter()
+            .unwrap()
+            .chain(disbursal_txs.iter().unwrap());
+        for (i, tx) in txs.enumerate() {
+            let tx = tx.unwrap();
+            assert_eq!(tx.input.len(), 1);
+            let input = tx.input.get(0).unwrap().unwrap();
+            let prevout_value = unspent.remove(&*input.prevout).unwrap();
+            assert_eq!(prevout_value, input.amount);
+            assert!(tx.value().unwrap() < input.amount);
+            fees += input.amount - tx.value().unwrap();
+
+            let txid = tx.txid().unwrap();
+            for (vout, output) in tx.output.iter().unwrap().enumerate() {
+                let output = output.unwrap();
+                if i < intermediate_txs.len() as usize {
+                    unspent.insert(OutPoint::new(txid, vout as u32), output.value);
+                } else {
+                    disbursed += output.value;
+                }
+            }
+        }
+
+        // only the excess reserve output and the value of the pruned output,
+        // less its share of the fees, are left unspent
+        assert_eq!(unspent.len(), 2);
+        let pruned_value = *unspent.values().min().unwrap();
+        assert!(pruned_value > 590 && pruned_value < 600);
+        assert!(disbursed < 7_000_000);
+        assert!(disbursed > 6_000_000);
+
+        // value is conserved
+        let unspent_value: u64 = unspent.values().sum();
+        assert_eq!(reserve_value, disbursed + unspent_value + fees);
+    }
+
     #[cfg(feature = "full")]
     #[test]
     #[serial_test::serial]
@@ -1764,6 +2625,7 @@ mod test {
                 &[0u8],
                 100_000_000,
                 (9, 10),
+                false,
             )
             .unwrap();
             let mut queue = queue.borrow_mut();
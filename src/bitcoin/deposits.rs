@@ -0,0 +1,460 @@
+use super::txid_set::Outpoint;
+use crate::app::Dest;
+use bitcoin::hashes::Hash;
+use bitcoin::OutPoint;
+use orga::collections::{Deque, Map};
+use orga::{orga, Error, Result};
+use sha2::{Digest, Sha256};
+
+/// A deposit which has been relayed to the chain.
+#[orga]
+#[derive(Clone)]
+pub struct Deposit {
+    pub dest: Dest,
+    /// The value of the deposit output, in satoshis.
+    pub amount: u64,
+    /// The miner fee for spending the deposit output, in satoshis.
+    pub miner_fee: u64,
+    /// The deposit fee paid to the reward pool, in micro-satoshis.
+    pub deposit_fee: u64,
+    /// The amount of nBTC minted for the destination after fees, in
+    /// micro-satoshis.
+    pub value: u64,
+    pub btc_height: u32,
+    /// The index of the checkpoint whose transaction spends the deposit output.
+    /// This moves forward if the input is carried over because its checkpoint
+    /// had too many inputs.
+    pub checkpoint_index: u32,
+    /// Whether the checkpoint spending the deposit has stopped building.
+    pub swept: bool,
+    /// Whether the minted nBTC has been credited to the destination, which
+    /// happens once the checkpoint the deposit was relayed into is complete.
+    pub credited: bool,
+    /// Whether the deposit was relayed while the reserve was over its capacity
+    /// limit. Such deposits wait in a queue until there is capacity for them.
+    pub overflowed: bool,
+    /// Whether the deposit was relayed while the reserve was over its capacity
+    /// limit and is being paid back to the destination's recovery script
+    /// rather than credited.
+    pub refunded: bool,
+}
+
+impl Deposit {
+    pub fn new(
+        dest: Dest,
+        amount: u64,
+        miner_fee: u64,
+        deposit_fee: u64,
+        value: u64,
+        btc_height: u32,
+        checkpoint_index: u32,
+    ) -> Self {
+        Deposit {
+            dest,
+            amount,
+            miner_fee,
+            deposit_fee,
+            value,
+            btc_height,
+            checkpoint_index,
+            swept: false,
+            credited: false,
+            overflowed: false,
+            refunded: false,
+        }
+    }
+}
+
+/// The data needed to spend a deposit output which was queued because the
+/// reserve was over its capacity limit when the deposit was relayed.
+#[orga]
+pub struct QueuedDeposit {
+    pub outpoint: Outpoint,
+    /// The index of the checkpoint whose signatory set the deposit was sent
+    /// to.
+    pub sigset_index: u32,
+    pub taproot: bool,
+    /// The time the deposit was queued, in seconds.
+    pub queued_at: u64,
+}
+
+/// The record of relayed deposits, indexed by outpoint and by destination.
+#[orga]
+pub struct Deposits {
+    records: Map<Outpoint, Deposit>,
+    by_dest: Map<([u8; 32], Outpoint), ()>,
+    awaiting_sweep: Map<(u32, Outpoint), ()>,
+    awaiting_credit: Map<(u32, Outpoint), ()>,
+    overflow: Deque<QueuedDeposit>,
+    /// The outpoints of the recorded deposits, in the order they were
+    /// relayed, so the oldest records can be pruned.
+    order: Deque<Outpoint>,
+}
+
+fn dest_key(dest: &Dest) -> Result<[u8; 32]> {
+    let bytes = dest
+        .commitment_bytes()
+        .map_err(|e| Error::App(e.to_string()))?;
+    Ok(Sha256::digest(bytes).into())
+}
+
+#[orga]
+impl Deposits {
+    pub fn insert(&mut self, outpoint: Outpoint, deposit: Deposit) -> Result<()> {
+        self.by_dest
+            .insert((dest_key(&deposit.dest)?, outpoint), ())?;
+        self.awaiting_sweep
+            .insert((deposit.checkpoint_index, outpoint), ())?;
+        self.awaiting_credit
+            .insert((deposit.checkpoint_index, outpoint), ())?;
+        self.records.insert(outpoint, deposit)?;
+        self.order.push_back(outpoint)?;
+
+        Ok(())
+    }
+
+    /// Records a deposit relayed while the reserve was over its capacity
+    /// limit, queueing it to be added to a checkpoint once there is capacity.
+    pub fn insert_overflow(
+        &mut self,
+        outpoint: Outpoint,
+        mut deposit: Deposit,
+        queued: QueuedDeposit,
+    ) -> Result<()> {
+        deposit.overflowed = true;
+        self.by_dest
+            .insert((dest_key(&deposit.dest)?, outpoint), ())?;
+        self.records.insert(outpoint, deposit)?;
+        self.order.push_back(outpoint)?;
+        self.overflow.push_back(queued)?;
+
+        Ok(())
+    }
+
+    /// Records a deposit whose output is being paid back to the depositor in
+    /// the checkpoint at `deposit.checkpoint_index`.
+    pub fn insert_refund(&mut self, outpoint: Outpoint, mut deposit: Deposit) -> Result<()> {
+        deposit.refunded = true;
+        self.by_dest
+            .insert((dest_key(&deposit.dest)?, outpoint), ())?;
+        self.awaiting_sweep
+            .insert((deposit.checkpoint_index, outpoint), ())?;
+        self.records.insert(outpoint, deposit)?;
+        self.order.push_back(outpoint)?;
+
+        Ok(())
+    }
+
+    /// Removes the records of the oldest deposits, up to `limit`, which were
+    /// swept and credited or refunded before the checkpoint at
+    /// `checkpoint_index`, stopping at the first which wasn't. Returns how
+    /// many were removed.
+    pub fn prune(&mut self, checkpoint_index: u32, limit: usize) -> Result<usize> {
+        let mut count = 0;
+        while count < limit {
+            let outpoint = match self.order.front()? {
+                Some(outpoint) => *outpoint,
+                None => break,
+            };
+            let (prunable, key) = {
+                let deposit = self
+                    .records
+                    .get(outpoint)?
+                    .ok_or_else(|| Error::App("Deposit not found".to_string()))?;
+                let settled = deposit.swept && (deposit.credited || deposit.refunded);
+                (
+                    settled && deposit.checkpoint_index < checkpoint_index,
+                    dest_key(&deposit.dest)?,
+                )
+            };
+            if !prunable {
+                break;
+            }
+
+            self.by_dest.remove((key, outpoint))?;
+            self.records.remove(outpoint)?;
+            self.order.pop_front()?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Removes the oldest deposit from the overflow queue.
+    pub fn pop_overflow(&mut self) -> Result<Option<QueuedDeposit>> {
+        Ok(self.overflow.pop_front()?.map(|queued| queued.into_inner()))
+    }
+
+    /// Removes the oldest deposit from the overflow queue if it was queued
+    /// before `cutoff`.
+    pub fn pop_expired_overflow(&mut self, cutoff: u64) -> Result<Option<QueuedDeposit>> {
+        match self.overflow.front()? {
+            Some(queued) if queued.queued_at < cutoff => self.pop_overflow(),
+            _ => Ok(None),
+        }
+    }
+
+    /// Schedules a deposit taken from the overflow queue to be paid back to the
+    /// depositor, less `miner_fee`, in the checkpoint at `checkpoint_index`.
+    pub fn schedule_refund(
+        &mut self,
+        outpoint: Outpoint,
+        checkpoint_index: u32,
+        miner_fee: u64,
+    ) -> Result<()> {
+        let mut deposit = self
+            .records
+            .get_mut(outpoint)?
+            .ok_or_else(|| Error::App("Deposit not found".to_string()))?;
+        deposit.checkpoint_index = checkpoint_index;
+        deposit.miner_fee = miner_fee;
+        deposit.deposit_fee = 0;
+        deposit.value = 0;
+        deposit.refunded = true;
+        self.awaiting_sweep
+            .insert((checkpoint_index, outpoint), ())?;
+
+        Ok(())
+    }
+
+    /// Schedules a deposit taken from the overflow queue to be swept by, and
+    /// credited along with, the checkpoint at `checkpoint_index`.
+    pub fn schedule_overflow(&mut self, outpoint: Outpoint, checkpoint_index: u32) -> Result<()> {
+        let mut deposit = self
+            .records
+            .get_mut(outpoint)?
+            .ok_or_else(|| Error::App("Deposit not found".to_string()))?;
+        deposit.checkpoint_index = checkpoint_index;
+        self.awaiting_sweep
+            .insert((checkpoint_index, outpoint), ())?;
+        self.awaiting_credit
+            .insert((checkpoint_index, outpoint), ())?;
+
+        Ok(())
+    }
+
+    #[query]
+    pub fn overflow_len(&self) -> Result<u64> {
+        Ok(self.overflow.len())
+    }
+
+    #[query]
+    pub fn get(&self, outpoint: Outpoint) -> Result<Option<Deposit>> {
+        Ok(self.records.get(outpoint)?.map(|deposit| deposit.clone()))
+    }
+
+    #[query]
+    pub fn by_dest(&self, dest: Dest) -> Result<Vec<(Outpoint, Deposit)>> {
+        let key = dest_key(&dest)?;
+        let mut deposits = vec![];
+        for entry in self
+            .by_dest
+            .range((key, ([0; 32], 0))..=(key, ([u8::MAX; 32], u32::MAX)))?
+        {
+            let (entry, _) = entry?;
+            let outpoint = entry.1;
+            let deposit = self
+                .get(outpoint)?
+                .ok_or_else(|| Error::App("Deposit not found".to_string()))?;
+            deposits.push((outpoint, deposit));
+        }
+
+        Ok(deposits)
+    }
+
+    /// Marks the deposits spent by the transaction of a checkpoint which has
+    /// just stopped building as swept. Deposits whose inputs are not found
+    /// were carried over to the next checkpoint.
+    pub fn index_checkpoint(&mut self, checkpoint_index: u32, inputs: &[OutPoint]) -> Result<()> {
+        let outpoints = self
+            .awaiting_sweep
+            .range(
+                (checkpoint_index, ([0; 32], 0))..=(checkpoint_index, ([u8::MAX; 32], u32::MAX)),
+            )?
+            .map(|entry| entry.map(|(key, _)| key.1))
+            .collect::<Result<Vec<_>>>()?;
+
+        for outpoint in outpoints {
+            self.awaiting_sweep.remove((checkpoint_index, outpoint))?;
+
+            let mut deposit = self
+                .records
+                .get_mut(outpoint)?
+                .ok_or_else(|| Error::App("Deposit not found".to_string()))?;
+
+            let swept = inputs
+                .iter()
+                .any(|input| input.txid.into_inner() == outpoint.0 && input.vout == outpoint.1);
+            if swept {
+                deposit.swept = true;
+            } else {
+                deposit.checkpoint_index = checkpoint_index + 1;
+                self.awaiting_sweep
+                    .insert((checkpoint_index + 1, outpoint), ())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Marks the deposits relayed into checkpoints up to and including
+    /// `checkpoint_index` as credited.
+    pub fn mark_credited(&mut self, checkpoint_index: u32) -> Result<()> {
+        let keys = self
+            .awaiting_credit
+            .range(..=(checkpoint_index, ([u8::MAX; 32], u32::MAX)))?
+            .map(|entry| entry.map(|(key, _)| *key))
+            .collect::<Result<Vec<_>>>()?;
+
+        for key in keys {
+            self.awaiting_credit.remove(key)?;
+            let mut deposit = self
+                .records
+                .get_mut(key.1)?
+                .ok_or_else(|| Error::App("Deposit not found".to_string()))?;
+            deposit.credited = true;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::Txid;
+    use orga::coins::Address;
+
+    #[test]
+    fn sweep_and_credit() {
+        let mut deposits = Deposits::default();
+        let dest = Dest::Address(Address::NULL);
+        let a = ([1; 32], 0);
+        let b = ([2; 32], 1);
+        for outpoint in [a, b] {
+            let deposit = Deposit::new(dest.clone(), 10_000, 100, 99_000, 9_801_000, 10, 3);
+            deposits.insert(outpoint, deposit).unwrap();
+        }
+
+        // b was carried over to the next checkpoint
+        let inputs = vec![OutPoint {
+            txid: Txid::from_inner([1; 32]),
+            vout: 0,
+        }];
+        deposits.index_checkpoint(3, &inputs).unwrap();
+        let deposit_a = deposits.get(a).unwrap().unwrap();
+        let deposit_b = deposits.get(b).unwrap().unwrap();
+        assert!(deposit_a.swept);
+        assert!(!deposit_b.swept);
+        assert_eq!(deposit_b.checkpoint_index, 4);
+
+        deposits.mark_credited(2).unwrap();
+        assert!(!deposits.get(a).unwrap().unwrap().credited);
+        deposits.mark_credited(3).unwrap();
+        assert!(deposits.get(a).unwrap().unwrap().credited);
+        assert!(deposits.get(b).unwrap().unwrap().credited);
+
+        let by_dest = deposits.by_dest(dest.clone()).unwrap();
+        assert_eq!(
+            by_dest
+                .iter()
+                .map(|(outpoint, _)| *outpoint)
+                .collect::<Vec<_>>(),
+            vec![a, b]
+        );
+        assert!(deposits
+            .by_dest(Dest::Address(
+                "nomic100000aeu2lh0jrrnmn2npc88typ25u7t3aa64x"
+                    .parse()
+                    .unwrap()
+            ))
+            .unwrap()
+            .is_empty());
+
+        // b was only swept in checkpoint 4, which hasn't been indexed
+        assert_eq!(deposits.prune(10, 100).unwrap(), 1);
+        assert!(deposits.get(a).unwrap().is_none());
+        assert_eq!(deposits.by_dest(dest.clone()).unwrap().len(), 1);
+        assert_eq!(deposits.prune(10, 100).unwrap(), 0);
+
+        deposits
+            .index_checkpoint(
+                4,
+                &[OutPoint {
+                    txid: Txid::from_inner([2; 32]),
+                    vout: 1,
+                }],
+            )
+            .unwrap();
+        assert_eq!(deposits.prune(4, 100).unwrap(), 0);
+        assert_eq!(deposits.prune(5, 100).unwrap(), 1);
+        assert!(deposits.by_dest(dest).unwrap().is_empty());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut deposits = Deposits::default();
+        let dest = Dest::Address(Address::NULL);
+        let queued = |outpoint, queued_at| QueuedDeposit {
+            outpoint,
+            sigset_index: 3,
+            taproot: false,
+            queued_at,
+        };
+
+        let a = ([1; 32], 0);
+        let b = ([2; 32], 0);
+        for (outpoint, queued_at) in [(a, 100), (b, 200)] {
+            let deposit = Deposit::new(dest.clone(), 10_000, 100, 99_000, 9_801_000, 10, 3);
+            deposits
+                .insert_overflow(outpoint, deposit, queued(outpoint, queued_at))
+                .unwrap();
+        }
+        assert_eq!(deposits.overflow_len().unwrap(), 2);
+
+        // queued deposits are neither swept nor credited until scheduled
+        deposits.index_checkpoint(3, &[]).unwrap();
+        deposits.mark_credited(3).unwrap();
+        let deposit_a = deposits.get(a).unwrap().unwrap();
+        assert!(deposit_a.overflowed);
+        assert_eq!(deposit_a.checkpoint_index, 3);
+        assert!(!deposit_a.credited);
+
+        let next = deposits.pop_overflow().unwrap().unwrap();
+        assert_eq!(next.outpoint, a);
+        deposits.schedule_overflow(a, 5).unwrap();
+        assert_eq!(deposits.get(a).unwrap().unwrap().checkpoint_index, 5);
+        assert_eq!(deposits.overflow_len().unwrap(), 1);
+
+        let inputs = vec![OutPoint {
+            txid: Txid::from_inner([1; 32]),
+            vout: 0,
+        }];
+        deposits.index_checkpoint(5, &inputs).unwrap();
+        deposits.mark_credited(5).unwrap();
+        let deposit_a = deposits.get(a).unwrap().unwrap();
+        assert!(deposit_a.swept);
+        assert!(deposit_a.credited);
+        assert!(!deposits.get(b).unwrap().unwrap().credited);
+
+        // b is only expired once it was queued before the cutoff
+        assert!(deposits.pop_expired_overflow(200).unwrap().is_none());
+        let expired = deposits.pop_expired_overflow(201).unwrap().unwrap();
+        assert_eq!(expired.outpoint, b);
+        assert_eq!(deposits.overflow_len().unwrap(), 0);
+
+        deposits.schedule_refund(b, 6, 400).unwrap();
+        let inputs = vec![OutPoint {
+            txid: Txid::from_inner([2; 32]),
+            vout: 0,
+        }];
+        deposits.index_checkpoint(6, &inputs).unwrap();
+        deposits.mark_credited(6).unwrap();
+        let deposit_b = deposits.get(b).unwrap().unwrap();
+        assert!(deposit_b.refunded);
+        assert!(deposit_b.swept);
+        assert!(!deposit_b.credited);
+        assert_eq!(deposit_b.miner_fee, 400);
+        assert_eq!(deposit_b.value, 0);
+    }
+}
@@ -0,0 +1,790 @@
+//! A client for the [Electrum protocol](https://electrumx-spesmilo.readthedocs.io/en/latest/protocol.html)
+//! served over TCP by e.g. `electrs`, ElectrumX or Fulcrum, usable as a
+//! [`BitcoinSource`] for the relayer.
+//!
+//! Electrum servers index the best chain by height and by script, but don't
+//! serve whole blocks. The blocks returned by
+//! [`relevant_blocks`](BitcoinSource::relevant_blocks) only contain the
+//! transactions involving the given scripts, and merkle proofs are rebuilt
+//! from the server's merkle branches. Blocks are looked up by hash through the
+//! headers the client has seen, so a block which has left the best chain can
+//! only be found if it was seen while it was in it.
+
+use super::source::{BitcoinSource, HeaderInfo};
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::consensus::{deserialize, encode::serialize_hex, Encodable};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::util::merkleblock::PartialMerkleTree;
+use bitcoin::{
+    Block, BlockHash, BlockHeader, MerkleBlock, Script, Transaction, TxMerkleNode, Txid, VarInt,
+};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// The most headers a server returns for one `blockchain.block.headers`
+/// request.
+const MAX_HEADERS_PER_REQUEST: usize = 2016;
+
+/// How far back from the tip a block is searched for when the client hasn't
+/// seen its header yet.
+const HEADER_SEARCH_DEPTH: usize = 10 * MAX_HEADERS_PER_REQUEST;
+
+/// How long a request may take, including connecting, before the connection
+/// is dropped.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct Tip {
+    height: usize,
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct Headers {
+    count: usize,
+    hex: String,
+}
+
+#[derive(Deserialize)]
+struct HistoryTx {
+    tx_hash: Txid,
+    /// The height of the transaction's block, or 0 or -1 for transactions in
+    /// the mempool.
+    height: i64,
+}
+
+#[derive(Deserialize)]
+struct MerkleBranch {
+    merkle: Vec<TxMerkleNode>,
+    pos: usize,
+}
+
+struct Connection {
+    stream: BufReader<TcpStream>,
+    next_id: u64,
+}
+
+impl Connection {
+    async fn connect(addr: &str) -> std::io::Result<Self> {
+        let mut conn = Connection {
+            stream: BufReader::new(TcpStream::connect(addr).await?),
+            next_id: 0,
+        };
+        // servers expect the protocol version to be negotiated first
+        conn.exchange("server.version", &json!(["nomic", "1.4"]))
+            .await?;
+
+        Ok(conn)
+    }
+
+    /// Sends a request and reads lines until its response, skipping the
+    /// notifications for subscriptions.
+    async fn exchange(&mut self, method: &str, params: &Value) -> std::io::Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let mut req = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        })
+        .to_string();
+        req.push('\n');
+        self.stream.get_mut().write_all(req.as_bytes()).await?;
+
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof,
+                    "Connection closed by server",
+                ));
+            }
+            let res: Value = serde_json::from_str(&line)?;
+            if res["id"] == id {
+                return Ok(res);
+            }
+        }
+    }
+}
+
+pub struct ElectrumClient {
+    addr: String,
+    conn: tokio::sync::Mutex<Option<Connection>>,
+    /// The heights and headers of the blocks the client has seen, by hash.
+    headers: std::sync::Mutex<HashMap<BlockHash, (usize, BlockHeader)>>,
+}
+
+impl ElectrumClient {
+    /// Creates a client for the server at `addr`, e.g. `localhost:50001`. The
+    /// connection is made on the first request, and made again after a
+    /// request fails.
+    pub fn new(addr: &str) -> Self {
+        ElectrumClient {
+            addr: addr.trim_start_matches("tcp://").to_string(),
+            conn: tokio::sync::Mutex::new(None),
+            headers: std::sync::Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn request(&self, method: &str, params: Value) -> Result<Value> {
+        let mut conn = self.conn.lock().await;
+        let res = tokio::time::timeout(TIMEOUT, async {
+            if conn.is_none() {
+                *conn = Some(Connection::connect(&self.addr).await?);
+            }
+            conn.as_mut().unwrap().exchange(method, &params).await
+        })
+        .await;
+
+        let mut res = match res {
+            Ok(Ok(res)) => res,
+            Ok(Err(err)) => {
+                *conn = None;
+                return Err(Error::Relayer(format!("Electrum request failed: {}", err)));
+            }
+            Err(_) => {
+                *conn = None;
+                return Err(Error::Relayer(format!(
+                    "Electrum request timed out: {}",
+                    method
+                )));
+            }
+        };
+
+        if !res["error"].is_null() {
+            return Err(Error::Relayer(format!(
+                "Electrum server responded with error: {}",
+                res["error"]
+            )));
+        }
+
+        Ok(res["result"].take())
+    }
+
+    async fn call<T: DeserializeOwned>(&self, method: &str, params: Value) -> Result<T> {
+        serde_json::from_value(self.request(method, params).await?)
+            .map_err(|err| Error::Relayer(format!("Invalid Electrum response: {}", err)))
+    }
+
+    fn cache_header(&self, height: usize, header: BlockHeader) {
+        self.headers
+            .lock()
+            .unwrap()
+            .insert(header.block_hash(), (height, header));
+    }
+
+    fn cached_header(&self, hash: &BlockHash) -> Option<(usize, BlockHeader)> {
+        self.headers.lock().unwrap().get(hash).copied()
+    }
+
+    async fn tip(&self) -> Result<(usize, BlockHeader)> {
+        let tip: Tip = self.call("blockchain.headers.subscribe", json!([])).await?;
+        let header: BlockHeader = deserialize(&decode_hex(&tip.hex)?)?;
+        self.cache_header(tip.height, header);
+
+        Ok((tip.height, header))
+    }
+
+    async fn header_at(&self, height: usize) -> Result<BlockHeader> {
+        let hex: String = self
+            .call("blockchain.block.header", json!([height]))
+            .await?;
+        let header: BlockHeader = deserialize(&decode_hex(&hex)?)?;
+        self.cache_header(height, header);
+
+        Ok(header)
+    }
+
+    /// Returns up to `count` consecutive headers of the best chain, starting
+    /// at `start_height`.
+    async fn headers(&self, start_height: usize, count: usize) -> Result<Vec<BlockHeader>> {
+        let res: Headers = self
+            .call("blockchain.block.headers", json!([start_height, count]))
+            .await?;
+        let bytes = decode_hex(&res.hex)?;
+        if bytes.len() != res.count * 80 {
+            return Err(Error::Relayer(
+                "Invalid Electrum response: wrong headers length".to_string(),
+            ));
+        }
+
+        let mut headers = vec![];
+        for (i, bytes) in bytes.chunks(80).enumerate() {
+            let header: BlockHeader = deserialize(bytes)?;
+            self.cache_header(start_height + i, header);
+            headers.push(header);
+        }
+
+        Ok(headers)
+    }
+
+    /// Returns the height and header of the block, searching back from the
+    /// tip if the client hasn't seen it.
+    async fn find_header(&self, hash: &BlockHash) -> Result<(usize, BlockHeader)> {
+        if let Some(found) = self.cached_header(hash) {
+            return Ok(found);
+        }
+
+        let (tip_height, _) = self.tip().await?;
+        let floor = (tip_height + 1).saturating_sub(HEADER_SEARCH_DEPTH);
+        let mut end = tip_height + 1;
+        while end > floor {
+            let start = end.saturating_sub(MAX_HEADERS_PER_REQUEST).max(floor);
+            let headers = self.headers(start, end - start).await?;
+            if let Some(i) = headers.iter().position(|h| h.block_hash() == *hash) {
+                return Ok((start + i, headers[i]));
+            }
+            end = start;
+        }
+
+        Err(Error::Relayer(format!(
+            "Block {} not found within {} blocks of the tip",
+            hash, HEADER_SEARCH_DEPTH
+        )))
+    }
+
+    /// Returns the heights of the confirmed transactions involving `script`.
+    async fn script_history(&self, script: &Script) -> Result<Vec<(usize, Txid)>> {
+        let history: Vec<HistoryTx> = self
+            .call(
+                "blockchain.scripthash.get_history",
+                json!([script_hash(script)]),
+            )
+            .await?;
+
+        Ok(history
+            .into_iter()
+            .filter(|tx| tx.height > 0)
+            .map(|tx| (tx.height as usize, tx.tx_hash))
+            .collect())
+    }
+
+    async fn get_transaction(&self, txid: &Txid) -> Result<Transaction> {
+        let hex: String = self
+            .call("blockchain.transaction.get", json!([txid]))
+            .await?;
+        let tx: Transaction = deserialize(&decode_hex(&hex)?)?;
+        if tx.txid() != *txid {
+            return Err(Error::Relayer(format!(
+                "Electrum server returned the wrong transaction for {}",
+                txid
+            )));
+        }
+
+        Ok(tx)
+    }
+}
+
+fn decode_hex(hex: &str) -> Result<Vec<u8>> {
+    hex::decode(hex).map_err(|err| Error::Relayer(format!("Invalid Electrum response: {}", err)))
+}
+
+/// The key of a script in the server's index: the SHA256 hash of the script,
+/// with its bytes reversed, in hex.
+fn script_hash(script: &Script) -> String {
+    let mut hash = sha256::Hash::hash(script.as_bytes()).into_inner();
+    hash.reverse();
+    hex::encode(hash)
+}
+
+/// Builds a partial merkle tree proving only the transaction at `pos` in its
+/// block, from its merkle branch as returned by
+/// `blockchain.transaction.get_merkle`, listed from the leaves up.
+///
+/// The branch doesn't say how many transactions the block has, so the
+/// smallest number consistent with it is used. Any such number gives the same
+/// tree along the branch, so the tree has the block's merkle root.
+fn partial_merkle_tree(
+    txid: &Txid,
+    branch: &[TxMerkleNode],
+    pos: usize,
+) -> Result<PartialMerkleTree> {
+    let depth = branch.len();
+    if depth >= 32 || pos >> depth != 0 {
+        return Err(Error::Relayer("Invalid merkle branch".to_string()));
+    }
+
+    // the last node of a level with an odd number of nodes is paired with
+    // itself, so a branch node equal to the node on the path is no sibling
+    let min_tree_txs = if depth == 0 {
+        1
+    } else {
+        (1 << (depth - 1)) + 1
+    };
+    let mut min_txs = (pos + 1).max(min_tree_txs);
+    let mut max_txs = 1 << depth;
+    let mut has_sibling = vec![];
+    let mut node = TxMerkleNode::from_inner(txid.into_inner());
+    for (level, sibling) in branch.iter().enumerate() {
+        let index = pos >> level;
+        let is_left = index % 2 == 0;
+        let exists = !is_left || *sibling != node;
+        if is_left {
+            let txs_before_sibling = (index + 1) << level;
+            if exists {
+                min_txs = min_txs.max(txs_before_sibling + 1);
+            } else {
+                max_txs = max_txs.min(txs_before_sibling);
+            }
+        }
+        has_sibling.push(exists);
+
+        let (left, right) = if is_left {
+            (node, *sibling)
+        } else {
+            (*sibling, node)
+        };
+        let mut data = left.into_inner().to_vec();
+        data.extend(right.into_inner());
+        node = TxMerkleNode::hash(&data);
+    }
+    if min_txs > max_txs {
+        return Err(Error::Relayer("Invalid merkle branch".to_string()));
+    }
+
+    // the tree is serialized depth-first, so siblings to the right of the
+    // path come after the path's leaf, innermost first
+    let mut bits = vec![];
+    let mut hashes = vec![];
+    let mut right_siblings = vec![];
+    for level in (0..=depth).rev() {
+        let index = pos >> level;
+        if level < depth && index % 2 == 1 {
+            bits.push(false);
+            hashes.push(branch[level]);
+        }
+        bits.push(true);
+        if level == 0 {
+            hashes.push(TxMerkleNode::from_inner(txid.into_inner()));
+        }
+        if level < depth && index % 2 == 0 && has_sibling[level] {
+            right_siblings.push(level);
+        }
+    }
+    for level in right_siblings.into_iter().rev() {
+        bits.push(false);
+        hashes.push(branch[level]);
+    }
+
+    let mut flags = vec![0u8; (bits.len() + 7) / 8];
+    for (i, bit) in bits.iter().enumerate() {
+        flags[i / 8] |= (*bit as u8) << (i % 8);
+    }
+
+    let mut bytes = vec![];
+    (min_txs as u32).consensus_encode(&mut bytes)?;
+    VarInt(hashes.len() as u64).consensus_encode(&mut bytes)?;
+    for hash in hashes {
+        hash.consensus_encode(&mut bytes)?;
+    }
+    flags.consensus_encode(&mut bytes)?;
+
+    Ok(deserialize(&bytes)?)
+}
+
+#[async_trait]
+impl BitcoinSource for ElectrumClient {
+    async fn best_block_hash(&self) -> Result<BlockHash> {
+        Ok(self.tip().await?.1.block_hash())
+    }
+
+    async fn header_info(&self, hash: &BlockHash) -> Result<HeaderInfo> {
+        let (height, header) = self.find_header(hash).await?;
+        let (tip_height, tip) = self.tip().await?;
+
+        let in_best_chain = if height == tip_height {
+            tip.block_hash() == *hash
+        } else {
+            height < tip_height && self.header_at(height).await?.block_hash() == *hash
+        };
+        let confirmations = if in_best_chain {
+            (tip_height + 1 - height) as i32
+        } else {
+            -1
+        };
+        let next_block_hash = if in_best_chain && height < tip_height {
+            Some(self.header_at(height + 1).await?.block_hash())
+        } else {
+            None
+        };
+
+        Ok(HeaderInfo {
+            hash: *hash,
+            height,
+            confirmations,
+            previous_block_hash: (height > 0).then_some(header.prev_blockhash),
+            next_block_hash,
+        })
+    }
+
+    async fn block_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        Ok(self.find_header(hash).await?.1)
+    }
+
+    async fn block(&self, hash: &BlockHash) -> Result<Block> {
+        Err(Error::Relayer(format!(
+            "Cannot fetch block {}, Electrum servers do not serve whole blocks",
+            hash
+        )))
+    }
+
+    /// Fetches the transaction by id. Whether it is in the block is checked
+    /// when its proof is fetched with
+    /// [`tx_out_proof`](BitcoinSource::tx_out_proof).
+    async fn transaction(
+        &self,
+        txid: &Txid,
+        _block_hash: &BlockHash,
+    ) -> Result<Option<Transaction>> {
+        Ok(Some(self.get_transaction(txid).await?))
+    }
+
+    /// Looks up each script's history in the server's script index, returning
+    /// blocks which only contain the transactions involving the scripts.
+    async fn relevant_blocks(
+        &self,
+        n: usize,
+        tip: &BlockHash,
+        scripts: &[Script],
+    ) -> Result<Vec<(u32, Block)>> {
+        if scripts.is_empty() || n == 0 {
+            return Ok(vec![]);
+        }
+
+        // the script index only covers the best chain
+        let tip_info = self.header_info(tip).await?;
+        if tip_info.confirmations < 0 {
+            return Err(Error::Relayer(format!(
+                "Block {} is not in the Electrum server's best chain",
+                tip
+            )));
+        }
+
+        let end_height = tip_info.height;
+        let start_height = (end_height + 1).saturating_sub(n);
+        let mut txids: BTreeMap<usize, Vec<Txid>> = BTreeMap::new();
+        for script in scripts {
+            for (height, txid) in self.script_history(script).await? {
+                if height < start_height || height > end_height {
+                    continue;
+                }
+                let block_txids = txids.entry(height).or_default();
+                if !block_txids.contains(&txid) {
+                    block_txids.push(txid);
+                }
+            }
+        }
+
+        let mut blocks = vec![];
+        for (height, txids) in txids {
+            let header = self.header_at(height).await?;
+            let mut txdata = vec![];
+            for txid in txids.iter() {
+                txdata.push(self.get_transaction(txid).await?);
+            }
+            blocks.push((height as u32, Block { header, txdata }));
+        }
+
+        Ok(blocks)
+    }
+
+    async fn send_raw_transaction(&self, tx: &Transaction) -> Result<()> {
+        self.request(
+            "blockchain.transaction.broadcast",
+            json!([serialize_hex(tx)]),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn tx_out_proof(&self, txid: &Txid, block_hash: &BlockHash) -> Result<MerkleBlock> {
+        let (height, header) = self.find_header(block_hash).await?;
+        let branch: MerkleBranch = self
+            .call("blockchain.transaction.get_merkle", json!([txid, height]))
+            .await?;
+        let txn = partial_merkle_tree(txid, &branch.merkle, branch.pos)?;
+
+        let (mut matches, mut indexes) = (vec![], vec![]);
+        match txn.extract_matches(&mut matches, &mut indexes) {
+            Ok(root) if root == header.merkle_root && matches == [*txid] => {
+                Ok(MerkleBlock { header, txn })
+            }
+            _ => Err(Error::Relayer(format!(
+                "Transaction {} is not in block {}",
+                txid, block_hash
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::util::hash::bitcoin_merkle_root;
+    use bitcoin::{OutPoint, PackedLockTime, TxIn, TxOut};
+    use tokio::net::TcpListener;
+
+    fn tx(script_pubkey: &Script, vout: u32) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), vout),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        }
+    }
+
+    /// Builds a chain with the given transactions in each block, after a
+    /// coinbase-like transaction unique to the block.
+    fn chain(txdata: Vec<Vec<Transaction>>) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for (height, txdata) in txdata.into_iter().enumerate() {
+            let mut block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash: blocks
+                        .last()
+                        .map_or(BlockHash::all_zeros(), |b| b.block_hash()),
+                    merkle_root: TxMerkleNode::all_zeros(),
+                    time: height as u32,
+                    bits: 0x207fffff,
+                    nonce: 0,
+                },
+                txdata: [vec![tx(&Script::new(), height as u32)], txdata].concat(),
+            };
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// The merkle branch of the transaction at `pos`, as Electrum servers
+    /// return it.
+    fn merkle_branch(txids: &[Txid], mut pos: usize) -> Vec<TxMerkleNode> {
+        let mut level: Vec<_> = txids
+            .iter()
+            .map(|txid| TxMerkleNode::from_inner(txid.into_inner()))
+            .collect();
+        let mut branch = vec![];
+        while level.len() > 1 {
+            if level.len() % 2 == 1 {
+                level.push(*level.last().unwrap());
+            }
+            branch.push(level[pos ^ 1]);
+            level = level
+                .chunks(2)
+                .map(|pair| {
+                    let mut data = pair[0].into_inner().to_vec();
+                    data.extend(pair[1].into_inner());
+                    TxMerkleNode::hash(&data)
+                })
+                .collect();
+            pos /= 2;
+        }
+        branch
+    }
+
+    #[test]
+    fn partial_merkle_tree_from_branch() {
+        for num_txs in 1..=33u8 {
+            let txids: Vec<_> = (0..num_txs).map(|i| Txid::from_inner([i; 32])).collect();
+            let root = bitcoin_merkle_root(
+                txids
+                    .iter()
+                    .map(|txid| TxMerkleNode::from_inner(txid.into_inner())),
+            )
+            .unwrap();
+
+            for (pos, txid) in txids.iter().enumerate() {
+                let branch = merkle_branch(&txids, pos);
+                let tree = partial_merkle_tree(txid, &branch, pos).unwrap();
+                let (mut matches, mut indexes) = (vec![], vec![]);
+                assert_eq!(
+                    tree.extract_matches(&mut matches, &mut indexes).unwrap(),
+                    root
+                );
+                assert_eq!(matches, vec![*txid]);
+            }
+        }
+
+        let txids: Vec<_> = (0..3).map(|i| Txid::from_inner([i; 32])).collect();
+        assert!(partial_merkle_tree(&txids[0], &merkle_branch(&txids, 0), 4).is_err());
+    }
+
+    /// Answers an Electrum request for the chain `blocks`.
+    fn respond(blocks: &[Block], method: &str, params: &Value) -> Value {
+        let tip = blocks.len() - 1;
+        let find_tx = |txid: &Value| {
+            blocks.iter().enumerate().find_map(|(height, block)| {
+                block
+                    .txdata
+                    .iter()
+                    .position(|tx| json!(tx.txid()) == *txid)
+                    .map(|pos| (height, pos))
+            })
+        };
+
+        match method {
+            "server.version" => json!(["fake", "1.4"]),
+            "blockchain.headers.subscribe" => json!({
+                "height": tip,
+                "hex": serialize_hex(&blocks[tip].header),
+            }),
+            "blockchain.block.header" => {
+                let height = params[0].as_u64().unwrap() as usize;
+                json!(serialize_hex(&blocks[height].header))
+            }
+            "blockchain.block.headers" => {
+                let start = params[0].as_u64().unwrap() as usize;
+                let end = (start + params[1].as_u64().unwrap() as usize).min(tip + 1);
+                let hex: String = blocks[start..end]
+                    .iter()
+                    .map(|block| serialize_hex(&block.header))
+                    .collect();
+                json!({ "count": end - start, "hex": hex, "max": MAX_HEADERS_PER_REQUEST })
+            }
+            "blockchain.scripthash.get_history" => {
+                let mut history = vec![];
+                for (height, block) in blocks.iter().enumerate() {
+                    for tx in block.txdata.iter() {
+                        let pays_script = tx
+                            .output
+                            .iter()
+                            .any(|output| json!(script_hash(&output.script_pubkey)) == params[0]);
+                        if pays_script {
+                            history.push(json!({ "tx_hash": tx.txid(), "height": height }));
+                        }
+                    }
+                }
+                json!(history)
+            }
+            "blockchain.transaction.get" => {
+                let (height, pos) = find_tx(&params[0]).unwrap();
+                json!(serialize_hex(&blocks[height].txdata[pos]))
+            }
+            "blockchain.transaction.get_merkle" => {
+                let (height, pos) = find_tx(&params[0]).unwrap();
+                let txids: Vec<_> = blocks[height].txdata.iter().map(|tx| tx.txid()).collect();
+                json!({
+                    "block_height": height,
+                    "merkle": merkle_branch(&txids, pos),
+                    "pos": pos,
+                })
+            }
+            "blockchain.transaction.broadcast" => {
+                let tx: Transaction =
+                    deserialize(&hex::decode(params[0].as_str().unwrap()).unwrap()).unwrap();
+                json!(tx.txid())
+            }
+            _ => Value::Null,
+        }
+    }
+
+    /// Serves the Electrum protocol for the chain `blocks`, sending a
+    /// notification before each response, and returns its address.
+    async fn fake_electrum(blocks: Vec<Block>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap() > 0 {
+                let req: Value = serde_json::from_str(&line).unwrap();
+                let method = req["method"].as_str().unwrap();
+                let res = match respond(&blocks, method, &req["params"]) {
+                    Value::Null => json!({
+                        "id": req["id"],
+                        "error": { "code": 1, "message": "unknown method" },
+                    }),
+                    result => json!({ "id": req["id"], "result": result }),
+                };
+                let notification = json!({
+                    "method": "blockchain.headers.subscribe",
+                    "params": [{ "height": 0, "hex": "" }],
+                });
+                let out = format!("{}\n{}\n", notification, res);
+                stream.get_mut().write_all(out.as_bytes()).await.unwrap();
+                line.clear();
+            }
+        });
+
+        format!("tcp://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn source() {
+        let watched = Script::from(vec![0x51, 0x52]);
+        let mut txdata = vec![vec![]; 10];
+        txdata[2] = vec![tx(&watched, 1_000)];
+        txdata[7] = (0..4).map(|i| tx(&Script::new(), 2_000 + i)).collect();
+        txdata[7].push(tx(&watched, 1_001));
+        let blocks = chain(txdata);
+        let client = ElectrumClient::new(&fake_electrum(blocks.clone()).await);
+
+        let tip = client.best_block_hash().await.unwrap();
+        assert_eq!(tip, blocks[9].block_hash());
+
+        let info = client.header_info(&blocks[2].block_hash()).await.unwrap();
+        assert_eq!(
+            info,
+            HeaderInfo {
+                hash: blocks[2].block_hash(),
+                height: 2,
+                confirmations: 8,
+                previous_block_hash: Some(blocks[1].block_hash()),
+                next_block_hash: Some(blocks[3].block_hash()),
+            }
+        );
+        let header = client.block_header(&blocks[5].block_hash()).await.unwrap();
+        assert_eq!(header, blocks[5].header);
+
+        let relevant = client
+            .relevant_blocks(5, &tip, &[watched.clone()])
+            .await
+            .unwrap();
+        assert_eq!(relevant.len(), 1);
+        assert_eq!(relevant[0].0, 7);
+        assert_eq!(relevant[0].1.block_hash(), blocks[7].block_hash());
+        assert_eq!(relevant[0].1.txdata, vec![blocks[7].txdata[5].clone()]);
+        let relevant = client.relevant_blocks(10, &tip, &[watched]).await.unwrap();
+        assert_eq!(relevant.len(), 2);
+
+        let deposit = &blocks[7].txdata[5];
+        let proof = client
+            .tx_out_proof(&deposit.txid(), &blocks[7].block_hash())
+            .await
+            .unwrap();
+        assert_eq!(proof.header, blocks[7].header);
+        let (mut matches, mut indexes) = (vec![], vec![]);
+        assert_eq!(
+            proof
+                .txn
+                .extract_matches(&mut matches, &mut indexes)
+                .unwrap(),
+            blocks[7].header.merkle_root
+        );
+        assert_eq!(matches, vec![deposit.txid()]);
+        assert!(client
+            .tx_out_proof(&deposit.txid(), &blocks[6].block_hash())
+            .await
+            .is_err());
+
+        client.send_raw_transaction(deposit).await.unwrap();
+        let err = client.block(&tip).await.unwrap_err();
+        assert!(err.to_string().contains("whole blocks"));
+    }
+}
@@ -0,0 +1,473 @@
+//! A client for the HTTP API of an [Esplora](https://github.com/Blockstream/esplora)
+//! server, e.g. `https://blockstream.info/api` or a self-hosted
+//! `electrs`, usable as a [`BitcoinSource`] for the relayer.
+//!
+//! Servers which only speak the Electrum protocol can be used through
+//! [`ElectrumClient`](super::electrum::ElectrumClient) instead.
+
+use super::source::{BitcoinSource, HeaderInfo, BLOCK_FETCH_CONCURRENCY};
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::consensus::{deserialize, encode::serialize_hex};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::{Block, BlockHash, BlockHeader, MerkleBlock, Script, Transaction, Txid};
+use futures::{StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+/// How many confirmed transactions Esplora returns per page of a script's
+/// history.
+const CHAIN_TXS_PAGE_SIZE: usize = 25;
+
+#[derive(Deserialize)]
+struct BlockStatus {
+    in_best_chain: bool,
+    height: Option<usize>,
+    next_best: Option<BlockHash>,
+}
+
+#[derive(Deserialize)]
+struct TxStatus {
+    block_height: Option<u32>,
+    block_hash: Option<BlockHash>,
+}
+
+#[derive(Deserialize)]
+struct ScriptTx {
+    txid: Txid,
+    status: TxStatus,
+}
+
+pub struct EsploraClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl EsploraClient {
+    /// Creates a client for the API at `url`, e.g.
+    /// `https://blockstream.info/testnet/api`.
+    pub fn new(url: &str) -> Self {
+        EsploraClient {
+            client: reqwest::Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+        }
+    }
+
+    async fn send(&self, req: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let res = req
+            .send()
+            .await
+            .map_err(|err| Error::Relayer(format!("Esplora request failed: {}", err)))?;
+
+        if !res.status().is_success() {
+            let status = res.status();
+            let body = res.text().await.unwrap_or_default();
+            return Err(Error::Relayer(format!(
+                "Esplora responded with code {}: {}",
+                status, body
+            )));
+        }
+
+        Ok(res)
+    }
+
+    async fn get_text(&self, path: &str) -> Result<String> {
+        let req = self.client.get(format!("{}{}", self.url, path));
+        self.send(req)
+            .await?
+            .text()
+            .await
+            .map_err(|err| Error::Relayer(format!("Invalid Esplora response: {}", err)))
+    }
+
+    async fn get_bytes(&self, path: &str) -> Result<Vec<u8>> {
+        let req = self.client.get(format!("{}{}", self.url, path));
+        Ok(self
+            .send(req)
+            .await?
+            .bytes()
+            .await
+            .map_err(|err| Error::Relayer(format!("Invalid Esplora response: {}", err)))?
+            .to_vec())
+    }
+
+    async fn get_hex(&self, path: &str) -> Result<Vec<u8>> {
+        hex::decode(self.get_text(path).await?.trim())
+            .map_err(|err| Error::Relayer(format!("Invalid Esplora response: {}", err)))
+    }
+
+    async fn get_json<T: for<'de> Deserialize<'de>>(&self, path: &str) -> Result<T> {
+        serde_json::from_str(&self.get_text(path).await?)
+            .map_err(|err| Error::Relayer(format!("Invalid Esplora response: {}", err)))
+    }
+
+    async fn tip_height(&self) -> Result<usize> {
+        self.get_text("/blocks/tip/height")
+            .await?
+            .trim()
+            .parse()
+            .map_err(|err| Error::Relayer(format!("Invalid Esplora response: {}", err)))
+    }
+
+    /// Returns the heights and hashes of the blocks at or above `min_height`
+    /// with confirmed transactions involving `script`, highest first.
+    async fn script_blocks(
+        &self,
+        script: &Script,
+        min_height: u32,
+    ) -> Result<Vec<(u32, BlockHash)>> {
+        let scripthash = sha256::Hash::hash(script.as_bytes());
+        let mut blocks = vec![];
+        let mut last_seen = None;
+
+        loop {
+            let path = match last_seen {
+                Some(txid) => format!("/scripthash/{}/txs/chain/{}", scripthash, txid),
+                None => format!("/scripthash/{}/txs/chain", scripthash),
+            };
+            let page: Vec<ScriptTx> = self.get_json(&path).await?;
+
+            for tx in page.iter() {
+                if let (Some(height), Some(hash)) = (tx.status.block_height, tx.status.block_hash) {
+                    if height < min_height {
+                        return Ok(blocks);
+                    }
+                    blocks.push((height, hash));
+                }
+            }
+
+            match page.last() {
+                Some(tx) if page.len() >= CHAIN_TXS_PAGE_SIZE => last_seen = Some(tx.txid),
+                _ => return Ok(blocks),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl BitcoinSource for EsploraClient {
+    async fn best_block_hash(&self) -> Result<BlockHash> {
+        BlockHash::from_str(self.get_text("/blocks/tip/hash").await?.trim())
+            .map_err(|err| Error::Relayer(format!("Invalid Esplora response: {}", err)))
+    }
+
+    async fn header_info(&self, hash: &BlockHash) -> Result<HeaderInfo> {
+        let status: BlockStatus = self.get_json(&format!("/block/{}/status", hash)).await?;
+        let header = self.block_header(hash).await?;
+        let height = match status.height {
+            Some(height) => height,
+            None => self
+                .get_json::<serde_json::Value>(&format!("/block/{}", hash))
+                .await?["height"]
+                .as_u64()
+                .ok_or_else(|| Error::Relayer("Invalid Esplora response".to_string()))?
+                as usize,
+        };
+        let confirmations = if status.in_best_chain {
+            (self.tip_height().await? + 1).saturating_sub(height) as i32
+        } else {
+            -1
+        };
+
+        Ok(HeaderInfo {
+            hash: *hash,
+            height,
+            confirmations,
+            previous_block_hash: (height > 0).then_some(header.prev_blockhash),
+            next_block_hash: status.next_best,
+        })
+    }
+
+    async fn block_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        Ok(deserialize(
+            &self.get_hex(&format!("/block/{}/header", hash)).await?,
+        )?)
+    }
+
+    async fn block(&self, hash: &BlockHash) -> Result<Block> {
+        Ok(deserialize(
+            &self.get_bytes(&format!("/block/{}/raw", hash)).await?,
+        )?)
+    }
+
+    /// Looks up the blocks with transactions involving each script in the
+    /// server's script index, so only those blocks are downloaded.
+    async fn relevant_blocks(
+        &self,
+        n: usize,
+        tip: &BlockHash,
+        scripts: &[Script],
+    ) -> Result<Vec<(u32, Block)>> {
+        if scripts.is_empty() || n == 0 {
+            return Ok(vec![]);
+        }
+
+        let tip_info = self.header_info(tip).await?;
+        // the script index only covers the best chain, and downloading every
+        // block takes fewer requests than looking up more scripts than blocks
+        if tip_info.confirmations < 0 || scripts.len() > n {
+            return self.blocks(n, tip).await;
+        }
+
+        let end_height = tip_info.height as u32;
+        let start_height = (end_height + 1).saturating_sub(n as u32);
+        let mut hashes = BTreeMap::new();
+        for script in scripts {
+            for (height, hash) in self.script_blocks(script, start_height).await? {
+                if height <= end_height {
+                    hashes.insert(height, hash);
+                }
+            }
+        }
+
+        futures::stream::iter(hashes)
+            .map(|(height, hash)| async move { Ok((height, self.block(&hash).await?)) })
+            .buffered(BLOCK_FETCH_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    async fn send_raw_transaction(&self, tx: &Transaction) -> Result<()> {
+        let req = self
+            .client
+            .post(format!("{}/tx", self.url))
+            .body(serialize_hex(tx));
+        self.send(req).await?;
+
+        Ok(())
+    }
+
+    async fn tx_out_proof(&self, txid: &Txid, block_hash: &BlockHash) -> Result<MerkleBlock> {
+        let status: serde_json::Value = self.get_json(&format!("/tx/{}/status", txid)).await?;
+        if status["block_hash"].as_str() != Some(block_hash.to_string().as_str()) {
+            return Err(Error::Relayer(format!(
+                "Transaction {} is not in block {}",
+                txid, block_hash
+            )));
+        }
+
+        Ok(deserialize(
+            &self
+                .get_hex(&format!("/tx/{}/merkleblock-proof", txid))
+                .await?,
+        )?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::consensus::serialize;
+    use bitcoin::{OutPoint, PackedLockTime, TxIn, TxMerkleNode, TxOut};
+    use serde_json::json;
+    use std::sync::{Arc, Mutex};
+    use warp::hyper::body::Bytes;
+    use warp::Filter;
+
+    fn tx(script_pubkey: &Script, vout: u32) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), vout),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey: script_pubkey.clone(),
+            }],
+        }
+    }
+
+    /// Builds a chain with the given transactions in each block, timestamped
+    /// with its height.
+    fn chain(txdata: Vec<Vec<Transaction>>) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for (height, txdata) in txdata.into_iter().enumerate() {
+            let mut block = Block {
+                header: BlockHeader {
+                    version: 1,
+                    prev_blockhash: blocks
+                        .last()
+                        .map_or(BlockHash::all_zeros(), |b| b.block_hash()),
+                    merkle_root: TxMerkleNode::all_zeros(),
+                    time: height as u32,
+                    bits: 0x207fffff,
+                    nonce: 0,
+                },
+                txdata: [vec![tx(&Script::new(), height as u32)], txdata].concat(),
+            };
+            block.header.merkle_root = block.compute_merkle_root().unwrap();
+            blocks.push(block);
+        }
+        blocks
+    }
+
+    /// Answers a request to the Esplora API for the chain `blocks`.
+    fn respond(blocks: &[Block], path: &str, body: &[u8]) -> (u16, Vec<u8>) {
+        let height_of = |hash: &str| {
+            blocks
+                .iter()
+                .position(|b| b.block_hash().to_string() == hash)
+        };
+        let tip = blocks.len() - 1;
+        let parts: Vec<_> = path.trim_start_matches('/').split('/').collect();
+
+        let res = match parts.as_slice() {
+            ["blocks", "tip", "hash"] => Some(blocks[tip].block_hash().to_string().into_bytes()),
+            ["blocks", "tip", "height"] => Some(tip.to_string().into_bytes()),
+            ["block", hash, "status"] => height_of(hash).map(|height| {
+                json!({
+                    "in_best_chain": true,
+                    "height": height,
+                    "next_best": blocks.get(height + 1).map(|b| b.block_hash()),
+                })
+                .to_string()
+                .into_bytes()
+            }),
+            ["block", hash, "header"] => {
+                height_of(hash).map(|height| serialize_hex(&blocks[height].header).into_bytes())
+            }
+            ["block", hash, "raw"] => height_of(hash).map(|height| serialize(&blocks[height])),
+            ["scripthash", scripthash, "txs", "chain", last_seen @ ..] => {
+                let mut txs = vec![];
+                for (height, block) in blocks.iter().enumerate().rev() {
+                    for tx in block.txdata.iter() {
+                        let pays_script = tx.output.iter().any(|output| {
+                            sha256::Hash::hash(output.script_pubkey.as_bytes()).to_string()
+                                == *scripthash
+                        });
+                        if pays_script {
+                            txs.push(json!({
+                                "txid": tx.txid(),
+                                "status": {
+                                    "confirmed": true,
+                                    "block_height": height,
+                                    "block_hash": block.block_hash(),
+                                },
+                            }));
+                        }
+                    }
+                }
+                let start = match last_seen {
+                    [txid] => txs.iter().position(|tx| tx["txid"] == *txid).unwrap() + 1,
+                    _ => 0,
+                };
+                let page: Vec<_> = txs[start..].iter().take(CHAIN_TXS_PAGE_SIZE).collect();
+                Some(serde_json::to_vec(&page).unwrap())
+            }
+            ["tx"] => {
+                let tx: Transaction = deserialize(&hex::decode(body).unwrap()).unwrap();
+                Some(tx.txid().to_string().into_bytes())
+            }
+            _ => None,
+        };
+
+        match res {
+            Some(body) => (200, body),
+            None => (404, b"Not found".to_vec()),
+        }
+    }
+
+    /// Serves the Esplora API for the chain `blocks`, returning its URL and
+    /// the paths requested from it.
+    async fn fake_esplora(blocks: Vec<Block>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let reqs = requests.clone();
+
+        let route = warp::path::full().and(warp::body::bytes()).map(
+            move |path: warp::path::FullPath, body: Bytes| {
+                reqs.lock().unwrap().push(path.as_str().to_string());
+                let (status, body) = respond(&blocks, path.as_str(), &body);
+                warp::http::Response::builder()
+                    .status(status)
+                    .body(body)
+                    .unwrap()
+            },
+        );
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+
+        (format!("http://{}/", addr), requests)
+    }
+
+    #[tokio::test]
+    async fn source() {
+        let blocks = chain(vec![vec![]; 5]);
+        let (url, requests) = fake_esplora(blocks.clone()).await;
+        let client = EsploraClient::new(&url);
+
+        let tip = client.best_block_hash().await.unwrap();
+        assert_eq!(tip, blocks[4].block_hash());
+
+        let info = client.header_info(&blocks[2].block_hash()).await.unwrap();
+        assert_eq!(
+            info,
+            HeaderInfo {
+                hash: blocks[2].block_hash(),
+                height: 2,
+                confirmations: 3,
+                previous_block_hash: Some(blocks[1].block_hash()),
+                next_block_hash: Some(blocks[3].block_hash()),
+            }
+        );
+
+        let block = client.block(&blocks[3].block_hash()).await.unwrap();
+        assert_eq!(block, blocks[3]);
+        let fetched = client.blocks(3, &tip).await.unwrap();
+        assert_eq!(fetched.len(), 3);
+        assert_eq!(fetched[0], (2, blocks[2].clone()));
+
+        client
+            .send_raw_transaction(&blocks[1].txdata[0])
+            .await
+            .unwrap();
+        assert!(requests.lock().unwrap().contains(&"/tx".to_string()));
+
+        let err = client.block(&BlockHash::all_zeros()).await.unwrap_err();
+        assert!(err.to_string().contains("404"));
+    }
+
+    #[tokio::test]
+    async fn relevant_blocks_by_script() {
+        let watched = Script::from(vec![0x51, 0x52]);
+        let mut txdata = vec![vec![]; 40];
+        txdata[3] = vec![tx(&watched, 1_000)];
+        txdata[30] = vec![tx(&watched, 1_001)];
+        // more than a page of the script's history
+        txdata[35] = (0..30).map(|i| tx(&watched, 2_000 + i)).collect();
+        let blocks = chain(txdata);
+
+        let (url, requests) = fake_esplora(blocks.clone()).await;
+        let client = EsploraClient::new(&url);
+        let tip = blocks[39].block_hash();
+
+        let relevant = client
+            .relevant_blocks(20, &tip, &[watched.clone()])
+            .await
+            .unwrap();
+        let heights: Vec<_> = relevant.iter().map(|(height, _)| *height).collect();
+        assert_eq!(heights, vec![30, 35]);
+        assert_eq!(relevant[1].1, blocks[35]);
+
+        let raw_requests = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|path| path.ends_with("/raw"))
+            .count();
+        assert_eq!(raw_requests, 2);
+
+        let relevant = client
+            .relevant_blocks(20, &tip, &[Script::from(vec![0x53])])
+            .await
+            .unwrap();
+        assert!(relevant.is_empty());
+        assert!(client
+            .relevant_blocks(20, &tip, &[])
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::bitcoin::adapter::Adapter;
+use crate::bitcoin::{BitcoinNetwork, DEFAULT_NETWORK};
 use crate::error::{Error, Result};
 use bitcoin::blockdata::block::BlockHeader;
 use bitcoin::consensus::Encodable;
@@ -20,6 +21,13 @@ const RETARGET_INTERVAL: u32 = 2016;
 const TARGET_SPACING: u32 = 10 * 60;
 const TARGET_TIMESPAN: u32 = RETARGET_INTERVAL * TARGET_SPACING;
 const MAX_TARGET: u32 = 0x1d00ffff;
+const SIGNET_MAX_TARGET: u32 = 0x1e0377ae;
+/// The proof-of-work limit of regtest blocks, which are mined at the lowest
+/// difficulty.
+const REGTEST_MAX_TARGET: u32 = 0x207fffff;
+/// The most a Testnet4 block starting a retarget period may be timestamped
+/// before its parent (BIP94).
+const MAX_TIMEWARP: u32 = 600;
 
 #[orga(skip(Default))]
 #[derive(Clone, Debug, PartialEq)]
@@ -40,6 +48,10 @@ impl WrappedHeader {
         }
     }
 
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
     pub fn time(&self) -> u32 {
         self.header.time
     }
@@ -178,7 +190,7 @@ impl WorkHeader {
 }
 
 // TODO: implement trait that returns constants for bitcoin::Network variants
-#[orga(skip(Default))]
+#[orga(skip(Default), version = 1)]
 #[derive(Clone, Debug)]
 pub struct Config {
     pub max_length: u64,
@@ -191,16 +203,60 @@ pub struct Config {
     pub retargeting: bool,
     pub min_difficulty_blocks: bool,
     pub encoded_trusted_header: LengthVec<u8, u8>,
+    /// Whether the BIP94 rules used by Testnet4 apply: retargets are based on
+    /// the target of the first block of the period, so min-difficulty blocks
+    /// can't lower the difficulty, and the first block of a period can't be
+    /// timestamped more than [`MAX_TIMEWARP`] seconds before its parent.
+    #[orga(version(V1))]
+    pub bip94: bool,
+    /// The network the header chain belongs to, chosen at genesis.
+    #[orga(version(V1))]
+    pub network: BitcoinNetwork,
+}
+
+impl MigrateFrom<ConfigV0> for ConfigV1 {
+    fn migrate_from(value: ConfigV0) -> OrgaResult<Self> {
+        Ok(Self {
+            max_length: value.max_length,
+            max_time_increase: value.max_time_increase,
+            trusted_height: value.trusted_height,
+            retarget_interval: value.retarget_interval,
+            target_spacing: value.target_spacing,
+            target_timespan: value.target_timespan,
+            max_target: value.max_target,
+            retargeting: value.retargeting,
+            min_difficulty_blocks: value.min_difficulty_blocks,
+            encoded_trusted_header: value.encoded_trusted_header,
+            bip94: false,
+            network: network_of(&value)?,
+        })
+    }
+}
+
+/// Infers the network of a header chain configured before the network was
+/// stored, from its trusted header and the rules it was configured with.
+/// Testnet4 chains always store their network, since the BIP94 rules were
+/// added along with it.
+fn network_of(config: &ConfigV0) -> OrgaResult<BitcoinNetwork> {
+    let trusted_header: Adapter<BlockHeader> =
+        Decode::decode(config.encoded_trusted_header.as_slice())?;
+
+    Ok(
+        if !config.retargeting || trusted_header.bits == REGTEST_MAX_TARGET {
+            BitcoinNetwork::Regtest
+        } else if config.max_target == SIGNET_MAX_TARGET {
+            BitcoinNetwork::Signet
+        } else if config.min_difficulty_blocks {
+            BitcoinNetwork::Testnet
+        } else {
+            BitcoinNetwork::Bitcoin
+        },
+    )
 }
 
 impl Default for Config {
     fn default() -> Self {
-        match super::NETWORK {
-            bitcoin::Network::Bitcoin => Config::mainnet(),
-            bitcoin::Network::Testnet => Config::testnet(),
-            bitcoin::Network::Regtest => Config::regtest(),
-            _ => unimplemented!(),
-        }
+        Config::for_network(DEFAULT_NETWORK)
     }
 }
 
@@ -211,6 +267,16 @@ impl Default for Config {
 // }
 
 impl Config {
+    pub fn for_network(network: BitcoinNetwork) -> Self {
+        match network {
+            BitcoinNetwork::Bitcoin => Config::mainnet(),
+            BitcoinNetwork::Testnet => Config::testnet(),
+            BitcoinNetwork::Testnet4 => Config::testnet4(),
+            BitcoinNetwork::Signet => Config::signet(),
+            BitcoinNetwork::Regtest => Config::regtest(),
+        }
+    }
+
     pub fn mainnet() -> Self {
         let checkpoint_json = include_str!("./checkpoint.json");
         let checkpoint: (u32, BlockHeader) = serde_json::from_str(checkpoint_json).unwrap();
@@ -230,6 +296,8 @@ impl Config {
             encoded_trusted_header: header_bytes.try_into().unwrap(),
             retargeting: true,
             min_difficulty_blocks: false,
+            bip94: false,
+            network: BitcoinNetwork::Bitcoin,
         }
     }
 
@@ -252,6 +320,8 @@ impl Config {
             encoded_trusted_header: header_bytes.try_into().unwrap(),
             retargeting: true,
             min_difficulty_blocks: true,
+            bip94: false,
+            network: BitcoinNetwork::Testnet,
         }
     }
 
@@ -274,6 +344,63 @@ impl Config {
             encoded_trusted_header: header_bytes.try_into().unwrap(),
             retargeting: false,
             min_difficulty_blocks: true,
+            bip94: false,
+            network: BitcoinNetwork::Regtest,
+        }
+    }
+
+    /// Testnet4, which has Testnet3's min-difficulty blocks along with the
+    /// BIP94 retargeting and timewarp rules.
+    pub fn testnet4() -> Self {
+        let checkpoint_json = include_str!("./testnet4_checkpoint.json");
+        let checkpoint: (u32, BlockHeader) = serde_json::from_str(checkpoint_json).unwrap();
+        let (height, header) = checkpoint;
+
+        let mut header_bytes = vec![];
+        header.consensus_encode(&mut header_bytes).unwrap();
+
+        Self {
+            max_length: MAX_LENGTH,
+            max_time_increase: MAX_TIME_INCREASE,
+            retarget_interval: RETARGET_INTERVAL,
+            target_spacing: TARGET_SPACING,
+            target_timespan: TARGET_TIMESPAN,
+            max_target: MAX_TARGET,
+            trusted_height: height,
+            encoded_trusted_header: header_bytes.try_into().unwrap(),
+            retargeting: true,
+            min_difficulty_blocks: true,
+            bip94: true,
+            network: BitcoinNetwork::Testnet4,
+        }
+    }
+
+    /// The default Signet. Its blocks retarget like mainnet's, with a lower
+    /// proof-of-work limit. The block signatures required by the signet
+    /// challenge are committed in the coinbase transaction, so they can't be
+    /// checked from headers and the chain is trusted to be the signet's by
+    /// its trusted header.
+    pub fn signet() -> Self {
+        let checkpoint_json = include_str!("./signet_checkpoint.json");
+        let checkpoint: (u32, BlockHeader) = serde_json::from_str(checkpoint_json).unwrap();
+        let (height, header) = checkpoint;
+
+        let mut header_bytes = vec![];
+        header.consensus_encode(&mut header_bytes).unwrap();
+
+        Self {
+            max_length: MAX_LENGTH,
+            max_time_increase: MAX_TIME_INCREASE,
+            retarget_interval: RETARGET_INTERVAL,
+            target_spacing: TARGET_SPACING,
+            target_timespan: TARGET_TIMESPAN,
+            max_target: SIGNET_MAX_TARGET,
+            trusted_height: height,
+            encoded_trusted_header: header_bytes.try_into().unwrap(),
+            retargeting: true,
+            min_difficulty_blocks: false,
+            bip94: false,
+            network: BitcoinNetwork::Signet,
         }
     }
 }
@@ -445,6 +572,7 @@ impl HeaderQueue {
             if self.deque.len() >= 11 {
                 self.validate_time(header)?;
             }
+            self.validate_timewarp(header, prev_header)?;
 
             let target = self.get_next_target(header, prev_header)?;
             header.validate_pow(&target)?;
@@ -507,18 +635,19 @@ impl HeaderQueue {
             return Ok(WrappedHeader::u256_from_compact(header.bits()));
         }
 
-        if header.height() < self.config.retarget_interval {
+        if header.height() + 1 < self.config.retarget_interval {
             return Err(Error::Header("Invalid trusted header. Trusted header have height which is a multiple of the retarget interval".into()));
         }
 
-        let prev_retarget = match self.get_by_height(first_reorg_height)? {
-            Some(inner) => inner.time(),
+        let first_header = match self.get_by_height(first_reorg_height)? {
+            Some(inner) => inner.header,
             None => {
                 return Err(Error::Header(
                     "No previous retargeting header exists".into(),
                 ));
             }
         };
+        let prev_retarget = first_header.time();
 
         let mut timespan = header.time() - prev_retarget;
 
@@ -533,7 +662,14 @@ impl HeaderQueue {
         let target_timespan = WrappedHeader::u32_to_u256(self.config.target_timespan);
         let timespan = WrappedHeader::u32_to_u256(timespan);
 
-        let target = header.target() * timespan / target_timespan;
+        // under BIP94 the last block of the period may be a min-difficulty
+        // block, so the first block's target is used instead
+        let prev_target = if self.config.bip94 {
+            first_header.target()
+        } else {
+            header.target()
+        };
+        let target = prev_target * timespan / target_timespan;
         let target_u32 = BlockHeader::compact_target_from_u256(&target);
         let target = WrappedHeader::u256_from_compact(target_u32);
 
@@ -559,6 +695,27 @@ impl HeaderQueue {
         Ok(work)
     }
 
+    /// Checks the BIP94 timewarp rule, if enabled: the first block of a
+    /// retarget period can't be timestamped more than [`MAX_TIMEWARP`]
+    /// seconds before its parent.
+    fn validate_timewarp(
+        &self,
+        header: &WrappedHeader,
+        previous_header: &WrappedHeader,
+    ) -> Result<()> {
+        if !self.config.bip94 || header.height() % self.config.retarget_interval != 0 {
+            return Ok(());
+        }
+
+        if header.time() < previous_header.time().saturating_sub(MAX_TIMEWARP) {
+            return Err(Error::Header(
+                "Header timestamp is too far before the previous header".into(),
+            ));
+        }
+
+        Ok(())
+    }
+
     fn validate_time(&self, current_header: &WrappedHeader) -> Result<()> {
         let mut prev_stamps: Vec<u32> = Vec::with_capacity(11);
 
@@ -669,8 +826,18 @@ impl HeaderQueue {
         Ok(())
     }
 
+    /// Sets the number of headers to keep before pruning. Unlike
+    /// [`Self::configure`], this does not reset the queue.
+    pub fn set_max_length(&mut self, max_length: u64) {
+        self.config.max_length = max_length;
+    }
+
     pub fn network(&self) -> bitcoin::Network {
-        super::NETWORK
+        self.config.network.network()
+    }
+
+    pub fn bitcoin_network(&self) -> BitcoinNetwork {
+        self.config.network
     }
 }
 
@@ -680,6 +847,7 @@ mod test {
     use bitcoin::hash_types::TxMerkleNode;
     use bitcoin::hashes::hex::FromHex;
     use bitcoin::hashes::sha256d::Hash;
+    use bitcoin::hashes::Hash as _;
     use bitcoin::BlockHash;
     use chrono::{TimeZone, Utc};
     use orga::context::Context;
@@ -867,6 +1035,8 @@ mod test {
             ]
             .try_into()
             .unwrap(),
+            bip94: false,
+            network: BitcoinNetwork::Bitcoin,
         };
         let mut q = HeaderQueue::default();
         q.configure(test_config).unwrap();
@@ -911,6 +1081,8 @@ mod test {
             ]
             .try_into()
             .unwrap(),
+            bip94: false,
+            network: BitcoinNetwork::Bitcoin,
         };
 
         let adapter = Adapter::new(header);
@@ -959,6 +1131,8 @@ mod test {
             ]
             .try_into()
             .unwrap(),
+            bip94: false,
+            network: BitcoinNetwork::Bitcoin,
         };
 
         let adapter = Adapter::new(header);
@@ -967,4 +1141,77 @@ mod test {
         q.configure(test_config).unwrap();
         q.add_into_iter(header_list).unwrap();
     }
+
+    #[test]
+    fn trusted_genesis_headers() {
+        let trusted_hash = |config: Config| {
+            let header: Adapter<BlockHeader> =
+                Decode::decode(config.encoded_trusted_header.as_slice()).unwrap();
+            header.block_hash().to_string()
+        };
+
+        assert_eq!(
+            trusted_hash(Config::testnet4()),
+            "00000000da84f2bafbbc53dee25a72ae507ff4914b867c565be350b0da8bf043"
+        );
+        assert_eq!(
+            trusted_hash(Config::signet()),
+            "00000008819873e925422c1ff0f99f7cc9bbb232af63a077a480a3633bee1ef6"
+        );
+    }
+
+    #[test]
+    fn bip94_timewarp() {
+        let header = |height: u32, time: u32| {
+            WrappedHeader::from_header(
+                &BlockHeader {
+                    version: 0x1,
+                    prev_blockhash: BlockHash::all_zeros(),
+                    merkle_root: TxMerkleNode::all_zeros(),
+                    time,
+                    bits: MAX_TARGET,
+                    nonce: 0,
+                },
+                height,
+            )
+        };
+
+        let mut q = HeaderQueue::default();
+        let prev = header(2015, 1_000_000);
+        let early = header(2016, 1_000_000 - MAX_TIMEWARP - 1);
+        let allowed = header(2016, 1_000_000 - MAX_TIMEWARP);
+
+        q.config.bip94 = false;
+        q.validate_timewarp(&early, &prev).unwrap();
+
+        q.config.bip94 = true;
+        q.validate_timewarp(&allowed, &prev).unwrap();
+        assert!(q.validate_timewarp(&early, &prev).is_err());
+        q.validate_timewarp(&header(2017, 0), &prev).unwrap();
+    }
+
+    #[test]
+    fn migrate_config_network() {
+        let migrate = |config: Config| {
+            ConfigV1::migrate_from(ConfigV0 {
+                max_length: config.max_length,
+                max_time_increase: config.max_time_increase,
+                trusted_height: config.trusted_height,
+                retarget_interval: config.retarget_interval,
+                target_spacing: config.target_spacing,
+                target_timespan: config.target_timespan,
+                max_target: config.max_target,
+                retargeting: config.retargeting,
+                min_difficulty_blocks: config.min_difficulty_blocks,
+                encoded_trusted_header: config.encoded_trusted_header,
+            })
+            .unwrap()
+            .network
+        };
+
+        assert_eq!(migrate(Config::mainnet()), BitcoinNetwork::Bitcoin);
+        assert_eq!(migrate(Config::testnet()), BitcoinNetwork::Testnet);
+        assert_eq!(migrate(Config::signet()), BitcoinNetwork::Signet);
+        assert_eq!(migrate(Config::regtest()), BitcoinNetwork::Regtest);
+    }
 }
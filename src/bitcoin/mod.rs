@@ -12,7 +12,8 @@ use bitcoin::hashes::Hash;
 use bitcoin::util::bip32::ExtendedPubKey;
 use bitcoin::Script;
 use bitcoin::{util::merkleblock::PartialMerkleTree, Transaction};
-use checkpoint::CheckpointQueue;
+use checkpoint::{CheckpointQueue, CheckpointStatus};
+use deposits::{Deposit, Deposits, QueuedDeposit};
 use header_queue::HeaderQueue;
 use orga::coins::{Accounts, Address, Amount, Coin, Give, Symbol, Take};
 use orga::collections::Map;
@@ -31,20 +32,43 @@ use orga::query::FieldQuery;
 use orga::state::State;
 use orga::store::Store;
 use orga::{Error as OrgaError, Result as OrgaResult};
-use serde::Serialize;
+use reserves::{CheckpointProof, ReserveReport, SumNode, SumProof, SumTree};
+use serde::{Deserialize, Serialize};
 use signatory::SignatorySet;
 use txid_set::OutpointSet;
+use withdrawals::{Withdrawal, WithdrawalInfo, WithdrawalStatus, Withdrawals};
 
 pub mod adapter;
+#[cfg(feature = "full")]
+pub mod address_server;
+#[cfg(feature = "full")]
+pub mod archive;
 pub mod checkpoint;
+pub mod deposits;
+#[cfg(feature = "full")]
+pub mod electrum;
+#[cfg(feature = "full")]
+pub mod esplora;
 pub mod header_queue;
 #[cfg(feature = "full")]
+pub mod p2p;
+#[cfg(feature = "full")]
 pub mod relayer;
+#[cfg(feature = "full")]
+pub mod relayer_db;
+#[cfg(feature = "full")]
+pub mod remote_signer;
+pub mod reserves;
 pub mod signatory;
 #[cfg(feature = "full")]
 pub mod signer;
+#[cfg(feature = "full")]
+pub mod source;
 pub mod threshold_sig;
 pub mod txid_set;
+#[cfg(feature = "full")]
+pub mod watched_addrs;
+pub mod withdrawals;
 
 #[derive(State, Debug, Clone, Encode, Decode, Default, Migrate, Serialize)]
 pub struct Nbtc(());
@@ -53,12 +77,130 @@ impl Symbol for Nbtc {
     const NAME: &'static str = "usat";
 }
 
+/// A Bitcoin network the bridge can follow. Testnet4 is distinguished from
+/// Testnet3 here, though both use the `Testnet` address and key encodings.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BitcoinNetwork {
+    Bitcoin,
+    Testnet,
+    Testnet4,
+    Signet,
+    Regtest,
+}
+
+impl BitcoinNetwork {
+    /// The network used to encode addresses.
+    pub fn network(self) -> bitcoin::Network {
+        match self {
+            BitcoinNetwork::Bitcoin => bitcoin::Network::Bitcoin,
+            BitcoinNetwork::Testnet | BitcoinNetwork::Testnet4 => bitcoin::Network::Testnet,
+            BitcoinNetwork::Signet => bitcoin::Network::Signet,
+            BitcoinNetwork::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+
+    /// The network extended keys are encoded for. Every network other than
+    /// mainnet uses testnet keys.
+    pub fn key_network(self) -> bitcoin::Network {
+        match self {
+            BitcoinNetwork::Bitcoin => bitcoin::Network::Bitcoin,
+            _ => bitcoin::Network::Testnet,
+        }
+    }
+}
+
+impl std::str::FromStr for BitcoinNetwork {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "bitcoin" | "mainnet" => BitcoinNetwork::Bitcoin,
+            "testnet" | "testnet3" => BitcoinNetwork::Testnet,
+            "testnet4" => BitcoinNetwork::Testnet4,
+            "signet" => BitcoinNetwork::Signet,
+            "regtest" => BitcoinNetwork::Regtest,
+            _ => {
+                return Err(OrgaError::App(format!("Unknown Bitcoin network: {}", s)).into());
+            }
+        })
+    }
+}
+
+impl Migrate for BitcoinNetwork {}
+
+impl State for BitcoinNetwork {
+    #[inline]
+    fn attach(&mut self, _: Store) -> OrgaResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn flush<W: std::io::Write>(self, out: &mut W) -> OrgaResult<()> {
+        Ok(self.encode_into(out)?)
+    }
+
+    fn load(_store: Store, bytes: &mut &[u8]) -> OrgaResult<Self> {
+        Ok(Self::decode(bytes)?)
+    }
+}
+
+impl orga::query::Query for BitcoinNetwork {
+    type Query = ();
+
+    fn query(&self, _: ()) -> OrgaResult<()> {
+        Ok(())
+    }
+}
+
+impl orga::call::Call for BitcoinNetwork {
+    type Call = ();
+
+    fn call(&mut self, _: ()) -> OrgaResult<()> {
+        Ok(())
+    }
+}
+
+impl Describe for BitcoinNetwork {
+    fn describe() -> orga::describe::Descriptor {
+        orga::describe::Builder::new::<Self>().build()
+    }
+}
+
+/// The network genesis state is created for when the genesis file doesn't
+/// choose one, as selected by the build's features.
 #[cfg(all(not(feature = "testnet"), not(feature = "devnet")))]
-pub const NETWORK: ::bitcoin::Network = ::bitcoin::Network::Bitcoin;
-#[cfg(all(feature = "testnet", not(feature = "devnet")))]
-pub const NETWORK: ::bitcoin::Network = ::bitcoin::Network::Testnet;
+pub const DEFAULT_NETWORK: BitcoinNetwork = BitcoinNetwork::Bitcoin;
+#[cfg(all(
+    feature = "testnet",
+    not(feature = "devnet"),
+    not(feature = "signet"),
+    not(feature = "testnet4")
+))]
+pub const DEFAULT_NETWORK: BitcoinNetwork = BitcoinNetwork::Testnet;
+#[cfg(all(feature = "testnet4", not(feature = "devnet"), not(feature = "signet")))]
+pub const DEFAULT_NETWORK: BitcoinNetwork = BitcoinNetwork::Testnet4;
+#[cfg(all(feature = "signet", not(feature = "devnet")))]
+pub const DEFAULT_NETWORK: BitcoinNetwork = BitcoinNetwork::Signet;
 #[cfg(all(feature = "devnet", feature = "testnet"))]
-pub const NETWORK: ::bitcoin::Network = ::bitcoin::Network::Regtest;
+pub const DEFAULT_NETWORK: BitcoinNetwork = BitcoinNetwork::Regtest;
+
+/// Reads the network chosen by the genesis file's app state, given as
+/// `{"bitcoin": {"network": "regtest"}}`. Returns `None` if the app state
+/// doesn't choose one.
+pub fn genesis_network(app_state: &[u8]) -> Result<Option<BitcoinNetwork>> {
+    if app_state.is_empty() {
+        return Ok(None);
+    }
+
+    let app_state: serde_json::Value = serde_json::from_slice(app_state)
+        .map_err(|e| OrgaError::App(format!("Invalid genesis app state: {}", e)))?;
+
+    match &app_state["bitcoin"]["network"] {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(network) => Ok(Some(network.parse()?)),
+        _ => Err(OrgaError::App("Genesis Bitcoin network must be a string".to_string()).into()),
+    }
+}
 
 #[orga(skip(Default), version = 2)]
 pub struct Config {
@@ -124,6 +266,13 @@ impl MigrateFrom<ConfigV1> for ConfigV2 {
 }
 
 impl Config {
+    pub fn for_network(network: BitcoinNetwork) -> Self {
+        match network {
+            BitcoinNetwork::Regtest => Config::regtest(),
+            _ => Config::bitcoin(),
+        }
+    }
+
     fn bitcoin() -> Self {
         Self {
             min_withdrawal_checkpoints: 4,
@@ -151,11 +300,7 @@ impl Config {
 
 impl Default for Config {
     fn default() -> Self {
-        match NETWORK {
-            bitcoin::Network::Regtest => Config::regtest(),
-            bitcoin::Network::Testnet | bitcoin::Network::Bitcoin => Config::bitcoin(),
-            _ => unimplemented!(),
-        }
+        Config::for_network(DEFAULT_NETWORK)
     }
 }
 
@@ -163,7 +308,73 @@ pub fn calc_deposit_fee(amount: u64) -> u64 {
     amount / 100
 }
 
-#[orga(version = 1)]
+/// The fraction of the reserve by which the nBTC supply may exceed it, after
+/// counting the fees paid out of the reserve, before the bridge is paused.
+/// This allows for depositors carried over to a later checkpoint having been
+/// charged at an earlier fee rate.
+pub const SUPPLY_TOLERANCE: (u64, u64) = (1, 100);
+
+/// Seconds a deposit relayed while the reserve is over its capacity limit can
+/// wait in the overflow queue before it is paid back to the depositor. This
+/// must be shorter than the checkpoint queue's `max_age`, so the checkpoint
+/// whose signatory set the deposit was sent to is still retained.
+pub const MAX_QUEUED_DEPOSIT_AGE: u64 = 60 * 60 * 24 * 7;
+
+/// The maximum number of deposit records, and of withdrawal records, removed
+/// each time a checkpoint is pushed, bounding the work done in a block.
+pub const MAX_PRUNED_RECORDS: usize = 1_000;
+
+/// Builds the input spending a deposit output, checking that the output pays
+/// to the signatory set's script committing to `dest_bytes`.
+fn deposit_input(
+    prevout: bitcoin::OutPoint,
+    output: &bitcoin::TxOut,
+    sigset: &SignatorySet,
+    threshold: (u64, u64),
+    dest_bytes: &[u8],
+) -> Result<Input> {
+    // deposits may pay to either the P2WSH or P2TR form of the signatory
+    // script, so addresses handed out before a change of output mode remain
+    // valid until the signatory set expires
+    let taproot = output.script_pubkey.is_v1_p2tr();
+    let expected_script = if taproot {
+        sigset.taproot_output_script(dest_bytes, threshold)?
+    } else {
+        sigset.output_script(dest_bytes, threshold)?
+    };
+    if output.script_pubkey != expected_script {
+        return Err(OrgaError::App(
+            "Output script does not match signature set".to_string(),
+        ))?;
+    }
+
+    Input::new(
+        prevout,
+        sigset,
+        dest_bytes,
+        output.value,
+        threshold,
+        taproot,
+    )
+}
+
+/// The value left to pay back to a depositor after paying the miner fees for
+/// spending their deposit output and creating a refund output. Returns `None`
+/// if the refund would be dust.
+pub fn refund_value(input: &Input, script: &Script, fee_rate: u64) -> Option<u64> {
+    // outputs are an 8-byte value, a 1-byte script length and the script
+    let output_vsize = script.len() as u64 + 9;
+    let fee = (input.est_vsize() + output_vsize) * fee_rate;
+    let value = input.amount.checked_sub(fee)?;
+
+    if value < script.dust_value().to_sat() {
+        return None;
+    }
+
+    Some(value)
+}
+
+#[orga(version = 2)]
 pub struct Bitcoin {
     #[call]
     pub headers: HeaderQueue,
@@ -177,6 +388,35 @@ pub struct Bitcoin {
 
     pub recovery_scripts: Map<Address, Adapter<Script>>,
     pub config: Config,
+
+    #[orga(version(V2))]
+    pub withdrawals: Withdrawals,
+    #[orga(version(V2))]
+    pub deposits: Deposits,
+    /// Proofs of inclusion of the checkpoint transactions relayed as
+    /// confirmed, by checkpoint index.
+    #[orga(version(V2))]
+    pub checkpoint_proofs: Map<u32, CheckpointProof>,
+    /// The nBTC transferred out over IBC and not yet returned, in
+    /// micro-satoshis.
+    #[orga(version(V2))]
+    pub ibc_escrow: u64,
+    /// Whether deposits, withdrawals and IBC transfers of nBTC are halted,
+    /// set when the nBTC supply is found to exceed the reserve.
+    #[orga(version(V2))]
+    pub paused: bool,
+    /// The validators voting to resume the bridge while it is paused.
+    #[orga(version(V2))]
+    pub resume_votes: Map<ConsensusKey, ()>,
+    /// The total of the checkpoints' fees paid out of the reserve rather than
+    /// charged to depositors and withdrawers, in satoshis. The nBTC supply
+    /// exceeds the reserve by this much.
+    #[orga(version(V2))]
+    pub reserve_fees: u64,
+    /// The nBTC supply in micro-satoshis, updated as nBTC is minted for
+    /// deposits and burned for withdrawals and fees.
+    #[orga(version(V2))]
+    pub nbtc_supply: u64,
 }
 
 impl MigrateFrom<BitcoinV0> for BitcoinV1 {
@@ -185,6 +425,32 @@ impl MigrateFrom<BitcoinV0> for BitcoinV1 {
     }
 }
 
+impl MigrateFrom<BitcoinV1> for BitcoinV2 {
+    fn migrate_from(value: BitcoinV1) -> OrgaResult<Self> {
+        Ok(Self {
+            headers: value.headers,
+            processed_outpoints: value.processed_outpoints,
+            checkpoints: value.checkpoints,
+            accounts: value.accounts,
+            signatory_keys: value.signatory_keys,
+            reward_pool: value.reward_pool,
+            recovery_scripts: value.recovery_scripts,
+            config: value.config,
+            withdrawals: Withdrawals::default(),
+            deposits: Deposits::default(),
+            checkpoint_proofs: Map::new(),
+            // seeded from the IBC module's escrow accounts when the app
+            // migrates, since the IBC module isn't reachable from here
+            ibc_escrow: 0,
+            paused: false,
+            resume_votes: Map::new(),
+            // seeded when the app migrates, along with the IBC escrow
+            reserve_fees: 0,
+            nbtc_supply: 0,
+        })
+    }
+}
+
 pub type ConsensusKey = [u8; 32];
 
 // #[derive(Call, Query, Clone, Debug, Client, PartialEq, Serialize)]
@@ -287,6 +553,19 @@ impl Bitcoin {
         self.config = config;
     }
 
+    /// Configures the header queue, checkpoint queue and bridge for
+    /// `network`, resetting the header queue to the network's trusted header.
+    /// Called at genesis when the genesis file chooses a network.
+    pub fn configure_network(&mut self, network: BitcoinNetwork) -> Result<()> {
+        self.headers
+            .configure(header_queue::Config::for_network(network))?;
+        self.checkpoints
+            .configure(checkpoint::Config::for_network(network));
+        self.configure(Config::for_network(network));
+
+        Ok(())
+    }
+
     pub fn config() -> Config {
         Config::default()
     }
@@ -312,10 +591,7 @@ impl Bitcoin {
                     "Signer does not have a consensus key".to_string(),
                 ))
             })?;
-            let regtest_mode = self.network() == bitcoin::Network::Regtest
-                && _signatory_key.network == bitcoin::Network::Testnet;
-
-            if !regtest_mode && _signatory_key.network != self.network() {
+            if _signatory_key.network != self.headers.bitcoin_network().key_network() {
                 return Err(Error::Orga(orga::Error::App(
                     "Signatory key network does not match network".to_string(),
                 )));
@@ -351,7 +627,8 @@ impl Bitcoin {
 
     #[cfg(feature = "full")]
     pub fn should_push_checkpoint(&mut self) -> Result<bool> {
-        self.checkpoints.should_push(self.signatory_keys.map())
+        self.checkpoints
+            .should_push(self.signatory_keys.map(), self.headers.height()?)
     }
 
     pub fn relay_deposit(
@@ -364,12 +641,118 @@ impl Bitcoin {
         dest: super::app::Dest,
     ) -> Result<()> {
         exempt_from_fee()?;
+        self.check_not_paused()?;
 
         let now = self
             .context::<Time>()
             .ok_or_else(|| Error::Orga(OrgaError::App("No time context available".to_string())))?
             .seconds as u64;
 
+        let output = self.verify_deposit_output(&btc_tx, btc_height, &btc_proof, btc_vout)?;
+
+        if output.value < self.config.min_deposit_amount {
+            return Err(OrgaError::App(
+                "Deposit amount is below minimum".to_string(),
+            ))?;
+        }
+
+        let checkpoint = self.checkpoints.get(sigset_index)?;
+        let sigset = checkpoint.sigset.clone();
+
+        if now > sigset.deposit_timeout() {
+            return Err(OrgaError::App("Deposit timeout has expired".to_string()))?;
+        }
+
+        let dest_bytes = dest.commitment_bytes()?;
+        let threshold = checkpoint.sigset_threshold;
+        let prevout = bitcoin::OutPoint {
+            txid: btc_tx.txid(),
+            vout: btc_vout,
+        };
+        let input = deposit_input(prevout, &output, &sigset, threshold, &dest_bytes)?;
+        let taproot = input.is_taproot();
+        let input_size = input.est_vsize();
+
+        let miner_fee = input_size * checkpoint.fee_rate;
+        let value = output.value.checked_sub(miner_fee).ok_or_else(|| {
+            OrgaError::App("Deposit amount is too small to pay its spending fee".to_string())
+        })? * self.config.units_per_sat;
+
+        let outpoint = (btc_tx.txid().into_inner(), btc_vout);
+        if self.processed_outpoints.contains(outpoint)? {
+            return Err(OrgaError::App(
+                "Output has already been relayed".to_string(),
+            ))?;
+        }
+        self.processed_outpoints
+            .insert(outpoint, sigset.deposit_timeout())?;
+
+        let deposit_fee = calc_deposit_fee(value);
+        let mut deposit = Deposit::new(
+            dest.clone(),
+            output.value,
+            miner_fee,
+            deposit_fee,
+            value - deposit_fee,
+            btc_height,
+            self.checkpoints.index,
+        );
+
+        if !self.checkpoints.building()?.deposits_enabled {
+            // the reserve is over its capacity limit, so rather than adding
+            // to it, the deposit is either paid back to the depositor's
+            // recovery script or queued until there is capacity
+            if let Some(refund_script) = self.refund_script(&dest)? {
+                let fee_rate = self.checkpoints.building()?.fee_rate;
+                if let Some(refund_value) = refund_value(&input, &refund_script, fee_rate) {
+                    deposit.miner_fee = output.value - refund_value;
+                    deposit.deposit_fee = 0;
+                    deposit.value = 0;
+                    self.push_refund(input, refund_script, refund_value)?;
+                    self.deposits.insert_refund(outpoint, deposit)?;
+                    return Ok(());
+                }
+            }
+
+            let queued = QueuedDeposit {
+                outpoint,
+                sigset_index,
+                taproot,
+                queued_at: now,
+            };
+            self.deposits.insert_overflow(outpoint, deposit, queued)?;
+            return Ok(());
+        }
+
+        let mut building_mut = self.checkpoints.building_mut()?;
+        let mut building_checkpoint_batch = building_mut
+            .batches
+            .get_mut(BatchType::Checkpoint as u64)?
+            .unwrap();
+        let mut checkpoint_tx = building_checkpoint_batch.get_mut(0)?.unwrap();
+        checkpoint_tx.input.push_back(input)?;
+
+        self.reward_pool.give(Nbtc::mint(deposit.deposit_fee))?;
+
+        self.checkpoints
+            .building_mut()?
+            .insert_pending(dest, Nbtc::mint(deposit.value))?;
+        self.nbtc_supply += deposit.deposit_fee + deposit.value;
+
+        self.deposits.insert(outpoint, deposit)?;
+
+        Ok(())
+    }
+
+    /// Checks that the transaction is included in a sufficiently confirmed
+    /// block of the header chain, and returns the output at `btc_vout`.
+    fn verify_deposit_output(
+        &self,
+        btc_tx: &Transaction,
+        btc_height: u32,
+        btc_proof: &PartialMerkleTree,
+        btc_vout: u32,
+    ) -> Result<bitcoin::TxOut> {
         let btc_header = self
             .headers
             .get_by_height(btc_height)?
@@ -400,84 +783,285 @@ impl Bitcoin {
             ))?;
         }
 
-        if btc_vout as usize >= btc_tx.output.len() {
-            return Err(OrgaError::App("Output index is out of bounds".to_string()))?;
+        btc_tx
+            .output
+            .get(btc_vout as usize)
+            .cloned()
+            .ok_or_else(|| OrgaError::App("Output index is out of bounds".to_string()).into())
+    }
+
+    /// Claims a refund of a deposit which can never be credited because it is
+    /// below the minimum deposit amount, was made after the signatory set's
+    /// deposit timeout, or can't pay its spending fee. The call must be signed
+    /// by the account the deposit commits to. The deposit output is spent in
+    /// the building checkpoint, paying its value minus miner fees to
+    /// `refund_script`.
+    #[call]
+    pub fn claim_refund(
+        &mut self,
+        btc_tx: Adapter<Transaction>,
+        btc_height: u32,
+        btc_proof: Adapter<PartialMerkleTree>,
+        btc_vout: u32,
+        sigset_index: u32,
+        dest: Dest,
+        refund_script: Adapter<Script>,
+    ) -> Result<()> {
+        exempt_from_fee()?;
+        self.check_not_paused()?;
+
+        let now = self
+            .context::<Time>()
+            .ok_or_else(|| Error::Orga(OrgaError::App("No time context available".to_string())))?
+            .seconds as u64;
+
+        let signer = self
+            .context::<Signer>()
+            .ok_or_else(|| Error::Orga(OrgaError::App("No Signer context available".into())))?
+            .signer
+            .ok_or_else(|| Error::Orga(OrgaError::App("Call must be signed".into())))?;
+
+        let owner = match &dest {
+            Dest::Address(address) => *address,
+            Dest::Ibc(ibc_dest) => ibc_dest.sender_address()?,
+        };
+        if owner != signer {
+            return Err(OrgaError::App("Deposit is not owned by signer".to_string()).into());
         }
-        let output = &btc_tx.output[btc_vout as usize];
 
-        if output.value < self.config.min_deposit_amount {
+        let output = self.verify_deposit_output(&btc_tx, btc_height, &btc_proof, btc_vout)?;
+
+        let outpoint = (btc_tx.txid().into_inner(), btc_vout);
+        if self.processed_outpoints.contains(outpoint)? || self.deposits.get(outpoint)?.is_some() {
             return Err(OrgaError::App(
-                "Deposit amount is below minimum".to_string(),
+                "Output has already been relayed".to_string(),
             ))?;
         }
 
         let checkpoint = self.checkpoints.get(sigset_index)?;
         let sigset = checkpoint.sigset.clone();
-
-        if now > sigset.deposit_timeout() {
-            return Err(OrgaError::App("Deposit timeout has expired".to_string()))?;
-        }
+        let threshold = checkpoint.sigset_threshold;
 
         let dest_bytes = dest.commitment_bytes()?;
-        let expected_script =
-            sigset.output_script(&dest_bytes, self.checkpoints.config.sigset_threshold)?;
-        if output.script_pubkey != expected_script {
-            return Err(OrgaError::App(
-                "Output script does not match signature set".to_string(),
-            ))?;
-        }
-
         let prevout = bitcoin::OutPoint {
             txid: btc_tx.txid(),
             vout: btc_vout,
         };
-        let input = Input::new(
-            prevout,
-            &sigset,
-            &dest_bytes,
-            output.value,
-            self.checkpoints.config.sigset_threshold,
-        )?;
-        let input_size = input.est_vsize();
+        let input = deposit_input(prevout, &output, &sigset, threshold, &dest_bytes)?;
 
-        let fee = input_size * checkpoint.fee_rate;
-        let value = output.value.checked_sub(fee).ok_or_else(|| {
-            OrgaError::App("Deposit amount is too small to pay its spending fee".to_string())
-        })? * self.config.units_per_sat;
-
-        let outpoint = (btc_tx.txid().into_inner(), btc_vout);
-        if self.processed_outpoints.contains(outpoint)? {
+        // deposits which can be relayed must be relayed rather than refunded
+        let relayable = output.value >= self.config.min_deposit_amount
+            && now <= sigset.deposit_timeout()
+            && output.value > input.est_vsize() * checkpoint.fee_rate;
+        if relayable {
             return Err(OrgaError::App(
-                "Output has already been relayed".to_string(),
+                "Deposit can be relayed and is not eligible for a refund".to_string(),
             ))?;
         }
+
+        let fee_rate = self.checkpoints.building()?.fee_rate;
+        let value = refund_value(&input, &refund_script, fee_rate).ok_or_else(|| {
+            OrgaError::App("Deposit amount is too small to pay for a refund".to_string())
+        })?;
+
         self.processed_outpoints
             .insert(outpoint, sigset.deposit_timeout())?;
 
-        let mut building_mut = self.checkpoints.building_mut()?;
-        if !building_mut.deposits_enabled {
-            return Err(OrgaError::App(
-                "Deposits are disabled for the given checkpoint".to_string(),
-            ))?;
+        let mut deposit = Deposit::new(
+            dest,
+            output.value,
+            output.value - value,
+            0,
+            0,
+            btc_height,
+            self.checkpoints.index,
+        );
+        deposit.refunded = true;
+        self.push_refund(input, refund_script.into_inner(), value)?;
+        self.deposits.insert_refund(outpoint, deposit)?;
+
+        Ok(())
+    }
+
+    /// The script a deposit to `dest` is paid back to if it can't be credited,
+    /// if the destination account has set one.
+    fn refund_script(&self, dest: &Dest) -> Result<Option<Script>> {
+        match dest {
+            Dest::Address(address) => Ok(self
+                .recovery_scripts
+                .get(*address)?
+                .map(|script| script.clone().into_inner())),
+            _ => Ok(None),
         }
-        let mut building_checkpoint_batch = building_mut
+    }
+
+    /// Adds an input spending a deposit output to the building checkpoint,
+    /// along with an output paying `value` back to the depositor.
+    fn push_refund(&mut self, input: Input, script: Script, value: u64) -> Result<()> {
+        let mut building = self.checkpoints.building_mut()?;
+        let mut checkpoint_batch = building
             .batches
             .get_mut(BatchType::Checkpoint as u64)?
             .unwrap();
-        let mut checkpoint_tx = building_checkpoint_batch.get_mut(0)?.unwrap();
+        let mut checkpoint_tx = checkpoint_batch.get_mut(0)?.unwrap();
         checkpoint_tx.input.push_back(input)?;
+        checkpoint_tx
+            .output
+            .push_back(Adapter::new(bitcoin::TxOut {
+                value,
+                script_pubkey: script,
+            }))?;
 
-        let mut minted_nbtc = Nbtc::mint(value);
-        let deposit_fee = minted_nbtc.take(calc_deposit_fee(value))?;
-        self.reward_pool.give(deposit_fee)?;
+        Ok(())
+    }
+
+    /// Removes the deposit and withdrawal records settled by checkpoints which
+    /// are no longer retained by the checkpoint queue.
+    #[cfg(feature = "full")]
+    fn prune_records(&mut self) -> Result<()> {
+        let first_index = self.checkpoints.index + 1 - self.checkpoints.len()?;
+        self.deposits.prune(first_index, MAX_PRUNED_RECORDS)?;
+        self.withdrawals.prune(first_index, MAX_PRUNED_RECORDS)?;
+
+        Ok(())
+    }
+
+    /// Adds queued deposits to the building checkpoint, oldest first, until
+    /// the reserve reaches its capacity limit. Deposits which have been queued
+    /// for longer than [`MAX_QUEUED_DEPOSIT_AGE`] are paid back first.
+    #[cfg(feature = "full")]
+    fn drain_overflow(&mut self) -> Result<()> {
+        if self.paused || self.checkpoints.index == 0 {
+            return Ok(());
+        }
+
+        self.expire_overflow()?;
+
+        if !self.checkpoints.building()?.deposits_enabled {
+            return Ok(());
+        }
+
+        // the reserve of the checkpoint which just stopped building includes
+        // all deposits relayed so far
+        let mut value_locked = self
+            .checkpoints
+            .get(self.checkpoints.index - 1)?
+            .reserve_output()?
+            .map_or(0, |output| output.value);
+
+        while value_locked < self.config.capacity_limit {
+            let queued = match self.deposits.pop_overflow()? {
+                Some(queued) => queued,
+                None => break,
+            };
+            let deposit = self
+                .deposits
+                .get(queued.outpoint)?
+                .ok_or_else(|| OrgaError::App("Deposit not found".to_string()))?;
+            let input = self.queued_input(&queued, &deposit)?;
+            self.admit_queued(queued.outpoint, input, &deposit)?;
+
+            value_locked += deposit.amount;
+        }
+
+        Ok(())
+    }
+
+    /// Pays back the deposits which have been in the overflow queue for longer
+    /// than [`MAX_QUEUED_DEPOSIT_AGE`] to the depositor's recovery script.
+    /// Deposits with nowhere to be paid back to are added to the reserve
+    /// despite its capacity limit, rather than being left in the queue.
+    #[cfg(feature = "full")]
+    fn expire_overflow(&mut self) -> Result<()> {
+        // the building checkpoint was just created
+        let now = self.checkpoints.building()?.create_time();
+        let cutoff = now.saturating_sub(MAX_QUEUED_DEPOSIT_AGE);
+
+        while let Some(queued) = self.deposits.pop_expired_overflow(cutoff)? {
+            let deposit = self
+                .deposits
+                .get(queued.outpoint)?
+                .ok_or_else(|| OrgaError::App("Deposit not found".to_string()))?;
+            let input = self.queued_input(&queued, &deposit)?;
+
+            let fee_rate = self.checkpoints.building()?.fee_rate;
+            let refund = self
+                .refund_script(&deposit.dest)?
+                .and_then(|script| Some((refund_value(&input, &script, fee_rate)?, script)));
+            match refund {
+                Some((value, script)) => {
+                    self.push_refund(input, script, value)?;
+                    self.deposits.schedule_refund(
+                        queued.outpoint,
+                        self.checkpoints.index,
+                        deposit.amount - value,
+                    )?;
+                }
+                None => self.admit_queued(queued.outpoint, input, &deposit)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Builds the input spending a queued deposit's output, using the
+    /// signatory set of the checkpoint the deposit was sent to.
+    #[cfg(feature = "full")]
+    fn queued_input(&self, queued: &QueuedDeposit, deposit: &Deposit) -> Result<Input> {
+        let checkpoint = self.checkpoints.get(queued.sigset_index)?;
+        let prevout = bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_inner(queued.outpoint.0),
+            vout: queued.outpoint.1,
+        };
 
+        Input::new(
+            prevout,
+            &checkpoint.sigset,
+            &deposit.dest.commitment_bytes()?,
+            deposit.amount,
+            checkpoint.sigset_threshold,
+            queued.taproot,
+        )
+    }
+
+    /// Adds a queued deposit's input to the building checkpoint and mints its
+    /// nBTC, to be credited when the checkpoint completes.
+    #[cfg(feature = "full")]
+    fn admit_queued(
+        &mut self,
+        outpoint: txid_set::Outpoint,
+        input: Input,
+        deposit: &Deposit,
+    ) -> Result<()> {
+        let mut building = self.checkpoints.building_mut()?;
+        let mut checkpoint_batch = building
+            .batches
+            .get_mut(BatchType::Checkpoint as u64)?
+            .unwrap();
+        let mut checkpoint_tx = checkpoint_batch.get_mut(0)?.unwrap();
+        checkpoint_tx.input.push_back(input)?;
+
+        self.reward_pool.give(Nbtc::mint(deposit.deposit_fee))?;
         self.checkpoints
             .building_mut()?
-            .insert_pending(dest, minted_nbtc)?;
+            .insert_pending(deposit.dest.clone(), Nbtc::mint(deposit.value))?;
+        self.nbtc_supply += deposit.deposit_fee + deposit.value;
+        self.deposits
+            .schedule_overflow(outpoint, self.checkpoints.index)?;
 
         Ok(())
     }
 
+    #[query]
+    pub fn deposit(&self, outpoint: txid_set::Outpoint) -> Result<Option<Deposit>> {
+        Ok(self.deposits.get(outpoint)?)
+    }
+
+    #[query]
+    pub fn deposits_by_dest(&self, dest: Dest) -> Result<Vec<(txid_set::Outpoint, Deposit)>> {
+        Ok(self.deposits.by_dest(dest)?)
+    }
+
     #[call]
     pub fn relay_checkpoint(
         &mut self,
@@ -528,6 +1112,14 @@ impl Bitcoin {
         }
 
         self.checkpoints.confirmed_index = Some(cp_index);
+        self.checkpoint_proofs.insert(
+            cp_index,
+            CheckpointProof {
+                btc_height,
+                block_hash: btc_header.block_hash().into_inner(),
+                proof: btc_proof,
+            },
+        )?;
         log::info!(
             "Checkpoint {} confirmed at Bitcoin height {}",
             cp_index,
@@ -537,6 +1129,71 @@ impl Bitcoin {
         Ok(())
     }
 
+    /// Rolls `confirmed_index` back to the last checkpoint whose confirming
+    /// block is still part of the header chain, in case the block of the
+    /// latest confirmation was replaced in a reorg. Checkpoints between the
+    /// two are counted as unconfirmed again, so the relayer re-relays their
+    /// confirmations and the fee rate is adjusted as if they were never
+    /// confirmed.
+    pub fn revert_reorged_confirmations(&mut self) -> Result<()> {
+        let confirmed_index = match self.checkpoints.confirmed_index {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+
+        // confirmations relayed before proofs were stored can't be checked
+        if self.confirmation_in_chain(confirmed_index)?.unwrap_or(true) {
+            return Ok(());
+        }
+
+        let first_index = self.checkpoints.index + 1 - self.checkpoints.len()?;
+        let mut index = confirmed_index;
+        let new_confirmed_index = loop {
+            match self.confirmation_in_chain(index)? {
+                Some(true) => break Some(index),
+                Some(false) => {
+                    self.checkpoint_proofs.remove(index)?;
+                }
+                // confirmations without a stored proof were only implied by
+                // the reverted ones
+                None => {}
+            }
+
+            if index <= first_index {
+                break None;
+            }
+            index -= 1;
+        };
+
+        log::warn!(
+            "Checkpoint {} confirmation was reorged out, reverting confirmed index to {:?}",
+            confirmed_index,
+            new_confirmed_index,
+        );
+        self.checkpoints.confirmed_index = new_confirmed_index;
+
+        Ok(())
+    }
+
+    /// Returns whether the block of the checkpoint's stored confirmation
+    /// proof is in the header chain, or `None` if there is no stored proof.
+    /// Blocks below the oldest header in the queue are assumed to be in the
+    /// chain.
+    fn confirmation_in_chain(&self, index: u32) -> Result<Option<bool>> {
+        let proof = match self.checkpoint_proofs.get(index)? {
+            Some(proof) => proof,
+            None => return Ok(None),
+        };
+
+        let in_chain = match self.headers.get_by_height(proof.btc_height) {
+            Ok(Some(header)) => header.block_hash().into_inner() == proof.block_hash,
+            Ok(None) => false,
+            Err(_) => true,
+        };
+
+        Ok(Some(in_chain))
+    }
+
     pub fn withdraw(&mut self, script_pubkey: Adapter<Script>, amount: Amount) -> Result<()> {
         exempt_from_fee()?;
 
@@ -548,10 +1205,23 @@ impl Bitcoin {
 
         self.accounts.withdraw(signer, amount)?.burn();
 
-        self.add_withdrawal(script_pubkey, amount)
+        self.add_withdrawal(signer, script_pubkey, amount)?;
+
+        Ok(())
     }
 
-    pub fn add_withdrawal(&mut self, script_pubkey: Adapter<Script>, amount: Amount) -> Result<()> {
+    /// Adds an output for the withdrawal to the building checkpoint and records
+    /// it against `owner`, returning the withdrawal's id. The nBTC for the
+    /// withdrawal must already have been burned by the caller, and is removed
+    /// from the running supply total here.
+    pub fn add_withdrawal(
+        &mut self,
+        owner: Address,
+        script_pubkey: Adapter<Script>,
+        amount: Amount,
+    ) -> Result<u64> {
+        self.check_not_paused()?;
+
         if script_pubkey.len() as u64 > self.config.max_withdrawal_script_length {
             return Err(OrgaError::App("Script exceeds maximum length".to_string()).into());
         }
@@ -590,10 +1260,13 @@ impl Bitcoin {
             .into());
         }
 
-        let output = bitcoin::TxOut {
-            script_pubkey: script_pubkey.into_inner(),
+        let withdrawal = Withdrawal::new(
+            owner,
+            script_pubkey,
+            amount.into(),
             value,
-        };
+            self.checkpoints.index,
+        );
 
         let mut checkpoint = self.checkpoints.building_mut()?;
         let mut building_checkpoint_batch = checkpoint
@@ -601,7 +1274,139 @@ impl Bitcoin {
             .get_mut(BatchType::Checkpoint as u64)?
             .unwrap();
         let mut checkpoint_tx = building_checkpoint_batch.get_mut(0)?.unwrap();
-        checkpoint_tx.output.push_back(Adapter::new(output))?;
+        checkpoint_tx
+            .output
+            .push_back(Adapter::new(withdrawal.to_txout()))?;
+
+        self.nbtc_supply = self.nbtc_supply.saturating_sub(withdrawal.amount);
+
+        Ok(self.withdrawals.insert(withdrawal)?)
+    }
+
+    /// Cancels a withdrawal whose checkpoint is still building, removing its
+    /// output from the checkpoint transaction and re-crediting the burned nBTC
+    /// to its owner, minus the transfer fee.
+    #[call]
+    pub fn cancel_withdrawal(&mut self, id: u64) -> Result<()> {
+        exempt_from_fee()?;
+
+        let signer = self
+            .context::<Signer>()
+            .ok_or_else(|| Error::Orga(OrgaError::App("No Signer context available".into())))?
+            .signer
+            .ok_or_else(|| Error::Orga(OrgaError::App("Call must be signed".into())))?;
+
+        let withdrawal = self
+            .withdrawals
+            .get(id)?
+            .ok_or_else(|| OrgaError::App("Withdrawal not found".to_string()))?;
+
+        if withdrawal.owner != signer {
+            return Err(OrgaError::App("Withdrawal is not owned by signer".to_string()).into());
+        }
+
+        if withdrawal.cancelled {
+            return Err(OrgaError::App("Withdrawal has already been cancelled".to_string()).into());
+        }
+
+        if withdrawal.checkpoint_index != self.checkpoints.index {
+            return Err(OrgaError::App(
+                "Withdrawal can no longer be cancelled, its checkpoint is being signed".to_string(),
+            )
+            .into());
+        }
+
+        let mut checkpoint = self.checkpoints.building_mut()?;
+        let mut building_checkpoint_batch = checkpoint
+            .batches
+            .get_mut(BatchType::Checkpoint as u64)?
+            .unwrap();
+        let mut checkpoint_tx = building_checkpoint_batch.get_mut(0)?.unwrap();
+        if !checkpoint_tx.remove_output(&withdrawal.to_txout())? {
+            return Err(OrgaError::App("Withdrawal output not found".to_string()).into());
+        }
+
+        self.withdrawals.set_cancelled(id)?;
+
+        let mut coins = Coin::<Nbtc>::mint(withdrawal.amount);
+        self.nbtc_supply += withdrawal.amount;
+        let transfer_fee = coins.take(self.config.transfer_fee)?;
+        self.reward_pool.give(transfer_fee)?;
+        self.accounts.deposit(signer, coins)?;
+
+        Ok(())
+    }
+
+    #[query]
+    pub fn withdrawal(&self, id: u64) -> Result<Option<WithdrawalInfo>> {
+        self.withdrawals
+            .get(id)?
+            .map(|withdrawal| self.withdrawal_info(id, withdrawal))
+            .transpose()
+    }
+
+    #[query]
+    pub fn withdrawals_by_owner(&self, owner: Address) -> Result<Vec<WithdrawalInfo>> {
+        self.withdrawals
+            .ids_by_owner(owner)?
+            .into_iter()
+            .map(|id| {
+                let withdrawal = self
+                    .withdrawals
+                    .get(id)?
+                    .ok_or_else(|| OrgaError::App("Withdrawal not found".to_string()))?;
+                self.withdrawal_info(id, withdrawal)
+            })
+            .collect()
+    }
+
+    fn withdrawal_info(&self, id: u64, withdrawal: Withdrawal) -> Result<WithdrawalInfo> {
+        let confirmed = self
+            .checkpoints
+            .confirmed_index
+            .map_or(false, |index| index >= withdrawal.checkpoint_index);
+
+        let status = if withdrawal.cancelled {
+            WithdrawalStatus::Cancelled
+        } else if withdrawal.output_index.is_none() {
+            WithdrawalStatus::Pending
+        } else if confirmed {
+            WithdrawalStatus::Confirmed
+        } else {
+            // pruned checkpoints are always complete
+            match self.checkpoints.get(withdrawal.checkpoint_index) {
+                Ok(checkpoint) if checkpoint.status == CheckpointStatus::Signing => {
+                    WithdrawalStatus::Signing
+                }
+                _ => WithdrawalStatus::Signed,
+            }
+        };
+
+        Ok(WithdrawalInfo {
+            id,
+            withdrawal,
+            status,
+        })
+    }
+
+    /// Indexes the deposits swept and withdrawals paid out by the checkpoint
+    /// which has just stopped building.
+    fn index_checkpoint(&mut self) -> Result<()> {
+        if self.checkpoints.index == 0 {
+            return Ok(());
+        }
+
+        let cp_index = self.checkpoints.index - 1;
+        let checkpoint_tx = self.checkpoints.get(cp_index)?.checkpoint_tx()?;
+        self.withdrawals
+            .index_checkpoint(cp_index, checkpoint_tx.txid(), &checkpoint_tx.output)?;
+
+        let inputs: Vec<_> = checkpoint_tx
+            .input
+            .iter()
+            .map(|input| input.previous_output)
+            .collect();
+        self.deposits.index_checkpoint(cp_index, &inputs)?;
 
         Ok(())
     }
@@ -636,9 +1441,298 @@ impl Bitcoin {
         xpub: Xpub,
         sigs: LengthVec<u16, Signature>,
         cp_index: u32,
+        fee_rate_estimate: Option<u64>,
     ) -> Result<()> {
-        self.checkpoints
-            .sign(xpub, sigs, cp_index, self.headers.height()?)
+        self.checkpoints.sign(
+            xpub,
+            sigs,
+            cp_index,
+            self.headers.height()?,
+            fee_rate_estimate,
+        )
+    }
+
+    /// Compares the reserve output of a completed checkpoint with the nBTC
+    /// supply. The liabilities are those at the queried height, so deposits
+    /// and withdrawals in later checkpoints account for any difference.
+    #[query]
+    pub fn reserve_report(&self, index: u32) -> Result<ReserveReport> {
+        let checkpoint = self.checkpoints.get(index)?;
+        if !matches!(checkpoint.status, CheckpointStatus::Complete) {
+            return Err(OrgaError::App("Checkpoint is not complete".to_string()).into());
+        }
+        let checkpoint_tx = checkpoint.checkpoint_tx()?;
+        let reserve_value = checkpoint
+            .reserve_output()?
+            .ok_or_else(|| OrgaError::App("Checkpoint has no reserve output".to_string()))?
+            .value;
+
+        let (btc_height, inclusion_proof) = match self.checkpoint_proofs.get(index)? {
+            Some(proof) => (Some(proof.btc_height), Some(proof.proof.clone())),
+            None => (None, None),
+        };
+
+        let balances_root = self.balances_tree()?.root();
+        let pending = self.pending_nbtc()?;
+        let reward_pool = u64::from(self.reward_pool.amount);
+        let liabilities = balances_root.sum + pending + reward_pool + self.ibc_escrow;
+
+        Ok(ReserveReport {
+            checkpoint_index: index,
+            reserve_outpoint: (checkpoint_tx.txid().into_inner(), 0),
+            reserve_value,
+            btc_height,
+            inclusion_proof,
+            account_balances: balances_root.sum,
+            pending,
+            reward_pool,
+            ibc_escrow: self.ibc_escrow,
+            liabilities,
+            reserve_fees: self.reserve_fees,
+            balances_root,
+        })
+    }
+
+    /// Returns the proof that the account's balance was counted in the
+    /// `balances_root` of reserve reports made at the queried height, along
+    /// with the root and the balance.
+    #[query]
+    pub fn balance_proof(&self, address: Address) -> Result<(SumNode, u64, SumProof)> {
+        let balances = self.account_balances()?;
+        let index = balances
+            .iter()
+            .position(|(account, _)| *account == address)
+            .ok_or_else(|| OrgaError::App("Account has no balance".to_string()))?;
+        let balance = balances[index].1;
+
+        let tree = SumTree::build(balances)?;
+        let proof = tree.proof(index).unwrap();
+
+        Ok((tree.root(), balance, proof))
+    }
+
+    /// The nBTC in checkpoints' pending transfers which hasn't been credited
+    /// yet, in micro-satoshis.
+    fn pending_nbtc(&self) -> Result<u64> {
+        let mut pending = 0u64;
+        let first_pending = self.checkpoints.last_completed_index().unwrap_or(0);
+        for i in first_pending..=self.checkpoints.index {
+            for entry in self.checkpoints.get(i)?.pending.iter()? {
+                let (_, coins) = entry?;
+                pending += u64::from(coins.amount);
+            }
+        }
+
+        Ok(pending)
+    }
+
+    /// The BTC backing the nBTC supply, in micro-satoshis: the inputs of the
+    /// building checkpoint, which are the reserve of the previous checkpoint
+    /// and any deposits carried over from it, along with the fees paid out of
+    /// the reserve.
+    fn backing(&self) -> Result<u128> {
+        let mut backing = self.reserve_fees;
+        let building = self.checkpoints.building()?;
+        let checkpoint_batch = building.batches.get(BatchType::Checkpoint as u64)?.unwrap();
+        let checkpoint_tx = checkpoint_batch.get(0)?.unwrap();
+        for input in checkpoint_tx.input.iter()? {
+            backing += input?.amount;
+        }
+
+        Ok(backing as u128 * self.config.units_per_sat as u128)
+    }
+
+    /// Removes burned nBTC from the running supply total. Called for nBTC
+    /// burned outside of withdrawals, such as fees paid in nBTC.
+    pub fn burn_nbtc(&mut self, coins: Coin<Nbtc>) {
+        self.nbtc_supply = self.nbtc_supply.saturating_sub(u64::from(coins.amount));
+        coins.burn();
+    }
+
+    /// Seeds the running nBTC supply total by summing the account balances,
+    /// pending transfers, reward pool and IBC escrow. Called when the app
+    /// migrates, after the IBC escrow is seeded.
+    pub fn seed_supply(&mut self) -> Result<()> {
+        self.nbtc_supply = self
+            .account_balances()?
+            .iter()
+            .map(|(_, balance)| balance)
+            .sum::<u64>()
+            + self.pending_nbtc()?
+            + u64::from(self.reward_pool.amount)
+            + self.ibc_escrow;
+
+        Ok(())
+    }
+
+    /// Counts the fees the checkpoints still in the queue paid out of the
+    /// reserve before they were tracked. Called when the app migrates.
+    ///
+    /// The fees paid by pruned checkpoints are unknown, so they are not
+    /// counted. Any remaining amount by which the nBTC supply exceeds its
+    /// backing is logged, and pauses the bridge when the next checkpoint
+    /// advances if it is more than [`SUPPLY_TOLERANCE`].
+    pub fn seed_reserve_fees(&mut self) -> Result<()> {
+        let mut reserve_fees = 0;
+        for checkpoint in self.checkpoints.queue.iter()? {
+            let checkpoint = checkpoint?;
+            if checkpoint.status != CheckpointStatus::Building {
+                reserve_fees += checkpoint.untracked_reserve_fee()?;
+            }
+        }
+        self.reserve_fees += reserve_fees;
+
+        let supply = self.nbtc_supply as u128;
+        let backing = self.backing()?;
+        if supply > backing {
+            log::error!(
+                "nBTC supply of {} exceeds reserve backing of {} by {} after counting {} sats of fees paid out of the reserve",
+                supply,
+                backing,
+                supply - backing,
+                reserve_fees,
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Checks that the nBTC supply is backed by the reserve, pausing the
+    /// bridge if it exceeds the reserve by more than [`SUPPLY_TOLERANCE`].
+    /// Called when a checkpoint stops building, after counting the fee it
+    /// pays out of the reserve.
+    #[cfg(feature = "full")]
+    fn check_supply(&mut self) -> Result<()> {
+        if self.checkpoints.index > 0 {
+            let advanced = self.checkpoints.get(self.checkpoints.index - 1)?;
+            self.reserve_fees += advanced.reserve_fee;
+        }
+
+        if self.paused {
+            return Ok(());
+        }
+
+        let backing = self.backing()?;
+        let tolerance = backing * SUPPLY_TOLERANCE.0 as u128 / SUPPLY_TOLERANCE.1 as u128;
+        let supply = self.nbtc_supply as u128;
+
+        if supply > backing + tolerance {
+            let checkpoint_index = self.checkpoints.index - 1;
+            log::error!(
+                "nBTC supply of {} exceeds reserve backing of {} at checkpoint {}, pausing bridge",
+                supply,
+                backing,
+                checkpoint_index,
+            );
+            self.paused = true;
+
+            if let Some(events) = Context::resolve::<orga::plugins::Events>() {
+                let attribute =
+                    |key: &'static str, value: String| orga::abci::messages::EventAttribute {
+                        key: key.into(),
+                        value: value.into(),
+                        index: true,
+                    };
+                events.add(orga::abci::messages::Event {
+                    r#type: "bridge_paused".to_string(),
+                    attributes: vec![
+                        attribute("checkpoint_index", checkpoint_index.to_string()),
+                        attribute("supply", supply.to_string()),
+                        attribute("backing", backing.to_string()),
+                    ],
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an error if the bridge has been paused.
+    pub fn check_not_paused(&self) -> Result<()> {
+        if self.paused {
+            return Err(OrgaError::App("Bridge is paused".to_string()).into());
+        }
+
+        Ok(())
+    }
+
+    /// Pauses or resumes the bridge, clearing any votes to resume it.
+    pub fn set_paused(&mut self, paused: bool) -> Result<()> {
+        self.paused = paused;
+
+        let voters = self
+            .resume_votes
+            .iter()?
+            .map(|entry| entry.map(|(cons_key, _)| *cons_key).map_err(Error::from))
+            .collect::<Result<Vec<ConsensusKey>>>()?;
+        for cons_key in voters {
+            self.resume_votes.remove(cons_key)?;
+        }
+
+        Ok(())
+    }
+
+    /// Votes to resume the paused bridge. The call must be signed by a
+    /// validator, and the bridge resumes once validators with more than 2/3
+    /// of the voting power have voted.
+    #[call]
+    pub fn vote_resume(&mut self) -> Result<()> {
+        #[cfg(feature = "full")]
+        {
+            exempt_from_fee()?;
+
+            if !self.paused {
+                return Err(OrgaError::App("Bridge is not paused".to_string()).into());
+            }
+
+            let signer = self
+                .context::<Signer>()
+                .ok_or_else(|| Error::Orga(OrgaError::App("No Signer context available".into())))?
+                .signer
+                .ok_or_else(|| Error::Orga(OrgaError::App("Call must be signed".into())))?;
+
+            let validators: &mut Validators = self.context().ok_or_else(|| {
+                Error::Orga(orga::Error::App("No validator context found".to_string()))
+            })?;
+            let consensus_key = validators.consensus_key(signer)?.ok_or_else(|| {
+                Error::Orga(orga::Error::App(
+                    "Signer does not have a consensus key".to_string(),
+                ))
+            })?;
+            let entries = validators.entries()?;
+
+            self.resume_votes.insert(consensus_key, ())?;
+
+            let mut total_power = 0u64;
+            let mut resume_power = 0u64;
+            for ValidatorEntry { power, pubkey } in entries {
+                total_power += power;
+                if self.resume_votes.contains_key(pubkey)? {
+                    resume_power += power;
+                }
+            }
+
+            if resume_power as u128 * 3 > total_power as u128 * 2 {
+                log::info!("Validator vote resumed bridge");
+                self.set_paused(false)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn account_balances(&self) -> Result<Vec<(Address, u64)>> {
+        let mut balances = vec![];
+        for entry in self.accounts.iter()? {
+            let (address, coins) = entry?;
+            balances.push((*address, u64::from(coins.amount)));
+        }
+
+        Ok(balances)
+    }
+
+    fn balances_tree(&self) -> Result<SumTree> {
+        Ok(SumTree::build(self.account_balances()?)?)
     }
 
     #[query]
@@ -756,6 +1850,8 @@ impl Bitcoin {
             false
         };
 
+        self.revert_reorged_confirmations()?;
+
         let pushed = self
             .checkpoints
             .maybe_step(
@@ -769,6 +1865,10 @@ impl Bitcoin {
             .map_err(|err| OrgaError::App(err.to_string()))?;
 
         if pushed {
+            self.index_checkpoint()?;
+            self.check_supply()?;
+            self.drain_overflow()?;
+            self.prune_records()?;
             self.offline_signers()
         } else {
             Ok(vec![])
@@ -829,6 +1929,9 @@ impl Bitcoin {
             }
         }
 
+        self.deposits
+            .mark_credited(self.checkpoints.last_completed_index()?)?;
+
         // TODO: drain iter
         let pending = &mut self.checkpoints.last_completed_mut()?.pending;
         let keys = pending
@@ -999,4 +2102,148 @@ mod tests {
 
         Context::remove::<Paid>();
     }
+
+    #[test]
+    fn refund_value() {
+        let input = Input {
+            amount: 10_000,
+            est_witness_vsize: 60,
+            ..Default::default()
+        };
+        // 22-byte P2WPKH script
+        let script = Script::new_v0_p2wpkh(&bitcoin::WPubkeyHash::all_zeros());
+
+        // (100 + 31) vbytes at 10 sats/vbyte
+        assert_eq!(super::refund_value(&input, &script, 10), Some(8_690));
+        assert_eq!(super::refund_value(&input, &script, 76), None);
+        assert_eq!(super::refund_value(&input, &script, 100), None);
+    }
+
+    fn push_header(btc: &mut Bitcoin, nonce: u32) {
+        let height = btc.headers.height().unwrap() + 1;
+        btc.headers
+            .deque
+            .push_back(WorkHeader::new(
+                WrappedHeader::new(
+                    Adapter::new(BlockHeader {
+                        bits: 0,
+                        merkle_root: TxMerkleNode::all_zeros(),
+                        nonce,
+                        prev_blockhash: BlockHash::all_zeros(),
+                        time: height,
+                        version: 0,
+                    }),
+                    height,
+                ),
+                bitcoin::util::uint::Uint256([0, 0, 0, 0]),
+            ))
+            .unwrap();
+    }
+
+    /// Returns a `Bitcoin` with headers at heights 1 to 10 and checkpoints 0
+    /// to 3 completed, with confirmation proofs stored for each `(index,
+    /// height)` in `proofs` and the highest of them as the confirmed index.
+    fn with_confirmations(proofs: &[(u32, u32)]) -> Bitcoin {
+        let mut btc = Bitcoin::default();
+        for _ in 0..10 {
+            push_header(&mut btc, 0);
+        }
+
+        for i in 0..5 {
+            let mut cp = checkpoint::Checkpoint::new(SignatorySet::default(), (9, 10)).unwrap();
+            if i < 4 {
+                cp.status = CheckpointStatus::Complete;
+            }
+            btc.checkpoints.queue.push_back(cp).unwrap();
+        }
+        btc.checkpoints.index = 4;
+
+        for &(index, btc_height) in proofs {
+            let header = btc.headers.get_by_height(btc_height).unwrap().unwrap();
+            btc.checkpoint_proofs
+                .insert(
+                    index,
+                    CheckpointProof {
+                        btc_height,
+                        block_hash: header.block_hash().into_inner(),
+                        proof: Adapter::new(PartialMerkleTree::from_txids(
+                            &[Txid::all_zeros()],
+                            &[true],
+                        )),
+                    },
+                )
+                .unwrap();
+        }
+        btc.checkpoints.confirmed_index = proofs.iter().map(|(index, _)| *index).max();
+
+        btc
+    }
+
+    /// Replaces the headers from `height` up to height 10 with different
+    /// blocks.
+    fn reorg(btc: &mut Bitcoin, height: u32) {
+        while btc.headers.height().unwrap() >= height {
+            btc.headers.deque.pop_back().unwrap();
+        }
+        while btc.headers.height().unwrap() < 10 {
+            push_header(btc, 1);
+        }
+    }
+
+    #[test]
+    fn revert_reorged_confirmation() {
+        let mut btc = with_confirmations(&[(1, 3), (3, 8)]);
+        reorg(&mut btc, 6);
+
+        assert_eq!(btc.confirmation_in_chain(3).unwrap(), Some(false));
+        assert_eq!(btc.confirmation_in_chain(2).unwrap(), None);
+        btc.revert_reorged_confirmations().unwrap();
+
+        assert_eq!(btc.checkpoints.confirmed_index, Some(1));
+        assert!(btc.checkpoint_proofs.get(3).unwrap().is_none());
+        assert!(btc.checkpoint_proofs.get(1).unwrap().is_some());
+    }
+
+    #[test]
+    fn keep_confirmation_below_reorg() {
+        let mut btc = with_confirmations(&[(1, 3), (3, 8)]);
+        reorg(&mut btc, 9);
+
+        assert_eq!(btc.confirmation_in_chain(3).unwrap(), Some(true));
+        btc.revert_reorged_confirmations().unwrap();
+
+        assert_eq!(btc.checkpoints.confirmed_index, Some(3));
+        assert!(btc.checkpoint_proofs.get(3).unwrap().is_some());
+    }
+
+    #[test]
+    fn revert_reorged_confirmation_without_earlier_proof() {
+        let mut btc = with_confirmations(&[(3, 8)]);
+        reorg(&mut btc, 6);
+        btc.revert_reorged_confirmations().unwrap();
+
+        // no earlier confirmation is known, so every checkpoint is counted as
+        // unconfirmed
+        assert_eq!(btc.checkpoints.confirmed_index, None);
+        assert!(btc.checkpoint_proofs.get(3).unwrap().is_none());
+
+        // confirmations relayed before proofs were stored are kept
+        let mut btc = with_confirmations(&[]);
+        btc.checkpoints.confirmed_index = Some(3);
+        reorg(&mut btc, 1);
+        btc.revert_reorged_confirmations().unwrap();
+        assert_eq!(btc.checkpoints.confirmed_index, Some(3));
+    }
+
+    #[test]
+    fn genesis_network_from_app_state() {
+        assert_eq!(genesis_network(b"").unwrap(), None);
+        assert_eq!(genesis_network(b"{}").unwrap(), None);
+        assert_eq!(
+            genesis_network(br#"{"bitcoin":{"network":"signet"}}"#).unwrap(),
+            Some(BitcoinNetwork::Signet)
+        );
+        assert!(genesis_network(br#"{"bitcoin":{"network":"litecoin"}}"#).is_err());
+        assert!(genesis_network(br#"{"bitcoin":{"network":4}}"#).is_err());
+    }
 }
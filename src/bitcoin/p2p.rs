@@ -0,0 +1,995 @@
+//! A Bitcoin P2P light client, which the relayer can use instead of a full
+//! node's RPC.
+//!
+//! The client connects to a peer which serves compact block filters (BIP 157).
+//! It syncs headers forward from an anchor header taken from the sidechain's
+//! header queue, only checking that they link together and meet their own
+//! proof-of-work target: the header queue fully validates them once they are
+//! relayed. Blocks are only downloaded when their basic filter (BIP 158)
+//! matches a watched script, and transactions are broadcast by sending them to
+//! the peer.
+//!
+//! Filters are checked against the filter header chain the peer serves, and
+//! the filter headers are cross-checked with any further peers the client is
+//! given. A peer can only hide blocks from the relayer by serving a false
+//! filter header chain, which the other peers then disagree with. A peer can
+//! not make the relayer relay anything the sidechain would accept without the
+//! data being in the Bitcoin chain.
+
+use super::source::{BitcoinSource, HeaderInfo};
+use crate::app_client;
+use crate::error::{Error, Result};
+use crate::utils::time_now;
+use async_trait::async_trait;
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::hashes::Hash;
+use bitcoin::network::address::Address;
+use bitcoin::network::constants::{Network, ServiceFlags};
+use bitcoin::network::message::{NetworkMessage, RawNetworkMessage};
+use bitcoin::network::message_blockdata::{GetHeadersMessage, Inventory};
+use bitcoin::network::message_filter::{CFilter, GetCFHeaders, GetCFilters};
+use bitcoin::network::message_network::VersionMessage;
+use bitcoin::util::bip158::BlockFilter;
+use bitcoin::util::uint::Uint256;
+use bitcoin::{Block, BlockHash, BlockHeader, FilterHeader, Script, Transaction};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::io::{BufReader, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How far below the sidechain's header tip the client anchors its header
+/// chain, which bounds how far back it can scan for transactions.
+pub const ANCHOR_DEPTH: u32 = 2016;
+
+/// The BIP 158 basic filter type.
+const BASIC_FILTER: u8 = 0;
+/// The most filters a peer serves for one `getcfilters` message.
+const MAX_FILTER_BATCH: u32 = 1_000;
+/// The most headers a peer sends in one `headers` message.
+const MAX_HEADERS: usize = 2_000;
+const TIMEOUT: Duration = Duration::from_secs(30);
+const USER_AGENT: &str = "/nomic-relayer/";
+
+/// The best header chain known to the client, starting at its anchor.
+struct HeaderChain {
+    base_height: u32,
+    headers: Vec<BlockHeader>,
+    heights: HashMap<BlockHash, u32>,
+    /// The verified filter header of each header, once it has been fetched.
+    filter_headers: Vec<Option<FilterHeader>>,
+}
+
+impl HeaderChain {
+    fn new(anchor: BlockHeader, height: u32) -> Self {
+        HeaderChain {
+            base_height: height,
+            heights: [(anchor.block_hash(), height)].into(),
+            headers: vec![anchor],
+            filter_headers: vec![None],
+        }
+    }
+
+    fn tip_height(&self) -> u32 {
+        self.base_height + self.headers.len() as u32 - 1
+    }
+
+    fn tip_hash(&self) -> BlockHash {
+        self.headers.last().unwrap().block_hash()
+    }
+
+    fn get(&self, height: u32) -> Option<&BlockHeader> {
+        let index = height.checked_sub(self.base_height)?;
+        self.headers.get(index as usize)
+    }
+
+    fn filter_header(&self, height: u32) -> Option<FilterHeader> {
+        let index = height.checked_sub(self.base_height)?;
+        self.filter_headers.get(index as usize).copied().flatten()
+    }
+
+    /// Records the verified filter headers of the blocks from `start_height`
+    /// on, checking that they follow on from the filter header before them if
+    /// it is known.
+    fn set_filter_headers(&mut self, start_height: u32, headers: &[FilterHeader]) -> Result<()> {
+        let (prev, headers) = headers
+            .split_first()
+            .ok_or_else(|| Error::Relayer("Missing previous filter header".to_string()))?;
+        let prev_height = start_height.checked_sub(1);
+        if let Some(known) = prev_height.and_then(|height| self.filter_header(height)) {
+            if known != *prev {
+                return Err(Error::Relayer(format!(
+                    "Filter headers from height {} do not follow on from the verified ones",
+                    start_height
+                )));
+            }
+        }
+
+        for (height, header) in (start_height..).zip(headers) {
+            let index = height
+                .checked_sub(self.base_height)
+                .ok_or_else(|| Error::Relayer("Filter header is below the anchor".to_string()))?;
+            match self.filter_headers.get_mut(index as usize) {
+                Some(slot) => *slot = Some(*header),
+                None => return Err(Error::Relayer("Filter header is above the tip".to_string())),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn height_of(&self, hash: &BlockHash) -> Result<u32> {
+        self.heights
+            .get(hash)
+            .copied()
+            .ok_or_else(|| Error::Relayer(format!("Block {} is not in the header chain", hash)))
+    }
+
+    fn info(&self, hash: &BlockHash) -> Result<HeaderInfo> {
+        let height = self.height_of(hash)?;
+
+        Ok(HeaderInfo {
+            hash: *hash,
+            height: height as usize,
+            confirmations: (self.tip_height() - height + 1) as i32,
+            previous_block_hash: height
+                .checked_sub(1)
+                .and_then(|prev| self.get(prev))
+                .map(|header| header.block_hash()),
+            next_block_hash: self.get(height + 1).map(|header| header.block_hash()),
+        })
+    }
+
+    /// Hashes of headers going back from the tip, exponentially further
+    /// apart, ending with the anchor.
+    fn locator(&self) -> Vec<BlockHash> {
+        let mut locator = vec![];
+        let mut height = self.tip_height();
+        let mut step = 1;
+        while height > self.base_height {
+            locator.push(self.get(height).unwrap().block_hash());
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step).max(self.base_height);
+        }
+        locator.push(self.headers[0].block_hash());
+
+        locator
+    }
+
+    /// Adds headers received from the peer, replacing the headers after their
+    /// fork point if they have more work. Returns whether the tip changed.
+    fn extend(&mut self, headers: &[BlockHeader]) -> Result<bool> {
+        let first = match headers.first() {
+            Some(first) => first,
+            None => return Ok(false),
+        };
+
+        let mut fork_height = self.height_of(&first.prev_blockhash)?;
+        let mut prev_hash = first.prev_blockhash;
+        for header in headers {
+            if header.prev_blockhash != prev_hash {
+                return Err(Error::Relayer(
+                    "Received headers which do not form a chain".to_string(),
+                ));
+            }
+            prev_hash = header.validate_pow(&header.target())?;
+        }
+
+        let mut new_headers = headers;
+        while let Some(header) = new_headers.first() {
+            if self.get(fork_height + 1) != Some(header) {
+                break;
+            }
+            fork_height += 1;
+            new_headers = &new_headers[1..];
+        }
+        if new_headers.is_empty() {
+            return Ok(false);
+        }
+
+        let fork_index = (fork_height - self.base_height + 1) as usize;
+        let work = |headers: &[BlockHeader]| {
+            headers
+                .iter()
+                .fold(Uint256::default(), |work, header| work + header.work())
+        };
+        if work(new_headers) <= work(&self.headers[fork_index..]) {
+            return Ok(false);
+        }
+
+        for header in self.headers.drain(fork_index..) {
+            self.heights.remove(&header.block_hash());
+        }
+        self.filter_headers.truncate(fork_index);
+        for header in new_headers {
+            self.headers.push(*header);
+            self.filter_headers.push(None);
+            self.heights.insert(
+                header.block_hash(),
+                self.base_height + self.headers.len() as u32 - 1,
+            );
+        }
+
+        Ok(true)
+    }
+}
+
+/// A connection to a peer, after the version handshake.
+struct Peer {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    magic: u32,
+}
+
+impl Peer {
+    fn connect(addr: SocketAddr, network: Network) -> Result<Self> {
+        let stream = TcpStream::connect_timeout(&addr, TIMEOUT)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let mut peer = Peer {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: stream,
+            magic: network.magic(),
+        };
+
+        peer.send(NetworkMessage::Version(version_message(
+            addr,
+            ServiceFlags::NONE,
+        )))?;
+
+        let (mut version, mut verack) = (false, false);
+        while !(version && verack) {
+            match peer.recv()? {
+                NetworkMessage::Version(msg) => {
+                    if !msg.services.has(ServiceFlags::COMPACT_FILTERS) {
+                        return Err(Error::Relayer(
+                            "Peer does not serve compact block filters".to_string(),
+                        ));
+                    }
+                    peer.send(NetworkMessage::Verack)?;
+                    version = true;
+                }
+                NetworkMessage::Verack => verack = true,
+                _ => {}
+            }
+        }
+        info!("Connected to Bitcoin peer {}", addr);
+
+        Ok(peer)
+    }
+
+    fn send(&mut self, payload: NetworkMessage) -> Result<()> {
+        let msg = RawNetworkMessage {
+            magic: self.magic,
+            payload,
+        };
+        self.writer.write_all(&serialize(&msg))?;
+
+        Ok(())
+    }
+
+    /// Receives the next message from the peer, answering pings along the
+    /// way.
+    fn recv(&mut self) -> Result<NetworkMessage> {
+        loop {
+            let msg = RawNetworkMessage::consensus_decode(&mut self.reader)?;
+            if msg.magic != self.magic {
+                return Err(Error::Relayer("Peer is on a different network".to_string()));
+            }
+
+            match msg.payload {
+                NetworkMessage::Ping(nonce) => self.send(NetworkMessage::Pong(nonce))?,
+                payload => return Ok(payload),
+            }
+        }
+    }
+
+    /// Waits until the peer has processed all messages sent so far.
+    fn ping(&mut self) -> Result<()> {
+        let nonce = rand::random();
+        self.send(NetworkMessage::Ping(nonce))?;
+        loop {
+            if let NetworkMessage::Pong(n) = self.recv()? {
+                if n == nonce {
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn get_headers(&mut self, locator: Vec<BlockHash>) -> Result<Vec<BlockHeader>> {
+        self.send(NetworkMessage::GetHeaders(GetHeadersMessage::new(
+            locator,
+            BlockHash::all_zeros(),
+        )))?;
+        loop {
+            if let NetworkMessage::Headers(headers) = self.recv()? {
+                return Ok(headers);
+            }
+        }
+    }
+
+    /// Fetches the filter hashes of the blocks from `start_height` to
+    /// `stop_hash` and returns the filter headers they chain to, starting with
+    /// the filter header of the block before `start_height`.
+    fn get_filter_headers(
+        &mut self,
+        start_height: u32,
+        stop_hash: BlockHash,
+    ) -> Result<Vec<FilterHeader>> {
+        self.send(NetworkMessage::GetCFHeaders(GetCFHeaders {
+            filter_type: BASIC_FILTER,
+            start_height,
+            stop_hash,
+        }))?;
+
+        loop {
+            if let NetworkMessage::CFHeaders(msg) = self.recv()? {
+                if msg.filter_type != BASIC_FILTER || msg.stop_hash != stop_hash {
+                    continue;
+                }
+
+                let mut headers = vec![msg.previous_filter_header];
+                for filter_hash in msg.filter_hashes {
+                    let header = filter_hash.filter_header(headers.last().unwrap());
+                    headers.push(header);
+                }
+                return Ok(headers);
+            }
+        }
+    }
+
+    fn get_filters(&mut self, start_height: u32, stop_hash: BlockHash) -> Result<Vec<CFilter>> {
+        self.send(NetworkMessage::GetCFilters(GetCFilters {
+            filter_type: BASIC_FILTER,
+            start_height,
+            stop_hash,
+        }))?;
+
+        let mut filters = vec![];
+        loop {
+            if let NetworkMessage::CFilter(filter) = self.recv()? {
+                if filter.filter_type != BASIC_FILTER {
+                    continue;
+                }
+                let done = filter.block_hash == stop_hash;
+                filters.push(filter);
+                if done {
+                    return Ok(filters);
+                }
+            }
+        }
+    }
+
+    fn get_block(&mut self, hash: BlockHash) -> Result<Block> {
+        self.send(NetworkMessage::GetData(vec![Inventory::WitnessBlock(hash)]))?;
+        loop {
+            match self.recv()? {
+                NetworkMessage::Block(block) if block.block_hash() == hash => {
+                    if !block.check_merkle_root() || !block.check_witness_commitment() {
+                        return Err(Error::Relayer(format!("Peer sent invalid block {}", hash)));
+                    }
+                    return Ok(block);
+                }
+                NetworkMessage::NotFound(_) => {
+                    return Err(Error::Relayer(format!("Peer does not have block {}", hash)))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+fn version_message(receiver: SocketAddr, services: ServiceFlags) -> VersionMessage {
+    VersionMessage::new(
+        services,
+        time_now() as i64,
+        Address::new(&receiver, ServiceFlags::NONE),
+        Address::new(&([0, 0, 0, 0], 0).into(), services),
+        rand::random(),
+        USER_AGENT.to_string(),
+        0,
+    )
+}
+
+struct Inner {
+    addr: SocketAddr,
+    network: Network,
+    peer: Option<Peer>,
+    /// The peers the filter headers are cross-checked with, and their
+    /// connections.
+    witnesses: Vec<(SocketAddr, Option<Peer>)>,
+    chain: HeaderChain,
+}
+
+/// A light client connected to a Bitcoin peer, which cross-checks the peer's
+/// filter headers with any witness peers. Clones share the connections and
+/// header chain.
+#[derive(Clone)]
+pub struct P2pClient {
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl P2pClient {
+    /// Creates a client which syncs headers from `anchor`, at `anchor_height`,
+    /// connecting to the peer at `addr` and the peers at `witnesses` on first
+    /// use.
+    pub fn new(
+        addr: SocketAddr,
+        witnesses: Vec<SocketAddr>,
+        network: Network,
+        anchor: BlockHeader,
+        anchor_height: u32,
+    ) -> Self {
+        P2pClient {
+            inner: Arc::new(Mutex::new(Inner {
+                addr,
+                network,
+                peer: None,
+                witnesses: witnesses.into_iter().map(|addr| (addr, None)).collect(),
+                chain: HeaderChain::new(anchor, anchor_height),
+            })),
+        }
+    }
+
+    /// Creates a client anchored [`ANCHOR_DEPTH`] headers below the tip of the
+    /// sidechain's header queue, or at its oldest header, for the sidechain's
+    /// network.
+    pub async fn from_sidechain(
+        addr: SocketAddr,
+        witnesses: Vec<SocketAddr>,
+        app_client_addr: &str,
+    ) -> Result<Self> {
+        let (network, anchor, anchor_height) = app_client(app_client_addr)
+            .query(|app| {
+                let headers = &app.bitcoin.headers;
+                let height = headers.height()?;
+                let first_height = height + 1 - headers.len() as u32;
+                let anchor_height = height.saturating_sub(ANCHOR_DEPTH).max(first_height);
+                let anchor = headers
+                    .get_by_height(anchor_height)?
+                    .ok_or_else(|| orga::Error::App("Header not found".to_string()))?;
+                Ok((headers.network(), *anchor.header.header(), anchor_height))
+            })
+            .await?;
+
+        Ok(Self::new(addr, witnesses, network, anchor, anchor_height))
+    }
+
+    /// Runs `op` with a connection to the peer, connecting if needed. The
+    /// connection is dropped if `op` fails, so the next call reconnects.
+    fn with_peer<T>(&self, op: impl FnOnce(&mut Peer, &mut HeaderChain) -> Result<T>) -> Result<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+
+        if inner.peer.is_none() {
+            inner.peer = Some(Peer::connect(inner.addr, inner.network)?);
+        }
+        let res = op(inner.peer.as_mut().unwrap(), &mut inner.chain);
+        if res.is_err() {
+            inner.peer = None;
+        }
+
+        res
+    }
+
+    /// Fetches the filter headers of the blocks from `start_height` to
+    /// `stop_hash` from the peer and checks that the reachable witness peers
+    /// agree on them, then records them in the header chain. Returns the
+    /// headers, starting with the filter header of the block before
+    /// `start_height`.
+    fn verified_filter_headers(
+        &self,
+        start_height: u32,
+        stop_hash: BlockHash,
+    ) -> Result<Vec<FilterHeader>> {
+        let headers = self.with_peer(|peer, chain| {
+            let headers = peer.get_filter_headers(start_height, stop_hash)?;
+            let expected = chain.height_of(&stop_hash)? - start_height + 2;
+            if headers.len() != expected as usize {
+                return Err(Error::Relayer(
+                    "Peer sent filter headers for the wrong blocks".to_string(),
+                ));
+            }
+            Ok(headers)
+        })?;
+
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let mut checked = 0;
+        for (addr, witness) in inner.witnesses.iter_mut() {
+            if witness.is_none() {
+                match Peer::connect(*addr, inner.network) {
+                    Ok(peer) => *witness = Some(peer),
+                    Err(err) => {
+                        warn!("Could not connect to witness peer {}: {}", addr, err);
+                        continue;
+                    }
+                }
+            }
+
+            let witness_headers = match witness
+                .as_mut()
+                .unwrap()
+                .get_filter_headers(start_height, stop_hash)
+            {
+                Ok(headers) => headers,
+                Err(err) => {
+                    warn!(
+                        "Could not get filter headers from witness peer {}: {}",
+                        addr, err
+                    );
+                    *witness = None;
+                    continue;
+                }
+            };
+            if witness_headers != headers {
+                return Err(Error::Relayer(format!(
+                    "Witness peer {} disagrees with the peer on the filter headers from height {}",
+                    addr, start_height
+                )));
+            }
+            checked += 1;
+        }
+        if checked == 0 && !inner.witnesses.is_empty() {
+            warn!("No witness peers could cross-check filter headers");
+        }
+
+        inner.chain.set_filter_headers(start_height, &headers)?;
+
+        Ok(headers)
+    }
+
+    fn with_chain<T>(&self, op: impl FnOnce(&HeaderChain) -> Result<T>) -> Result<T> {
+        op(&self.inner.lock().unwrap().chain)
+    }
+
+    /// Runs `op` on tokio's blocking thread pool, since the peer's socket and
+    /// the lock held while using it would otherwise stall the async tasks
+    /// sharing a worker thread.
+    async fn spawn_blocking<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&P2pClient) -> Result<T> + Send + 'static,
+    ) -> Result<T> {
+        let client = self.clone();
+        tokio::task::spawn_blocking(move || op(&client))
+            .await
+            .map_err(|err| Error::Relayer(format!("P2P task failed: {}", err)))?
+    }
+
+    /// Fetches headers from the peer until it has no more to send.
+    pub fn sync_headers(&self) -> Result<()> {
+        self.with_peer(|peer, chain| loop {
+            let headers = peer.get_headers(chain.locator())?;
+            if chain.extend(&headers)? {
+                debug!(
+                    "Synced headers:\n\thash={}\n\theight={}",
+                    chain.tip_hash(),
+                    chain.tip_height()
+                );
+            }
+            if headers.len() < MAX_HEADERS {
+                return Ok(());
+            }
+        })
+    }
+
+    /// Returns the blocks among the `n` ending at `tip` whose filters match
+    /// any of `scripts`, with their heights, in increasing height order.
+    /// Blocks below the anchor are not scanned, and filters which don't match
+    /// the verified filter headers are rejected.
+    pub fn matching_blocks(
+        &self,
+        n: usize,
+        tip: &BlockHash,
+        scripts: &[Script],
+    ) -> Result<Vec<(u32, Block)>> {
+        let (start_height, end_height) = self.with_chain(|chain| {
+            let end_height = chain.height_of(tip)?;
+            let start_height = (end_height + 1)
+                .saturating_sub(n as u32)
+                .max(chain.base_height);
+            Ok((start_height, end_height))
+        })?;
+        if scripts.is_empty() || n == 0 {
+            return Ok(vec![]);
+        }
+
+        let mut blocks = vec![];
+        let mut batch_start = start_height;
+        while batch_start <= end_height {
+            let batch_end = (batch_start + MAX_FILTER_BATCH - 1).min(end_height);
+            let stop_hash =
+                self.with_chain(|chain| Ok(chain.get(batch_end).unwrap().block_hash()))?;
+            let filter_headers = self.verified_filter_headers(batch_start, stop_hash)?;
+
+            self.with_peer(|peer, chain| {
+                let filters = peer.get_filters(batch_start, stop_hash)?;
+
+                for (i, (height, filter)) in (batch_start..=batch_end).zip(filters).enumerate() {
+                    if Some(filter.block_hash) != chain.get(height).map(|h| h.block_hash()) {
+                        return Err(Error::Relayer(
+                            "Peer sent filters for the wrong blocks".to_string(),
+                        ));
+                    }
+
+                    let block_filter = BlockFilter::new(&filter.filter);
+                    if block_filter.filter_header(&filter_headers[i]) != filter_headers[i + 1] {
+                        return Err(Error::Relayer(format!(
+                            "Filter for block {} does not match its filter header",
+                            filter.block_hash
+                        )));
+                    }
+
+                    let matches = block_filter
+                        .match_any(
+                            &filter.block_hash,
+                            &mut scripts.iter().map(|script| script.as_bytes()),
+                        )
+                        .map_err(|err| Error::Relayer(format!("Invalid block filter: {}", err)))?;
+                    if matches {
+                        blocks.push((height, peer.get_block(filter.block_hash)?));
+                    }
+                }
+
+                Ok(())
+            })?;
+
+            batch_start = batch_end + 1;
+        }
+
+        Ok(blocks)
+    }
+
+    /// Sends the transaction to the peer, returning once the peer has
+    /// processed it.
+    pub fn send_transaction(&self, tx: &Transaction) -> Result<()> {
+        self.with_peer(|peer, _| {
+            peer.send(NetworkMessage::Tx(tx.clone()))?;
+            peer.ping()
+        })
+    }
+}
+
+#[async_trait]
+impl BitcoinSource for P2pClient {
+    async fn best_block_hash(&self) -> Result<BlockHash> {
+        self.spawn_blocking(|client| {
+            client.sync_headers()?;
+            client.with_chain(|chain| Ok(chain.tip_hash()))
+        })
+        .await
+    }
+
+    async fn header_info(&self, hash: &BlockHash) -> Result<HeaderInfo> {
+        let hash = *hash;
+        self.spawn_blocking(move |client| client.with_chain(|chain| chain.info(&hash)))
+            .await
+    }
+
+    async fn block_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        let hash = *hash;
+        self.spawn_blocking(move |client| {
+            client.with_chain(|chain| Ok(*chain.get(chain.height_of(&hash)?).unwrap()))
+        })
+        .await
+    }
+
+    async fn block(&self, hash: &BlockHash) -> Result<Block> {
+        let hash = *hash;
+        self.spawn_blocking(move |client| client.with_peer(|peer, _| peer.get_block(hash)))
+            .await
+    }
+
+    async fn send_raw_transaction(&self, tx: &Transaction) -> Result<()> {
+        let tx = tx.clone();
+        self.spawn_blocking(move |client| client.send_transaction(&tx))
+            .await
+    }
+
+    async fn relevant_blocks(
+        &self,
+        n: usize,
+        tip: &BlockHash,
+        scripts: &[Script],
+    ) -> Result<Vec<(u32, Block)>> {
+        let tip = *tip;
+        let scripts = scripts.to_vec();
+        self.spawn_blocking(move |client| client.matching_blocks(n, &tip, &scripts))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::network::message_filter::CFHeaders;
+    use bitcoin::{FilterHash, OutPoint, PackedLockTime, TxIn, TxMerkleNode, TxOut, Txid};
+    use std::net::TcpListener;
+
+    fn tx(input: OutPoint, script_sig: Vec<u8>, script_pubkey: Script) -> Transaction {
+        Transaction {
+            version: 1,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: input,
+                script_sig: script_sig.into(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: 100_000,
+                script_pubkey,
+            }],
+        }
+    }
+
+    /// Mines a block on `prev_blockhash`, timestamped with its height.
+    fn mine(prev_blockhash: BlockHash, height: u32, mut txdata: Vec<Transaction>) -> Block {
+        let coinbase = tx(
+            OutPoint::null(),
+            height.to_le_bytes().to_vec(),
+            Script::new(),
+        );
+        txdata.insert(0, coinbase);
+
+        let mut block = Block {
+            header: BlockHeader {
+                version: 1,
+                prev_blockhash,
+                merkle_root: TxMerkleNode::all_zeros(),
+                time: height,
+                bits: 0x207fffff,
+                nonce: 0,
+            },
+            txdata,
+        };
+        block.header.merkle_root = block.compute_merkle_root().unwrap();
+        while block.header.validate_pow(&block.header.target()).is_err() {
+            block.header.nonce += 1;
+        }
+
+        block
+    }
+
+    fn mine_chain(prev: &Block, start_height: u32, len: u32) -> Vec<Block> {
+        let mut blocks: Vec<Block> = vec![];
+        for height in start_height..start_height + len {
+            let prev_hash = blocks.last().unwrap_or(prev).block_hash();
+            blocks.push(mine(prev_hash, height, vec![]));
+        }
+        blocks
+    }
+
+    /// The basic filter of the block, leaving out all but the coinbase if
+    /// `hidden` is the block's hash.
+    fn filter(block: &Block, hidden: Option<BlockHash>) -> BlockFilter {
+        let mut block = block.clone();
+        if Some(block.block_hash()) == hidden {
+            block.txdata.truncate(1);
+        }
+        BlockFilter::new_script_filter(&block, |_| Ok(Script::new())).unwrap()
+    }
+
+    /// Serves `blocks` to one connection, recording the transactions it
+    /// receives. The filter and filter header chain served for the block
+    /// `hidden` leave out its transactions, as a dishonest peer's would.
+    fn fake_peer(
+        blocks: Vec<Block>,
+        hidden: Option<BlockHash>,
+    ) -> (SocketAddr, Arc<Mutex<Vec<Transaction>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(vec![]));
+
+        let filters: Vec<_> = blocks.iter().map(|block| filter(block, hidden)).collect();
+        let mut filter_headers: Vec<FilterHeader> = vec![];
+        for filter in filters.iter() {
+            let prev = filter_headers
+                .last()
+                .copied()
+                .unwrap_or_else(FilterHeader::all_zeros);
+            filter_headers.push(filter.filter_header(&prev));
+        }
+
+        let txs = received.clone();
+        std::thread::spawn(move || {
+            let (stream, peer_addr) = listener.accept().unwrap();
+            let mut peer = Peer {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                writer: stream,
+                magic: Network::Regtest.magic(),
+            };
+            let index_of = |hash: &BlockHash| blocks.iter().position(|b| b.block_hash() == *hash);
+            let index_at = |height: u32| blocks.iter().position(|b| b.header.time == height);
+
+            while let Ok(msg) = peer.recv() {
+                let res = match msg {
+                    NetworkMessage::Version(_) => peer
+                        .send(NetworkMessage::Version(version_message(
+                            peer_addr,
+                            ServiceFlags::NETWORK | ServiceFlags::COMPACT_FILTERS,
+                        )))
+                        .and_then(|_| peer.send(NetworkMessage::Verack)),
+                    NetworkMessage::GetHeaders(msg) => {
+                        let start = msg.locator_hashes.iter().find_map(index_of).unwrap();
+                        let headers = blocks[start + 1..]
+                            .iter()
+                            .take(MAX_HEADERS)
+                            .map(|block| block.header)
+                            .collect();
+                        peer.send(NetworkMessage::Headers(headers))
+                    }
+                    NetworkMessage::GetCFHeaders(msg) => {
+                        let stop = index_of(&msg.stop_hash).unwrap();
+                        let start = index_at(msg.start_height).unwrap();
+                        let previous_filter_header = start
+                            .checked_sub(1)
+                            .map_or_else(FilterHeader::all_zeros, |prev| filter_headers[prev]);
+                        peer.send(NetworkMessage::CFHeaders(CFHeaders {
+                            filter_type: BASIC_FILTER,
+                            stop_hash: msg.stop_hash,
+                            previous_filter_header,
+                            filter_hashes: filters[start..=stop]
+                                .iter()
+                                .map(|filter| FilterHash::hash(&filter.content))
+                                .collect(),
+                        }))
+                    }
+                    NetworkMessage::GetCFilters(msg) => {
+                        let stop = index_of(&msg.stop_hash).unwrap();
+                        let start = index_at(msg.start_height).unwrap();
+                        (start..=stop).try_for_each(|i| {
+                            peer.send(NetworkMessage::CFilter(CFilter {
+                                filter_type: BASIC_FILTER,
+                                block_hash: blocks[i].block_hash(),
+                                filter: filters[i].content.clone(),
+                            }))
+                        })
+                    }
+                    NetworkMessage::GetData(inv) => inv.iter().try_for_each(|inv| match inv {
+                        Inventory::WitnessBlock(hash) => {
+                            let block = blocks[index_of(hash).unwrap()].clone();
+                            peer.send(NetworkMessage::Block(block))
+                        }
+                        _ => Ok(()),
+                    }),
+                    NetworkMessage::Tx(tx) => {
+                        txs.lock().unwrap().push(tx);
+                        Ok(())
+                    }
+                    _ => Ok(()),
+                };
+                if res.is_err() {
+                    break;
+                }
+            }
+        });
+
+        (addr, received)
+    }
+
+    #[test]
+    fn header_chain_reorg() {
+        let anchor = mine(BlockHash::all_zeros(), 100, vec![]);
+        let mut chain = HeaderChain::new(anchor.header, 100);
+
+        let headers = |blocks: &[Block]| blocks.iter().map(|b| b.header).collect::<Vec<_>>();
+        let a = mine_chain(&anchor, 101, 3);
+        assert!(chain.extend(&headers(&a)).unwrap());
+        assert!(!chain.extend(&headers(&a[1..])).unwrap());
+        assert_eq!(chain.tip_hash(), a[2].block_hash());
+
+        let b = mine_chain(&a[0], 102, 1);
+        assert!(!chain.extend(&headers(&b)).unwrap());
+        assert_eq!(chain.tip_hash(), a[2].block_hash());
+
+        let c = mine_chain(&a[0], 102, 3);
+        assert!(chain.extend(&headers(&c)).unwrap());
+        assert_eq!(chain.tip_height(), 104);
+        assert_eq!(chain.tip_hash(), c[2].block_hash());
+        assert!(chain.height_of(&a[2].block_hash()).is_err());
+        assert_eq!(chain.height_of(&a[0].block_hash()).unwrap(), 101);
+
+        let unconnected = mine_chain(&b[0], 103, 1);
+        assert!(chain.extend(&headers(&unconnected)).is_err());
+    }
+
+    #[tokio::test]
+    async fn fake_peer_client() {
+        let watched = Script::from(vec![0x51, 0x52]);
+        let anchor = mine(BlockHash::all_zeros(), 100, vec![]);
+        let mut blocks = vec![anchor.clone()];
+        blocks.extend(mine_chain(&anchor, 101, 9));
+
+        let deposit = tx(OutPoint::new(Txid::all_zeros(), 1), vec![], watched.clone());
+        blocks.push(mine(blocks[9].block_hash(), 110, vec![deposit.clone()]));
+        let other = tx(
+            OutPoint::new(Txid::all_zeros(), 2),
+            vec![],
+            Script::from(vec![0x53]),
+        );
+        blocks.push(mine(blocks[10].block_hash(), 111, vec![other]));
+        let tip = mine_chain(&blocks[11], 112, 1_200);
+        blocks.extend(tip);
+
+        let (addr, received) = fake_peer(blocks.clone(), None);
+        let (witness, _) = fake_peer(blocks.clone(), None);
+        let client = P2pClient::new(addr, vec![witness], Network::Regtest, anchor.header, 100);
+
+        let tip_hash = client.best_block_hash().await.unwrap();
+        assert_eq!(tip_hash, blocks.last().unwrap().block_hash());
+        let info = client.header_info(&tip_hash).await.unwrap();
+        assert_eq!(info.height, 1_311);
+        assert_eq!(info.confirmations, 1);
+        assert_eq!(info.next_block_hash, None);
+        let info = client.header_info(&anchor.block_hash()).await.unwrap();
+        assert_eq!(info.previous_block_hash, None);
+        assert_eq!(info.next_block_hash, Some(blocks[1].block_hash()));
+
+        let matches = client
+            .relevant_blocks(2_000, &tip_hash, &[watched.clone()])
+            .await
+            .unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(matches[0].0, 110);
+        assert_eq!(matches[0].1, blocks[10]);
+        assert!(client
+            .relevant_blocks(1_200, &tip_hash, &[watched])
+            .await
+            .unwrap()
+            .is_empty());
+
+        let proof = client
+            .tx_out_proof(&deposit.txid(), &blocks[10].block_hash())
+            .await
+            .unwrap();
+        let mut txids = vec![];
+        let mut indexes = vec![];
+        let root = proof.txn.extract_matches(&mut txids, &mut indexes).unwrap();
+        assert_eq!(root, blocks[10].header.merkle_root);
+        assert_eq!(txids, vec![deposit.txid()]);
+        assert!(client
+            .tx_out_proof(&deposit.txid(), &blocks[11].block_hash())
+            .await
+            .is_err());
+
+        client.send_raw_transaction(&deposit).await.unwrap();
+        assert_eq!(*received.lock().unwrap(), vec![deposit]);
+    }
+
+    #[tokio::test]
+    async fn hidden_block_is_detected() {
+        let watched = Script::from(vec![0x51, 0x52]);
+        let anchor = mine(BlockHash::all_zeros(), 100, vec![]);
+        let mut blocks = vec![anchor.clone()];
+        blocks.extend(mine_chain(&anchor, 101, 9));
+        let deposit = tx(OutPoint::new(Txid::all_zeros(), 1), vec![], watched.clone());
+        blocks.push(mine(blocks[9].block_hash(), 110, vec![deposit]));
+        let tip = mine_chain(&blocks[10], 111, 5);
+        blocks.extend(tip);
+        let hidden = Some(blocks[10].block_hash());
+
+        // without a witness, a peer serving a consistent but false filter
+        // header chain hides the block
+        let (addr, _) = fake_peer(blocks.clone(), hidden);
+        let client = P2pClient::new(addr, vec![], Network::Regtest, anchor.header, 100);
+        let tip_hash = client.best_block_hash().await.unwrap();
+        assert!(client
+            .relevant_blocks(100, &tip_hash, &[watched.clone()])
+            .await
+            .unwrap()
+            .is_empty());
+
+        // an honest witness disagrees with its filter headers
+        let (addr, _) = fake_peer(blocks.clone(), hidden);
+        let (witness, _) = fake_peer(blocks.clone(), None);
+        let client = P2pClient::new(addr, vec![witness], Network::Regtest, anchor.header, 100);
+        let tip_hash = client.best_block_hash().await.unwrap();
+        assert!(client
+            .relevant_blocks(100, &tip_hash, &[watched])
+            .await
+            .is_err());
+    }
+}
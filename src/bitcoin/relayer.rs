@@ -2,25 +2,26 @@ use super::signatory::Signatory;
 use super::SignatorySet;
 use crate::app::Dest;
 use crate::app_client;
-use crate::bitcoin::{adapter::Adapter, header_queue::WrappedHeader};
+use crate::bitcoin::adapter::Adapter;
+use crate::bitcoin::address_server;
+use crate::bitcoin::header_queue::WrappedHeader;
+use crate::bitcoin::relayer_db::{PendingDeposit, RelayerDb, ScanCursor};
+use crate::bitcoin::source::{BitcoinSource, HeaderInfo};
+use crate::bitcoin::watched_addrs::WatchedScriptStore;
 use crate::error::Error;
 use crate::error::Result;
+use crate::metrics::metrics;
 use crate::utils::time_now;
 use bitcoin::consensus::{Decodable, Encodable};
-use bitcoin::{hashes::Hash, Block, BlockHash, Transaction};
-use bitcoind::bitcoincore_rpc::json::GetBlockHeaderResult;
-use bitcoind::bitcoincore_rpc::{Client as BitcoinRpcClient, RpcApi};
+use bitcoin::{hashes::Hash, Block, BlockHash, OutPoint, Script, Transaction};
 use log::{debug, error, info, warn};
-use orga::encoding::Decode;
 use orga::macros::build_call;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 use std::future::Future;
-use std::sync::Arc;
+use std::path::Path;
 use tokio::join;
 use tokio::sync::mpsc::Receiver;
-use tokio::sync::Mutex;
-use warp::reject;
 use warp::reply::Json;
 
 pub fn warp_reply_json<T>(val: T) -> Json
@@ -31,24 +32,47 @@ where
 }
 
 const HEADER_BATCH_SIZE: usize = 250;
-const THRESHOLD: (u64, u64) = (9, 10);
+/// How many blocks back deposits are scanned for when the relayer has no
+/// scan cursor, or when a new deposit address is announced.
+const DEPOSIT_RESCAN_DEPTH: usize = 1100;
 
 pub struct Relayer {
-    btc_client: BitcoinRpcClient,
+    btc_client: Box<dyn BitcoinSource>,
     app_client_addr: String,
+    db: RelayerDb,
+    address_server: address_server::Config,
 
     scripts: Option<WatchedScriptStore>,
+    /// Scripts of newly announced addresses, which still need to be scanned
+    /// for in past blocks.
+    backfill_scripts: Vec<Script>,
 }
 
 impl Relayer {
-    pub fn new(btc_client: BitcoinRpcClient, app_client_addr: String) -> Self {
+    pub fn new(btc_client: impl BitcoinSource + 'static, app_client_addr: String) -> Self {
         Relayer {
-            btc_client,
+            btc_client: Box::new(btc_client),
             app_client_addr,
+            db: RelayerDb::default(),
+            address_server: Default::default(),
             scripts: None,
+            backfill_scripts: vec![],
         }
     }
 
+    /// Persists the relayer's progress to `db`, rather than only in memory.
+    pub fn with_db(mut self, db: RelayerDb) -> Self {
+        self.db = db;
+        self
+    }
+
+    /// Configures the server which the deposit relay runs for clients to
+    /// announce their deposit addresses.
+    pub fn with_address_server(mut self, config: address_server::Config) -> Self {
+        self.address_server = config;
+        self
+    }
+
     async fn sidechain_block_hash(&self) -> Result<BlockHash> {
         let hash = app_client(&self.app_client_addr)
             .query(|app| Ok(app.bitcoin.headers.hash()?))
@@ -63,6 +87,7 @@ impl Relayer {
         loop {
             if let Err(e) = self.relay_headers().await {
                 error!("Header relay error: {}", e);
+                metrics().loop_error("header_relay");
             }
 
             tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
@@ -73,7 +98,7 @@ impl Relayer {
         let mut last_hash = None;
 
         loop {
-            let fullnode_hash = self.btc_client.get_best_block_hash()?;
+            let fullnode_hash = self.btc_client.best_block_hash().await?;
             let sidechain_hash = self.sidechain_block_hash().await?;
 
             if fullnode_hash != sidechain_hash {
@@ -82,16 +107,19 @@ impl Relayer {
                 continue;
             }
 
+            metrics().header_lag.set(0);
             if last_hash.is_none() || last_hash.is_some_and(|h| h != fullnode_hash) {
                 last_hash = Some(fullnode_hash);
-                let info = self.btc_client.get_block_info(&fullnode_hash)?;
+                let info = self.btc_client.header_info(&fullnode_hash).await?;
                 info!(
                     "Sidechain header state is up-to-date:\n\thash={}\n\theight={}",
                     info.hash, info.height
                 );
             }
 
-            self.btc_client.wait_for_new_block(3_000)?;
+            self.btc_client
+                .wait_for_new_block(std::time::Duration::from_secs(3))
+                .await?;
         }
     }
 
@@ -107,6 +135,7 @@ impl Relayer {
             loop {
                 if let Err(e) = self.relay_deposits(&mut recv).await {
                     error!("Deposit relay error: {}", e);
+                    metrics().loop_error("deposit_relay");
                 }
 
                 tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
@@ -119,162 +148,155 @@ impl Relayer {
 
     fn create_address_server(&self) -> (impl Future<Output = ()>, Receiver<(Dest, u32)>) {
         let (send, recv) = tokio::sync::mpsc::channel(1024);
+        let server = address_server::serve(
+            self.address_server.clone(),
+            self.app_client_addr.clone(),
+            send,
+        );
 
-        let sigsets = Arc::new(Mutex::new(BTreeMap::new()));
-
-        // TODO: pass into closures more cleanly
-        let app_client_addr: &'static str = self.app_client_addr.clone().leak();
-
-        // TODO: configurable listen address
-        use bytes::Bytes;
-        use warp::Filter;
-        let bcast_route = warp::post()
-            .and(warp::path("address"))
-            .and(warp::query::<DepositAddress>())
-            .and(warp::filters::body::bytes())
-            .map(move |query: DepositAddress, body| (query, send.clone(), sigsets.clone(), body))
-            .and_then(
-                async move |(query, send, sigsets, body): (
-                    DepositAddress,
-                    tokio::sync::mpsc::Sender<_>,
-                    Arc<Mutex<BTreeMap<_, _>>>,
-                    Bytes,
-                )| {
-                    let dest = Dest::decode(body.to_vec().as_slice())
-                        .map_err(|e| warp::reject::custom(Error::from(e)))?;
-
-                    let mut sigsets = sigsets.lock().await;
-
-                    //TODO: Replace catch-all 404 rejections
-                    let sigset = match sigsets.get(&query.sigset_index) {
-                        Some(sigset) => sigset,
-                        None => {
-                            app_client(app_client_addr)
-                                .query(|app| {
-                                    let cp = app.bitcoin.checkpoints.get(query.sigset_index)?;
-                                    if !cp.deposits_enabled {
-                                        return Err(orga::Error::App(
-                                            "Deposits disabled for this checkpoint".to_string(),
-                                        ));
-                                    }
-                                    let sigset = cp.sigset.clone();
-                                    Ok(sigsets.insert(query.sigset_index, sigset))
-                                })
-                                .await
-                                .map_err(|e| warp::reject::custom(Error::from(e)))?;
-                            // TODO: prune sigsets
-                            sigsets.get(&query.sigset_index).unwrap()
-                        }
-                    };
-                    let expected_addr = ::bitcoin::Address::from_script(
-                        &sigset
-                            .output_script(
-                                dest.commitment_bytes().map_err(|_| reject())?.as_slice(),
-                                THRESHOLD,
-                            )
-                            .map_err(warp::reject::custom)?,
-                        super::NETWORK,
-                    )
-                    .unwrap()
-                    .to_string();
-                    if expected_addr != query.deposit_addr {
-                        return Err(warp::reject::custom(Error::InvalidDepositAddress));
-                    }
-
-                    Ok::<_, warp::Rejection>((dest, query.sigset_index, send))
-                },
-            )
-            .then(
-                async move |(dest, sigset_index, send): (
-                    Dest,
-                    u32,
-                    tokio::sync::mpsc::Sender<_>,
-                )| {
-                    debug!("Received deposit commitment: {:?}, {}", dest, sigset_index);
-                    send.send((dest, sigset_index)).await.unwrap();
-                    "OK"
-                },
-            );
-
-        let sigset_route = warp::path("sigset")
-            .and_then(move || async {
-                let sigset = app_client(app_client_addr)
-                    .query(|app| {
-                        let sigset: RawSignatorySet =
-                            app.bitcoin.checkpoints.active_sigset()?.into();
-                        Ok(sigset)
-                    })
-                    .await
-                    .map_err(|_| reject())?;
-
-                Ok::<_, warp::Rejection>(warp::reply::json(&sigset))
-            })
-            .with(warp::cors().allow_any_origin());
-
-        let server = warp::serve(
-            warp::any().and(bcast_route).or(sigset_route).with(
-                warp::cors()
-                    .allow_any_origin()
-                    .allow_headers(vec![
-                        "User-Agent",
-                        "Sec-Fetch-Mode",
-                        "Referer",
-                        "Origin",
-                        "Access-Control-Request-Method",
-                        "Access-Control-Request-Headers",
-                        "content-type",
-                    ])
-                    .allow_method("POST"),
-            ),
-        )
-        .run(([0, 0, 0, 0], 8999));
         (server, recv)
     }
 
     async fn relay_deposits(&mut self, recv: &mut Receiver<(Dest, u32)>) -> Result<!> {
-        let mut prev_tip = None;
         loop {
             tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
 
             self.insert_announced_addrs(recv).await?;
 
             let tip = self.sidechain_block_hash().await?;
-            let prev = prev_tip.unwrap_or(tip);
-            if prev_tip.is_some() && prev == tip {
-                continue;
-            }
-
-            let start_height = self.common_ancestor(tip, prev)?.height;
-            let end_height = self.btc_client.get_block_header_info(&tip)?.height;
-            let num_blocks = (end_height - start_height).max(1100);
+            let tip_height = self.btc_client.header_info(&tip).await?.height;
 
-            self.scan_for_deposits(num_blocks).await?;
+            if !self.backfill_scripts.is_empty() {
+                let scripts = self.backfill_scripts.clone();
+                self.scan_for_deposits(tip, DEPOSIT_RESCAN_DEPTH, &scripts)
+                    .await?;
+                self.backfill_scripts.clear();
+            }
 
-            prev_tip = Some(tip);
+            let num_blocks = match self.db.deposit_cursor() {
+                Some(cursor) if cursor.hash == tip => None,
+                Some(cursor) => match self.common_ancestor(tip, cursor.hash).await {
+                    Ok(ancestor) => Some(tip_height - ancestor.height),
+                    Err(err) => {
+                        warn!("Rescanning deposits, scan cursor not found: {}", err);
+                        Some(DEPOSIT_RESCAN_DEPTH)
+                    }
+                },
+                None => Some(DEPOSIT_RESCAN_DEPTH),
+            };
+            if let Some(num_blocks) = num_blocks {
+                let scripts: Vec<_> = self
+                    .scripts
+                    .as_ref()
+                    .unwrap()
+                    .watched()
+                    .scripts()
+                    .cloned()
+                    .collect();
+                self.scan_for_deposits(tip, num_blocks, &scripts).await?;
+
+                let cursor = ScanCursor {
+                    hash: tip,
+                    height: tip_height as u32,
+                };
+                self.db
+                    .set_deposit_cursor(cursor, DEPOSIT_RESCAN_DEPTH as u32)?;
+
+                self.retry_pending_deposits(cursor.height).await?;
+            }
         }
     }
 
-    async fn scan_for_deposits(&mut self, num_blocks: usize) -> Result<BlockHash> {
-        let tip = self.sidechain_block_hash().await?;
-        let base_height = self.btc_client.get_block_header_info(&tip)?.height;
-        let blocks = self.last_n_blocks(num_blocks, tip)?;
+    async fn scan_for_deposits(
+        &self,
+        tip: BlockHash,
+        num_blocks: usize,
+        scripts: &[Script],
+    ) -> Result<()> {
+        let blocks = self
+            .btc_client
+            .relevant_blocks(num_blocks, &tip, scripts)
+            .await?;
 
-        for (i, block) in blocks.into_iter().enumerate().rev() {
-            let height = (base_height - i) as u32;
+        for (height, block) in blocks {
+            let block_hash = block.block_hash();
             for (tx, matches) in self.relevant_txs(&block) {
                 for output in matches {
-                    if let Err(err) = self
-                        .maybe_relay_deposit(tx, height, &block.block_hash(), output)
-                        .await
-                    {
-                        // TODO: filter out harmless errors (e.g. deposit too small)
-                        warn!("Skipping deposit for error: {}", err);
-                    }
+                    self.handle_deposit(tx, height, &block_hash, output).await?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Relays the deposit unless it was already handled, recording it in the
+    /// database as handled or, if relaying fails, as pending.
+    async fn handle_deposit(
+        &self,
+        tx: &Transaction,
+        height: u32,
+        block_hash: &BlockHash,
+        output: OutputMatch,
+    ) -> Result<()> {
+        let outpoint = OutPoint::new(tx.txid(), output.vout);
+        if self.db.has_deposit(&outpoint) {
+            return Ok(());
+        }
+
+        match self
+            .maybe_relay_deposit(tx, height, block_hash, output)
+            .await
+        {
+            Ok(()) => self.db.insert_deposit(outpoint, height),
+            Err(err) => {
+                warn!("Deferring deposit for error: {}", err);
+                self.db.insert_pending_deposit(PendingDeposit {
+                    outpoint,
+                    block_hash: *block_hash,
+                    height,
+                })
+            }
+        }
+    }
+
+    /// Retries relaying deposits which previously failed, giving up on those
+    /// more than [`DEPOSIT_RESCAN_DEPTH`] blocks deep.
+    async fn retry_pending_deposits(&self, tip_height: u32) -> Result<()> {
+        for pending in self.db.pending_deposits() {
+            if pending.height + (DEPOSIT_RESCAN_DEPTH as u32) < tip_height {
+                warn!("Giving up on relaying deposit {}", pending.outpoint);
+                self.db.remove_pending_deposit(&pending.outpoint)?;
+                continue;
+            }
+
+            let tx = self
+                .btc_client
+                .transaction(&pending.outpoint.txid, &pending.block_hash)
+                .await?;
+            let tx = tx.as_ref();
+            let output = tx.and_then(|tx| {
+                self.relevant_outputs(tx)
+                    .find(|output| output.vout == pending.outpoint.vout)
+            });
+            let (tx, output) = match (tx, output) {
+                (Some(tx), Some(output)) => (tx, output),
+                _ => {
+                    self.db.remove_pending_deposit(&pending.outpoint)?;
+                    continue;
                 }
+            };
+
+            if self
+                .maybe_relay_deposit(tx, pending.height, &pending.block_hash, output)
+                .await
+                .is_ok()
+            {
+                self.db.insert_deposit(pending.outpoint, pending.height)?;
             }
         }
 
-        Ok(tip)
+        Ok(())
     }
 
     pub async fn start_emergency_disbursal_transaction_relay(&mut self) -> Result<()> {
@@ -284,6 +306,7 @@ impl Relayer {
             if let Err(e) = self.relay_emergency_disbursal_transactions().await {
                 if !e.to_string().contains("No completed checkpoints yet") {
                     error!("Emergency disbursal relay error: {}", e);
+                    metrics().loop_error("emergency_disbursal_relay");
                 }
             }
 
@@ -292,43 +315,22 @@ impl Relayer {
     }
 
     async fn relay_emergency_disbursal_transactions(&mut self) -> Result<()> {
-        use std::time::{SystemTime, UNIX_EPOCH};
-
-        let mut relayed = HashSet::new();
         loop {
             let disbursal_txs = app_client(&self.app_client_addr)
                 .query(|app| Ok(app.bitcoin.checkpoints.emergency_disbursal_txs()?))
                 .await?;
 
             for tx in disbursal_txs.iter() {
-                if relayed.contains(&tx.txid()) {
+                let now = time_now();
+                if !self.db.should_broadcast(&tx.txid(), now) {
                     continue;
                 }
 
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
                 if now < tx.lock_time.to_u32() as u64 {
                     return Ok(());
                 }
 
-                let mut tx_bytes = vec![];
-                tx.consensus_encode(&mut tx_bytes)?;
-
-                match self.btc_client.send_raw_transaction(&tx_bytes) {
-                    Ok(_) => {
-                        info!("Relayed emergency disbursal transaction: {}", tx.txid());
-                    }
-                    Err(err) if err.to_string().contains("bad-txns-inputs-missingorspent") => {}
-                    Err(err)
-                        if err
-                            .to_string()
-                            .contains("Transaction already in block chain") => {}
-                    Err(err) => Err(err)?,
-                }
-
-                relayed.insert(tx.txid());
+                self.broadcast(tx, now).await?;
             }
 
             tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
@@ -342,6 +344,7 @@ impl Relayer {
             if let Err(e) = self.relay_checkpoints().await {
                 if !e.to_string().contains("No completed checkpoints yet") {
                     error!("Checkpoint relay error: {}", e);
+                    metrics().loop_error("checkpoint_relay");
                 }
             }
 
@@ -355,45 +358,64 @@ impl Relayer {
             .await?;
         info!("Last checkpoint tx: {}", last_checkpoint.txid());
 
-        let mut relayed = HashSet::new();
-
         loop {
-            let txs = app_client(&self.app_client_addr)
-                .query(|app| Ok(app.bitcoin.checkpoints.completed_txs(1_000)?))
+            let (txs, num_unconfirmed) = app_client(&self.app_client_addr)
+                .query(|app| {
+                    let checkpoints = &app.bitcoin.checkpoints;
+                    Ok((
+                        checkpoints.completed_txs(1_000)?,
+                        checkpoints.num_unconfirmed()?,
+                    ))
+                })
                 .await?;
-            for tx in txs {
-                if relayed.contains(&tx.txid()) {
-                    continue;
-                }
-
-                let mut tx_bytes = vec![];
-                tx.consensus_encode(&mut tx_bytes)?;
 
-                match self.btc_client.send_raw_transaction(&tx_bytes) {
-                    Ok(_) => {
-                        info!("Relayed checkpoint: {}", tx.txid());
-                    }
-                    Err(err) if err.to_string().contains("bad-txns-inputs-missingorspent") => {}
-                    Err(err)
-                        if err
-                            .to_string()
-                            .contains("Transaction already in block chain") => {}
-                    Err(err) => Err(err)?,
+            // checkpoints the sidechain has seen confirmed need no relaying
+            let unconfirmed = txs.len().saturating_sub(num_unconfirmed as usize);
+            for tx in &txs[unconfirmed..] {
+                let now = time_now();
+                if !self.db.should_broadcast(&tx.txid(), now) {
+                    continue;
                 }
 
-                relayed.insert(tx.txid());
+                self.broadcast(tx, now).await?;
             }
 
             tokio::time::sleep(tokio::time::Duration::from_secs(2)).await;
         }
     }
 
+    /// Broadcasts the transaction and records the broadcast, so it is
+    /// broadcast again after [`REBROADCAST_INTERVAL`](crate::bitcoin::relayer_db::REBROADCAST_INTERVAL) unless it
+    /// is already in the Bitcoin chain.
+    async fn broadcast(&self, tx: &Transaction, now: u64) -> Result<()> {
+        match self.btc_client.send_raw_transaction(tx).await {
+            Ok(_) => {
+                info!("Broadcast transaction: {}", tx.txid());
+            }
+            Err(err)
+                if err
+                    .to_string()
+                    .contains("Transaction already in block chain") =>
+            {
+                return self.db.insert_settled(tx.txid());
+            }
+            // the inputs may be spent by this transaction in a block, or be
+            // missing because a parent was dropped from the mempool and has
+            // not been broadcast again yet
+            Err(err) if err.to_string().contains("bad-txns-inputs-missingorspent") => {}
+            Err(err) => Err(err)?,
+        }
+
+        self.db.insert_broadcast(tx.txid(), now)
+    }
+
     pub async fn start_checkpoint_conf_relay(&mut self) -> Result<()> {
         info!("Starting checkpoint confirmation relay...");
 
         loop {
             if let Err(e) = self.relay_checkpoint_confs().await {
                 error!("Checkpoint confirmation relay error: {}", e);
+                metrics().loop_error("checkpoint_conf_relay");
             }
 
             tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
@@ -442,16 +464,16 @@ impl Relayer {
                 .await?;
             let unconfirmed_txid = tx.txid();
 
-            let maybe_conf = self.scan_for_txid(unconfirmed_txid, 100).await?;
+            let maybe_conf = self.scan_for_tx(&tx, 100).await?;
             if let Some((height, block_hash)) = maybe_conf {
                 if height > btc_height - min_confs {
                     continue;
                 }
-                let proof_bytes = self
-                    .btc_client
-                    .get_tx_out_proof(&[unconfirmed_txid], Some(&block_hash))?;
                 let proof = Adapter::new(
-                    ::bitcoin::MerkleBlock::consensus_decode(&mut proof_bytes.as_slice())?.txn,
+                    self.btc_client
+                        .tx_out_proof(&unconfirmed_txid, &block_hash)
+                        .await?
+                        .txn,
                 );
 
                 app_client(&self.app_client_addr)
@@ -470,17 +492,24 @@ impl Relayer {
         }
     }
 
-    async fn scan_for_txid(
+    async fn scan_for_tx(
         &mut self,
-        txid: bitcoin::Txid,
+        tx: &Transaction,
         num_blocks: usize,
     ) -> Result<Option<(u32, BlockHash)>> {
+        let txid = tx.txid();
         let tip = self.sidechain_block_hash().await?;
-        let base_height = self.btc_client.get_block_header_info(&tip)?.height;
-        let blocks = self.last_n_blocks(num_blocks, tip)?;
+        let scripts: Vec<_> = tx
+            .output
+            .iter()
+            .map(|output| output.script_pubkey.clone())
+            .collect();
+        let blocks = self
+            .btc_client
+            .relevant_blocks(num_blocks, &tip, &scripts)
+            .await?;
 
-        for (i, block) in blocks.into_iter().enumerate().rev() {
-            let height = (base_height - i) as u32;
+        for (height, block) in blocks {
             for tx in block.txdata.iter() {
                 if tx.txid() == txid {
                     return Ok(Some((height, block.block_hash())));
@@ -494,41 +523,41 @@ impl Relayer {
     async fn insert_announced_addrs(&mut self, recv: &mut Receiver<(Dest, u32)>) -> Result<()> {
         while let Ok((addr, sigset_index)) = recv.try_recv() {
             let sigset_res = app_client(&self.app_client_addr)
-                .query(|app| Ok(app.bitcoin.checkpoints.get(sigset_index)?.sigset.clone()))
+                .query(|app| {
+                    let checkpoint = app.bitcoin.checkpoints.get(sigset_index)?;
+                    Ok((checkpoint.sigset.clone(), checkpoint.sigset_threshold))
+                })
                 .await;
-            let sigset = match sigset_res {
-                Ok(sigset) => sigset,
+            let (sigset, threshold) = match sigset_res {
+                Ok(res) => res,
                 Err(err) => {
                     error!("{}", err);
                     continue;
                 }
             };
 
-            self.scripts.as_mut().unwrap().insert(addr, &sigset)?;
+            let scripts = self
+                .scripts
+                .as_mut()
+                .unwrap()
+                .insert(addr, &sigset, threshold)?;
+            self.backfill_scripts.extend(scripts);
         }
 
-        self.scripts.as_mut().unwrap().scripts.remove_expired()?;
+        let scripts = self.scripts.as_mut().unwrap();
+        scripts.remove_expired()?;
+        metrics()
+            .watched_scripts
+            .set(scripts.watched().scripts().count() as i64);
 
         Ok(())
     }
 
-    pub fn last_n_blocks(&self, n: usize, hash: BlockHash) -> Result<Vec<Block>> {
-        let mut blocks = vec![];
-
-        let mut hash = bitcoin::BlockHash::from_inner(hash.into_inner());
-
-        for _ in 0..n {
-            let block = self.btc_client.get_block(&hash.clone())?;
-            hash = block.header.prev_blockhash;
+    /// Returns the `n` blocks ending at `hash`, newest first.
+    pub async fn last_n_blocks(&self, n: usize, hash: BlockHash) -> Result<Vec<Block>> {
+        let blocks = self.btc_client.blocks(n, &hash).await?;
 
-            let mut block_bytes = vec![];
-            block.consensus_encode(&mut block_bytes).unwrap();
-            let block = Block::consensus_decode(&mut block_bytes.as_slice()).unwrap();
-
-            blocks.push(block);
-        }
-
-        Ok(blocks)
+        Ok(blocks.into_iter().rev().map(|(_, block)| block).collect())
     }
 
     pub fn relevant_txs<'a>(
@@ -560,7 +589,7 @@ impl Relayer {
                 self.scripts
                     .as_ref()
                     .unwrap()
-                    .scripts
+                    .watched()
                     .get(&script)
                     .map(|(dest, sigset_index)| OutputMatch {
                         sigset_index,
@@ -591,10 +620,7 @@ impl Relayer {
             return Ok(());
         }
 
-        let proof_bytes = self
-            .btc_client
-            .get_tx_out_proof(&[tx.txid()], Some(block_hash))?;
-        let proof = ::bitcoin::MerkleBlock::consensus_decode(&mut proof_bytes.as_slice())?.txn;
+        let proof = self.btc_client.tx_out_proof(&txid, block_hash).await?.txn;
 
         {
             let mut tx_bytes = vec![];
@@ -632,6 +658,7 @@ impl Relayer {
             };
         }
 
+        metrics().deposits_relayed.inc();
         info!(
             "Relayed deposit: {} sats, {:?}",
             tx.output[vout as usize].value, dest
@@ -645,16 +672,19 @@ impl Relayer {
         fullnode_hash: BlockHash,
         sidechain_hash: BlockHash,
     ) -> Result<()> {
-        let fullnode_info = self.btc_client.get_block_header_info(&fullnode_hash)?;
-        let sidechain_info = self.btc_client.get_block_header_info(&sidechain_hash)?;
+        let fullnode_info = self.btc_client.header_info(&fullnode_hash).await?;
+        let sidechain_info = self.btc_client.header_info(&sidechain_hash).await?;
+        metrics()
+            .header_lag
+            .set(fullnode_info.height as i64 - sidechain_info.height as i64);
 
         if fullnode_info.height < sidechain_info.height {
             // full node is still syncing
             return Ok(());
         }
 
-        let start = self.common_ancestor(fullnode_hash, sidechain_hash)?;
-        let batch = self.get_header_batch(start.hash)?;
+        let start = self.common_ancestor(fullnode_hash, sidechain_hash).await?;
+        let batch = self.get_header_batch(start.hash).await?;
 
         info!(
             "Relaying headers...\n\thash={}\n\theight={}\n\tbatch_len={}",
@@ -685,17 +715,17 @@ impl Relayer {
         Ok(())
     }
 
-    fn get_header_batch(&self, from_hash: BlockHash) -> Result<Vec<WrappedHeader>> {
-        let mut cursor = self.btc_client.get_block_header_info(&from_hash)?;
+    async fn get_header_batch(&self, from_hash: BlockHash) -> Result<Vec<WrappedHeader>> {
+        let mut cursor = self.btc_client.header_info(&from_hash).await?;
 
         let mut headers = Vec::with_capacity(HEADER_BATCH_SIZE);
         for _ in 0..HEADER_BATCH_SIZE {
             match cursor.next_block_hash {
-                Some(next_hash) => cursor = self.btc_client.get_block_header_info(&next_hash)?,
+                Some(next_hash) => cursor = self.btc_client.header_info(&next_hash).await?,
                 None => break,
             };
 
-            let header = self.btc_client.get_block_header(&cursor.hash)?;
+            let header = self.btc_client.block_header(&cursor.hash).await?;
             let mut header_bytes = vec![];
             header.consensus_encode(&mut header_bytes).unwrap();
             let header =
@@ -709,9 +739,9 @@ impl Relayer {
         Ok(headers)
     }
 
-    fn common_ancestor(&self, a: BlockHash, b: BlockHash) -> Result<GetBlockHeaderResult> {
-        let mut a = self.btc_client.get_block_header_info(&a)?;
-        let mut b = self.btc_client.get_block_header_info(&b)?;
+    async fn common_ancestor(&self, a: BlockHash, b: BlockHash) -> Result<HeaderInfo> {
+        let mut a = self.btc_client.header_info(&a).await?;
+        let mut b = self.btc_client.header_info(&b).await?;
 
         while a != b {
             if a.height > b.height && (b.confirmations - 1) as usize == a.height - b.height {
@@ -720,10 +750,10 @@ impl Relayer {
                 return Ok(a);
             } else if a.height > b.height {
                 let prev = a.previous_block_hash.unwrap();
-                a = self.btc_client.get_block_header_info(&prev)?;
+                a = self.btc_client.header_info(&prev).await?;
             } else {
                 let prev = b.previous_block_hash.unwrap();
-                b = self.btc_client.get_block_header_info(&prev)?;
+                b = self.btc_client.header_info(&prev).await?;
             }
         }
 
@@ -747,10 +777,11 @@ pub struct OutputMatch {
 pub struct RawSignatorySet {
     pub signatories: Vec<RawSignatory>,
     pub index: u32,
+    pub threshold: (u64, u64),
 }
 
-impl From<SignatorySet> for RawSignatorySet {
-    fn from(sigset: SignatorySet) -> Self {
+impl RawSignatorySet {
+    pub fn new(sigset: SignatorySet, threshold: (u64, u64)) -> Self {
         let signatories = sigset
             .iter()
             .map(|s| RawSignatory::from(s.clone()))
@@ -759,6 +790,7 @@ impl From<SignatorySet> for RawSignatorySet {
         RawSignatorySet {
             signatories,
             index: sigset.index(),
+            threshold,
         }
     }
 }
@@ -783,7 +815,7 @@ impl From<Signatory> for RawSignatory {
 #[derive(Default)]
 pub struct WatchedScripts {
     scripts: HashMap<::bitcoin::Script, (Dest, u32)>,
-    sigsets: BTreeMap<u32, (SignatorySet, Vec<Dest>)>,
+    sigsets: BTreeMap<u32, (SignatorySet, (u64, u64), Vec<Dest>)>,
 }
 
 impl WatchedScripts {
@@ -799,157 +831,227 @@ impl WatchedScripts {
         self.scripts.contains_key(script)
     }
 
+    /// Iterates over the watched deposit scripts.
+    pub fn scripts(&self) -> impl Iterator<Item = &::bitcoin::Script> {
+        self.scripts.keys()
+    }
+
+    /// The number of watched deposit addresses.
     pub fn len(&self) -> usize {
-        self.scripts.len()
+        self.sigsets.values().map(|(_, _, dests)| dests.len()).sum()
     }
 
     pub fn is_empty(&self) -> bool {
         self.scripts.is_empty()
     }
 
-    pub fn insert(&mut self, dest: Dest, sigset: &SignatorySet) -> Result<bool> {
-        let script = self.derive_script(&dest, sigset, THRESHOLD)?;
+    /// Watches the deposit scripts for the destination, using the threshold
+    /// stored with the signatory set's checkpoint.
+    pub fn insert(
+        &mut self,
+        dest: Dest,
+        sigset: &SignatorySet,
+        threshold: (u64, u64),
+    ) -> Result<bool> {
+        let scripts = self.derive_scripts(&dest, sigset, threshold)?;
 
-        if self.scripts.contains_key(&script) {
-            return Ok(false);
+        Ok(self.insert_scripts(dest, sigset, threshold, scripts))
+    }
+
+    /// Watches deposit scripts already derived for the destination, returning
+    /// false if they were already watched.
+    pub(crate) fn insert_scripts(
+        &mut self,
+        dest: Dest,
+        sigset: &SignatorySet,
+        threshold: (u64, u64),
+        scripts: impl IntoIterator<Item = ::bitcoin::Script>,
+    ) -> bool {
+        let mut scripts = scripts.into_iter().peekable();
+        match scripts.peek() {
+            Some(script) if !self.scripts.contains_key(script) => {}
+            _ => return false,
         }
 
-        self.scripts.insert(script, (dest.clone(), sigset.index()));
+        for script in scripts {
+            self.scripts.insert(script, (dest.clone(), sigset.index()));
+        }
 
-        let (_, dests) = self
-            .sigsets
-            .entry(sigset.index())
-            .or_insert((sigset.clone(), vec![]));
+        let (_, _, dests) =
+            self.sigsets
+                .entry(sigset.index())
+                .or_insert((sigset.clone(), threshold, vec![]));
         dests.push(dest);
 
-        Ok(true)
+        true
     }
 
-    pub fn remove_expired(&mut self) -> Result<()> {
+    /// Stops watching the addresses of signatory sets whose deposit timeout
+    /// has passed, returning the indexes of those signatory sets.
+    pub fn remove_expired(&mut self) -> Result<Vec<u32>> {
         let now = time_now();
 
-        for (_, (sigset, dests)) in self.sigsets.iter() {
-            if now < sigset.deposit_timeout() {
-                break;
-            }
+        let expired: Vec<_> = self
+            .sigsets
+            .iter()
+            .take_while(|(_, (sigset, _, _))| now >= sigset.deposit_timeout())
+            .map(|(index, _)| *index)
+            .collect();
+        if expired.is_empty() {
+            return Ok(expired);
+        }
 
-            for dest in dests {
-                let script = self.derive_script(dest, sigset, THRESHOLD)?; // TODO: get threshold from state
-                self.scripts.remove(&script);
-            }
+        for index in expired.iter() {
+            self.sigsets.remove(index);
         }
+        self.scripts
+            .retain(|_, (_, sigset_index)| !expired.contains(sigset_index));
 
-        Ok(())
+        Ok(expired)
     }
 
-    fn derive_script(
+    /// Derives both the P2WSH and P2TR deposit scripts for the destination,
+    /// since depositors may have been given either form of address.
+    pub(crate) fn derive_scripts(
         &self,
         dest: &Dest,
         sigset: &SignatorySet,
         threshold: (u64, u64),
-    ) -> Result<::bitcoin::Script> {
-        sigset.output_script(dest.commitment_bytes()?.as_slice(), threshold)
+    ) -> Result<[::bitcoin::Script; 2]> {
+        let dest_bytes = dest.commitment_bytes()?;
+        Ok([
+            sigset.output_script(dest_bytes.as_slice(), threshold)?,
+            sigset.taproot_output_script(dest_bytes.as_slice(), threshold)?,
+        ])
     }
 }
 
-use std::fs::File;
-use std::io::{self, BufRead, BufReader, Write};
-use std::path::Path;
-
-pub struct WatchedScriptStore {
-    scripts: WatchedScripts,
-    file: File,
-}
-
-impl WatchedScriptStore {
-    pub async fn open<P: AsRef<Path>>(path: P, app_client_addr: &str) -> Result<Self> {
-        let path = path.as_ref().join("watched-addrs.csv");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use bitcoin::{BlockHeader, TxMerkleNode};
+    use bitcoind::bitcoincore_rpc::{Auth, Client as BitcoinRpcClient, RpcApi};
+    use bitcoind::BitcoinD;
+    use std::sync::Arc;
 
-        let mut scripts = WatchedScripts::new();
-        Self::maybe_load(&path, &mut scripts, app_client_addr).await?;
+    /// An in-memory chain of empty blocks, which may include forks.
+    #[derive(Default)]
+    struct MockSource {
+        blocks: HashMap<BlockHash, (usize, Block)>,
+        best: Vec<BlockHash>,
+    }
 
-        let tmp_path = path.with_file_name("watched-addrs-tmp.csv");
-        let mut tmp_file = File::create(&tmp_path)?;
-        for (addr, sigset_index) in scripts.scripts.values() {
-            Self::write(&mut tmp_file, addr, *sigset_index)?;
+    impl MockSource {
+        fn add_chain(
+            &mut self,
+            parent: Option<BlockHash>,
+            len: usize,
+            nonce: u32,
+        ) -> Vec<BlockHash> {
+            let mut prev_blockhash = parent.unwrap_or_else(BlockHash::all_zeros);
+            let mut height = parent.map_or(0, |hash| self.blocks[&hash].0 + 1);
+            let mut hashes = vec![];
+            for _ in 0..len {
+                let block = Block {
+                    header: BlockHeader {
+                        version: 1,
+                        prev_blockhash,
+                        merkle_root: TxMerkleNode::all_zeros(),
+                        time: height as u32,
+                        bits: 0x207fffff,
+                        nonce,
+                    },
+                    txdata: vec![],
+                };
+                prev_blockhash = block.block_hash();
+                self.blocks.insert(prev_blockhash, (height, block));
+                hashes.push(prev_blockhash);
+                height += 1;
+            }
+            hashes
         }
-        tmp_file.flush()?;
-        drop(tmp_file);
-        std::fs::rename(tmp_path, &path)?;
-
-        let file = File::options().append(true).create(true).open(&path)?;
-
-        info!("Keeping track of deposit addresses at {}", path.display());
 
-        Ok(WatchedScriptStore { scripts, file })
+        fn get(&self, hash: &BlockHash) -> Result<&(usize, Block)> {
+            self.blocks
+                .get(hash)
+                .ok_or_else(|| Error::Relayer(format!("Unknown block {}", hash)))
+        }
     }
 
-    async fn maybe_load<P: AsRef<Path>>(
-        path: P,
-        scripts: &mut WatchedScripts,
-        app_client_addr: &str,
-    ) -> Result<()> {
-        let file = match File::open(&path) {
-            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
-            Err(e) => return Err(e.into()),
-            Ok(file) => file,
-        };
+    #[async_trait]
+    impl BitcoinSource for MockSource {
+        async fn best_block_hash(&self) -> Result<BlockHash> {
+            Ok(*self.best.last().unwrap())
+        }
 
-        let mut sigsets = BTreeMap::new();
-        app_client(app_client_addr)
-            .query(|app| {
-                for (index, checkpoint) in app.bitcoin.checkpoints.all()? {
-                    sigsets.insert(index, checkpoint.sigset.clone());
-                }
-                Ok(())
+        async fn header_info(&self, hash: &BlockHash) -> Result<HeaderInfo> {
+            let (height, block) = self.get(hash)?;
+            let in_best_chain = self.best.get(*height) == Some(hash);
+
+            Ok(HeaderInfo {
+                hash: *hash,
+                height: *height,
+                confirmations: if in_best_chain {
+                    (self.best.len() - height) as i32
+                } else {
+                    -1
+                },
+                previous_block_hash: (*height > 0).then_some(block.header.prev_blockhash),
+                next_block_hash: if in_best_chain {
+                    self.best.get(height + 1).copied()
+                } else {
+                    None
+                },
             })
-            .await?;
-
-        let lines = BufReader::new(file).lines();
-        for line in lines {
-            let line = line?;
-            let items: Vec<_> = line.split(',').collect();
-
-            let sigset_index: u32 = items[1]
-                .parse()
-                .map_err(|_| orga::Error::App("Could not parse sigset index".to_string()))?;
-            let sigset = match sigsets.get(&sigset_index) {
-                Some(sigset) => sigset,
-                None => continue,
-            };
-
-            let dest = Dest::from_base64(items[0])?;
-
-            scripts.insert(dest, sigset)?;
         }
 
-        scripts.remove_expired()?;
+        async fn block_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+            Ok(self.get(hash)?.1.header)
+        }
 
-        info!("Loaded {} deposit addresses", scripts.len());
+        async fn block(&self, hash: &BlockHash) -> Result<Block> {
+            Ok(self.get(hash)?.1.clone())
+        }
 
-        Ok(())
+        async fn send_raw_transaction(&self, _tx: &Transaction) -> Result<()> {
+            Ok(())
+        }
     }
 
-    pub fn insert(&mut self, dest: Dest, sigset: &SignatorySet) -> Result<()> {
-        if self.scripts.insert(dest.clone(), sigset)? {
-            Self::write(&mut self.file, &dest, sigset.index())?;
+    #[tokio::test]
+    async fn mock_source_fork() {
+        let mut source = MockSource::default();
+        source.best = source.add_chain(None, 10, 0);
+        let fork = source.add_chain(Some(source.best[5]), 3, 1);
+        let best = source.best.clone();
+        let relayer = Relayer::new(source, "http://localhost:26657".to_string());
+
+        let ancestor = relayer.common_ancestor(best[9], fork[2]).await.unwrap();
+        assert_eq!(ancestor.hash, best[5]);
+        assert_eq!(ancestor.height, 5);
+
+        let headers = relayer.get_header_batch(best[3]).await.unwrap();
+        assert_eq!(headers.len(), 6);
+        assert_eq!(headers[0].height(), 4);
+        for (header, hash) in headers.iter().zip(&best[4..]) {
+            assert_eq!(header.block_hash(), *hash);
         }
 
-        Ok(())
-    }
-
-    fn write(file: &mut File, dest: &Dest, sigset_index: u32) -> Result<()> {
-        writeln!(file, "{},{}", dest.to_base64()?, sigset_index)?;
-        file.flush()?;
-        Ok(())
+        let blocks = relayer
+            .btc_client
+            .relevant_blocks(3, &best[9], &[])
+            .await
+            .unwrap();
+        let heights: Vec<_> = blocks.iter().map(|(height, _)| *height).collect();
+        assert_eq!(heights, vec![7, 8, 9]);
+
+        let blocks = relayer.btc_client.blocks(20, &fork[2]).await.unwrap();
+        let hashes: Vec<_> = blocks.iter().map(|(_, block)| block.block_hash()).collect();
+        assert_eq!(hashes[..6], best[..6]);
+        assert_eq!(hashes[6..], fork[..]);
+        assert!(relayer.last_n_blocks(0, best[9]).await.unwrap().is_empty());
     }
-}
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use bitcoind::bitcoincore_rpc::{Auth, RpcApi};
-    use bitcoind::BitcoinD;
 
     #[tokio::test]
     async fn relayer_fetch_batch() {
@@ -964,10 +1066,10 @@ mod tests {
             BitcoinRpcClient::new(&bitcoind_url, Auth::CookieFile(bitcoin_cookie_file)).unwrap();
 
         bitcoind.client.generate_to_address(25, &address).unwrap();
-        let relayer = Relayer::new(rpc_client, "http://localhost:26657".to_string());
+        let relayer = Relayer::new(Arc::new(rpc_client), "http://localhost:26657".to_string());
 
         let block_hash = bitcoind.client.get_block_hash(30).unwrap();
-        let headers = relayer.get_header_batch(block_hash).unwrap();
+        let headers = relayer.get_header_batch(block_hash).await.unwrap();
 
         assert_eq!(headers.len(), 25);
 
@@ -996,9 +1098,9 @@ mod tests {
             BitcoinRpcClient::new(&bitcoind_url, Auth::CookieFile(bitcoin_cookie_file)).unwrap();
 
         bitcoind.client.generate_to_address(7, &address).unwrap();
-        let relayer = Relayer::new(rpc_client, "http://localhost:26657".to_string());
+        let relayer = Relayer::new(Arc::new(rpc_client), "http://localhost:26657".to_string());
         let block_hash = bitcoind.client.get_block_hash(30).unwrap();
-        let headers = relayer.get_header_batch(block_hash).unwrap();
+        let headers = relayer.get_header_batch(block_hash).await.unwrap();
 
         assert_eq!(headers.len(), 7);
 
@@ -0,0 +1,325 @@
+//! The relayer's persistent progress, so that restarting it does not rescan
+//! blocks or re-broadcast transactions it already handled. Broadcast
+//! transactions are broadcast again every [`REBROADCAST_INTERVAL`] until they
+//! are known to be in the Bitcoin chain, in case they were dropped from the
+//! mempool.
+//!
+//! The database is a single JSON file in the relayer home. Changes are made
+//! in memory and written by a background thread at most once per
+//! [`FLUSH_INTERVAL`], atomically (by renaming a synced temporary file over
+//! it). Losing the last changes in a crash only causes some blocks to be
+//! rescanned or some transactions to be broadcast again. The database is
+//! shared between the relayer's loops by cloning the [`RelayerDb`] handle.
+
+use crate::error::{Error, Result};
+use bitcoin::{BlockHash, OutPoint, Txid};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The name of the database file in the relayer home.
+pub const FILE_NAME: &str = "relayer-db.json";
+
+/// How many broadcast and settled txids are remembered. Checkpoints are only
+/// queried from the last 1,000, so older txids will never be seen again.
+const MAX_RELAYED_TXIDS: usize = 10_000;
+
+/// How long to wait before broadcasting a transaction again, in seconds.
+pub const REBROADCAST_INTERVAL: u64 = 10 * 60;
+
+/// How long the writer thread collects changes before writing them.
+pub const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The last block a scan processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanCursor {
+    pub hash: BlockHash,
+    pub height: u32,
+}
+
+/// A deposit which failed to relay, to be retried.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingDeposit {
+    pub outpoint: OutPoint,
+    pub block_hash: BlockHash,
+    pub height: u32,
+}
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Data {
+    deposit_cursor: Option<ScanCursor>,
+    /// The last broadcast time of transactions not known to be in the
+    /// Bitcoin chain, keyed by the string form of their txid.
+    #[serde(default)]
+    broadcasts: BTreeMap<String, u64>,
+    /// Txids of transactions in the Bitcoin chain, which are never broadcast
+    /// again, oldest first.
+    #[serde(default)]
+    settled_txids: VecDeque<Txid>,
+    /// Deposit outpoints which were relayed or need no relaying, keyed by
+    /// their string form, with the height of their block.
+    deposits: BTreeMap<String, u32>,
+    pending_deposits: Vec<PendingDeposit>,
+    #[serde(skip)]
+    settled_index: HashSet<Txid>,
+}
+
+/// A handle to the relayer database. Clones share the same state.
+#[derive(Clone, Default)]
+pub struct RelayerDb {
+    data: Arc<Mutex<Data>>,
+    /// The database file's writer, or `None` to only keep the state in
+    /// memory.
+    writer: Option<Writer>,
+    /// Wakes the writer thread, which exits once every handle is dropped.
+    notify: Option<SyncSender<()>>,
+}
+
+#[derive(Clone)]
+struct Writer {
+    path: PathBuf,
+    /// Held while writing the file.
+    file_lock: Arc<Mutex<()>>,
+}
+
+impl Writer {
+    /// Writes a snapshot of `data` to the file and syncs it to disk.
+    fn write(&self, data: &Mutex<Data>) -> Result<()> {
+        let _guard = self.file_lock.lock().unwrap();
+        let snapshot = data.lock().unwrap().clone();
+
+        let bytes = serde_json::to_vec(&snapshot)
+            .map_err(|err| Error::Relayer(format!("Could not encode database: {}", err)))?;
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        std::fs::rename(tmp_path, &self.path)?;
+
+        // sync the directory so the rename survives a crash
+        let dir = match self.path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        Ok(())
+    }
+}
+
+impl RelayerDb {
+    /// Opens the database at `path`, which is created on the first change if
+    /// it doesn't exist.
+    pub fn open(path: PathBuf) -> Result<Self> {
+        let mut data: Data = if path.exists() {
+            let bytes = std::fs::read(&path)?;
+            serde_json::from_slice(&bytes)
+                .map_err(|err| Error::Relayer(format!("Invalid relayer database: {}", err)))?
+        } else {
+            Data::default()
+        };
+        data.settled_index = data.settled_txids.iter().copied().collect();
+        let data = Arc::new(Mutex::new(data));
+
+        let writer = Writer {
+            path,
+            file_lock: Arc::default(),
+        };
+
+        // a single pending notification is enough, since each write includes
+        // every change made before it
+        let (notify, changes) = mpsc::sync_channel(1);
+        let thread_data = data.clone();
+        let thread_writer = writer.clone();
+        std::thread::spawn(move || {
+            while changes.recv().is_ok() {
+                std::thread::sleep(FLUSH_INTERVAL);
+                while changes.try_recv().is_ok() {}
+
+                if let Err(err) = thread_writer.write(&thread_data) {
+                    error!("Could not write relayer database: {}", err);
+                }
+            }
+        });
+
+        Ok(RelayerDb {
+            data,
+            writer: Some(writer),
+            notify: Some(notify),
+        })
+    }
+
+    /// Applies `op` to the state and schedules a write to the file.
+    fn update<T>(&self, op: impl FnOnce(&mut Data) -> T) -> Result<T> {
+        let res = op(&mut self.data.lock().unwrap());
+
+        if let Some(notify) = &self.notify {
+            if let Err(TrySendError::Disconnected(_)) = notify.try_send(()) {
+                return Err(Error::Relayer("Relayer database writer stopped".into()));
+            }
+        }
+
+        Ok(res)
+    }
+
+    /// Writes the state to the file now, rather than waiting for the writer
+    /// thread.
+    pub fn flush(&self) -> Result<()> {
+        match &self.writer {
+            Some(writer) => writer.write(&self.data),
+            None => Ok(()),
+        }
+    }
+
+    pub fn deposit_cursor(&self) -> Option<ScanCursor> {
+        self.data.lock().unwrap().deposit_cursor
+    }
+
+    /// Records that deposits were scanned up to `cursor`, forgetting deposits
+    /// more than `keep_depth` blocks below it, which will not be scanned
+    /// again.
+    pub fn set_deposit_cursor(&self, cursor: ScanCursor, keep_depth: u32) -> Result<()> {
+        self.update(|data| {
+            data.deposit_cursor = Some(cursor);
+            let min_height = cursor.height.saturating_sub(keep_depth);
+            data.deposits.retain(|_, height| *height >= min_height);
+        })
+    }
+
+    /// Returns true if the transaction is not settled and was not broadcast
+    /// in the last [`REBROADCAST_INTERVAL`] seconds before `now`.
+    pub fn should_broadcast(&self, txid: &Txid, now: u64) -> bool {
+        let data = self.data.lock().unwrap();
+        if data.settled_index.contains(txid) {
+            return false;
+        }
+
+        match data.broadcasts.get(&txid.to_string()) {
+            Some(time) => now >= time + REBROADCAST_INTERVAL,
+            None => true,
+        }
+    }
+
+    /// Records that the transaction was broadcast at `now`, forgetting the
+    /// oldest broadcasts if there are too many.
+    pub fn insert_broadcast(&self, txid: Txid, now: u64) -> Result<()> {
+        self.update(|data| {
+            data.broadcasts.insert(txid.to_string(), now);
+            while data.broadcasts.len() > MAX_RELAYED_TXIDS {
+                let oldest = data
+                    .broadcasts
+                    .iter()
+                    .min_by_key(|(_, time)| **time)
+                    .map(|(txid, _)| txid.clone())
+                    .unwrap();
+                data.broadcasts.remove(&oldest);
+            }
+        })
+    }
+
+    /// Records that the transaction is in the Bitcoin chain, so it will not
+    /// be broadcast again.
+    pub fn insert_settled(&self, txid: Txid) -> Result<()> {
+        self.update(|data| {
+            data.broadcasts.remove(&txid.to_string());
+            if !data.settled_index.insert(txid) {
+                return;
+            }
+            data.settled_txids.push_back(txid);
+            while data.settled_txids.len() > MAX_RELAYED_TXIDS {
+                let txid = data.settled_txids.pop_front().unwrap();
+                data.settled_index.remove(&txid);
+            }
+        })
+    }
+
+    pub fn has_deposit(&self, outpoint: &OutPoint) -> bool {
+        self.data
+            .lock()
+            .unwrap()
+            .deposits
+            .contains_key(&outpoint.to_string())
+    }
+
+    /// Records that the deposit was handled, removing it from the pending
+    /// deposits.
+    pub fn insert_deposit(&self, outpoint: OutPoint, height: u32) -> Result<()> {
+        self.update(|data| {
+            data.pending_deposits
+                .retain(|pending| pending.outpoint != outpoint);
+            data.deposits.insert(outpoint.to_string(), height);
+        })
+    }
+
+    pub fn pending_deposits(&self) -> Vec<PendingDeposit> {
+        self.data.lock().unwrap().pending_deposits.clone()
+    }
+
+    pub fn insert_pending_deposit(&self, deposit: PendingDeposit) -> Result<()> {
+        self.update(|data| {
+            if !data.pending_deposits.contains(&deposit) {
+                data.pending_deposits.push(deposit);
+            }
+        })
+    }
+
+    pub fn remove_pending_deposit(&self, outpoint: &OutPoint) -> Result<()> {
+        self.update(|data| {
+            data.pending_deposits
+                .retain(|pending| pending.outpoint != *outpoint);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash;
+
+    #[test]
+    fn reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(FILE_NAME);
+        let txid = Txid::from_inner([1; 32]);
+        let outpoint = OutPoint::new(txid, 2);
+        let cursor = ScanCursor {
+            hash: BlockHash::from_inner([3; 32]),
+            height: 1_000,
+        };
+
+        let db = RelayerDb::open(path.clone()).unwrap();
+        assert_eq!(db.deposit_cursor(), None);
+        let broadcast_txid = Txid::from_inner([5; 32]);
+        db.insert_broadcast(broadcast_txid, 100).unwrap();
+        db.insert_settled(txid).unwrap();
+        db.insert_deposit(outpoint, 500).unwrap();
+        db.insert_deposit(OutPoint::new(txid, 3), 950).unwrap();
+        db.insert_pending_deposit(PendingDeposit {
+            outpoint: OutPoint::new(txid, 4),
+            block_hash: cursor.hash,
+            height: 990,
+        })
+        .unwrap();
+        db.set_deposit_cursor(cursor, 100).unwrap();
+        db.flush().unwrap();
+
+        let db = RelayerDb::open(path).unwrap();
+        assert_eq!(db.deposit_cursor(), Some(cursor));
+        assert!(!db.should_broadcast(&txid, u64::MAX));
+        assert!(db.should_broadcast(&Txid::all_zeros(), 0));
+        assert!(!db.should_broadcast(&broadcast_txid, 100 + REBROADCAST_INTERVAL - 1));
+        assert!(db.should_broadcast(&broadcast_txid, 100 + REBROADCAST_INTERVAL));
+        assert!(!db.has_deposit(&outpoint));
+        assert!(db.has_deposit(&OutPoint::new(txid, 3)));
+        assert_eq!(db.pending_deposits().len(), 1);
+
+        db.insert_deposit(OutPoint::new(txid, 4), 990).unwrap();
+        assert!(db.pending_deposits().is_empty());
+    }
+}
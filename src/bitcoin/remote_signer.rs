@@ -0,0 +1,477 @@
+//! Signing with a signatory key held by a separate process, so the extended
+//! private key never lives on the validator host.
+//!
+//! The key is held by a [`SignerDaemon`] (`nomic signer-daemon`), and the
+//! validator's signer forwards its `to_sign` requests to it through a
+//! [`RemoteSigner`]. The daemon does not trust those requests: it queries its
+//! own Nomic node for the checkpoint being signed, only signs messages that
+//! checkpoint needs from its key, and applies its own limits on the
+//! withdrawal and signatory set change rates.
+//!
+//! Requests and responses are newline-delimited JSON, sent over a Unix socket
+//! or over TCP with mutual TLS.
+
+use super::checkpoint::CheckpointStatus;
+use super::signer::{check_change_rates, SigningKey};
+use super::threshold_sig::{SigScheme, Signature};
+use crate::app_client;
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::util::bip32::{ExtendedPrivKey, ExtendedPubKey};
+use log::{info, warn};
+use orga::encoding::LengthVec;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::fs::Permissions;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio_rustls::rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// The maximum length of a request or response line.
+const MAX_MESSAGE_LEN: u64 = 1 << 20;
+
+/// Where a signer daemon listens.
+#[derive(Clone, Debug)]
+pub enum Endpoint {
+    /// A Unix socket at this path.
+    Unix(PathBuf),
+    /// A TCP address (`host:port`), authenticated with mutual TLS. When
+    /// connecting, `host` must be a DNS name in the daemon's certificate.
+    Tls { addr: String, tls: TlsFiles },
+}
+
+/// PEM files for mutual TLS. The daemon and its clients each present a
+/// certificate signed by the CA, and only accept peers which do the same.
+#[derive(Clone, Debug)]
+pub struct TlsFiles {
+    pub ca: PathBuf,
+    pub cert: PathBuf,
+    /// A PKCS #8 private key for `cert`.
+    pub key: PathBuf,
+}
+
+impl Endpoint {
+    /// Parses `unix:<path>`, or a `host:port` address which requires `tls`.
+    pub fn parse(endpoint: &str, tls: Option<TlsFiles>) -> Result<Self> {
+        if let Some(path) = endpoint.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(path.into()));
+        }
+
+        let tls = tls.ok_or_else(|| {
+            Error::Signer(format!(
+                "TCP signer endpoint {} requires TLS certificates",
+                endpoint
+            ))
+        })?;
+        Ok(Endpoint::Tls {
+            addr: endpoint.to_string(),
+            tls,
+        })
+    }
+}
+
+impl TlsFiles {
+    fn certs(path: &Path) -> Result<Vec<Certificate>> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+        Ok(rustls_pemfile::certs(&mut reader)?
+            .into_iter()
+            .map(Certificate)
+            .collect())
+    }
+
+    fn key(&self) -> Result<PrivateKey> {
+        let mut reader = std::io::BufReader::new(std::fs::File::open(&self.key)?);
+        rustls_pemfile::pkcs8_private_keys(&mut reader)?
+            .into_iter()
+            .next()
+            .map(PrivateKey)
+            .ok_or_else(|| Error::Signer(format!("No PKCS #8 key found in {}", self.key.display())))
+    }
+
+    fn roots(&self) -> Result<RootCertStore> {
+        let mut roots = RootCertStore::empty();
+        for cert in Self::certs(&self.ca)? {
+            roots
+                .add(&cert)
+                .map_err(|err| Error::Signer(format!("Invalid CA certificate: {}", err)))?;
+        }
+
+        Ok(roots)
+    }
+
+    fn client_config(&self) -> Result<ClientConfig> {
+        let mut config = ClientConfig::new();
+        config.root_store = self.roots()?;
+        config
+            .set_single_client_cert(Self::certs(&self.cert)?, self.key()?)
+            .map_err(|err| Error::Signer(format!("Invalid TLS certificate: {}", err)))?;
+
+        Ok(config)
+    }
+
+    fn server_config(&self) -> Result<ServerConfig> {
+        let mut config = ServerConfig::new(AllowAnyAuthenticatedClient::new(self.roots()?));
+        config
+            .set_single_cert(Self::certs(&self.cert)?, self.key()?)
+            .map_err(|err| Error::Signer(format!("Invalid TLS certificate: {}", err)))?;
+
+        Ok(config)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "method", rename_all = "snake_case")]
+pub enum Request {
+    Xpub,
+    Sign {
+        checkpoint_index: u32,
+        to_sign: Vec<([u8; 32], u32, SigScheme)>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Xpub(ExtendedPubKey),
+    /// Hex-encoded compact signatures, in the order of the request's
+    /// messages.
+    Signatures(Vec<String>),
+    Error(String),
+}
+
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Connection for T {}
+
+/// Reads a message line, returning `None` if the stream is closed.
+async fn read_message<T: DeserializeOwned>(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> Result<Option<T>> {
+    let mut line = String::new();
+    let len = reader.take(MAX_MESSAGE_LEN).read_line(&mut line).await?;
+    if len == 0 {
+        return Ok(None);
+    }
+    if !line.ends_with('\n') {
+        return Err(Error::Signer("Message is too long".to_string()));
+    }
+
+    serde_json::from_str(&line)
+        .map(Some)
+        .map_err(|err| Error::Signer(format!("Invalid message: {}", err)))
+}
+
+async fn write_message(writer: &mut (impl AsyncWrite + Unpin), msg: &impl Serialize) -> Result<()> {
+    let mut bytes = serde_json::to_vec(msg)
+        .map_err(|err| Error::Signer(format!("Could not encode message: {}", err)))?;
+    bytes.push(b'\n');
+    writer.write_all(&bytes).await?;
+    writer.flush().await?;
+
+    Ok(())
+}
+
+/// A [`SigningKey`] held by a signer daemon.
+pub struct RemoteSigner {
+    endpoint: Endpoint,
+    tls: Option<TlsConnector>,
+    xpub: ExtendedPubKey,
+}
+
+impl RemoteSigner {
+    /// Connects to the daemon at `endpoint` and fetches its xpub.
+    pub async fn connect(endpoint: Endpoint) -> Result<Self> {
+        let tls = match &endpoint {
+            Endpoint::Unix(_) => None,
+            Endpoint::Tls { tls, .. } => Some(TlsConnector::from(Arc::new(tls.client_config()?))),
+        };
+
+        let xpub = match request(&endpoint, tls.as_ref(), &Request::Xpub).await? {
+            Response::Xpub(xpub) => xpub,
+            res => return Err(Error::Signer(format!("Unexpected response: {:?}", res))),
+        };
+        info!("Using remote signatory key {}", xpub);
+
+        Ok(RemoteSigner {
+            endpoint,
+            tls,
+            xpub,
+        })
+    }
+}
+
+async fn open(endpoint: &Endpoint, tls: Option<&TlsConnector>) -> Result<Box<dyn Connection>> {
+    match endpoint {
+        Endpoint::Unix(path) => Ok(Box::new(UnixStream::connect(path).await?)),
+        Endpoint::Tls { addr, .. } => {
+            let host = addr
+                .rsplit_once(':')
+                .map_or(addr.as_str(), |(host, _)| host);
+            let name = DNSNameRef::try_from_ascii_str(host)
+                .map_err(|_| Error::Signer(format!("Invalid signer host name: {}", host)))?;
+            let stream = TcpStream::connect(addr).await?;
+            let stream = tls.unwrap().connect(name, stream).await?;
+
+            Ok(Box::new(stream))
+        }
+    }
+}
+
+/// Sends a request on a new connection and reads its response.
+async fn request(
+    endpoint: &Endpoint,
+    tls: Option<&TlsConnector>,
+    req: &Request,
+) -> Result<Response> {
+    let mut stream = BufReader::new(open(endpoint, tls).await?);
+    write_message(&mut stream, req).await?;
+
+    match read_message(&mut stream).await? {
+        None => Err(Error::Signer("Signer closed the connection".to_string())),
+        Some(Response::Error(err)) => Err(Error::Signer(format!("Remote signer: {}", err))),
+        Some(res) => Ok(res),
+    }
+}
+
+#[async_trait]
+impl SigningKey for RemoteSigner {
+    async fn xpub(&self) -> Result<ExtendedPubKey> {
+        Ok(self.xpub)
+    }
+
+    async fn sign(
+        &self,
+        checkpoint_index: u32,
+        to_sign: &[([u8; 32], u32, SigScheme)],
+    ) -> Result<LengthVec<u16, Signature>> {
+        let req = Request::Sign {
+            checkpoint_index,
+            to_sign: to_sign.to_vec(),
+        };
+        let sigs = match request(&self.endpoint, self.tls.as_ref(), &req).await? {
+            Response::Signatures(sigs) => sigs,
+            res => return Err(Error::Signer(format!("Unexpected response: {:?}", res))),
+        };
+        if sigs.len() != to_sign.len() {
+            return Err(Error::Signer(format!(
+                "Expected {} signatures, got {}",
+                to_sign.len(),
+                sigs.len()
+            )));
+        }
+
+        Ok(sigs
+            .iter()
+            .map(|sig| {
+                let bytes = hex::decode(sig)
+                    .map_err(|err| Error::Signer(format!("Invalid signature: {}", err)))?;
+                let sig = bytes
+                    .try_into()
+                    .map_err(|_| Error::Signer("Invalid signature length".to_string()))?;
+                Ok(Signature(sig))
+            })
+            .collect::<Result<Vec<_>>>()?
+            .try_into()?)
+    }
+}
+
+/// The limits a signer daemon applies before signing a checkpoint.
+#[derive(Clone, Copy, Debug)]
+pub struct Policy {
+    pub max_withdrawal_rate: f64,
+    pub max_sigset_change_rate: f64,
+}
+
+/// Holds a signatory key and signs checkpoints for [`RemoteSigner`] clients.
+pub struct SignerDaemon {
+    xpriv: ExtendedPrivKey,
+    xpub: ExtendedPubKey,
+    policy: Policy,
+    app_client_addr: String,
+}
+
+impl SignerDaemon {
+    /// Creates a daemon which checks requests against the Nomic node at
+    /// `app_client_addr`.
+    pub fn new(xpriv: ExtendedPrivKey, policy: Policy, app_client_addr: String) -> Self {
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        SignerDaemon {
+            xpub: ExtendedPubKey::from_priv(&secp, &xpriv),
+            xpriv,
+            policy,
+            app_client_addr,
+        }
+    }
+
+    /// Accepts connections at `endpoint` until an error occurs.
+    pub async fn listen(self, endpoint: &Endpoint) -> Result<()> {
+        let daemon = Arc::new(self);
+
+        match endpoint {
+            Endpoint::Unix(path) => {
+                if path.exists() {
+                    std::fs::remove_file(path)?;
+                }
+                let listener = UnixListener::bind(path)?;
+                std::fs::set_permissions(path, Permissions::from_mode(0o600))?;
+                info!("Signer daemon listening at {}", path.display());
+
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    let daemon = daemon.clone();
+                    tokio::spawn(async move { daemon.serve_connection(stream).await });
+                }
+            }
+            Endpoint::Tls { addr, tls } => {
+                let acceptor = TlsAcceptor::from(Arc::new(tls.server_config()?));
+                let listener = TcpListener::bind(addr).await?;
+                info!("Signer daemon listening at {}", addr);
+
+                loop {
+                    let (stream, peer) = listener.accept().await?;
+                    let acceptor = acceptor.clone();
+                    let daemon = daemon.clone();
+                    tokio::spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => daemon.serve_connection(stream).await,
+                            Err(err) => warn!("TLS handshake with {} failed: {}", peer, err),
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    async fn serve_connection(&self, stream: impl Connection) {
+        let mut stream = BufReader::new(stream);
+
+        loop {
+            let res = match read_message(&mut stream).await {
+                Ok(None) => return,
+                Ok(Some(req)) => self.handle(req).await,
+                Err(err) => Err(err),
+            };
+            let res = res.unwrap_or_else(|err| {
+                warn!("Refused signing request: {}", err);
+                Response::Error(err.to_string())
+            });
+
+            if write_message(&mut stream, &res).await.is_err() {
+                return;
+            }
+        }
+    }
+
+    async fn handle(&self, req: Request) -> Result<Response> {
+        match req {
+            Request::Xpub => Ok(Response::Xpub(self.xpub)),
+            Request::Sign {
+                checkpoint_index,
+                to_sign,
+            } => {
+                self.check(checkpoint_index, &to_sign).await?;
+                let sigs = self.xpriv.sign(checkpoint_index, &to_sign).await?;
+
+                Ok(Response::Signatures(
+                    sigs.iter().map(|sig| hex::encode(sig.0)).collect(),
+                ))
+            }
+        }
+    }
+
+    /// Checks a signing request against the daemon's own view of the chain
+    /// and its policy.
+    async fn check(&self, index: u32, to_sign: &[([u8; 32], u32, SigScheme)]) -> Result<()> {
+        let client = app_client(&self.app_client_addr);
+        let xpub = &self.xpub;
+        let (status, needed, fee_rate) = client
+            .query(|app| {
+                let checkpoint = app.bitcoin.checkpoints.get(index)?;
+                Ok((
+                    checkpoint.status,
+                    checkpoint.to_sign(xpub.into())?,
+                    checkpoint.fee_rate,
+                ))
+            })
+            .await?;
+
+        if matches!(status, CheckpointStatus::Building) {
+            return Err(Error::Signer(format!(
+                "Checkpoint {} is still building",
+                index
+            )));
+        }
+        if let Some((msg, _, _)) = to_sign.iter().find(|msg| !needed.contains(msg)) {
+            return Err(Error::Signer(format!(
+                "Checkpoint {} does not need a signature for {}",
+                index,
+                hex::encode(msg)
+            )));
+        }
+
+        check_change_rates(
+            &client,
+            self.policy.max_withdrawal_rate,
+            self.policy.max_sigset_change_rate,
+        )
+        .await?;
+
+        info!(
+            "Signing checkpoint {} ({} inputs, fee rate {} sat/vB)",
+            index,
+            to_sign.len(),
+            fee_rate
+        );
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn unix_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = Endpoint::parse(
+            &format!("unix:{}", dir.path().join("signer.sock").display()),
+            None,
+        )
+        .unwrap();
+        assert!(Endpoint::parse("signer.example.com:8444", None).is_err());
+
+        let xpriv = ExtendedPrivKey::new_master(bitcoin::Network::Regtest, &[1; 32]).unwrap();
+        let policy = Policy {
+            max_withdrawal_rate: 0.1,
+            max_sigset_change_rate: 0.1,
+        };
+        // no node is listening, so signing requests can not be checked
+        let daemon = SignerDaemon::new(xpriv, policy, "http://localhost:1".to_string());
+        let listen_endpoint = endpoint.clone();
+        tokio::spawn(async move { daemon.listen(&listen_endpoint).await });
+
+        let signer = loop {
+            if let Ok(signer) = RemoteSigner::connect(endpoint.clone()).await {
+                break signer;
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        assert_eq!(signer.xpub().await.unwrap(), xpriv.xpub().await.unwrap());
+
+        let err = signer
+            .sign(0, &[([2; 32], 0, SigScheme::Ecdsa)])
+            .await
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Remote signer: "));
+    }
+}
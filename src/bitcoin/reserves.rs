@@ -0,0 +1,211 @@
+use super::adapter::Adapter;
+use super::txid_set::Outpoint;
+use bitcoin::util::merkleblock::PartialMerkleTree;
+use orga::coins::Address;
+use orga::encoding::Encode;
+use orga::{orga, Error, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// A node of a Merkle sum tree, committing to the balances below it and their
+/// total.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SumNode {
+    pub hash: [u8; 32],
+    pub sum: u64,
+}
+
+impl SumNode {
+    pub fn leaf(address: Address, balance: u64) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update([0]);
+        hasher.update(address.encode()?);
+        hasher.update(balance.to_be_bytes());
+
+        Ok(SumNode {
+            hash: hasher.finalize().into(),
+            sum: balance,
+        })
+    }
+
+    pub fn parent(left: &SumNode, right: &SumNode) -> Result<Self> {
+        let mut hasher = Sha256::new();
+        hasher.update([1]);
+        hasher.update(left.hash);
+        hasher.update(left.sum.to_be_bytes());
+        hasher.update(right.hash);
+        hasher.update(right.sum.to_be_bytes());
+
+        let sum = left
+            .sum
+            .checked_add(right.sum)
+            .ok_or_else(|| Error::App("Balance sum overflowed".to_string()))?;
+
+        Ok(SumNode {
+            hash: hasher.finalize().into(),
+            sum,
+        })
+    }
+}
+
+/// A Merkle sum tree over account balances. Each parent commits to the sums of
+/// its children, so a balance can't be left out of the root's total without
+/// invalidating the inclusion proofs of the balances which were counted.
+pub struct SumTree {
+    levels: Vec<Vec<SumNode>>,
+}
+
+impl SumTree {
+    /// Builds the tree over `(address, balance)` leaves, in the given order.
+    /// An odd node at the end of a level is carried up to the next level.
+    pub fn build(balances: impl IntoIterator<Item = (Address, u64)>) -> Result<Self> {
+        let leaves = balances
+            .into_iter()
+            .map(|(address, balance)| SumNode::leaf(address, balance))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let level = levels.last().unwrap();
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => SumNode::parent(left, right),
+                    [node] => Ok(*node),
+                    _ => unreachable!(),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            levels.push(next);
+        }
+
+        Ok(SumTree { levels })
+    }
+
+    /// The root of the tree, or a zero node if there are no balances.
+    pub fn root(&self) -> SumNode {
+        self.levels
+            .last()
+            .and_then(|level| level.first())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn proof(&self, index: usize) -> Option<SumProof> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+
+        let mut siblings = vec![];
+        let mut index = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling_index = index ^ 1;
+            if let Some(sibling) = level.get(sibling_index) {
+                siblings.push((*sibling, sibling_index < index));
+            }
+            index /= 2;
+        }
+
+        Some(SumProof { siblings })
+    }
+}
+
+/// A proof that a balance was counted in the root of a [`SumTree`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SumProof {
+    /// The siblings of the nodes on the path from the leaf to the root, and
+    /// whether each sibling is on the left.
+    pub siblings: Vec<(SumNode, bool)>,
+}
+
+impl SumProof {
+    pub fn verify(&self, address: Address, balance: u64, root: &SumNode) -> Result<bool> {
+        let mut node = SumNode::leaf(address, balance)?;
+        for (sibling, is_left) in &self.siblings {
+            node = if *is_left {
+                SumNode::parent(sibling, &node)?
+            } else {
+                SumNode::parent(&node, sibling)?
+            };
+        }
+
+        Ok(node == *root)
+    }
+}
+
+/// The proof of a checkpoint transaction's inclusion in a Bitcoin block,
+/// stored when the checkpoint is relayed as confirmed.
+#[orga(skip(Default))]
+pub struct CheckpointProof {
+    pub btc_height: u32,
+    /// The hash of the block the proof was made against, so the confirmation
+    /// can be reverted if the block is reorged out of the header chain.
+    pub block_hash: [u8; 32],
+    pub proof: Adapter<PartialMerkleTree>,
+}
+
+/// A comparison of a checkpoint's reserve output with the nBTC supply.
+#[derive(Clone, Debug, Serialize)]
+pub struct ReserveReport {
+    pub checkpoint_index: u32,
+    /// The outpoint of the checkpoint's reserve output.
+    pub reserve_outpoint: Outpoint,
+    /// The value of the reserve output, in satoshis.
+    pub reserve_value: u64,
+    /// The height of the block including the checkpoint transaction, and the
+    /// Merkle proof of its inclusion, if the checkpoint was relayed as
+    /// confirmed.
+    pub btc_height: Option<u32>,
+    pub inclusion_proof: Option<Adapter<PartialMerkleTree>>,
+    /// The total nBTC in accounts, in micro-satoshis. This is the sum of
+    /// `balances_root`.
+    pub account_balances: u64,
+    /// The nBTC waiting to be credited from checkpoints' pending transfers,
+    /// in micro-satoshis.
+    pub pending: u64,
+    pub reward_pool: u64,
+    /// The nBTC transferred out over IBC and not yet returned, in
+    /// micro-satoshis.
+    pub ibc_escrow: u64,
+    /// The total of the above nBTC liabilities, in micro-satoshis.
+    pub liabilities: u64,
+    /// The fees paid out of the reserve rather than charged to depositors and
+    /// withdrawers, in satoshis, by which the liabilities are expected to
+    /// exceed the reserve.
+    pub reserve_fees: u64,
+    /// The root of a Merkle sum tree over account balances, ordered by
+    /// address.
+    pub balances_root: SumNode,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orga::encoding::Decode;
+
+    fn address(byte: u8) -> Address {
+        let mut bytes = vec![];
+        Address::NULL.encode_into(&mut bytes).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] = byte;
+        Address::decode(bytes.as_slice()).unwrap()
+    }
+
+    #[test]
+    fn sum_tree() {
+        let balances: Vec<_> = (1..=5).map(|i| (address(i), i as u64 * 100)).collect();
+        let tree = SumTree::build(balances.clone()).unwrap();
+        let root = tree.root();
+        assert_eq!(root.sum, 1_500);
+
+        for (i, (address, balance)) in balances.iter().enumerate() {
+            let proof = tree.proof(i).unwrap();
+            assert!(proof.verify(*address, *balance, &root).unwrap());
+            assert!(!proof.verify(*address, balance - 1, &root).unwrap());
+        }
+        assert!(tree.proof(5).is_none());
+
+        let empty = SumTree::build(vec![]).unwrap();
+        assert_eq!(empty.root(), SumNode::default());
+        assert!(empty.proof(0).is_none());
+    }
+}
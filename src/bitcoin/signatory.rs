@@ -4,12 +4,18 @@
 #[cfg(feature = "full")]
 use crate::error::Error;
 use crate::error::Result;
+use bitcoin::blockdata::opcodes::all::{
+    OP_ADD, OP_CHECKSIGADD, OP_DROP, OP_ELSE, OP_ENDIF, OP_GREATERTHAN, OP_IF, OP_SWAP,
+};
+use bitcoin::blockdata::script::Builder;
 use bitcoin::secp256k1::Context as SecpContext;
 use bitcoin::secp256k1::PublicKey;
 use bitcoin::secp256k1::Secp256k1;
 use bitcoin::secp256k1::Verification;
+use bitcoin::secp256k1::XOnlyPublicKey;
 #[cfg(feature = "full")]
 use bitcoin::util::bip32::ChildNumber;
+use bitcoin::util::taproot::{LeafVersion, TaprootBuilder, TaprootSpendInfo};
 use bitcoin::Script;
 use bitcoin_script::bitcoin_script as script;
 #[cfg(feature = "full")]
@@ -31,6 +37,14 @@ use super::Xpub;
 pub const MAX_DEPOSIT_AGE: u64 = 60 * 60 * 24 * 5;
 pub const MAX_SIGNATORIES: u64 = 20;
 
+/// The BIP341 "nothing up my sleeve" point, used as the internal key of
+/// taproot reserve and deposit outputs so they can only be spent through the
+/// signatory script path.
+const TAPROOT_INTERNAL_KEY: [u8; 32] = [
+    0x50, 0x92, 0x9b, 0x74, 0xc1, 0xa0, 0x49, 0x54, 0xb7, 0x8b, 0x4b, 0x60, 0x35, 0xe9, 0x7a, 0x5e,
+    0x07, 0x8a, 0x5a, 0x0f, 0x28, 0xec, 0x96, 0xd5, 0x47, 0xbf, 0xee, 0x9a, 0xce, 0x80, 0x3a, 0xc0,
+];
+
 #[orga]
 #[derive(Clone, Debug, PartialOrd, PartialEq, Eq, Ord)]
 pub struct Signatory {
@@ -52,6 +66,30 @@ where
         .public_key)
 }
 
+/// Builds the taproot spend info for an output whose only spending path is
+/// the given tapscript leaf.
+pub fn taproot_spend_info(leaf_script: &Script) -> Result<TaprootSpendInfo> {
+    let secp = Secp256k1::verification_only();
+    let internal_key = XOnlyPublicKey::from_slice(&TAPROOT_INTERNAL_KEY)?;
+
+    TaprootBuilder::new()
+        .add_leaf(0, leaf_script.clone())
+        .map_err(|err| OrgaError::App(err.to_string()))?
+        .finalize(&secp, internal_key)
+        .map_err(|_| OrgaError::App("Could not finalize taproot tree".to_string()).into())
+}
+
+/// Returns the serialized control block used to spend a taproot output through
+/// the given tapscript leaf.
+pub fn taproot_control_block(leaf_script: &Script) -> Result<Vec<u8>> {
+    let spend_info = taproot_spend_info(leaf_script)?;
+    let control_block = spend_info
+        .control_block(&(leaf_script.clone(), LeafVersion::TapScript))
+        .ok_or_else(|| OrgaError::App("Leaf script is not part of taproot tree".to_string()))?;
+
+    Ok(control_block.serialize())
+}
+
 #[orga]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct SignatorySet {
@@ -214,6 +252,72 @@ impl SignatorySet {
         Ok(self.redeem_script(dest, threshold)?.to_v0_p2wsh())
     }
 
+    /// Builds the tapscript leaf for the signatory set. This performs the same
+    /// weighted threshold check as the P2WSH redeem script, but uses x-only
+    /// keys and `OP_CHECKSIGADD` so that signatories sign with Schnorr
+    /// signatures.
+    ///
+    /// `OP_CHECKSIGADD` takes `<sig> <n> <pubkey>` with the pubkey on top, so
+    /// each signatory's check pushes `0 <pubkey>` above its signature.
+    pub fn taproot_script(&self, dest: &[u8], threshold: (u64, u64)) -> Result<Script> {
+        let truncation = self.get_truncation(23);
+
+        let x_only = |signatory: &Signatory| -> Result<[u8; 32]> {
+            let pubkey = PublicKey::from_slice(signatory.pubkey.as_slice())?;
+            Ok(pubkey.x_only_public_key().0.serialize())
+        };
+
+        let mut iter = self.signatories.iter();
+
+        // first signatory
+        let signatory = iter.next().ok_or_else(|| {
+            OrgaError::App("Cannot create taproot script for empty signatory set".to_string())
+        })?;
+        let truncated_voting_power = signatory.voting_power >> truncation;
+        let mut builder = Builder::new()
+            .push_int(0)
+            .push_slice(&x_only(signatory)?)
+            .push_opcode(OP_CHECKSIGADD)
+            .push_opcode(OP_IF)
+            .push_int(truncated_voting_power as i64)
+            .push_opcode(OP_ELSE)
+            .push_int(0)
+            .push_opcode(OP_ENDIF);
+
+        // all other signatories
+        for signatory in iter {
+            let truncated_voting_power = signatory.voting_power >> truncation;
+            builder = builder
+                .push_opcode(OP_SWAP)
+                .push_int(0)
+                .push_slice(&x_only(signatory)?)
+                .push_opcode(OP_CHECKSIGADD)
+                .push_opcode(OP_IF)
+                .push_int(truncated_voting_power as i64)
+                .push_opcode(OP_ADD)
+                .push_opcode(OP_ENDIF);
+        }
+
+        // > threshold check
+        let truncated_threshold = self.signature_threshold(threshold) >> truncation;
+        builder = builder
+            .push_int(truncated_threshold as i64)
+            .push_opcode(OP_GREATERTHAN);
+
+        // depositor data commitment
+        let data = &dest.encode()?[..];
+        builder = builder.push_slice(data).push_opcode(OP_DROP);
+
+        Ok(builder.into_script())
+    }
+
+    pub fn taproot_output_script(&self, dest: &[u8], threshold: (u64, u64)) -> Result<Script> {
+        let leaf_script = self.taproot_script(dest, threshold)?;
+        let spend_info = taproot_spend_info(&leaf_script)?;
+
+        Ok(Script::new_v1_p2tr_tweaked(spend_info.output_key()))
+    }
+
     fn get_truncation(&self, target_precision: u32) -> u32 {
         let vp_bits = u64::BITS - self.present_vp.leading_zeros();
         vp_bits.saturating_sub(target_precision)
@@ -238,11 +342,133 @@ impl SignatorySet {
     pub fn est_witness_vsize(&self) -> u64 {
         self.signatories.len() as u64 * 79 + 39
     }
+
+    /// Estimated witness size when spending through the taproot script path.
+    /// Schnorr signatures and x-only keys are smaller than their ECDSA
+    /// counterparts, at the cost of a 33-byte control block.
+    pub fn est_taproot_witness_vsize(&self) -> u64 {
+        self.signatories.len() as u64 * 72 + 73
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    // use super::*;
+    use super::*;
+    #[cfg(feature = "full")]
+    use bitcoin::{
+        secp256k1::{KeyPair, Message, SecretKey},
+        util::sighash::{Prevouts, SchnorrSighashType, SighashCache},
+        util::taproot::TapLeafHash,
+        Address, Amount, OutPoint, PackedLockTime, Sequence, Transaction, TxIn, TxOut, Witness,
+    };
+    #[cfg(feature = "full")]
+    use bitcoind::{bitcoincore_rpc::RpcApi, BitcoinD};
+
+    #[cfg(feature = "full")]
+    #[test]
+    fn taproot_script_spend() {
+        let secp = Secp256k1::new();
+        let keys: Vec<_> = (1..=3u8)
+            .map(|i| SecretKey::from_slice(&[i; 32]).unwrap())
+            .collect();
+        let sigset = SignatorySet {
+            create_time: 0,
+            present_vp: 100,
+            possible_vp: 100,
+            index: 0,
+            signatories: keys
+                .iter()
+                .zip([50, 30, 20])
+                .map(|(key, voting_power)| Signatory {
+                    voting_power,
+                    pubkey: PublicKey::from_secret_key(&secp, key).into(),
+                })
+                .collect(),
+        };
+        let dest = [1, 2, 3];
+        let leaf_script = sigset.taproot_script(&dest, (2, 3)).unwrap();
+        let script_pubkey = sigset.taproot_output_script(&dest, (2, 3)).unwrap();
+
+        let bitcoind = BitcoinD::new(bitcoind::downloaded_exe_path().unwrap()).unwrap();
+        let client = &bitcoind.client;
+        let miner = client.get_new_address(None, None).unwrap();
+        client.generate_to_address(101, &miner).unwrap();
+
+        let address = Address::from_script(&script_pubkey, bitcoin::Network::Regtest).unwrap();
+        let txid = client
+            .send_to_address(
+                &address,
+                Amount::from_sat(100_000),
+                None,
+                None,
+                None,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        let funding_tx = client.get_raw_transaction(&txid, None).unwrap();
+        let vout = funding_tx
+            .output
+            .iter()
+            .position(|output| output.script_pubkey == script_pubkey)
+            .unwrap();
+        let prevout = funding_tx.output[vout].clone();
+
+        let mut tx = Transaction {
+            version: 2,
+            lock_time: PackedLockTime(0),
+            input: vec![TxIn {
+                previous_output: OutPoint::new(txid, vout as u32),
+                script_sig: Script::new(),
+                sequence: Sequence(u32::MAX),
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 90_000,
+                script_pubkey: miner.script_pubkey(),
+            }],
+        };
+        let leaf_hash = TapLeafHash::from_script(&leaf_script, LeafVersion::TapScript);
+        let sighash = SighashCache::new(&tx)
+            .taproot_script_spend_signature_hash(
+                0,
+                &Prevouts::All(&[prevout]),
+                leaf_hash,
+                SchnorrSighashType::Default,
+            )
+            .unwrap();
+        let msg = Message::from_slice(&sighash[..]).unwrap();
+        let control_block = taproot_control_block(&leaf_script).unwrap();
+
+        // the witness holds a signature (or an empty push) for each
+        // signatory, with the first signatory's on top of the stack
+        let mut accepted = |signers: &[usize]| {
+            let mut witness: Vec<Vec<u8>> = keys
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(i, key)| {
+                    if !signers.contains(&i) {
+                        return vec![];
+                    }
+                    let keypair = KeyPair::from_secret_key(&secp, key);
+                    secp.sign_schnorr_no_aux_rand(&msg, &keypair)[..].to_vec()
+                })
+                .collect();
+            witness.push(leaf_script.to_bytes());
+            witness.push(control_block.clone());
+            tx.input[0].witness = Witness::from_vec(witness);
+
+            client.test_mempool_accept(&[&tx]).unwrap()[0].allowed
+        };
+
+        // the threshold is 2/3 of 100 voting power
+        assert!(!accepted(&[0]));
+        assert!(!accepted(&[1, 2]));
+        assert!(accepted(&[0, 2]));
+        assert!(accepted(&[0, 1, 2]));
+    }
 
     // #[test]
     // #[should_panic(expected = "Cannot build script for empty signatory set")]
@@ -1,10 +1,13 @@
 use crate::app::{InnerApp, Nom};
 use crate::bitcoin::checkpoint::CheckpointStatus;
-use crate::bitcoin::threshold_sig::Signature;
+use crate::bitcoin::threshold_sig::{SigScheme, Signature};
 use crate::error::Result;
-use bitcoin::secp256k1::{Message, Secp256k1};
+use crate::metrics::metrics;
+use async_trait::async_trait;
+use bitcoin::secp256k1::{KeyPair, Message, Secp256k1};
 use bitcoin::util::bip32::{ChildNumber, ExtendedPrivKey, ExtendedPubKey};
-use log::info;
+use bitcoind::bitcoincore_rpc::{Client as BitcoinRpcClient, RpcApi};
+use log::{info, warn};
 use orga::client::{AppClient, Wallet};
 use orga::coins::Address;
 use orga::encoding::LengthVec;
@@ -14,14 +17,52 @@ use rand::Rng;
 use std::fs;
 use std::marker::PhantomData;
 use std::path::Path;
+use std::sync::Arc;
 use std::time::SystemTime;
 
+/// The confirmation target, in blocks, of the fee rate estimates reported
+/// when signing.
+const FEE_ESTIMATE_TARGET: u16 = 2;
+
+/// A signatory key which can sign checkpoints, either held in-process or by a
+/// [remote signer](super::remote_signer).
+#[async_trait]
+pub trait SigningKey: Send + Sync {
+    async fn xpub(&self) -> Result<ExtendedPubKey>;
+
+    /// Signs the messages returned by `to_sign` for the checkpoint at
+    /// `checkpoint_index`.
+    async fn sign(
+        &self,
+        checkpoint_index: u32,
+        to_sign: &[([u8; 32], u32, SigScheme)],
+    ) -> Result<LengthVec<u16, Signature>>;
+}
+
+#[async_trait]
+impl SigningKey for ExtendedPrivKey {
+    async fn xpub(&self) -> Result<ExtendedPubKey> {
+        let secp = Secp256k1::signing_only();
+        Ok(ExtendedPubKey::from_priv(&secp, self))
+    }
+
+    async fn sign(
+        &self,
+        _checkpoint_index: u32,
+        to_sign: &[([u8; 32], u32, SigScheme)],
+    ) -> Result<LengthVec<u16, Signature>> {
+        let secp = Secp256k1::signing_only();
+        sign(&secp, self, to_sign)
+    }
+}
+
 pub struct Signer<W, F> {
     op_addr: Address,
-    xpriv: ExtendedPrivKey,
+    key: Box<dyn SigningKey>,
     max_withdrawal_rate: f64,
     max_sigset_change_rate: f64,
     app_client: F,
+    btc_client: Option<Arc<BitcoinRpcClient>>,
     _phantom: PhantomData<W>,
 }
 
@@ -29,38 +70,17 @@ impl<W: Wallet, F> Signer<W, F>
 where
     F: Fn() -> AppClient<InnerApp, InnerApp, HttpClient, Nom, W>,
 {
+    /// Loads the signatory key at `key_path`, or generates one for `network`
+    /// if there is none.
     pub fn load_or_generate<P: AsRef<Path>>(
         op_addr: Address,
         key_path: P,
+        network: super::BitcoinNetwork,
         max_withdrawal_rate: f64,
         max_sigset_change_rate: f64,
         app_client: F,
     ) -> Result<Self> {
-        let path = key_path.as_ref();
-        let xpriv = if path.exists() {
-            info!("Loading signatory key from {}", path.display());
-            let bytes = fs::read(path)?;
-            let text = String::from_utf8(bytes).unwrap();
-            text.trim().parse()?
-        } else {
-            info!("Generating signatory key at {}", path.display());
-            let seed: [u8; 32] = rand::thread_rng().gen();
-
-            let network = if super::NETWORK == bitcoin::Network::Regtest {
-                bitcoin::Network::Testnet
-            } else {
-                super::NETWORK
-            };
-            let xpriv = ExtendedPrivKey::new_master(network, seed.as_slice())?;
-
-            fs::write(path, xpriv.to_string().as_bytes())?;
-
-            xpriv
-        };
-
-        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
-        let xpub = ExtendedPubKey::from_priv(&secp, &xpriv);
-        dbg!("Signatory xpub:\n{}", xpub);
+        let xpriv = load_or_generate_xpriv(key_path, network)?;
 
         Ok(Self::new(
             op_addr,
@@ -73,7 +93,7 @@ where
 
     pub fn new(
         op_addr: Address,
-        xpriv: ExtendedPrivKey,
+        key: impl SigningKey + 'static,
         max_withdrawal_rate: f64,
         max_sigset_change_rate: f64,
         app_client: F,
@@ -83,19 +103,26 @@ where
     {
         Signer {
             op_addr,
-            xpriv,
+            key: Box::new(key),
             max_withdrawal_rate,
             max_sigset_change_rate,
             app_client,
+            btc_client: None,
             _phantom: PhantomData,
         }
     }
 
+    /// Uses the given Bitcoin node to estimate fee rates, which are reported
+    /// along with signatures to set the fee rate of future checkpoints.
+    pub fn with_fee_estimator(mut self, btc_client: BitcoinRpcClient) -> Self {
+        self.btc_client = Some(Arc::new(btc_client));
+        self
+    }
+
     pub async fn start(mut self) -> Result<()> {
         const CHECKPOINT_WINDOW: u32 = 20;
         info!("Starting signer...");
-        let secp = Secp256k1::signing_only();
-        let xpub = ExtendedPubKey::from_priv(&secp, &self.xpriv);
+        let xpub = self.key.xpub().await?;
 
         let mut index = (self.app_client)()
             .query(|app| {
@@ -115,6 +142,7 @@ where
                 Ok(signed) => signed,
                 Err(e) => {
                     eprintln!("Signer error: {}", e);
+                    metrics().loop_error("signer");
                     false
                 }
             };
@@ -161,8 +189,6 @@ where
     }
 
     async fn try_sign(&mut self, xpub: &ExtendedPubKey, index: u32) -> Result<bool> {
-        let secp = Secp256k1::signing_only();
-
         let status = self
             .client()
             .query(|app| Ok(app.bitcoin.checkpoints.get(index)?.status))
@@ -184,75 +210,161 @@ where
         self.check_change_rates().await?;
         info!("Signing checkpoint ({} inputs)...", to_sign.len());
 
-        let sigs = sign(&secp, &self.xpriv, &to_sign)?;
+        let sigs = self.key.sign(index, &to_sign).await?;
+        let fee_rate = self.estimate_fee_rate().await;
 
         (self.app_client)()
             .call(
-                move |app| build_call!(app.bitcoin.sign(xpub.into(), sigs.clone(), index)),
+                move |app| {
+                    build_call!(app.bitcoin.sign(xpub.into(), sigs.clone(), index, fee_rate))
+                },
                 |app| build_call!(app.app_noop()),
             )
             .await?;
 
+        metrics().signatures_submitted.inc();
         info!("Submitted signatures");
 
         Ok(false)
     }
 
-    async fn check_change_rates(&self) -> Result<()> {
-        let checkpoint_index = (self.app_client)()
-            .query(|app| Ok(app.bitcoin.checkpoints.index()))
-            .await?;
-        if checkpoint_index < 100 {
-            return Ok(());
-        }
+    /// Returns the fee rate estimated by the Bitcoin node, in satoshis per
+    /// virtual byte, or `None` if there is no node or it has no estimate.
+    async fn estimate_fee_rate(&self) -> Option<u64> {
+        let btc_client = self.btc_client.clone()?;
+        // the RPC client blocks, so it is called off the async workers
+        let estimate = tokio::task::spawn_blocking(move || {
+            btc_client.estimate_smart_fee(FEE_ESTIMATE_TARGET, None)
+        })
+        .await;
+        let estimate = match estimate {
+            Ok(Ok(estimate)) => estimate,
+            Ok(Err(e)) => {
+                warn!("Fee estimation error: {}", e);
+                return None;
+            }
+            Err(e) => {
+                warn!("Fee estimation task failed: {}", e);
+                return None;
+            }
+        };
 
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs();
-        let rates = (self.app_client)()
-            .query(|app| Ok(app.bitcoin.change_rates(60 * 60 * 24, now)?))
-            .await?;
+        // estimates are given in BTC per kvB
+        estimate
+            .fee_rate
+            .map(|fee_rate| fee_rate.to_sat() / 1000)
+            .filter(|fee_rate| *fee_rate > 0)
+    }
 
-        let withdrawal_rate = rates.withdrawal as f64 / 10_000.0;
-        let sigset_change_rate = rates.sigset_change as f64 / 10_000.0;
+    async fn check_change_rates(&self) -> Result<()> {
+        check_change_rates(
+            &self.client(),
+            self.max_withdrawal_rate,
+            self.max_sigset_change_rate,
+        )
+        .await
+    }
+}
 
-        if withdrawal_rate > self.max_withdrawal_rate {
-            return Err(orga::Error::App(format!(
-                "Withdrawal rate of {} is above maximum of {}",
-                withdrawal_rate, self.max_withdrawal_rate
-            ))
-            .into());
-        }
+/// Loads the signatory key at `path`, or generates one for `network` if there
+/// is none.
+pub fn load_or_generate_xpriv<P: AsRef<Path>>(
+    path: P,
+    network: super::BitcoinNetwork,
+) -> Result<ExtendedPrivKey> {
+    let path = path.as_ref();
+    let xpriv = if path.exists() {
+        info!("Loading signatory key from {}", path.display());
+        let bytes = fs::read(path)?;
+        let text = String::from_utf8(bytes).unwrap();
+        text.trim().parse()?
+    } else {
+        info!("Generating signatory key at {}", path.display());
+        let seed: [u8; 32] = rand::thread_rng().gen();
+
+        let xpriv = ExtendedPrivKey::new_master(network.key_network(), seed.as_slice())?;
+
+        fs::write(path, xpriv.to_string().as_bytes())?;
+
+        xpriv
+    };
+
+    let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+    let xpub = ExtendedPubKey::from_priv(&secp, &xpriv);
+    dbg!("Signatory xpub:\n{}", xpub);
+
+    Ok(xpriv)
+}
 
-        if sigset_change_rate > self.max_sigset_change_rate {
-            return Err(orga::Error::App(format!(
-                "Signatory set change rate of {} is above maximum of {}",
-                sigset_change_rate, self.max_sigset_change_rate
-            ))
-            .into());
-        }
+/// Returns an error if the withdrawal or signatory set change rate of the
+/// checkpoint being signed is above the given maximum.
+pub async fn check_change_rates<W: Wallet>(
+    client: &AppClient<InnerApp, InnerApp, HttpClient, Nom, W>,
+    max_withdrawal_rate: f64,
+    max_sigset_change_rate: f64,
+) -> Result<()> {
+    let checkpoint_index = client
+        .query(|app| Ok(app.bitcoin.checkpoints.index()))
+        .await?;
+    if checkpoint_index < 100 {
+        return Ok(());
+    }
 
-        Ok(())
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let rates = client
+        .query(|app| Ok(app.bitcoin.change_rates(60 * 60 * 24, now)?))
+        .await?;
+
+    let withdrawal_rate = rates.withdrawal as f64 / 10_000.0;
+    let sigset_change_rate = rates.sigset_change as f64 / 10_000.0;
+
+    if withdrawal_rate > max_withdrawal_rate {
+        return Err(orga::Error::App(format!(
+            "Withdrawal rate of {} is above maximum of {}",
+            withdrawal_rate, max_withdrawal_rate
+        ))
+        .into());
     }
+
+    if sigset_change_rate > max_sigset_change_rate {
+        return Err(orga::Error::App(format!(
+            "Signatory set change rate of {} is above maximum of {}",
+            sigset_change_rate, max_sigset_change_rate
+        ))
+        .into());
+    }
+
+    Ok(())
 }
 
 pub fn sign(
     secp: &Secp256k1<bitcoin::secp256k1::SignOnly>,
     xpriv: &ExtendedPrivKey,
-    to_sign: &[([u8; 32], u32)],
+    to_sign: &[([u8; 32], u32, SigScheme)],
 ) -> Result<LengthVec<u16, Signature>> {
     Ok(to_sign
         .iter()
-        .map(|(msg, index)| {
+        .map(|(msg, index, scheme)| {
             let privkey = xpriv
                 .derive_priv(secp, &[ChildNumber::from_normal_idx(*index)?])?
                 .private_key;
+            let msg = Message::from_slice(&msg[..])?;
+
+            let sig = match scheme {
+                SigScheme::Ecdsa => secp.sign_ecdsa(&msg, &privkey).serialize_compact(),
+                SigScheme::Schnorr => {
+                    let keypair = KeyPair::from_secret_key(secp, &privkey);
+                    let schnorr_sig = secp.sign_schnorr_no_aux_rand(&msg, &keypair);
+                    let mut sig = [0; 64];
+                    sig.copy_from_slice(&schnorr_sig[..]);
+                    sig
+                }
+            };
 
-            Ok(secp
-                .sign_ecdsa(&Message::from_slice(&msg[..])?, &privkey)
-                .serialize_compact()
-                .into())
+            Ok(sig.into())
         })
         .collect::<Result<Vec<_>>>()?
         .try_into()?)
@@ -0,0 +1,197 @@
+//! The sources of Bitcoin chain data the relayer can read from.
+//!
+//! [`BitcoinSource`] is implemented for a shared Bitcoin Core RPC client, for a
+//! [`P2pClient`](super::p2p::P2pClient) connected to a peer, for an
+//! [`EsploraClient`](super::esplora::EsploraClient) and for an
+//! [`ElectrumClient`](super::electrum::ElectrumClient).
+
+use crate::error::{Error, Result};
+use async_trait::async_trait;
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::{Block, BlockHash, BlockHeader, MerkleBlock, Script, Transaction, Txid};
+use bitcoind::bitcoincore_rpc::{self, Client as BitcoinRpcClient, RpcApi};
+use futures::{StreamExt, TryStreamExt};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How many blocks are requested from a source at once.
+pub(crate) const BLOCK_FETCH_CONCURRENCY: usize = 16;
+
+/// A block header's position in the best chain known to a [`BitcoinSource`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HeaderInfo {
+    pub hash: BlockHash,
+    pub height: usize,
+    /// The number of blocks from this one to the tip, inclusive, or -1 if the
+    /// block is not in the best chain.
+    pub confirmations: i32,
+    pub previous_block_hash: Option<BlockHash>,
+    pub next_block_hash: Option<BlockHash>,
+}
+
+/// Where the relayer reads Bitcoin blocks from and broadcasts transactions
+/// to.
+#[async_trait]
+pub trait BitcoinSource: Send + Sync {
+    async fn best_block_hash(&self) -> Result<BlockHash>;
+
+    async fn header_info(&self, hash: &BlockHash) -> Result<HeaderInfo>;
+
+    async fn block_header(&self, hash: &BlockHash) -> Result<BlockHeader>;
+
+    async fn block(&self, hash: &BlockHash) -> Result<Block>;
+
+    /// Returns the transaction with the given id if it is in the block.
+    async fn transaction(
+        &self,
+        txid: &Txid,
+        block_hash: &BlockHash,
+    ) -> Result<Option<Transaction>> {
+        let block = self.block(block_hash).await?;
+
+        Ok(block.txdata.into_iter().find(|tx| tx.txid() == *txid))
+    }
+
+    /// Broadcasts the transaction. Errors for transactions which are already
+    /// confirmed or whose inputs are spent should keep the messages Bitcoin
+    /// Core uses for them, which the relayer ignores.
+    async fn send_raw_transaction(&self, tx: &Transaction) -> Result<()>;
+
+    /// Returns once the best block changes, or the timeout passes.
+    async fn wait_for_new_block(&self, timeout: Duration) -> Result<()> {
+        let start = Instant::now();
+        let tip = self.best_block_hash().await?;
+        while start.elapsed() < timeout {
+            tokio::time::sleep(Duration::from_secs(1).min(timeout)).await;
+            if self.best_block_hash().await? != tip {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the `n` blocks ending at `tip`, or fewer if the chain is
+    /// shorter, with their heights, in increasing height order. The headers
+    /// are walked back first so the blocks can be fetched concurrently.
+    async fn blocks(&self, n: usize, tip: &BlockHash) -> Result<Vec<(u32, Block)>> {
+        let tip_height = self.header_info(tip).await?.height;
+        let count = n.min(tip_height + 1);
+        let mut hashes = vec![];
+        let mut hash = *tip;
+        while hashes.len() < count {
+            hashes.push(hash);
+            if hashes.len() < count {
+                hash = self.block_header(&hash).await?.prev_blockhash;
+            }
+        }
+        hashes.reverse();
+
+        let base_height = tip_height + 1 - count;
+        futures::stream::iter(hashes.iter().enumerate())
+            .map(|(i, hash)| async move { Ok(((base_height + i) as u32, self.block(hash).await?)) })
+            .buffered(BLOCK_FETCH_CONCURRENCY)
+            .try_collect()
+            .await
+    }
+
+    /// Returns the blocks among the `n` ending at `tip` which may contain
+    /// outputs to `scripts`, with their heights, in increasing height order.
+    /// By default this is every block.
+    async fn relevant_blocks(
+        &self,
+        n: usize,
+        tip: &BlockHash,
+        _scripts: &[Script],
+    ) -> Result<Vec<(u32, Block)>> {
+        self.blocks(n, tip).await
+    }
+
+    /// Returns a merkle proof that the transaction is in the block.
+    async fn tx_out_proof(&self, txid: &Txid, block_hash: &BlockHash) -> Result<MerkleBlock> {
+        let block = self.block(block_hash).await?;
+        if !block.txdata.iter().any(|tx| tx.txid() == *txid) {
+            return Err(Error::Relayer(format!(
+                "Transaction {} is not in block {}",
+                txid, block_hash
+            )));
+        }
+
+        Ok(MerkleBlock::from_block_with_predicate(&block, |id| {
+            id == txid
+        }))
+    }
+}
+
+/// Runs a Bitcoin Core RPC call on tokio's blocking thread pool, since the
+/// client's requests block.
+async fn rpc<T: Send + 'static>(
+    client: &Arc<BitcoinRpcClient>,
+    op: impl FnOnce(&BitcoinRpcClient) -> bitcoincore_rpc::Result<T> + Send + 'static,
+) -> Result<T> {
+    let client = client.clone();
+    Ok(tokio::task::spawn_blocking(move || op(&client))
+        .await
+        .map_err(|err| Error::Relayer(format!("RPC task failed: {}", err)))??)
+}
+
+#[async_trait]
+impl BitcoinSource for Arc<BitcoinRpcClient> {
+    async fn best_block_hash(&self) -> Result<BlockHash> {
+        rpc(self, |client| client.get_best_block_hash()).await
+    }
+
+    async fn header_info(&self, hash: &BlockHash) -> Result<HeaderInfo> {
+        let hash = *hash;
+        let info = rpc(self, move |client| client.get_block_header_info(&hash)).await?;
+
+        Ok(HeaderInfo {
+            hash: info.hash,
+            height: info.height,
+            confirmations: info.confirmations,
+            previous_block_hash: info.previous_block_hash,
+            next_block_hash: info.next_block_hash,
+        })
+    }
+
+    async fn block_header(&self, hash: &BlockHash) -> Result<BlockHeader> {
+        let hash = *hash;
+        rpc(self, move |client| client.get_block_header(&hash)).await
+    }
+
+    async fn block(&self, hash: &BlockHash) -> Result<Block> {
+        let hash = *hash;
+        rpc(self, move |client| client.get_block(&hash)).await
+    }
+
+    async fn send_raw_transaction(&self, tx: &Transaction) -> Result<()> {
+        let mut tx_bytes = vec![];
+        tx.consensus_encode(&mut tx_bytes)?;
+        rpc(self, move |client| {
+            RpcApi::send_raw_transaction(client, tx_bytes.as_slice())
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn wait_for_new_block(&self, timeout: Duration) -> Result<()> {
+        let timeout = timeout.as_millis() as u64;
+        rpc(self, move |client| {
+            RpcApi::wait_for_new_block(client, timeout)
+        })
+        .await?;
+
+        Ok(())
+    }
+
+    async fn tx_out_proof(&self, txid: &Txid, block_hash: &BlockHash) -> Result<MerkleBlock> {
+        let (txid, block_hash) = (*txid, *block_hash);
+        let proof_bytes = rpc(self, move |client| {
+            client.get_tx_out_proof(&[txid], Some(&block_hash))
+        })
+        .await?;
+
+        Ok(MerkleBlock::consensus_decode(&mut proof_bytes.as_slice())?)
+    }
+}
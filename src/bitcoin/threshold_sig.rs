@@ -3,7 +3,7 @@ use bitcoin::blockdata::transaction::EcdsaSighashType;
 use bitcoin::secp256k1::{
     self,
     constants::{COMPACT_SIGNATURE_SIZE, MESSAGE_SIZE, PUBLIC_KEY_SIZE},
-    ecdsa, PublicKey, Secp256k1,
+    ecdsa, schnorr, PublicKey, Secp256k1,
 };
 use derive_more::{Deref, From};
 use orga::collections::{Map, Next};
@@ -15,10 +15,18 @@ use orga::query::FieldQuery;
 use orga::state::State;
 use orga::store::Store;
 use orga::{orga, Error, Result};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 pub type Message = [u8; MESSAGE_SIZE];
 
+/// The signature scheme signatories must use for a given input. P2WSH inputs
+/// are signed with ECDSA, taproot script-path inputs with Schnorr (BIP340).
+#[derive(Encode, Decode, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SigScheme {
+    Ecdsa,
+    Schnorr,
+}
+
 #[derive(Encode, Decode, State, Debug, Clone, Deref, From, Copy, Migrate, Serialize, Describe)]
 pub struct Signature(
     #[serde(serialize_with = "<[_]>::serialize")] pub [u8; COMPACT_SIGNATURE_SIZE],
@@ -214,7 +222,10 @@ impl ThresholdSig {
         self.message
     }
 
-    pub fn from_sigset(signatories: &SignatorySet) -> Result<Self> {
+    /// Creates a threshold signature for the signatory set, requiring more
+    /// than the given fraction of its voting power to sign. The fraction must
+    /// match the one used for the script being spent.
+    pub fn from_sigset(signatories: &SignatorySet, threshold: (u64, u64)) -> Result<Self> {
         let mut ts = ThresholdSig::default();
         let mut total_vp = 0;
 
@@ -231,26 +242,25 @@ impl ThresholdSig {
             total_vp += signatory.voting_power;
         }
 
-        // TODO: get threshold ratio from somewhere else
-        ts.threshold = ((total_vp as u128) * 9 / 10) as u64;
+        let (numerator, denominator) = threshold;
+        ts.threshold = ((total_vp as u128) * numerator as u128 / denominator as u128) as u64;
 
         Ok(ts)
     }
 
-    pub fn from_shares(shares: Vec<(Pubkey, Share)>) -> Result<Self> {
+    /// Creates an unsigned threshold signature from the shares of an existing
+    /// one, keeping its threshold of voting power.
+    pub fn from_shares(shares: Vec<(Pubkey, Share)>, threshold: u64) -> Result<Self> {
         let mut ts = ThresholdSig::default();
-        let mut total_vp = 0;
         let mut len = 0;
 
         for (pubkey, share) in shares.into_iter() {
             assert!(share.sig.is_none());
-            total_vp += share.power;
             len += 1;
             ts.sigs.insert(pubkey, share)?;
         }
 
-        // TODO: get threshold ratio from somewhere else
-        ts.threshold = ((total_vp as u128) * 9 / 10) as u64;
+        ts.threshold = threshold;
         ts.len = len;
 
         Ok(ts)
@@ -298,7 +308,7 @@ impl ThresholdSig {
     }
 
     // TODO: exempt from fee
-    pub fn sign(&mut self, pubkey: Pubkey, sig: Signature) -> Result<()> {
+    pub fn sign(&mut self, pubkey: Pubkey, sig: Signature, scheme: SigScheme) -> Result<()> {
         let share = self
             .sigs
             .get(pubkey)?
@@ -308,7 +318,7 @@ impl ThresholdSig {
             return Err(Error::App("Pubkey already signed".into()))?;
         }
 
-        self.verify(pubkey, sig)?;
+        self.verify(pubkey, sig, scheme)?;
 
         let mut share = self
             .sigs
@@ -321,22 +331,39 @@ impl ThresholdSig {
         Ok(())
     }
 
-    pub fn verify(&self, pubkey: Pubkey, sig: Signature) -> crate::error::Result<()> {
+    pub fn verify(
+        &self,
+        pubkey: Pubkey,
+        sig: Signature,
+        scheme: SigScheme,
+    ) -> crate::error::Result<()> {
         // TODO: re-use secp context
         let secp = Secp256k1::verification_only();
         let pubkey = PublicKey::from_slice(&pubkey.bytes)?;
         let msg = secp256k1::Message::from_slice(self.message.as_slice())?;
-        let sig = ecdsa::Signature::from_compact(sig.as_slice())?;
 
-        #[cfg(not(fuzzing))]
-        secp.verify_ecdsa(&msg, &sig, &pubkey)?;
+        match scheme {
+            SigScheme::Ecdsa => {
+                let sig = ecdsa::Signature::from_compact(sig.as_slice())?;
+
+                #[cfg(not(fuzzing))]
+                secp.verify_ecdsa(&msg, &sig, &pubkey)?;
+            }
+            SigScheme::Schnorr => {
+                let sig = schnorr::Signature::from_slice(sig.as_slice())?;
+                let (pubkey, _) = pubkey.x_only_public_key();
+
+                #[cfg(not(fuzzing))]
+                secp.verify_schnorr(&sig, &msg, &pubkey)?;
+            }
+        }
 
         Ok(())
     }
 
     // TODO: this shouldn't know so much about bitcoin-specific structure,
     // decouple by exposing a power-ordered iterator of Option<Signature>
-    pub fn to_witness(&self) -> crate::error::Result<Vec<Vec<u8>>> {
+    pub fn to_witness(&self, scheme: SigScheme) -> crate::error::Result<Vec<Vec<u8>>> {
         if !self.signed() {
             return Ok(vec![]);
         }
@@ -347,11 +374,15 @@ impl ThresholdSig {
         entries
             .into_iter()
             .map(|(_, share)| {
-                share.sig.map_or(Ok(vec![]), |sig| {
-                    let sig = ecdsa::Signature::from_compact(sig.as_slice())?;
-                    let mut v = sig.serialize_der().to_vec();
-                    v.push(EcdsaSighashType::All.to_u32() as u8);
-                    Ok(v)
+                share.sig.map_or(Ok(vec![]), |sig| match scheme {
+                    SigScheme::Ecdsa => {
+                        let sig = ecdsa::Signature::from_compact(sig.as_slice())?;
+                        let mut v = sig.serialize_der().to_vec();
+                        v.push(EcdsaSighashType::All.to_u32() as u8);
+                        Ok(v)
+                    }
+                    // SIGHASH_DEFAULT, so no sighash type byte is appended
+                    SigScheme::Schnorr => Ok(sig.to_vec()),
                 })
             })
             .collect()
@@ -0,0 +1,351 @@
+//! The relayer's store of the deposit addresses it watches, which clients
+//! announce to its address server.
+//!
+//! Addresses are kept in the `addrs` tree of an embedded
+//! [sled](https://docs.rs/sled) database, keyed by signatory set index then
+//! encoded destination and holding the address's deposit scripts, so a
+//! signatory set's addresses can be deleted once its deposits expire.
+//!
+//! Watch lists are exported and imported as CSV lines of
+//! `base64(dest),sigset_index`, the format of the `watched-addrs.csv` file
+//! kept by earlier relayers, which is imported into the store when found.
+
+use super::relayer::WatchedScripts;
+use super::signatory::SignatorySet;
+use crate::app::Dest;
+use crate::app_client;
+use crate::error::{Error, Result};
+use bitcoin::consensus::{serialize, Decodable};
+use bitcoin::Script;
+use log::info;
+use orga::encoding::{Decode, Encode};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+/// The name of the store's directory in the relayer home.
+pub const DIR_NAME: &str = "watched-addrs";
+
+/// The watch list file kept by earlier relayers in the relayer home.
+const LEGACY_FILE_NAME: &str = "watched-addrs.csv";
+
+/// The signatory sets on the sidechain with their thresholds, by index.
+type Sigsets = BTreeMap<u32, (SignatorySet, (u64, u64))>;
+
+async fn fetch_sigsets(app_client_addr: &str) -> Result<Sigsets> {
+    let mut sigsets = BTreeMap::new();
+    app_client(app_client_addr)
+        .query(|app| {
+            for (index, checkpoint) in app.bitcoin.checkpoints.all()? {
+                sigsets.insert(
+                    index,
+                    (checkpoint.sigset.clone(), checkpoint.sigset_threshold),
+                );
+            }
+            Ok(())
+        })
+        .await?;
+
+    Ok(sigsets)
+}
+
+fn db_error(err: sled::Error) -> Error {
+    Error::Relayer(format!("Watched address store error: {}", err))
+}
+
+fn addr_key(sigset_index: u32, dest: &Dest) -> Result<Vec<u8>> {
+    let mut key = sigset_index.to_be_bytes().to_vec();
+    key.extend(dest.encode()?);
+
+    Ok(key)
+}
+
+fn decode_addr_key(key: &[u8]) -> Result<(u32, Dest)> {
+    if key.len() < 4 {
+        return Err(Error::Relayer("Invalid watched address key".to_string()));
+    }
+    let sigset_index = u32::from_be_bytes(key[..4].try_into().unwrap());
+
+    Ok((sigset_index, Dest::decode(&key[4..])?))
+}
+
+fn decode_scripts(mut bytes: &[u8]) -> Result<Vec<Script>> {
+    let mut scripts = vec![];
+    while !bytes.is_empty() {
+        scripts.push(Script::consensus_decode(&mut bytes)?);
+    }
+
+    Ok(scripts)
+}
+
+pub struct WatchedScriptStore {
+    scripts: WatchedScripts,
+    db: sled::Db,
+    addrs: sled::Tree,
+}
+
+impl WatchedScriptStore {
+    /// Opens the store in the relayer home `path`, loading the addresses of
+    /// the signatory sets still on the sidechain.
+    pub async fn open<P: AsRef<Path>>(path: P, app_client_addr: &str) -> Result<Self> {
+        let path = path.as_ref();
+        let sigsets = fetch_sigsets(app_client_addr).await?;
+        let mut store = Self::open_db(path)?;
+
+        let legacy_path = path.join(LEGACY_FILE_NAME);
+        if legacy_path.exists() {
+            let file = BufReader::new(File::open(&legacy_path)?);
+            let count = store.import_csv(file, &sigsets)?;
+            std::fs::rename(&legacy_path, legacy_path.with_extension("csv.imported"))?;
+            info!(
+                "Imported {} deposit addresses from {}",
+                count,
+                legacy_path.display()
+            );
+        }
+
+        store.load(&sigsets)?;
+        info!(
+            "Keeping track of deposit addresses at {}",
+            path.join(DIR_NAME).display()
+        );
+
+        Ok(store)
+    }
+
+    fn open_db(path: &Path) -> Result<Self> {
+        let db = sled::open(path.join(DIR_NAME)).map_err(db_error)?;
+        let addrs = db.open_tree("addrs").map_err(db_error)?;
+
+        Ok(WatchedScriptStore {
+            scripts: WatchedScripts::new(),
+            db,
+            addrs,
+        })
+    }
+
+    /// Loads the stored addresses into memory, deleting those of signatory
+    /// sets which are no longer in `sigsets` or whose deposits expired.
+    fn load(&mut self, sigsets: &Sigsets) -> Result<()> {
+        let mut pruned = vec![];
+        for entry in self.addrs.iter() {
+            let (key, value) = entry.map_err(db_error)?;
+            let (sigset_index, dest) = decode_addr_key(&key)?;
+            match sigsets.get(&sigset_index) {
+                Some((sigset, threshold)) => {
+                    let scripts = decode_scripts(&value)?;
+                    self.scripts
+                        .insert_scripts(dest, sigset, *threshold, scripts);
+                }
+                None => pruned.push(sigset_index),
+            }
+        }
+
+        pruned.dedup();
+        for sigset_index in pruned {
+            self.delete_sigset(sigset_index)?;
+        }
+        self.remove_expired()?;
+
+        info!("Loaded {} deposit addresses", self.scripts.len());
+
+        Ok(())
+    }
+
+    /// The watched deposit scripts.
+    pub fn watched(&self) -> &WatchedScripts {
+        &self.scripts
+    }
+
+    /// Watches the destination's deposit scripts, returning them if they were
+    /// not already watched.
+    pub fn insert(
+        &mut self,
+        dest: Dest,
+        sigset: &SignatorySet,
+        threshold: (u64, u64),
+    ) -> Result<Vec<Script>> {
+        let scripts = self.scripts.derive_scripts(&dest, sigset, threshold)?;
+        if self.scripts.has(&scripts[0]) {
+            return Ok(vec![]);
+        }
+
+        let key = addr_key(sigset.index(), &dest)?;
+        let value: Vec<u8> = scripts.iter().flat_map(serialize).collect();
+        self.addrs.insert(key, value).map_err(db_error)?;
+        self.db.flush().map_err(db_error)?;
+
+        self.scripts
+            .insert_scripts(dest, sigset, threshold, scripts.clone());
+
+        Ok(scripts.to_vec())
+    }
+
+    /// Stops watching the addresses of signatory sets whose deposits expired,
+    /// deleting them from the store.
+    pub fn remove_expired(&mut self) -> Result<()> {
+        for sigset_index in self.scripts.remove_expired()? {
+            self.delete_sigset(sigset_index)?;
+        }
+
+        Ok(())
+    }
+
+    fn delete_sigset(&self, sigset_index: u32) -> Result<()> {
+        let entries = self
+            .addrs
+            .scan_prefix(sigset_index.to_be_bytes())
+            .collect::<sled::Result<Vec<_>>>()
+            .map_err(db_error)?;
+
+        let mut batch = sled::Batch::default();
+        for (key, _) in entries.iter() {
+            batch.remove(key.clone());
+        }
+        self.addrs.apply_batch(batch).map_err(db_error)?;
+        self.db.flush().map_err(db_error)?;
+
+        info!(
+            "Deleted {} expired deposit addresses of signatory set {}",
+            entries.len(),
+            sigset_index
+        );
+
+        Ok(())
+    }
+
+    /// Watches the addresses in a CSV watch list, skipping those of signatory
+    /// sets not in `sigsets`, and returns how many were added.
+    fn import_csv(&mut self, reader: impl BufRead, sigsets: &Sigsets) -> Result<usize> {
+        let mut count = 0;
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid = || Error::Relayer(format!("Invalid watch list line: {}", line));
+            let (dest, sigset_index) = line.split_once(',').ok_or_else(invalid)?;
+            let sigset_index: u32 = sigset_index.trim().parse().map_err(|_| invalid())?;
+            let (sigset, threshold) = match sigsets.get(&sigset_index) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let dest = Dest::from_base64(dest.trim())?;
+
+            if !self.insert(dest, sigset, *threshold)?.is_empty() {
+                count += 1;
+            }
+        }
+        self.remove_expired()?;
+
+        Ok(count)
+    }
+
+    /// Writes the watch list of the store in the relayer home `path` to
+    /// `writer`, returning how many addresses were written. The store can't
+    /// be opened while a relayer is using it.
+    pub fn export<P: AsRef<Path>>(path: P, mut writer: impl Write) -> Result<usize> {
+        let store = Self::open_db(path.as_ref())?;
+
+        let mut count = 0;
+        for entry in store.addrs.iter() {
+            let (key, _) = entry.map_err(db_error)?;
+            let (sigset_index, dest) = decode_addr_key(&key)?;
+            writeln!(writer, "{},{}", dest.to_base64()?, sigset_index)?;
+            count += 1;
+        }
+        writer.flush()?;
+
+        Ok(count)
+    }
+
+    /// Adds the addresses in the watch list read from `reader` to the store
+    /// in the relayer home `path`, skipping those whose deposits expired,
+    /// and returns how many were added. The store can't be opened while a
+    /// relayer is using it.
+    pub async fn import<P: AsRef<Path>>(
+        path: P,
+        reader: impl BufRead,
+        app_client_addr: &str,
+    ) -> Result<usize> {
+        let sigsets = fetch_sigsets(app_client_addr).await?;
+        let mut store = Self::open_db(path.as_ref())?;
+        store.load(&sigsets)?;
+
+        store.import_csv(reader, &sigsets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitcoin::signatory::Signatory;
+    use crate::utils::time_now;
+    use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use orga::coins::Address;
+
+    fn sigset(index: u32, create_time: u64) -> SignatorySet {
+        let secp = Secp256k1::new();
+        SignatorySet {
+            create_time,
+            present_vp: 100,
+            possible_vp: 100,
+            index,
+            signatories: (1..=2u8)
+                .map(|i| {
+                    let privkey = SecretKey::from_slice(&[i; 32]).unwrap();
+                    Signatory {
+                        voting_power: 50,
+                        pubkey: PublicKey::from_secret_key(&secp, &privkey).into(),
+                    }
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn expiry_and_export() {
+        let home = tempfile::tempdir().unwrap();
+        let mut sigsets = Sigsets::new();
+        sigsets.insert(0, (sigset(0, 0), (2, 3)));
+        sigsets.insert(1, (sigset(1, time_now()), (2, 3)));
+        let dest = |i| Dest::Address(Address::from_pubkey([i; 33]));
+
+        let mut store = WatchedScriptStore::open_db(home.path()).unwrap();
+        for (index, (sigset, threshold)) in sigsets.iter() {
+            for i in 0..2 {
+                let scripts = store.insert(dest(i), sigset, *threshold).unwrap();
+                assert_eq!(scripts.len(), 2);
+            }
+            assert!(store
+                .insert(dest(0), sigset, *threshold)
+                .unwrap()
+                .is_empty());
+            assert_eq!(store.addrs.scan_prefix(index.to_be_bytes()).count(), 2);
+        }
+        assert_eq!(store.watched().len(), 4);
+
+        store.remove_expired().unwrap();
+        assert_eq!(store.watched().len(), 2);
+        assert_eq!(store.addrs.len(), 2);
+        drop(store);
+
+        let mut store = WatchedScriptStore::open_db(home.path()).unwrap();
+        store.load(&sigsets).unwrap();
+        assert_eq!(store.watched().len(), 2);
+        drop(store);
+
+        let mut csv = vec![];
+        assert_eq!(
+            WatchedScriptStore::export(home.path(), &mut csv).unwrap(),
+            2
+        );
+
+        let other_home = tempfile::tempdir().unwrap();
+        let mut store = WatchedScriptStore::open_db(other_home.path()).unwrap();
+        assert_eq!(store.import_csv(csv.as_slice(), &sigsets).unwrap(), 2);
+        assert_eq!(store.watched().len(), 2);
+    }
+}
@@ -0,0 +1,310 @@
+use super::adapter::Adapter;
+use bitcoin::hashes::Hash;
+use bitcoin::{Script, TxOut, Txid};
+use orga::coins::Address;
+use orga::collections::Map;
+use orga::{orga, Error, Result};
+
+/// A withdrawal from nBTC to a Bitcoin output, recorded against the account
+/// which requested it.
+#[orga]
+#[derive(Clone)]
+pub struct Withdrawal {
+    pub owner: Address,
+    pub script_pubkey: Adapter<Script>,
+    /// The amount of nBTC burned for the withdrawal, in micro-satoshis.
+    pub amount: u64,
+    /// The value of the Bitcoin output, in satoshis, after the miner fee.
+    pub value: u64,
+    /// The index of the checkpoint the output is in. This moves forward if
+    /// the output is carried over because its checkpoint had too many outputs.
+    pub checkpoint_index: u32,
+    /// The index of the output in the checkpoint transaction, set once the
+    /// checkpoint is no longer building.
+    pub output_index: Option<u32>,
+    txid: Option<[u8; 32]>,
+    pub cancelled: bool,
+}
+
+impl Withdrawal {
+    pub fn new(
+        owner: Address,
+        script_pubkey: Adapter<Script>,
+        amount: u64,
+        value: u64,
+        checkpoint_index: u32,
+    ) -> Self {
+        Withdrawal {
+            owner,
+            script_pubkey,
+            amount,
+            value,
+            checkpoint_index,
+            output_index: None,
+            txid: None,
+            cancelled: false,
+        }
+    }
+
+    pub fn to_txout(&self) -> TxOut {
+        TxOut {
+            script_pubkey: self.script_pubkey.clone().into_inner(),
+            value: self.value,
+        }
+    }
+
+    /// The id of the checkpoint transaction paying out the withdrawal, if its
+    /// checkpoint is no longer building.
+    pub fn txid(&self) -> Option<Txid> {
+        self.txid.map(Txid::from_inner)
+    }
+}
+
+/// Where a withdrawal stands in its checkpoint's lifecycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WithdrawalStatus {
+    /// The output is in the building checkpoint and can still be cancelled.
+    Pending,
+    Cancelled,
+    /// The checkpoint paying out the withdrawal is being signed.
+    Signing,
+    /// The checkpoint has been signed and can be broadcast, but has not yet
+    /// been confirmed on the Bitcoin chain.
+    Signed,
+    Confirmed,
+}
+
+#[derive(Clone)]
+pub struct WithdrawalInfo {
+    pub id: u64,
+    pub withdrawal: Withdrawal,
+    pub status: WithdrawalStatus,
+}
+
+/// The record of withdrawals, indexed by id, by owner, and by the
+/// checkpoint they are waiting to be indexed in.
+#[orga]
+pub struct Withdrawals {
+    next_id: u64,
+    records: Map<u64, Withdrawal>,
+    by_owner: Map<(Address, u64), ()>,
+    by_checkpoint: Map<(u32, u64), ()>,
+}
+
+#[orga]
+impl Withdrawals {
+    /// Records a new withdrawal, returning its id.
+    pub fn insert(&mut self, withdrawal: Withdrawal) -> Result<u64> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        self.by_owner.insert((withdrawal.owner, id), ())?;
+        self.by_checkpoint
+            .insert((withdrawal.checkpoint_index, id), ())?;
+        self.records.insert(id, withdrawal)?;
+
+        Ok(id)
+    }
+
+    #[query]
+    pub fn get(&self, id: u64) -> Result<Option<Withdrawal>> {
+        Ok(self.records.get(id)?.map(|withdrawal| withdrawal.clone()))
+    }
+
+    pub fn set_cancelled(&mut self, id: u64) -> Result<()> {
+        let mut withdrawal = self
+            .records
+            .get_mut(id)?
+            .ok_or_else(|| Error::App("Withdrawal not found".to_string()))?;
+        withdrawal.cancelled = true;
+        self.by_checkpoint
+            .remove((withdrawal.checkpoint_index, id))?;
+
+        Ok(())
+    }
+
+    /// Removes the records of the oldest withdrawals, up to `limit`, which
+    /// were paid out or cancelled before the checkpoint at `checkpoint_index`,
+    /// stopping at the first which wasn't. Returns how many were removed.
+    pub fn prune(&mut self, checkpoint_index: u32, limit: usize) -> Result<usize> {
+        let pruned = self
+            .records
+            .iter()?
+            .map(|entry| {
+                entry.map(|(id, withdrawal)| {
+                    let settled = withdrawal.txid.is_some() || withdrawal.cancelled;
+                    let prunable = settled && withdrawal.checkpoint_index < checkpoint_index;
+                    (*id, withdrawal.owner, prunable)
+                })
+            })
+            .take_while(|entry| entry.as_ref().map_or(true, |(_, _, prunable)| *prunable))
+            .take(limit)
+            .collect::<Result<Vec<_>>>()?;
+
+        for (id, owner, _) in pruned.iter() {
+            self.records.remove(*id)?;
+            self.by_owner.remove((*owner, *id))?;
+        }
+
+        Ok(pruned.len())
+    }
+
+    /// Returns the ids of all withdrawals requested by the given owner, in the
+    /// order they were made.
+    #[query]
+    pub fn ids_by_owner(&self, owner: Address) -> Result<Vec<u64>> {
+        self.by_owner
+            .range((owner, 0)..=(owner, u64::MAX))?
+            .map(|entry| entry.map(|(key, _)| key.1))
+            .collect()
+    }
+
+    /// Records the position of each withdrawal in the transaction of a
+    /// checkpoint which has just stopped building. Withdrawals whose outputs
+    /// are not found were carried over to the next checkpoint, and are
+    /// indexed again when that checkpoint stops building.
+    pub fn index_checkpoint(
+        &mut self,
+        checkpoint_index: u32,
+        txid: Txid,
+        outputs: &[TxOut],
+    ) -> Result<()> {
+        let ids = self
+            .by_checkpoint
+            .range((checkpoint_index, 0)..=(checkpoint_index, u64::MAX))?
+            .map(|entry| entry.map(|(key, _)| key.1))
+            .collect::<Result<Vec<_>>>()?;
+
+        // outputs carried over from an earlier checkpoint aren't in the order
+        // their withdrawals were made, so each withdrawal is matched against
+        // every output not yet matched. The reserve output is always first.
+        let mut matched = vec![false; outputs.len()];
+        if let Some(reserve) = matched.first_mut() {
+            *reserve = true;
+        }
+        for id in ids {
+            self.by_checkpoint.remove((checkpoint_index, id))?;
+
+            let mut withdrawal = self
+                .records
+                .get_mut(id)?
+                .ok_or_else(|| Error::App("Withdrawal not found".to_string()))?;
+            let txout = withdrawal.to_txout();
+
+            match outputs
+                .iter()
+                .zip(matched.iter())
+                .position(|(output, matched)| !matched && *output == txout)
+            {
+                Some(output_index) => {
+                    matched[output_index] = true;
+                    withdrawal.output_index = Some(output_index as u32);
+                    withdrawal.txid = Some(txid.into_inner());
+                }
+                None => {
+                    withdrawal.checkpoint_index = checkpoint_index + 1;
+                    self.by_checkpoint.insert((checkpoint_index + 1, id), ())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn withdrawal(value: u64, checkpoint_index: u32) -> Withdrawal {
+        Withdrawal::new(
+            Address::NULL,
+            Adapter::new(Script::new()),
+            value * 1_000_000,
+            value,
+            checkpoint_index,
+        )
+    }
+
+    #[test]
+    fn index_checkpoint() {
+        let mut withdrawals = Withdrawals::default();
+        let a = withdrawals.insert(withdrawal(100, 0)).unwrap();
+        let b = withdrawals.insert(withdrawal(200, 0)).unwrap();
+        let c = withdrawals.insert(withdrawal(100, 0)).unwrap();
+        let d = withdrawals.insert(withdrawal(300, 0)).unwrap();
+        withdrawals.set_cancelled(b).unwrap();
+
+        let txout = |value| TxOut {
+            value,
+            script_pubkey: Script::new(),
+        };
+        // reserve output, then the outputs of a and c, with d carried over
+        let outputs = vec![txout(5000), txout(100), txout(100)];
+        withdrawals
+            .index_checkpoint(0, Txid::all_zeros(), &outputs)
+            .unwrap();
+
+        let get = |id| withdrawals.get(id).unwrap().unwrap();
+        assert_eq!(get(a).output_index, Some(1));
+        assert_eq!(get(a).txid(), Some(Txid::all_zeros()));
+        assert_eq!(get(b).output_index, None);
+        assert_eq!(get(c).output_index, Some(2));
+        assert_eq!(get(d).output_index, None);
+        assert_eq!(get(d).checkpoint_index, 1);
+
+        let outputs = vec![txout(5000), txout(300)];
+        withdrawals
+            .index_checkpoint(1, Txid::all_zeros(), &outputs)
+            .unwrap();
+        assert_eq!(withdrawals.get(d).unwrap().unwrap().output_index, Some(1));
+        assert_eq!(
+            withdrawals.ids_by_owner(Address::NULL).unwrap(),
+            vec![a, b, c, d]
+        );
+
+        // d was only paid out in checkpoint 1
+        assert_eq!(withdrawals.prune(1, 100).unwrap(), 3);
+        assert!(withdrawals.get(a).unwrap().is_none());
+        assert_eq!(withdrawals.ids_by_owner(Address::NULL).unwrap(), vec![d]);
+        assert_eq!(withdrawals.prune(1, 100).unwrap(), 0);
+        assert_eq!(withdrawals.prune(2, 100).unwrap(), 1);
+        assert!(withdrawals.ids_by_owner(Address::NULL).unwrap().is_empty());
+    }
+
+    #[test]
+    fn index_carried_over_checkpoint() {
+        let mut withdrawals = Withdrawals::default();
+        let a = withdrawals.insert(withdrawal(100, 0)).unwrap();
+        let b = withdrawals.insert(withdrawal(200, 0)).unwrap();
+        let c = withdrawals.insert(withdrawal(300, 0)).unwrap();
+        let d = withdrawals.insert(withdrawal(400, 0)).unwrap();
+
+        let txout = |value| TxOut {
+            value,
+            script_pubkey: Script::new(),
+        };
+        // b, c and d are carried over, and are popped off the end of the
+        // transaction so they are pushed onto the next one in reverse
+        let outputs = vec![txout(5000), txout(100)];
+        withdrawals
+            .index_checkpoint(0, Txid::all_zeros(), &outputs)
+            .unwrap();
+        let e = withdrawals.insert(withdrawal(500, 1)).unwrap();
+
+        let txid = Txid::from_inner([1; 32]);
+        let outputs = vec![txout(4000), txout(400), txout(300), txout(200), txout(500)];
+        withdrawals.index_checkpoint(1, txid, &outputs).unwrap();
+
+        let get = |id| withdrawals.get(id).unwrap().unwrap();
+        assert_eq!(get(a).output_index, Some(1));
+        assert_eq!(get(b).output_index, Some(3));
+        assert_eq!(get(c).output_index, Some(2));
+        assert_eq!(get(d).output_index, Some(1));
+        assert_eq!(get(e).output_index, Some(4));
+        for id in [b, c, d, e] {
+            assert_eq!(get(id).checkpoint_index, 1);
+            assert_eq!(get(id).txid(), Some(txid));
+        }
+    }
+}
@@ -52,6 +52,54 @@ pub struct Cosmos {
     pub chains: Map<ClientId, Chain>,
 }
 
+/// The nBTC held in the escrow accounts of the transfer channels on the given
+/// IBC client's connections, in micro-satoshis.
+pub fn client_escrowed_nbtc(ibc: &Ibc, client_id: &ClientId) -> Result<u64> {
+    let mut total_usats = 0;
+    let connection_ids = ibc.ctx.query_client_connections(client_id.clone())?;
+    for connection_id in connection_ids {
+        let channels = ibc.ctx.query_connection_channels(connection_id.clone())?;
+        for channel in channels {
+            if channel.port_id != ibc.transfer().get_port().unwrap().to_string() {
+                continue;
+            }
+            let port_id: PortId = channel
+                .port_id
+                .parse()
+                .map_err(|_| crate::error::Error::Ibc("Invalid port".to_string()))?;
+            let channel_id = channel
+                .channel_id
+                .parse()
+                .map_err(|_| crate::error::Error::Ibc("Invalid channel id".to_string()))?;
+
+            let escrow_address = ibc
+                .transfer()
+                .get_escrow_account(&port_id, &channel_id)
+                .map_err(|e| crate::error::Error::Ibc(e.to_string()))?;
+            let balance: u64 = ibc
+                .transfer()
+                .symbol_balance::<Nbtc>(escrow_address)
+                .map_err(|e| crate::error::Error::Ibc(e.to_string()))?
+                .into();
+            total_usats += balance;
+        }
+    }
+
+    Ok(total_usats)
+}
+
+/// The nBTC held in the escrow accounts of every transfer channel, in
+/// micro-satoshis.
+pub fn escrowed_nbtc(ibc: &Ibc) -> Result<u64> {
+    let mut total_usats = 0;
+    for entry in ibc.ctx.clients.iter()? {
+        let (client_id, _) = entry?;
+        total_usats += client_escrowed_nbtc(ibc, &client_id)?;
+    }
+
+    Ok(total_usats)
+}
+
 #[orga]
 impl Cosmos {
     #[query]
@@ -82,35 +130,7 @@ impl Cosmos {
             if !sigset.has_quorum() {
                 continue;
             }
-            let mut total_usats = 0;
-            let connection_ids = ibc.ctx.query_client_connections(client_id.clone())?;
-            for connection_id in connection_ids {
-                let channels = ibc.ctx.query_connection_channels(connection_id.clone())?;
-                for channel in channels {
-                    if channel.port_id != ibc.transfer().get_port().unwrap().to_string() {
-                        continue;
-                    }
-                    let port_id: PortId = channel
-                        .port_id
-                        .parse()
-                        .map_err(|_| crate::error::Error::Ibc("Invalid port".to_string()))?;
-                    let channel_id = channel
-                        .channel_id
-                        .parse()
-                        .map_err(|_| crate::error::Error::Ibc("Invalid channel id".to_string()))?;
-
-                    let escrow_address = ibc
-                        .transfer()
-                        .get_escrow_account(&port_id, &channel_id)
-                        .map_err(|e| crate::error::Error::Ibc(e.to_string()))?;
-                    let balance: u64 = ibc
-                        .transfer()
-                        .symbol_balance::<Nbtc>(escrow_address)
-                        .map_err(|e| crate::error::Error::Ibc(e.to_string()))?
-                        .into();
-                    total_usats += balance;
-                }
-            }
+            let total_usats = client_escrowed_nbtc(ibc, &client_id)?;
             outputs.push(bitcoin::TxOut {
                 value: total_usats / 1_000_000,
                 script_pubkey: sigset.output_script(&[0], RECOVERY_THRESHOLD)?,
@@ -0,0 +1,575 @@
+use crate::app::Nom;
+use crate::bitcoin::Bitcoin;
+use crate::error::{Error, Result};
+use orga::call::Call;
+use orga::coins::{Address, Staking};
+use orga::collections::Map;
+use orga::describe::Describe;
+use orga::encoding::{Decode, Encode};
+use orga::migrate::Migrate;
+use orga::orga;
+use orga::query::Query;
+use orga::state::State;
+use orga::store::Store;
+use orga::{Error as OrgaError, Result as OrgaResult};
+use serde::{Deserialize, Serialize};
+
+/// Seconds a parameter change proposal is open for voting.
+pub const VOTING_PERIOD: u64 = 60 * 60 * 24 * 3;
+/// Seconds between a proposal passing and its change being applied.
+pub const EXECUTION_DELAY: u64 = 60 * 60 * 24 * 2;
+/// Fraction of all staked tokens which must vote for a proposal to be valid.
+pub const QUORUM: (u64, u64) = (1, 3);
+/// Fraction of the voting stake which must approve for a proposal to pass.
+pub const PASS_THRESHOLD: (u64, u64) = (2, 3);
+/// Staked tokens, in micro-NOM, an address must have to make a proposal.
+pub const MIN_PROPOSER_STAKE: u64 = 1_000 * 1_000_000;
+/// The most proposals which can be open for voting at once.
+pub const MAX_OPEN_PROPOSALS: u64 = 16;
+/// The most proposals a single address can have open for voting at once.
+pub const MAX_OPEN_PROPOSALS_PER_PROPOSER: u64 = 2;
+/// Staked tokens, in micro-NOM, an address must have to vote on a proposal.
+/// Filling a proposal's [`MAX_VOTES`] takes at least 100,000 NOM staked, which
+/// is locked for the unbonding period if it is unstaked.
+pub const MIN_VOTER_STAKE: u64 = 100 * 1_000_000;
+/// The most addresses which can vote on a proposal. Each voter's stake is
+/// weighed when voting ends.
+pub const MAX_VOTES: u64 = 1_000;
+
+/// A change to a single bridge parameter, in the `bitcoin`, `checkpoint` or
+/// `header_queue` configuration, or to whether the bridge is paused.
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ParamChange {
+    MinDepositAmount(u64),
+    MinWithdrawalAmount(u64),
+    TransferFee(u64),
+    MinConfirmations(u32),
+    MaxOfflineCheckpoints(u32),
+    MinCheckpointConfirmations(u32),
+    CapacityLimit(u64),
+    MinCheckpointInterval(u64),
+    MaxCheckpointInterval(u64),
+    MaxInputs(u64),
+    MaxOutputs(u64),
+    TargetCheckpointInclusion(u32),
+    MinFeeRate(u64),
+    MaxFeeRate(u64),
+    SigsetThreshold(u64, u64),
+    EmergencyDisbursalLockTimeInterval(u32),
+    TaprootOutputs(bool),
+    HeaderQueueMaxLength(u64),
+    BridgePaused(bool),
+}
+
+impl ParamChange {
+    /// Parses a change from a parameter name, as it appears in the config
+    /// structs, and a value. Thresholds are given as `numerator/denominator`.
+    pub fn parse(name: &str, value: &str) -> Result<Self> {
+        use ParamChange::*;
+
+        fn num<T: std::str::FromStr>(value: &str) -> Result<T> {
+            value
+                .parse()
+                .map_err(|_| Error::Orga(OrgaError::App(format!("Invalid value: {}", value))))
+        }
+
+        Ok(match name {
+            "min_deposit_amount" => MinDepositAmount(num(value)?),
+            "min_withdrawal_amount" => MinWithdrawalAmount(num(value)?),
+            "transfer_fee" => TransferFee(num(value)?),
+            "min_confirmations" => MinConfirmations(num(value)?),
+            "max_offline_checkpoints" => MaxOfflineCheckpoints(num(value)?),
+            "min_checkpoint_confirmations" => MinCheckpointConfirmations(num(value)?),
+            "capacity_limit" => CapacityLimit(num(value)?),
+            "min_checkpoint_interval" => MinCheckpointInterval(num(value)?),
+            "max_checkpoint_interval" => MaxCheckpointInterval(num(value)?),
+            "max_inputs" => MaxInputs(num(value)?),
+            "max_outputs" => MaxOutputs(num(value)?),
+            "target_checkpoint_inclusion" => TargetCheckpointInclusion(num(value)?),
+            "min_fee_rate" => MinFeeRate(num(value)?),
+            "max_fee_rate" => MaxFeeRate(num(value)?),
+            "sigset_threshold" => {
+                let (numerator, denominator) = value.split_once('/').ok_or_else(|| {
+                    OrgaError::App("Threshold must be given as a fraction".to_string())
+                })?;
+                SigsetThreshold(num(numerator)?, num(denominator)?)
+            }
+            "emergency_disbursal_lock_time_interval" => {
+                EmergencyDisbursalLockTimeInterval(num(value)?)
+            }
+            "taproot_outputs" => TaprootOutputs(num(value)?),
+            "header_queue_max_length" => HeaderQueueMaxLength(num(value)?),
+            "bridge_paused" => BridgePaused(num(value)?),
+            _ => {
+                return Err(OrgaError::App(format!("Unknown parameter: {}", name)).into());
+            }
+        })
+    }
+
+    /// Checks that the change leaves the configuration in a usable state.
+    pub fn validate(&self, bitcoin: &Bitcoin) -> Result<()> {
+        use ParamChange::*;
+
+        let cp_config = &bitcoin.checkpoints.config;
+        let valid = match *self {
+            MinConfirmations(n) | MinCheckpointConfirmations(n) => n > 0,
+            MinCheckpointInterval(n) => n < cp_config.max_checkpoint_interval,
+            MaxCheckpointInterval(n) => n > cp_config.min_checkpoint_interval,
+            MaxInputs(n) | MaxOutputs(n) | HeaderQueueMaxLength(n) => n > 0,
+            TargetCheckpointInclusion(n) => n > 0,
+            MinFeeRate(n) => n > 0 && n <= cp_config.max_fee_rate,
+            MaxFeeRate(n) => n >= cp_config.min_fee_rate,
+            // signatories must never be able to spend with less than 2/3 of
+            // the voting power
+            SigsetThreshold(numerator, denominator) => {
+                numerator < denominator && numerator as u128 * 3 >= denominator as u128 * 2
+            }
+            _ => true,
+        };
+
+        if !valid {
+            return Err(OrgaError::App(format!("Invalid parameter change: {:?}", self)).into());
+        }
+
+        Ok(())
+    }
+
+    pub fn apply(&self, bitcoin: &mut Bitcoin) -> Result<()> {
+        use ParamChange::*;
+
+        self.validate(bitcoin)?;
+
+        let config = &mut bitcoin.config;
+        let cp_config = &mut bitcoin.checkpoints.config;
+        match *self {
+            MinDepositAmount(n) => config.min_deposit_amount = n,
+            MinWithdrawalAmount(n) => config.min_withdrawal_amount = n,
+            TransferFee(n) => config.transfer_fee = n,
+            MinConfirmations(n) => config.min_confirmations = n,
+            MaxOfflineCheckpoints(n) => config.max_offline_checkpoints = n,
+            MinCheckpointConfirmations(n) => config.min_checkpoint_confirmations = n,
+            CapacityLimit(n) => config.capacity_limit = n,
+            MinCheckpointInterval(n) => cp_config.min_checkpoint_interval = n,
+            MaxCheckpointInterval(n) => cp_config.max_checkpoint_interval = n,
+            MaxInputs(n) => cp_config.max_inputs = n,
+            MaxOutputs(n) => cp_config.max_outputs = n,
+            TargetCheckpointInclusion(n) => cp_config.target_checkpoint_inclusion = n,
+            MinFeeRate(n) => cp_config.min_fee_rate = n,
+            MaxFeeRate(n) => cp_config.max_fee_rate = n,
+            SigsetThreshold(numerator, denominator) => {
+                cp_config.sigset_threshold = (numerator, denominator)
+            }
+            EmergencyDisbursalLockTimeInterval(n) => {
+                cp_config.emergency_disbursal_lock_time_interval = n
+            }
+            TaprootOutputs(enabled) => cp_config.taproot_outputs = enabled,
+            HeaderQueueMaxLength(n) => bitcoin.headers.set_max_length(n),
+            BridgePaused(paused) => bitcoin.set_paused(paused)?,
+        }
+
+        Ok(())
+    }
+}
+
+impl Migrate for ParamChange {}
+
+// TODO: make it easy to derive State for simple types like this
+impl State for ParamChange {
+    #[inline]
+    fn attach(&mut self, _: Store) -> OrgaResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn flush<W: std::io::Write>(self, out: &mut W) -> OrgaResult<()> {
+        Ok(self.encode_into(out)?)
+    }
+
+    fn load(_store: Store, bytes: &mut &[u8]) -> OrgaResult<Self> {
+        Ok(Self::decode(bytes)?)
+    }
+}
+
+impl Query for ParamChange {
+    type Query = ();
+
+    fn query(&self, _: ()) -> OrgaResult<()> {
+        Ok(())
+    }
+}
+
+impl Call for ParamChange {
+    type Call = ();
+
+    fn call(&mut self, _: ()) -> OrgaResult<()> {
+        Ok(())
+    }
+}
+
+impl Describe for ParamChange {
+    fn describe() -> orga::describe::Descriptor {
+        orga::describe::Builder::new::<Self>().build()
+    }
+}
+
+#[derive(Debug, Encode, Decode, Default, Serialize, Deserialize, PartialEq, Eq, Clone, Copy)]
+pub enum ProposalStatus {
+    #[default]
+    Voting,
+    /// The proposal passed and its change will be applied at `execute_at`.
+    Passed,
+    Rejected,
+    Executed,
+    /// The proposal passed, but its change was no longer valid when it was
+    /// due to be applied.
+    Failed,
+}
+
+impl Migrate for ProposalStatus {}
+
+impl State for ProposalStatus {
+    #[inline]
+    fn attach(&mut self, _: Store) -> OrgaResult<()> {
+        Ok(())
+    }
+
+    #[inline]
+    fn flush<W: std::io::Write>(self, out: &mut W) -> OrgaResult<()> {
+        Ok(self.encode_into(out)?)
+    }
+
+    fn load(_store: Store, bytes: &mut &[u8]) -> OrgaResult<Self> {
+        Ok(Self::decode(bytes)?)
+    }
+}
+
+impl Query for ProposalStatus {
+    type Query = ();
+
+    fn query(&self, _: ()) -> OrgaResult<()> {
+        Ok(())
+    }
+}
+
+impl Call for ProposalStatus {
+    type Call = ();
+
+    fn call(&mut self, _: ()) -> OrgaResult<()> {
+        Ok(())
+    }
+}
+
+impl Describe for ProposalStatus {
+    fn describe() -> orga::describe::Descriptor {
+        orga::describe::Builder::new::<Self>().build()
+    }
+}
+
+#[orga(skip(Default))]
+#[derive(Clone)]
+pub struct Proposal {
+    pub proposer: Address,
+    pub change: ParamChange,
+    pub status: ProposalStatus,
+    pub voting_end: u64,
+    pub execute_at: u64,
+    /// The stake approving and rejecting the proposal, set when voting ends.
+    pub yes: u64,
+    pub no: u64,
+    /// The number of addresses which have voted.
+    pub votes: u64,
+}
+
+/// Staking-weighted proposals for changing bridge parameters. Votes are
+/// weighted by the voter's staked tokens at the end of the voting period.
+#[orga]
+pub struct ParamGovernance {
+    next_id: u64,
+    /// The number of proposals open for voting.
+    open: u64,
+    proposals: Map<u64, Proposal>,
+    votes: Map<(u64, Address), bool>,
+    voting: Map<(u64, u64), ()>,
+    queued: Map<(u64, u64), ()>,
+}
+
+fn staked(staking: &Staking<Nom>, address: Address) -> Result<u64> {
+    let mut total = 0u64;
+    for (_, delegation) in staking.delegations(address)? {
+        let staked: u64 = delegation.staked.into();
+        total += staked;
+    }
+
+    Ok(total)
+}
+
+#[orga]
+impl ParamGovernance {
+    pub fn propose(
+        &mut self,
+        proposer: Address,
+        change: ParamChange,
+        now: u64,
+        staking: &Staking<Nom>,
+        bitcoin: &Bitcoin,
+    ) -> Result<u64> {
+        if staked(staking, proposer)? < MIN_PROPOSER_STAKE {
+            return Err(OrgaError::App(format!(
+                "Proposer must have at least {} staked",
+                MIN_PROPOSER_STAKE
+            ))
+            .into());
+        }
+        if self.open >= MAX_OPEN_PROPOSALS {
+            return Err(OrgaError::App("Too many open proposals".to_string()).into());
+        }
+        if self.open_by(proposer)? >= MAX_OPEN_PROPOSALS_PER_PROPOSER {
+            return Err(OrgaError::App("Proposer has too many open proposals".to_string()).into());
+        }
+        change.validate(bitcoin)?;
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let voting_end = now + VOTING_PERIOD;
+        self.proposals.insert(
+            id,
+            Proposal {
+                proposer,
+                change,
+                status: ProposalStatus::Voting,
+                voting_end,
+                execute_at: voting_end + EXECUTION_DELAY,
+                yes: 0,
+                no: 0,
+                votes: 0,
+            },
+        )?;
+        self.voting.insert((voting_end, id), ())?;
+        self.open += 1;
+
+        Ok(id)
+    }
+
+    pub fn vote(
+        &mut self,
+        voter: Address,
+        id: u64,
+        approve: bool,
+        staking: &Staking<Nom>,
+    ) -> Result<()> {
+        if staked(staking, voter)? < MIN_VOTER_STAKE {
+            return Err(OrgaError::App(format!(
+                "Voter must have at least {} staked",
+                MIN_VOTER_STAKE
+            ))
+            .into());
+        }
+
+        let mut proposal = self
+            .proposals
+            .get_mut(id)?
+            .ok_or_else(|| OrgaError::App("Proposal not found".to_string()))?;
+        if proposal.status != ProposalStatus::Voting {
+            return Err(OrgaError::App("Voting has ended for proposal".to_string()).into());
+        }
+
+        // changing a vote doesn't count against the limit
+        if self.votes.get((id, voter))?.is_none() {
+            if proposal.votes >= MAX_VOTES {
+                return Err(OrgaError::App("Proposal has too many votes".to_string()).into());
+            }
+            proposal.votes += 1;
+        }
+
+        self.votes.insert((id, voter), approve)?;
+
+        Ok(())
+    }
+
+    /// Tallies the proposals whose voting period has ended, and returns the
+    /// changes of passed proposals which are due to be applied, removing them
+    /// from the queue. The caller reports the outcome with
+    /// [`Self::set_executed`].
+    pub fn step(&mut self, now: u64, staking: &Staking<Nom>) -> Result<Vec<(u64, ParamChange)>> {
+        let ended = self
+            .voting
+            .range(..(now, 0))?
+            .map(|entry| entry.map(|(key, _)| *key))
+            .collect::<OrgaResult<Vec<_>>>()?;
+
+        // stake is only weighed for proposals whose voting has just ended
+        let total_staked: u64 = if ended.is_empty() {
+            0
+        } else {
+            staking.staked()?.into()
+        };
+        for key in ended {
+            self.voting.remove(key)?;
+            self.open -= 1;
+            let id = key.1;
+
+            let votes = self
+                .votes
+                .range((id, Address::NULL)..)?
+                .take_while(|entry| entry.as_ref().map_or(true, |(key, _)| key.0 == id))
+                .map(|entry| entry.map(|(key, approve)| (key.1, *approve)))
+                .collect::<OrgaResult<Vec<_>>>()?;
+
+            let mut yes = 0u64;
+            let mut no = 0u64;
+            for (voter, approve) in votes {
+                let stake = staked(staking, voter)?;
+                if approve {
+                    yes += stake;
+                } else {
+                    no += stake;
+                }
+            }
+
+            let voted = (yes + no) as u128;
+            let quorum = voted * QUORUM.1 as u128 >= total_staked as u128 * QUORUM.0 as u128;
+            let passed =
+                quorum && yes as u128 * PASS_THRESHOLD.1 as u128 > voted * PASS_THRESHOLD.0 as u128;
+
+            let mut proposal = self
+                .proposals
+                .get_mut(id)?
+                .ok_or_else(|| OrgaError::App("Proposal not found".to_string()))?;
+            proposal.yes = yes;
+            proposal.no = no;
+            if passed {
+                proposal.status = ProposalStatus::Passed;
+                self.queued.insert((proposal.execute_at, id), ())?;
+            } else {
+                proposal.status = ProposalStatus::Rejected;
+            }
+        }
+
+        let due = self
+            .queued
+            .range(..(now, 0))?
+            .map(|entry| entry.map(|(key, _)| *key))
+            .collect::<OrgaResult<Vec<_>>>()?;
+        let mut changes = vec![];
+        for key in due {
+            self.queued.remove(key)?;
+            let proposal = self
+                .proposals
+                .get(key.1)?
+                .ok_or_else(|| OrgaError::App("Proposal not found".to_string()))?;
+            changes.push((key.1, proposal.change));
+        }
+
+        Ok(changes)
+    }
+
+    pub fn set_executed(&mut self, id: u64, success: bool) -> Result<()> {
+        let mut proposal = self
+            .proposals
+            .get_mut(id)?
+            .ok_or_else(|| OrgaError::App("Proposal not found".to_string()))?;
+        proposal.status = if success {
+            ProposalStatus::Executed
+        } else {
+            ProposalStatus::Failed
+        };
+
+        Ok(())
+    }
+
+    /// The number of proposals the given address has open for voting.
+    fn open_by(&self, proposer: Address) -> Result<u64> {
+        let mut count = 0;
+        for entry in self.voting.iter()? {
+            let (key, _) = entry?;
+            let proposal = self
+                .proposals
+                .get(key.1)?
+                .ok_or_else(|| OrgaError::App("Proposal not found".to_string()))?;
+            if proposal.proposer == proposer {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    #[query]
+    pub fn proposal(&self, id: u64) -> Result<Option<Proposal>> {
+        Ok(self.proposals.get(id)?.map(|proposal| proposal.clone()))
+    }
+
+    /// Proposals which are being voted on or are waiting to be applied.
+    #[query]
+    pub fn pending(&self) -> Result<Vec<(u64, Proposal)>> {
+        self.filtered(|status| matches!(status, ProposalStatus::Voting | ProposalStatus::Passed))
+    }
+
+    /// Proposals which have been rejected, applied, or failed to apply.
+    #[query]
+    pub fn past(&self) -> Result<Vec<(u64, Proposal)>> {
+        self.filtered(|status| !matches!(status, ProposalStatus::Voting | ProposalStatus::Passed))
+    }
+
+    #[query]
+    pub fn vote_of(&self, id: u64, voter: Address) -> Result<Option<bool>> {
+        Ok(self.votes.get((id, voter))?.map(|approve| *approve))
+    }
+
+    fn filtered(&self, f: impl Fn(ProposalStatus) -> bool) -> Result<Vec<(u64, Proposal)>> {
+        let mut proposals = vec![];
+        for entry in self.proposals.iter()? {
+            let (id, proposal) = entry?;
+            if f(proposal.status) {
+                proposals.push((*id, proposal.clone()));
+            }
+        }
+
+        Ok(proposals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_param_change() {
+        assert_eq!(
+            ParamChange::parse("capacity_limit", "100").unwrap(),
+            ParamChange::CapacityLimit(100)
+        );
+        assert_eq!(
+            ParamChange::parse("sigset_threshold", "3/4").unwrap(),
+            ParamChange::SigsetThreshold(3, 4)
+        );
+        assert_eq!(
+            ParamChange::parse("bridge_paused", "false").unwrap(),
+            ParamChange::BridgePaused(false)
+        );
+        assert!(ParamChange::parse("sigset_threshold", "3").is_err());
+        assert!(ParamChange::parse("max_fee_rate", "-1").is_err());
+        assert!(ParamChange::parse("unknown", "1").is_err());
+    }
+
+    #[test]
+    fn validate_param_change() {
+        let bitcoin = Bitcoin::default();
+        assert!(ParamChange::SigsetThreshold(2, 3)
+            .validate(&bitcoin)
+            .is_ok());
+        assert!(ParamChange::SigsetThreshold(1, 2)
+            .validate(&bitcoin)
+            .is_err());
+        assert!(ParamChange::SigsetThreshold(4, 4)
+            .validate(&bitcoin)
+            .is_err());
+        assert!(ParamChange::SigsetThreshold(u64::MAX - 1, u64::MAX)
+            .validate(&bitcoin)
+            .is_ok());
+        assert!(ParamChange::MinConfirmations(0).validate(&bitcoin).is_err());
+        assert!(ParamChange::MaxFeeRate(1).validate(&bitcoin).is_err());
+        assert!(ParamChange::CapacityLimit(0).validate(&bitcoin).is_ok());
+    }
+}
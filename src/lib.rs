@@ -20,8 +20,11 @@ pub mod app;
 pub mod bitcoin;
 pub mod cosmos;
 pub mod error;
+pub mod governance;
 pub mod incentives;
 #[cfg(feature = "full")]
+pub mod metrics;
+#[cfg(feature = "full")]
 pub mod network;
 #[cfg(feature = "full")]
 pub mod utils;
@@ -0,0 +1,241 @@
+//! Prometheus metrics for the node, relayer and signer processes, served over
+//! HTTP at `/metrics` when a process is started with `--metrics-addr`.
+//!
+//! The metrics live in a process-wide registry, so the relayer and signer
+//! loops update them directly. Metrics describing the chain's Bitcoin state
+//! are refreshed by [`poll_chain`].
+
+use crate::app_client;
+use crate::error::Result;
+use log::{error, info};
+use prometheus::{
+    Encoder, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use warp::Filter;
+
+/// How often [`poll_chain`] queries the chain.
+const POLL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+pub struct Metrics {
+    registry: Registry,
+    /// The number of blocks `app.bitcoin.headers` is behind the relayer's
+    /// Bitcoin source.
+    pub header_lag: IntGauge,
+    /// The number of deposit scripts the relayer watches.
+    pub watched_scripts: IntGauge,
+    pub deposits_relayed: IntCounter,
+    /// The index of the latest checkpoint with each status.
+    pub checkpoint_index: IntGaugeVec,
+    pub checkpoints_unconfirmed: IntGauge,
+    /// The fee rate of the building checkpoint, in satoshis per virtual byte.
+    pub checkpoint_fee_rate: IntGauge,
+    pub value_locked: IntGauge,
+    pub signatures_submitted: IntCounter,
+    /// Errors returned by each relayer or signer loop, labeled by loop name.
+    pub loop_errors: IntCounterVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new();
+
+        let header_lag = IntGauge::new(
+            "nomic_header_lag",
+            "Blocks the sidechain's Bitcoin headers are behind the relayer's Bitcoin source",
+        )
+        .unwrap();
+        let watched_scripts = IntGauge::new(
+            "nomic_watched_scripts",
+            "Deposit scripts watched by the relayer",
+        )
+        .unwrap();
+        let deposits_relayed = IntCounter::new(
+            "nomic_deposits_relayed_total",
+            "Deposits relayed to the sidechain",
+        )
+        .unwrap();
+        let checkpoint_index = IntGaugeVec::new(
+            Opts::new(
+                "nomic_checkpoint_index",
+                "Index of the latest checkpoint with each status",
+            ),
+            &["status"],
+        )
+        .unwrap();
+        let checkpoints_unconfirmed = IntGauge::new(
+            "nomic_checkpoints_unconfirmed",
+            "Completed checkpoints not yet confirmed on the Bitcoin chain",
+        )
+        .unwrap();
+        let checkpoint_fee_rate = IntGauge::new(
+            "nomic_checkpoint_fee_rate",
+            "Fee rate of the building checkpoint, in sats per vbyte",
+        )
+        .unwrap();
+        let value_locked = IntGauge::new(
+            "nomic_value_locked_sats",
+            "Value of the reserve output of the last completed checkpoint",
+        )
+        .unwrap();
+        let signatures_submitted = IntCounter::new(
+            "nomic_signatures_submitted_total",
+            "Checkpoint signatures submitted by the signer",
+        )
+        .unwrap();
+        let loop_errors = IntCounterVec::new(
+            Opts::new(
+                "nomic_loop_errors_total",
+                "Errors returned by each relayer or signer loop",
+            ),
+            &["loop"],
+        )
+        .unwrap();
+
+        registry.register(Box::new(header_lag.clone())).unwrap();
+        registry
+            .register(Box::new(watched_scripts.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(deposits_relayed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(checkpoint_index.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(checkpoints_unconfirmed.clone()))
+            .unwrap();
+        registry
+            .register(Box::new(checkpoint_fee_rate.clone()))
+            .unwrap();
+        registry.register(Box::new(value_locked.clone())).unwrap();
+        registry
+            .register(Box::new(signatures_submitted.clone()))
+            .unwrap();
+        registry.register(Box::new(loop_errors.clone())).unwrap();
+
+        Metrics {
+            registry,
+            header_lag,
+            watched_scripts,
+            deposits_relayed,
+            checkpoint_index,
+            checkpoints_unconfirmed,
+            checkpoint_fee_rate,
+            value_locked,
+            signatures_submitted,
+            loop_errors,
+        }
+    }
+
+    /// Counts an error returned by the named loop.
+    pub fn loop_error(&self, name: &str) {
+        self.loop_errors.with_label_values(&[name]).inc();
+    }
+
+    /// Encodes all metrics in the Prometheus text format.
+    pub fn encode(&self) -> String {
+        let mut buf = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+/// Returns the process-wide metrics.
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+/// Serves the metrics at `/metrics` on `addr`.
+pub async fn serve(addr: SocketAddr) {
+    let route = warp::path("metrics").and(warp::get()).map(|| {
+        warp::reply::with_header(
+            metrics().encode(),
+            "content-type",
+            TextEncoder::new().format_type(),
+        )
+    });
+
+    info!("Serving metrics at http://{}/metrics", addr);
+    warp::serve(route).run(addr).await;
+}
+
+/// Periodically updates the checkpoint and reserve metrics from the node at
+/// `app_client_addr`.
+pub async fn poll_chain(app_client_addr: String) {
+    loop {
+        if let Err(err) = update_chain_metrics(&app_client_addr).await {
+            error!("Metrics query error: {}", err);
+        }
+
+        tokio::time::sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn update_chain_metrics(app_client_addr: &str) -> Result<()> {
+    let (index, signing, completed, unconfirmed, fee_rate, value_locked) =
+        app_client(app_client_addr)
+            .query(|app| {
+                let checkpoints = &app.bitcoin.checkpoints;
+                let completed = checkpoints.last_completed_index().ok();
+                let value_locked = match completed {
+                    Some(_) => Some(app.bitcoin.value_locked()?),
+                    None => None,
+                };
+
+                Ok((
+                    checkpoints.index(),
+                    checkpoints.signing()?.is_some(),
+                    completed,
+                    checkpoints.num_unconfirmed()?,
+                    checkpoints.building()?.fee_rate,
+                    value_locked,
+                ))
+            })
+            .await?;
+
+    let m = metrics();
+    m.checkpoint_index
+        .with_label_values(&["building"])
+        .set(index as i64);
+    if signing {
+        m.checkpoint_index
+            .with_label_values(&["signing"])
+            .set(index as i64 - 1);
+    }
+    if let Some(completed) = completed {
+        m.checkpoint_index
+            .with_label_values(&["complete"])
+            .set(completed as i64);
+    }
+    m.checkpoints_unconfirmed.set(unconfirmed as i64);
+    m.checkpoint_fee_rate.set(fee_rate as i64);
+    if let Some(value_locked) = value_locked {
+        m.value_locked.set(value_locked as i64);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        let m = metrics();
+        m.deposits_relayed.inc();
+        m.loop_error("test");
+        m.checkpoint_index.with_label_values(&["building"]).set(12);
+
+        let text = m.encode();
+        assert!(text.contains("nomic_deposits_relayed_total"));
+        assert!(text.contains("nomic_loop_errors_total{loop=\"test\"} 1"));
+        assert!(text.contains("nomic_checkpoint_index{status=\"building\"} 12"));
+        assert!(text.contains("# TYPE nomic_header_lag gauge"));
+    }
+}
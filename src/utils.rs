@@ -9,6 +9,8 @@ use crate::bitcoin::checkpoint::Config as CheckpointQueueConfig;
 use crate::bitcoin::header_queue::Config as HeaderQueueConfig;
 #[cfg(feature = "full")]
 use crate::bitcoin::signer::Signer;
+#[cfg(feature = "full")]
+use crate::bitcoin::BitcoinNetwork;
 use crate::bitcoin::Config as BitcoinConfig;
 use crate::error::{Error, Result};
 use bitcoin::hashes::hex::ToHex;
@@ -166,10 +168,12 @@ pub fn setup_chain_id_context(chain_id: String) {
 }
 
 #[cfg(feature = "full")]
-pub fn test_bitcoin_client(bitcoind: &BitcoinD) -> BitcoinRpcClient {
+pub fn test_bitcoin_client(bitcoind: &BitcoinD) -> std::sync::Arc<BitcoinRpcClient> {
     let bitcoind_url = bitcoind.rpc_url();
     let bitcoin_cookie_file = bitcoind.params.cookie_file.clone();
-    BitcoinRpcClient::new(&bitcoind_url, Auth::CookieFile(bitcoin_cookie_file)).unwrap()
+    let client =
+        BitcoinRpcClient::new(&bitcoind_url, Auth::CookieFile(bitcoin_cookie_file)).unwrap();
+    std::sync::Arc::new(client)
 }
 
 pub fn address_from_privkey(privkey: &SecretKey) -> Address {
@@ -192,6 +196,7 @@ where
     Signer::load_or_generate(
         address_from_privkey(&load_privkey(home.as_ref()).unwrap()),
         key_path,
+        BitcoinNetwork::Regtest,
         0.1,
         1.0,
         client,
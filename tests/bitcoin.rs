@@ -59,7 +59,7 @@ async fn generate_deposit_address(address: &Address) -> Result<DepositAddress> {
         .query(|app| {
             Ok((
                 app.bitcoin.checkpoints.active_sigset()?,
-                app.bitcoin.checkpoints.config.sigset_threshold,
+                app.bitcoin.checkpoints.active_sigset_threshold()?,
             ))
         })
         .await?;
@@ -1036,7 +1036,7 @@ async fn signing_pruned_checkpoint_test() {
                     .get(0)?
                     .to_sign(slashable_signer_xpub)?
                     .iter()
-                    .map(|(msg, _)| {
+                    .map(|(msg, _, _)| {
                         Ok(secp
                             .sign_ecdsa(
                                 &Message::from_slice(&msg[..])?,
@@ -1072,7 +1072,11 @@ async fn signing_pruned_checkpoint_test() {
         let _res = app_client()
             .with_wallet(signer_wallet)
             .call(
-                move |app| build_call!(app.bitcoin.sign(slashable_signer_xpub, sigs.clone(), 0)),
+                move |app| {
+                    build_call!(app
+                        .bitcoin
+                        .sign(slashable_signer_xpub, sigs.clone(), 0, None))
+                },
                 |app| build_call!(app.app_noop()),
             )
             .await;
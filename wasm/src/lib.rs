@@ -11,7 +11,7 @@ use std::str::FromStr;
 // use crate::web_client::WebClient;
 use js_sys::{Array, Uint8Array};
 use nomic::app::{App, Dest, InnerApp, Nom};
-use nomic::bitcoin::{Nbtc, NETWORK as BITCOIN_NETWORK};
+use nomic::bitcoin::Nbtc;
 use nomic::orga::client::wallet::Unsigned;
 use nomic::orga::client::AppClient;
 use nomic::orga::coins::Address;
@@ -282,7 +282,7 @@ pub async fn get_recovery_address(address: String) -> Result<String, JsError> {
     Ok(app_client()
         .query(|app| {
             Ok(match app.bitcoin.recovery_scripts.get(address)? {
-                Some(script) => bitcoin::Address::from_script(&script, BITCOIN_NETWORK)
+                Some(script) => bitcoin::Address::from_script(&script, app.bitcoin.network())
                     .map_err(|e| OrgaError::App(format!("{:?}", e)))?
                     .to_string(),
                 None => "".to_string(),
@@ -421,21 +421,22 @@ pub async fn gen_deposit_addr(dest_addr: String) -> Result<DepositAddress, JsErr
         .parse()
         .map_err(|e| Error::Wasm(format!("{:?}", e)))?;
 
-    let (sigset, threshold) = app_client()
+    let (sigset, threshold, config, network) = app_client()
         .query(|app: InnerApp| {
             Ok((
                 app.bitcoin.checkpoints.active_sigset()?,
-                app.bitcoin.checkpoints.config.sigset_threshold,
+                app.bitcoin.checkpoints.active_sigset_threshold()?,
+                app.bitcoin.checkpoints.config.clone(),
+                app.bitcoin.network(),
             ))
         })
         .await?;
-    let script = sigset.output_script(
-        Dest::Address(dest_addr).commitment_bytes()?.as_slice(),
+    let script = config.output_script(
+        &sigset,
         threshold,
+        Dest::Address(dest_addr).commitment_bytes()?.as_slice(),
     )?;
-    // TODO: get network from somewhere
-    // TODO: make test/mainnet option configurable
-    let btc_addr = bitcoin::Address::from_script(&script, BITCOIN_NETWORK)?;
+    let btc_addr = bitcoin::Address::from_script(&script, network)?;
 
     Ok(DepositAddress {
         address: btc_addr.to_string(),
@@ -580,6 +581,24 @@ pub async fn withdraw(address: String, dest_addr: String, amount: u64) -> Result
     .await
 }
 
+#[wasm_bindgen(js_name = cancelWithdrawal)]
+pub async fn cancel_withdrawal(address: String, id: u64) -> Result<String, JsError> {
+    let mut value = serde_json::Map::new();
+    value.insert("id".to_string(), id.to_string().into());
+
+    let address = address
+        .parse()
+        .map_err(|e| Error::Wasm(format!("{:?}", e)))?;
+    gen_call_bytes(
+        address,
+        sdk::Msg {
+            type_: "nomic/MsgCancelWithdrawal".to_string(),
+            value: value.into(),
+        },
+    )
+    .await
+}
+
 #[wasm_bindgen(js_name = joinRewardAccounts)]
 pub async fn join_reward_accounts(
     source_address: String,